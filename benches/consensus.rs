@@ -0,0 +1,103 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Benchmarks for whole networks of nodes reaching consensus over the in-memory `simulator`
+//! network, which signs and verifies every message the same way a real network does; run with
+//! `cargo bench --features simulation`
+
+#[macro_use]
+extern crate criterion;
+extern crate sawtooth_pbft;
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
+use sawtooth_pbft::simulator::SimulatedNetwork;
+use sawtooth_pbft::state::PbftMode;
+
+/// How many nodes are in the networks that are benchmarked
+const NETWORK_SIZES: &[usize] = &[4, 7, 10, 16];
+
+/// How many blocks each consensus benchmark commits
+const BLOCKS: u64 = 3;
+
+/// The most steps a network can take to do what it's benchmarked doing
+const MAX_STEPS: usize = 200_000;
+
+const SEED: u64 = 1;
+
+/// Committing blocks, from the primary publishing each one to every node committing it
+fn consensus_rounds(c: &mut Criterion) {
+    let mut group = c.benchmark_group("consensus_rounds");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(BLOCKS));
+    for num_nodes in NETWORK_SIZES {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_nodes),
+            num_nodes,
+            |b, &num_nodes| {
+                b.iter_batched(
+                    || SimulatedNetwork::new(num_nodes, SEED),
+                    |mut sim| {
+                        assert!(sim.run_until_height(BLOCKS, MAX_STEPS));
+                        sim
+                    },
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Replacing a primary that crashed, from the other nodes' view change timeouts expiring to all
+/// of them being in the new view
+fn view_change(c: &mut Criterion) {
+    let mut group = c.benchmark_group("view_change");
+    group.sample_size(10);
+    for num_nodes in NETWORK_SIZES {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_nodes),
+            num_nodes,
+            |b, &num_nodes| {
+                b.iter_batched(
+                    || {
+                        let mut sim = SimulatedNetwork::new(num_nodes, SEED);
+                        assert!(sim.run_until_height(1, MAX_STEPS));
+                        sim.crash(0);
+                        sim
+                    },
+                    |mut sim| {
+                        for node in sim.live_nodes() {
+                            sim.expire_timeout(node);
+                        }
+                        assert!(sim.run_until(MAX_STEPS, |sim| {
+                            sim.live_nodes().iter().all(|node| {
+                                let state = &sim.nodes[*node].state;
+                                state.view == 1 && state.mode == PbftMode::Normal
+                            })
+                        }));
+                        sim
+                    },
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, consensus_rounds, view_change);
+criterion_main!(benches);
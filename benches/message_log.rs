@@ -0,0 +1,209 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Benchmarks for the `PbftLog` operations that nodes do for every message, with logs as full as
+//! they get right before a checkpoint
+
+#[macro_use]
+extern crate criterion;
+extern crate sawtooth_pbft;
+extern crate sawtooth_sdk;
+
+use criterion::{black_box, BatchSize, BenchmarkId, Criterion, Throughput};
+use sawtooth_pbft::config::PbftConfig;
+use sawtooth_pbft::handlers::make_msg_info;
+use sawtooth_pbft::message_log::PbftLog;
+use sawtooth_pbft::message_type::PbftMessageType;
+use sawtooth_pbft::protos::pbft_message::{PbftBlock, PbftMessage};
+use sawtooth_sdk::consensus::engine::PeerId;
+
+/// How many nodes are in the networks whose logs are benchmarked
+const NETWORK_SIZES: &[u64] = &[4, 7, 10, 16];
+
+/// The default configuration, which reaches a checkpoint every 100 blocks
+fn config() -> PbftConfig {
+    PbftConfig::default()
+}
+
+fn peer_id(node: u64) -> PeerId {
+    PeerId::from(format!("node {}", node).into_bytes())
+}
+
+/// A message from `signer` about the block at `seq_num`, in view 0
+fn message(msg_type: &PbftMessageType, seq_num: u64, signer: u64) -> PbftMessage {
+    let mut block = PbftBlock::new();
+    if *msg_type != PbftMessageType::Checkpoint {
+        block.set_block_id(format!("block {}", seq_num).into_bytes());
+        block.set_signer_id(Vec::<u8>::from(peer_id(0)));
+        block.set_block_num(seq_num);
+    }
+
+    let mut msg = PbftMessage::new();
+    msg.set_info(make_msg_info(msg_type, 0, seq_num, peer_id(signer)));
+    msg.set_block(block);
+    msg
+}
+
+/// The log of a node in a network of `num_nodes` nodes, right before the checkpoint at the end of
+/// a checkpoint period: every block since the last checkpoint has its `BlockNew`, the primary's
+/// `PrePrepare`, and a `Prepare` and `Commit` from every node, and every node has sent a
+/// `Checkpoint` for the last block
+fn full_log(num_nodes: u64) -> PbftLog {
+    let config = config();
+    let mut log = PbftLog::new(&config);
+    for seq_num in 1..=config.checkpoint_period {
+        log.add_message(message(&PbftMessageType::BlockNew, seq_num, 0))
+            .unwrap();
+        log.add_message(message(&PbftMessageType::PrePrepare, seq_num, 0))
+            .unwrap();
+        for node in 0..num_nodes {
+            log.add_message(message(&PbftMessageType::Prepare, seq_num, node))
+                .unwrap();
+            log.add_message(message(&PbftMessageType::Commit, seq_num, node))
+                .unwrap();
+        }
+    }
+    for node in 0..num_nodes {
+        log.add_message(message(
+            &PbftMessageType::Checkpoint,
+            config.checkpoint_period,
+            node,
+        ))
+        .unwrap();
+    }
+    log
+}
+
+/// How many messages are in a full log
+fn log_size(num_nodes: u64) -> u64 {
+    config().checkpoint_period * (2 + 2 * num_nodes) + num_nodes
+}
+
+fn f(num_nodes: u64) -> u64 {
+    (num_nodes - 1) / 3
+}
+
+fn add_message(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add_message");
+    for num_nodes in NETWORK_SIZES {
+        let seq_num = config().checkpoint_period + 1;
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_nodes),
+            num_nodes,
+            |b, &num_nodes| {
+                b.iter_batched_ref(
+                    || full_log(num_nodes),
+                    |log| log.add_message(message(&PbftMessageType::Prepare, seq_num, 0)),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+/// The scan over the whole log that every quorum check does
+fn get_messages_of_type(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_messages_of_type");
+    for num_nodes in NETWORK_SIZES {
+        let log = full_log(*num_nodes);
+        let seq_num = config().checkpoint_period / 2;
+        group.throughput(Throughput::Elements(log_size(*num_nodes)));
+        group.bench_function(BenchmarkId::from_parameter(num_nodes), |b| {
+            b.iter(|| {
+                log.get_messages_of_type(&PbftMessageType::Prepare, black_box(seq_num), 0)
+                    .len()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn prepared(c: &mut Criterion) {
+    let mut group = c.benchmark_group("prepared");
+    for num_nodes in NETWORK_SIZES {
+        let log = full_log(*num_nodes);
+        let msg = message(&PbftMessageType::Prepare, config().checkpoint_period / 2, 1);
+        log.prepared(&msg, f(*num_nodes))
+            .expect("Block in full log isn't prepared");
+        group.bench_function(BenchmarkId::from_parameter(num_nodes), |b| {
+            b.iter(|| log.prepared(black_box(&msg), f(*num_nodes)))
+        });
+    }
+    group.finish();
+}
+
+fn committed(c: &mut Criterion) {
+    let mut group = c.benchmark_group("committed");
+    for num_nodes in NETWORK_SIZES {
+        let log = full_log(*num_nodes);
+        let msg = message(&PbftMessageType::Commit, config().checkpoint_period / 2, 1);
+        log.committed(&msg, f(*num_nodes))
+            .expect("Block in full log isn't committed");
+        group.bench_function(BenchmarkId::from_parameter(num_nodes), |b| {
+            b.iter(|| log.committed(black_box(&msg), f(*num_nodes)))
+        });
+    }
+    group.finish();
+}
+
+/// The check that a checkpoint is stable
+fn check_msg_against_log(c: &mut Criterion) {
+    let mut group = c.benchmark_group("check_msg_against_log");
+    for num_nodes in NETWORK_SIZES {
+        let log = full_log(*num_nodes);
+        let msg = message(&PbftMessageType::Checkpoint, config().checkpoint_period, 1);
+        let cutoff = 2 * f(*num_nodes) + 1;
+        log.check_msg_against_log(&&msg, true, cutoff)
+            .expect("Checkpoint in full log isn't stable");
+        group.bench_function(BenchmarkId::from_parameter(num_nodes), |b| {
+            b.iter(|| log.check_msg_against_log(&&msg, true, black_box(cutoff)))
+        });
+    }
+    group.finish();
+}
+
+/// Garbage collecting everything before the checkpoint at the end of a checkpoint period
+fn garbage_collect(c: &mut Criterion) {
+    let mut group = c.benchmark_group("garbage_collect");
+    for num_nodes in NETWORK_SIZES {
+        group.throughput(Throughput::Elements(log_size(*num_nodes)));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_nodes),
+            num_nodes,
+            |b, &num_nodes| {
+                b.iter_batched_ref(
+                    || full_log(num_nodes),
+                    |log| log.garbage_collect(config().checkpoint_period, 0),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    add_message,
+    get_messages_of_type,
+    prepared,
+    committed,
+    check_msg_against_log,
+    garbage_collect
+);
+criterion_main!(benches);
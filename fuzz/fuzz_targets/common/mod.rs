@@ -0,0 +1,127 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! A node for the fuzz targets to send peer messages to
+
+// Not every fuzz target uses everything in here
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use hex;
+use serde_json;
+
+use sawtooth_pbft::message_type::PbftMessageType;
+use sawtooth_pbft::node::PbftNode;
+use sawtooth_pbft::replay::Replay;
+use sawtooth_pbft::signing::PbftSigner;
+use sawtooth_pbft::trace::Trace;
+use sawtooth_sdk::consensus::engine::{
+    Block, BlockId, PeerId, PeerInfo, PeerMessage, StartupState,
+};
+use sawtooth_sdk::signing::secp256k1::Secp256k1PrivateKey;
+
+/// How many nodes are in the network
+pub const NUM_NODES: u64 = 4;
+
+/// Every message type, in the order that `message_type` picks them
+const MESSAGE_TYPES: &[PbftMessageType] = &[
+    PbftMessageType::PrePrepare,
+    PbftMessageType::Prepare,
+    PbftMessageType::Commit,
+    PbftMessageType::BlockNew,
+    PbftMessageType::Checkpoint,
+    PbftMessageType::ViewChange,
+    PbftMessageType::NewView,
+    PbftMessageType::NetworkChange,
+    PbftMessageType::CatchUpRequest,
+    PbftMessageType::CatchUp,
+];
+
+/// The signer for node `num`; the nodes' private keys are just the numbers 1 through
+/// `NUM_NODES`, so that the fuzz targets can sign messages from any of them
+pub fn signer(num: u64) -> PbftSigner {
+    let private_key = Secp256k1PrivateKey::from_hex(&format!("{:064x}", num + 1))
+        .expect("Small numbers are valid private keys");
+    PbftSigner::new(Box::new(private_key))
+}
+
+/// Node 1 (a secondary) of a network of `NUM_NODES` nodes, in view 0 and connected to all of its
+/// peers, the same way it would be after being started by the engine
+pub fn node() -> PbftNode {
+    let peers: Vec<PeerId> = (0..NUM_NODES)
+        .map(|num| signer(num).get_public_key().unwrap())
+        .collect();
+    let peers_hex: Vec<String> = peers
+        .iter()
+        .map(|peer_id| hex::encode(Vec::<u8>::from(peer_id.clone())))
+        .collect();
+    let mut settings = HashMap::new();
+    settings.insert(
+        String::from("sawtooth.consensus.pbft.peers"),
+        serde_json::to_string(&peers_hex).unwrap(),
+    );
+
+    let trace = Trace {
+        startup_state: StartupState {
+            chain_head: Block {
+                block_id: BlockId::from(vec![0; 4]),
+                previous_id: BlockId::from(vec![]),
+                signer_id: PeerId::from(vec![]),
+                block_num: 0,
+                payload: vec![],
+                summary: vec![],
+            },
+            peers: peers
+                .iter()
+                .filter(|peer_id| **peer_id != peers[1])
+                .map(|peer_id| PeerInfo {
+                    peer_id: peer_id.clone(),
+                })
+                .collect(),
+            local_peer_info: PeerInfo {
+                peer_id: peers[1].clone(),
+            },
+        },
+        settings,
+        start: Duration::from_secs(0),
+        events: vec![],
+    };
+    Replay::new(trace, signer(1)).node
+}
+
+/// Pick a message type with a byte of fuzzer input; some bytes give a type that doesn't exist
+pub fn message_type(byte: u8) -> String {
+    MESSAGE_TYPES
+        .get(byte as usize % (MESSAGE_TYPES.len() + 1))
+        .map(String::from)
+        .unwrap_or_else(|| String::from("Bogus"))
+}
+
+/// Have the node handle a message, and format the result and the node's state the way the engine
+/// would log them, since that can go wrong too
+pub fn deliver(node: &mut PbftNode, message_type: String, content: Vec<u8>) {
+    let res = node.on_peer_message(&PeerMessage {
+        message_type,
+        content,
+    });
+    if let Err(err) = res {
+        let _ = err.to_string();
+    }
+    let _ = node.state.to_string();
+}
@@ -0,0 +1,38 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Feed arbitrary bytes to `PbftNode::on_peer_message` as a message of any type. The first byte
+//! of input picks the type, and the rest is the message's content. Since the bytes won't often be
+//! signed by one of the node's peers, this mostly checks that parsing and verifying untrusted
+//! messages never panics; `signed_peer_messages` gets past the signature checks.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate hex;
+extern crate sawtooth_pbft;
+extern crate sawtooth_sdk;
+extern crate serde_json;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((first, content)) = data.split_first() {
+        let mut node = common::node();
+        common::deliver(&mut node, common::message_type(*first), content.to_vec());
+    }
+});
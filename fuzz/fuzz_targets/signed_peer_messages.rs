@@ -0,0 +1,88 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Feed a sequence of messages from the node's peers to `PbftNode::on_peer_message`. Each message
+//! is a byte that picks its type, a byte that picks which peer it's from, a byte with its length,
+//! and then its content. Content that parses as the message type gets its `signer_id` set to the
+//! peer, and is signed with the peer's key, so that the node handles it the same way it would
+//! handle a message from a faulty peer.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate hex;
+extern crate protobuf;
+extern crate sawtooth_pbft;
+extern crate sawtooth_sdk;
+extern crate serde_json;
+
+mod common;
+
+use protobuf::Message;
+
+use sawtooth_pbft::message_type::PbftMessageType;
+use sawtooth_pbft::protos::pbft_message::{
+    PbftCatchUp, PbftMessage, PbftMessageInfo, PbftNetworkChange, PbftNewView, PbftViewChange,
+};
+use sawtooth_pbft::signing::PbftSigner;
+
+fuzz_target!(|data: &[u8]| {
+    let mut node = common::node();
+    let mut data = data;
+    while data.len() >= 3 {
+        let message_type = common::message_type(data[0]);
+        let signer = common::signer(u64::from(data[1]) % common::NUM_NODES);
+        let len = (data[2] as usize).min(data.len() - 3);
+        let content = &data[3..3 + len];
+        data = &data[3 + len..];
+
+        if let Some(signed) = sign_as(&message_type, content, &signer) {
+            common::deliver(&mut node, message_type, signed);
+        }
+    }
+});
+
+/// Parse the content the same way `signing::verify` does, make it from the signer, and sign it
+fn sign_as(message_type: &str, content: &[u8], signer: &PbftSigner) -> Option<Vec<u8>> {
+    let signer_id = Vec::<u8>::from(signer.get_public_key().ok()?);
+    let msg_bytes = match PbftMessageType::from(message_type) {
+        PbftMessageType::ViewChange => {
+            with_signer::<PbftViewChange>(content, PbftViewChange::mut_info, signer_id)
+        }
+        PbftMessageType::NewView => {
+            with_signer::<PbftNewView>(content, PbftNewView::mut_info, signer_id)
+        }
+        PbftMessageType::NetworkChange => {
+            with_signer::<PbftNetworkChange>(content, PbftNetworkChange::mut_info, signer_id)
+        }
+        PbftMessageType::CatchUp => {
+            with_signer::<PbftCatchUp>(content, PbftCatchUp::mut_info, signer_id)
+        }
+        _ => with_signer::<PbftMessage>(content, PbftMessage::mut_info, signer_id),
+    }?;
+    signer.sign(&msg_bytes).ok()?.write_to_bytes().ok()
+}
+
+fn with_signer<M: Message>(
+    content: &[u8],
+    info: fn(&mut M) -> &mut PbftMessageInfo,
+    signer_id: Vec<u8>,
+) -> Option<Vec<u8>> {
+    let mut msg = protobuf::parse_from_bytes::<M>(content).ok()?;
+    info(&mut msg).set_signer_id(signer_id);
+    msg.write_to_bytes().ok()
+}
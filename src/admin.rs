@@ -0,0 +1,276 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! A local admin endpoint that shows what the node is doing right now
+//!
+//! The node lives on the engine's thread, so the engine publishes a JSON snapshot of the node's
+//! state, log, and peers to a shared `NodeStatus` after every update it handles. The admin server
+//! answers `GET /status` with the latest snapshot.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use hex;
+use serde_json::{Map, Value};
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+use http;
+use node::PbftNode;
+use state::WorkingBlockOption;
+use timeline::Timeline;
+
+/// The latest snapshot of a node; safe to share between the engine and the admin server
+#[derive(Default)]
+pub struct NodeStatus {
+    snapshot: Mutex<String>,
+}
+
+impl NodeStatus {
+    pub fn new() -> Self {
+        NodeStatus::default()
+    }
+
+    /// Replace the snapshot with the node's current state
+    pub fn update(&self, node: &PbftNode) {
+        let snapshot = snapshot(node).to_string();
+        *self
+            .snapshot
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = snapshot;
+    }
+
+    /// The latest snapshot, as JSON; `null` if the engine hasn't started yet
+    pub fn get(&self) -> String {
+        let snapshot = self
+            .snapshot
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if snapshot.is_empty() {
+            String::from("null")
+        } else {
+            snapshot.clone()
+        }
+    }
+}
+
+/// Describe the node's state, its log, its peers (along with what it's heard from them), and the
+/// latest rounds of consensus it completed as JSON
+pub fn snapshot(node: &PbftNode) -> Value {
+    let state = &node.state;
+
+    let working_block = match state.working_block {
+        WorkingBlockOption::NoWorkingBlock => Value::Null,
+        WorkingBlockOption::TentativeWorkingBlock(ref block_id) => json!({
+            "block_id": hex::encode(Vec::<u8>::from(block_id.clone())),
+            "tentative": true,
+        }),
+        WorkingBlockOption::WorkingBlock(ref block) => json!({
+            "block_id": hex::encode(block.get_block_id()),
+            "block_num": block.get_block_num(),
+            "tentative": false,
+        }),
+    };
+
+    let activity: Map<String, Value> = state
+        .get_peer_ids()
+        .iter()
+        .filter_map(|peer_id| {
+            state.peer_activity.get(peer_id).map(|activity| {
+                let silence = state.peer_silence(peer_id).unwrap_or_default();
+                (
+                    peer_hex(peer_id),
+                    json!({
+                        "silence_ms": silence.as_millis() as u64,
+                        "silent": activity.silent,
+                        "last_view": activity.last_view,
+                        "last_seq_num": activity.last_seq_num,
+                        "messages": activity.messages,
+                    }),
+                )
+            })
+        })
+        .collect();
+
+    let remaining = state.timeout.remaining();
+    let (low_water_mark, high_water_mark) = node.msg_log.get_watermarks();
+    let (backlog, block_backlog) = node.msg_log.backlog_lengths();
+
+    json!({
+        "state": {
+            "id": state.id,
+            "peer_id": peer_hex(&state.get_own_peer_id()),
+            "is_primary": state.is_primary(),
+            "primary": peer_hex(&state.get_primary_peer_id()),
+            "view": state.view,
+            "pending_view": state.pending_view,
+            "seq_num": state.seq_num,
+            "phase": format!("{:?}", state.phase),
+            "mode": format!("{:?}", state.mode),
+            "working_block": working_block,
+            "pipelined_blocks": state.pipeline.keys().collect::<Vec<_>>(),
+            "timeout": {
+                "running": remaining.is_some(),
+                "expired": remaining.map_or(false, |left| left.as_millis() == 0),
+                "remaining_ms": remaining.map(|left| left.as_millis() as u64),
+                "duration_ms": state.timeout.duration().as_millis() as u64,
+            },
+        },
+        "log": {
+            "messages": node.msg_log.message_counts(),
+            "low_water_mark": low_water_mark,
+            "high_water_mark": high_water_mark,
+            "backlog": backlog,
+            "block_backlog": block_backlog,
+            "latest_stable_checkpoint": node
+                .msg_log
+                .latest_stable_checkpoint
+                .as_ref()
+                .map(|checkpoint| checkpoint.seq_num),
+        },
+        "peers": state.get_peer_ids().iter().map(peer_hex).collect::<Vec<_>>(),
+        "peer_activity": activity,
+        "f": state.f,
+        "recent_rounds": state.timelines.recent().map(Timeline::to_json).collect::<Vec<_>>(),
+    })
+}
+
+fn peer_hex(peer_id: &PeerId) -> String {
+    hex::encode(Vec::<u8>::from(peer_id.clone()))
+}
+
+/// Serve the node's status at `/status` on the given address, from a background thread. Returns
+/// the address the server is listening on, which is useful when binding to port 0.
+pub fn serve(status: Arc<NodeStatus>, address: &str) -> io::Result<SocketAddr> {
+    http::serve(address, "admin", move |path| match path {
+        "/status" => Some(("application/json", status.get())),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protos::pbft_message::PbftBlock;
+    use serde_json;
+    use simulator::SimulatedNetwork;
+    use state::PbftPhase;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    /// The snapshot describes the node's state, its log, and its peers
+    #[test]
+    fn node_snapshot() {
+        let mut sim = SimulatedNetwork::new(4, 1);
+        {
+            let node = &mut sim.nodes[1];
+            let mut block = PbftBlock::new();
+            block.set_block_id(vec![0xab, 0xcd]);
+            block.set_block_num(3);
+            node.state.working_block = WorkingBlockOption::WorkingBlock(block);
+            node.state.seq_num = 3;
+            node.state.phase = PbftPhase::Preparing;
+            node.state.timeout.start();
+        }
+
+        let snapshot = snapshot(&sim.nodes[1]);
+        let state = &snapshot["state"];
+        assert_eq!(state["id"], 1);
+        assert_eq!(state["is_primary"], false);
+        assert_eq!(state["view"], 0);
+        assert_eq!(state["seq_num"], 3);
+        assert_eq!(state["phase"], "Preparing");
+        assert_eq!(state["mode"], "Normal");
+        assert_eq!(state["working_block"]["block_id"], "abcd");
+        assert_eq!(state["working_block"]["block_num"], 3);
+        assert_eq!(state["working_block"]["tentative"], false);
+        assert_eq!(state["timeout"]["running"], true);
+        assert_eq!(state["timeout"]["expired"], false);
+
+        let log = &snapshot["log"];
+        assert_eq!(log["low_water_mark"], 0);
+        assert_eq!(log["backlog"], 0);
+        assert!(log["latest_stable_checkpoint"].is_null());
+
+        assert_eq!(snapshot["peers"].as_array().unwrap().len(), 4);
+        assert_eq!(snapshot["peers"][1], state["peer_id"]);
+        assert_eq!(snapshot["peers"][0], state["primary"]);
+
+        // What the node has heard from its peers, which is nothing yet
+        let activity = snapshot["peer_activity"].as_object().unwrap();
+        assert_eq!(activity.len(), 3);
+        let primary = state["primary"].as_str().unwrap();
+        assert_eq!(activity[primary]["silent"], false);
+        assert_eq!(activity[primary]["last_seq_num"], 0);
+        assert!(activity.get(state["peer_id"].as_str().unwrap()).is_none());
+    }
+
+    /// Once the network commits some blocks, the snapshot counts the messages that did it
+    #[test]
+    fn log_snapshot() {
+        let mut sim = SimulatedNetwork::new(4, 2);
+        assert!(sim.run_until_height(2, 10_000));
+
+        let snapshot = snapshot(&sim.nodes[2]);
+        assert!(snapshot["state"]["seq_num"].as_u64().unwrap() >= 2);
+        for msg_type in &["PrePrepare", "Prepare", "Commit"] {
+            assert!(
+                snapshot["log"]["messages"][*msg_type].as_u64().unwrap() > 0,
+                "No {} messages",
+                msg_type
+            );
+        }
+
+        // Each round the node completed is timed from start to finish
+        let rounds = snapshot["recent_rounds"].as_array().unwrap();
+        assert!(rounds.len() >= 2);
+        for (round, seq_num) in rounds.iter().zip(1..) {
+            assert_eq!(round["seq_num"], seq_num);
+            let events = round["events"].as_array().unwrap();
+            assert_eq!(events.last().unwrap()["event"], "BlockCommit");
+            assert_eq!(events.last().unwrap()["at_ms"], round["duration_ms"]);
+        }
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).expect("Failed to connect");
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    /// The server answers `/status` with the latest snapshot, and anything else with a 404
+    #[test]
+    fn http_server() {
+        let status = Arc::new(NodeStatus::new());
+        let addr = serve(status.clone(), "127.0.0.1:0").expect("Failed to serve");
+        assert!(get(addr, "/status").ends_with("\r\n\r\nnull"));
+
+        let sim = SimulatedNetwork::new(4, 3);
+        status.update(&sim.nodes[0]);
+        let response = get(addr, "/status");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        let snapshot: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(snapshot["state"]["is_primary"], true);
+
+        assert!(get(addr, "/metrics").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,72 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Checks the evidence of faulty nodes that `sawtooth-pbft` recorded, printing each record along
+//! with whether it proves that the node it names equivocated. Anyone can run this on an evidence
+//! file; it doesn't trust the node that wrote the file, or need any keys.
+
+#[macro_use]
+extern crate clap;
+extern crate hex;
+extern crate sawtooth_pbft;
+
+use std::path::Path;
+use std::process;
+
+use sawtooth_pbft::evidence::{describe, load_evidence, verify_evidence};
+
+fn main() {
+    let matches = clap_app!(pbft_evidence =>
+        (version: crate_version!())
+        (about: "Verifies the evidence of faulty nodes recorded by the Sawtooth PBFT engine")
+        (@arg evidence: +required
+         "path to the evidence file"))
+        .get_matches();
+
+    let evidence_path = matches
+        .value_of("evidence")
+        .expect("The evidence path is required");
+
+    let records = load_evidence(Path::new(evidence_path)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    let mut invalid = 0;
+    for (num, record) in records.iter().enumerate() {
+        match verify_evidence(record) {
+            Ok(peer_id) => {
+                println!("{}: Valid: {}", num, describe(record));
+                println!("  Faulty node: {}", hex::encode(Vec::<u8>::from(peer_id)));
+            }
+            Err(err) => {
+                println!("{}: Invalid: {}", num, err);
+                invalid += 1;
+            }
+        }
+    }
+
+    println!(
+        "{} records, {} valid, {} invalid",
+        records.len(),
+        records.len() - invalid,
+        invalid
+    );
+    if invalid > 0 {
+        process::exit(2);
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Replays a trace that `sawtooth-pbft --trace` recorded into a fresh node, printing each update
+//! and how the node's state changes, to reproduce what happened to the node offline. At the end,
+//! it checks the blocks the node committed and reports any problems it finds.
+
+#[macro_use]
+extern crate clap;
+extern crate log;
+extern crate sawtooth_pbft;
+extern crate simple_logger;
+
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::process;
+
+use sawtooth_pbft::replay::Replay;
+use sawtooth_pbft::signing::PbftSigner;
+use sawtooth_pbft::trace::load_trace;
+
+fn main() {
+    let matches = clap_app!(pbft_replay =>
+        (version: crate_version!())
+        (about: "Replays a trace recorded by the Sawtooth PBFT engine")
+        (@arg trace: +required
+         "path to the trace file")
+        (@arg key: -k --key +takes_value
+         "path to the recorded validator's private key (default /etc/sawtooth/keys/validator.priv)")
+        (@arg stop_at: -s --("stop-at") +takes_value
+         "stop after replaying this many updates, and print the node's whole state")
+        (@arg interactive: -i --interactive
+         "wait for Enter before replaying each update")
+        (@arg max_gap: -g --("max-gap") +takes_value
+         "report the node if it went this many milliseconds without committing a block")
+        (@arg verbose: -v --verbose +multiple
+         "increase output verbosity"))
+        .get_matches();
+
+    let log_level = match matches.occurrences_of("verbose") {
+        0 => log::Level::Warn,
+        1 => log::Level::Info,
+        2 => log::Level::Debug,
+        3 | _ => log::Level::Trace,
+    };
+
+    simple_logger::init_with_level(log_level).expect("Unable to initialize logger");
+
+    let trace_path = matches
+        .value_of("trace")
+        .expect("The trace path is required");
+
+    let key_path = matches
+        .value_of("key")
+        .unwrap_or("/etc/sawtooth/keys/validator.priv");
+
+    let stop_at: Option<usize> = matches.value_of("stop_at").map(|stop_at| {
+        stop_at.parse().unwrap_or_else(|_| {
+            eprintln!("--stop-at must be a number of updates");
+            process::exit(1);
+        })
+    });
+
+    let max_gap: Option<u64> = matches.value_of("max_gap").map(|max_gap| {
+        max_gap.parse().unwrap_or_else(|_| {
+            eprintln!("--max-gap must be a number of milliseconds");
+            process::exit(1);
+        })
+    });
+
+    let trace = load_trace(Path::new(trace_path)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    let signer = PbftSigner::from_key_file(Path::new(key_path)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    match signer.get_public_key() {
+        Ok(ref public_key) if public_key == &trace.startup_state.local_peer_info.peer_id => (),
+        _ => eprintln!("Warning: the key doesn't belong to the node that recorded the trace"),
+    }
+
+    let mut replay = Replay::new(trace, signer);
+    let mut last_state = replay.node.state.to_string();
+    println!("{}", last_state);
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock().lines();
+    while let Some(event) = replay.next_event() {
+        println!("{}", event);
+        if matches.is_present("interactive") && input.next().is_none() {
+            break;
+        }
+
+        let keep_going = replay.step();
+
+        // Only print the state when it changes, so the transitions stand out
+        let state = replay.node.state.to_string();
+        if state != last_state {
+            println!("  -> {}", state);
+            last_state = state;
+        }
+
+        if Some(replay.steps) == stop_at {
+            println!("{:#?}", replay.node.state);
+            break;
+        }
+        if !keep_going {
+            break;
+        }
+    }
+
+    let report = replay.report(max_gap);
+    println!("{}", report);
+    if !report.is_ok() {
+        process::exit(2);
+    }
+}
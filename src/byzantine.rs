@@ -0,0 +1,247 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! Byzantine nodes for the simulated network
+//!
+//! A Byzantine node runs the same `PbftNode` as every other node in the `simulator`, but the
+//! network changes the updates it sends to its peers before they're delivered, so that it can lie
+//! in ways an honest node never would. Messages are signed again with the Byzantine node's own key
+//! after they're changed, since that's all a real faulty node could do.
+
+use std::collections::HashMap;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use protobuf::{self, Message};
+use sawtooth_sdk::consensus::engine::{Block, BlockId, PeerId, PeerMessage, Update};
+
+use message_type::PbftMessageType;
+use protos::pbft_message::{PbftMessage, PbftSignedMessage};
+use signing::mock_signer;
+
+/// Ways that a Byzantine node can misbehave
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Fault {
+    /// Publishes a different block to each peer, and sends each peer a `PrePrepare` for the block
+    /// it got, all with the same sequence number
+    Equivocate,
+
+    /// Claims that its messages are from the next node in the network
+    ForgeSigner,
+
+    /// Labels its messages with the view before the one it's in
+    StaleView,
+
+    /// Never sends its `Commit` messages
+    WithholdCommits,
+
+    /// Sends `Checkpoint` messages for the sequence number after the one it's at
+    InvalidCheckpoints,
+}
+
+impl Fault {
+    /// Change an update that node `from` is sending to node `to`; `None` means that the update
+    /// isn't sent at all. Blocks that the node makes up are added to `blocks`.
+    pub fn tamper(
+        self,
+        from: usize,
+        to: usize,
+        update: Update,
+        peers: &[PeerId],
+        blocks: &mut HashMap<BlockId, Block>,
+    ) -> Option<Update> {
+        match update {
+            Update::BlockNew(block) => {
+                if self == Fault::Equivocate && block.signer_id == peers[from] {
+                    let variant = Block {
+                        block_id: variant_id(&block.block_id, to),
+                        ..block
+                    };
+                    blocks.insert(variant.block_id.clone(), variant.clone());
+                    Some(Update::BlockNew(variant))
+                } else {
+                    Some(Update::BlockNew(block))
+                }
+            }
+            Update::PeerMessage(message, sender_id) => self
+                .tamper_message(from, to, message, peers)
+                .map(|message| Update::PeerMessage(message, sender_id)),
+            update => Some(update),
+        }
+    }
+
+    // Change a message that node `from` is sending to node `to`, and sign it again
+    fn tamper_message(
+        self,
+        from: usize,
+        to: usize,
+        message: PeerMessage,
+        peers: &[PeerId],
+    ) -> Option<PeerMessage> {
+        let msg_type = PbftMessageType::from(message.message_type.as_str());
+        match msg_type {
+            PbftMessageType::PrePrepare
+            | PbftMessageType::Prepare
+            | PbftMessageType::Commit
+            | PbftMessageType::Checkpoint => (),
+            _ => return Some(message),
+        }
+
+        let signed_msg = protobuf::parse_from_bytes::<PbftSignedMessage>(&message.content)
+            .expect("Couldn't parse signed message");
+        let mut pbft_msg = protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message())
+            .expect("Couldn't parse message");
+
+        match (self, msg_type) {
+            (Fault::Equivocate, PbftMessageType::PrePrepare) => {
+                let block_id = variant_id(
+                    &BlockId::from(pbft_msg.get_block().get_block_id().to_vec()),
+                    to,
+                );
+                pbft_msg.mut_block().set_block_id(Vec::<u8>::from(block_id));
+            }
+            (Fault::ForgeSigner, _) => {
+                let forged = peers[(from + 1) % peers.len()].clone();
+                pbft_msg.mut_info().set_signer_id(Vec::<u8>::from(forged));
+            }
+            (Fault::StaleView, _) => {
+                let view = pbft_msg.get_info().get_view();
+                pbft_msg.mut_info().set_view(view.saturating_sub(1));
+            }
+            (Fault::WithholdCommits, PbftMessageType::Commit) => return None,
+            (Fault::InvalidCheckpoints, PbftMessageType::Checkpoint) => {
+                let seq_num = pbft_msg.get_info().get_seq_num();
+                pbft_msg.mut_info().set_seq_num(seq_num + 1);
+            }
+            _ => return Some(message),
+        }
+
+        let msg_bytes = pbft_msg
+            .write_to_bytes()
+            .expect("Couldn't serialize message");
+        let content = mock_signer(from as u64)
+            .sign(&msg_bytes)
+            .expect("Couldn't sign message")
+            .write_to_bytes()
+            .expect("Couldn't serialize signed message");
+        Some(PeerMessage {
+            message_type: message.message_type,
+            content,
+        })
+    }
+}
+
+// The ID of the version of a block that an equivocating node sends to node `to`
+fn variant_id(block_id: &BlockId, to: usize) -> BlockId {
+    let mut sha = Sha256::new();
+    sha.input_str(&format!("Block {:?} as sent to node {}", block_id, to));
+    BlockId::from(sha.result_str().as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use simulator::SimulatedNetwork;
+
+    const PATIENCE: usize = 200;
+    const MAX_STEPS: usize = 20_000;
+
+    /// Create a network of four nodes where node 0 (the first primary) is Byzantine
+    fn faulty_network(fault: Fault, seed: u64) -> SimulatedNetwork {
+        let mut config = mock_config(4);
+        config.checkpoint_period = 3;
+        let mut sim = SimulatedNetwork::with_config(&config, seed);
+        sim.set_fault(0, Some(fault));
+        sim
+    }
+
+    /// Make sure that the honest nodes have replaced node 0 as primary
+    fn assert_primary_replaced(sim: &SimulatedNetwork) {
+        let faulty_id = sim.nodes[0].state.get_own_peer_id();
+        for i in sim.honest_nodes() {
+            let state = &sim.nodes[i].state;
+            assert!(state.view > 0);
+            assert_ne!(state.get_primary_peer_id(), faulty_id);
+        }
+    }
+
+    /// A primary that sends each node a different block can't get any of them committed, so the
+    /// honest nodes replace it
+    #[test]
+    fn equivocating_primary() {
+        let mut sim = faulty_network(Fault::Equivocate, 11);
+        assert!(sim.run_with_timeouts(3, PATIENCE, MAX_STEPS));
+        assert!(sim.honest_nodes_agree());
+        assert_primary_replaced(&sim);
+    }
+
+    /// Messages that claim to be from a different node don't verify, so a primary whose
+    /// messages are all forged gets replaced
+    #[test]
+    fn forged_signer() {
+        let mut sim = faulty_network(Fault::ForgeSigner, 12);
+        assert!(sim.run_with_timeouts(3, PATIENCE, MAX_STEPS));
+        assert!(sim.honest_nodes_agree());
+        assert_primary_replaced(&sim);
+    }
+
+    /// Once the honest nodes have replaced the primary, the messages it keeps sending for its old
+    /// view don't count towards anything
+    #[test]
+    fn stale_view() {
+        let mut sim = faulty_network(Fault::StaleView, 13);
+        for i in sim.honest_nodes() {
+            sim.expire_timeout(i);
+        }
+        assert!(sim.run_with_timeouts(3, PATIENCE, MAX_STEPS));
+        assert!(sim.honest_nodes_agree());
+        assert_primary_replaced(&sim);
+    }
+
+    /// The honest nodes can commit blocks without the primary's `Commit` messages, so it doesn't
+    /// need to be replaced
+    #[test]
+    fn withheld_commits() {
+        let mut sim = faulty_network(Fault::WithholdCommits, 14);
+        assert!(sim.run_with_timeouts(3, PATIENCE, MAX_STEPS));
+        assert!(sim.honest_nodes_agree());
+        for i in sim.honest_nodes() {
+            assert_eq!(sim.nodes[i].state.view, 0);
+        }
+    }
+
+    /// Honest nodes only vouch for a checkpoint once they've reached it themselves, so the
+    /// primary's claims can't make a checkpoint stable before the block at it is committed; the
+    /// honest nodes still agree on checkpoints of their own
+    #[test]
+    fn invalid_checkpoints() {
+        let mut sim = faulty_network(Fault::InvalidCheckpoints, 15);
+        assert!(sim.run_with_timeouts(4, PATIENCE, MAX_STEPS));
+        assert!(sim.honest_nodes_agree());
+        let mut num_stable = 0;
+        for i in sim.honest_nodes() {
+            let node = &sim.nodes[i];
+            if let Some(ref checkpoint) = node.msg_log.latest_stable_checkpoint {
+                assert!(checkpoint.seq_num <= node.state.seq_num);
+                assert!(checkpoint.seq_num <= sim.height(i));
+                num_stable += 1;
+            }
+        }
+        assert!(num_stable > 0);
+    }
+}
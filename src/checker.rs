@@ -0,0 +1,503 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Checking the blocks that nodes committed for safety and liveness
+//!
+//! A `CommitHistory` is what one node's validator saw through `Service::commit_block`: which
+//! blocks were committed, in order, and when. The `CommitChecker` looks at the histories of some
+//! nodes, along with the messages the nodes sent and received, and reports anything that went
+//! wrong:
+//!
+//! + **Agreement:** No two nodes committed different blocks at the same height
+//! + **Validity:** Every committed block was pre-prepared by the primary of some view, either in a
+//!   `PrePrepare` or in a `NewView` message
+//! + **Liveness:** No node went more than a given amount of time without committing a block
+//!
+//! Time is measured in whatever unit the histories were recorded in; the `simulator` counts steps,
+//! and `replay` counts milliseconds since the trace started.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use hex;
+use protobuf;
+use sawtooth_sdk::consensus::engine::{BlockId, PeerId, PeerMessage};
+
+use message_type::PbftMessageType;
+use protos::pbft_message::{PbftBlock, PbftMessage, PbftNewView, PbftSignedMessage};
+use signing;
+
+/// A block that a node committed
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommittedBlock {
+    pub block_id: BlockId,
+    pub block_num: u64,
+
+    /// When the block was committed
+    pub at: u64,
+}
+
+/// The blocks that a node committed, in order
+#[derive(Debug, Clone)]
+pub struct CommitHistory {
+    /// The node's ID in the network (its index in the list of peers)
+    pub node: u64,
+
+    pub commits: Vec<CommittedBlock>,
+
+    /// When the node stopped being watched
+    pub end: u64,
+}
+
+/// Something that went wrong in the histories that were checked
+#[derive(Debug, PartialEq)]
+pub enum Violation {
+    /// Nodes committed different blocks at the same height; has the block each node committed
+    Disagreement {
+        block_num: u64,
+        blocks: Vec<(u64, BlockId)>,
+    },
+
+    /// A node committed a block that the primary never pre-prepared
+    NotPrePrepared {
+        node: u64,
+        block_num: u64,
+        block_id: BlockId,
+    },
+
+    /// A node went from `from` to `to` without committing a block, which is longer than allowed
+    NoProgress { node: u64, from: u64, to: u64 },
+}
+
+impl Violation {
+    /// Whether the violation is of a safety property (as opposed to liveness)
+    pub fn is_safety(&self) -> bool {
+        !matches!(self, Violation::NoProgress { .. })
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Disagreement { block_num, blocks } => {
+                write!(
+                    f,
+                    "Nodes committed different blocks at height {}:",
+                    block_num
+                )?;
+                for (node, block_id) in blocks {
+                    write!(
+                        f,
+                        " {:.6} (node {})",
+                        hex::encode(Vec::<u8>::from(block_id.clone())),
+                        node
+                    )?;
+                }
+                Ok(())
+            }
+            Violation::NotPrePrepared {
+                node,
+                block_num,
+                block_id,
+            } => write!(
+                f,
+                "Node {} committed block {:.6} at height {}, which no primary pre-prepared",
+                node,
+                hex::encode(Vec::<u8>::from(block_id.clone())),
+                block_num
+            ),
+            Violation::NoProgress { node, from, to } => write!(
+                f,
+                "Node {} didn't commit a block from {} to {}",
+                node, from, to
+            ),
+        }
+    }
+}
+
+/// The results of checking some commit histories
+#[derive(Debug, Default)]
+pub struct Report {
+    /// How many blocks each node committed, by node ID
+    pub commits: BTreeMap<u64, usize>,
+
+    pub violations: Vec<Violation>,
+}
+
+impl Report {
+    /// Whether the nodes agreed on valid blocks
+    pub fn is_safe(&self) -> bool {
+        !self.violations.iter().any(Violation::is_safety)
+    }
+
+    /// Whether the nodes kept committing blocks
+    pub fn is_live(&self) -> bool {
+        self.violations.iter().all(Violation::is_safety)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let commits: Vec<String> = self
+            .commits
+            .iter()
+            .map(|(node, commits)| format!("node {}: {}", node, commits))
+            .collect();
+        write!(f, "Blocks committed ({})", commits.join(", "))?;
+        if self.violations.is_empty() {
+            return write!(f, "; no violations");
+        }
+        for violation in &self.violations {
+            write!(f, "\n  {}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects evidence of which blocks were pre-prepared from the messages that nodes send, and
+/// checks commit histories against it
+pub struct CommitChecker {
+    peers: Vec<PeerId>,
+
+    /// The blocks that the primary of some view pre-prepared, by ID and block number
+    pre_prepared: HashSet<(Vec<u8>, u64)>,
+}
+
+impl CommitChecker {
+    /// Create a checker for a network with the given peers, which don't change
+    pub fn new(peers: Vec<PeerId>) -> Self {
+        CommitChecker {
+            peers,
+            pre_prepared: HashSet::new(),
+        }
+    }
+
+    /// Look at a message that a node sent or received. `PrePrepare`s and `NewView`s that were
+    /// validly signed by the primary of their view are kept as evidence that their blocks were
+    /// pre-prepared; everything else is ignored.
+    pub fn observe_message(&mut self, message: &PeerMessage) {
+        let msg_type = PbftMessageType::from(message.message_type.as_str());
+        if msg_type != PbftMessageType::PrePrepare && msg_type != PbftMessageType::NewView {
+            return;
+        }
+        let signed_msg = match protobuf::parse_from_bytes::<PbftSignedMessage>(&message.content) {
+            Ok(signed_msg) => signed_msg,
+            Err(_) => return,
+        };
+
+        let (info, blocks) = if msg_type == PbftMessageType::PrePrepare {
+            match protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message()) {
+                Ok(mut pre_prepare) => (pre_prepare.take_info(), vec![pre_prepare.take_block()]),
+                Err(_) => return,
+            }
+        } else {
+            match protobuf::parse_from_bytes::<PbftNewView>(signed_msg.get_message()) {
+                Ok(mut new_view) => {
+                    let view = new_view.get_info().get_view();
+                    let blocks = new_view
+                        .take_pre_prepares()
+                        .into_iter()
+                        .filter(|pre_prepare| pre_prepare.get_info().get_view() == view)
+                        .map(|mut pre_prepare| pre_prepare.take_block())
+                        .collect();
+                    (new_view.take_info(), blocks)
+                }
+                Err(_) => return,
+            }
+        };
+
+        // Only verify the signature if there's something new to learn from the message
+        let new_blocks: Vec<PbftBlock> = blocks
+            .into_iter()
+            .filter(|block| !self.pre_prepared.contains(&block_key(block)))
+            .collect();
+        if new_blocks.is_empty()
+            || self.peers.is_empty()
+            || info.get_signer_id() != &Vec::<u8>::from(self.primary(info.get_view()))[..]
+            || signing::verify(&msg_type, &signed_msg).is_err()
+        {
+            return;
+        }
+        for block in &new_blocks {
+            self.pre_prepared.insert(block_key(block));
+        }
+    }
+
+    /// Check the histories, allowing nodes to go `max_gap` without committing a block (or
+    /// forever, if `max_gap` is `None`)
+    pub fn check(&self, histories: &[CommitHistory], max_gap: Option<u64>) -> Report {
+        let mut report = Report::default();
+
+        let mut by_height: BTreeMap<u64, Vec<(u64, BlockId)>> = BTreeMap::new();
+        for history in histories {
+            report.commits.insert(history.node, history.commits.len());
+
+            for commit in &history.commits {
+                by_height
+                    .entry(commit.block_num)
+                    .or_insert_with(Vec::new)
+                    .push((history.node, commit.block_id.clone()));
+
+                let key = (Vec::<u8>::from(commit.block_id.clone()), commit.block_num);
+                if !self.pre_prepared.contains(&key) {
+                    report.violations.push(Violation::NotPrePrepared {
+                        node: history.node,
+                        block_num: commit.block_num,
+                        block_id: commit.block_id.clone(),
+                    });
+                }
+            }
+
+            if let Some(max_gap) = max_gap {
+                let mut last = 0;
+                for at in history
+                    .commits
+                    .iter()
+                    .map(|commit| commit.at)
+                    .chain(Some(history.end))
+                {
+                    if at.saturating_sub(last) > max_gap {
+                        report.violations.push(Violation::NoProgress {
+                            node: history.node,
+                            from: last,
+                            to: at,
+                        });
+                    }
+                    last = at;
+                }
+            }
+        }
+
+        for (block_num, blocks) in by_height {
+            let ids: HashSet<&BlockId> = blocks.iter().map(|(_, block_id)| block_id).collect();
+            if ids.len() > 1 {
+                report
+                    .violations
+                    .push(Violation::Disagreement { block_num, blocks });
+            }
+        }
+
+        report
+    }
+
+    // The primary of the given view
+    fn primary(&self, view: u64) -> PeerId {
+        self.peers[(view % self.peers.len() as u64) as usize].clone()
+    }
+}
+
+fn block_key(block: &PbftBlock) -> (Vec<u8>, u64) {
+    (block.get_block_id().to_vec(), block.get_block_num())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use handlers::make_msg_info;
+    use protobuf::{Message, RepeatedField};
+    use signing::mock_signer;
+
+    fn commit(block: u8, at: u64) -> CommittedBlock {
+        CommittedBlock {
+            block_id: BlockId::from(vec![block]),
+            block_num: u64::from(block),
+            at,
+        }
+    }
+
+    /// A `PrePrepare` for block `block` in the given view, from node `signer`
+    fn pre_prepare_msg(block: u8, view: u64, signer: u64) -> PbftMessage {
+        let mut msg = PbftMessage::new();
+        msg.set_info(make_msg_info(
+            &PbftMessageType::PrePrepare,
+            view,
+            u64::from(block),
+            mock_config(4).peers[signer as usize].clone(),
+        ));
+        msg.mut_block().set_block_id(vec![block]);
+        msg.mut_block().set_block_num(u64::from(block));
+        msg
+    }
+
+    /// Sign a message as node `signer`, and wrap it up the way the validator delivers it
+    fn peer_message<M: Message>(msg_type: &PbftMessageType, msg: &M, signer: u64) -> PeerMessage {
+        let signed = mock_signer(signer)
+            .sign(&msg.write_to_bytes().unwrap())
+            .unwrap();
+        PeerMessage {
+            message_type: String::from(msg_type),
+            content: signed.write_to_bytes().unwrap(),
+        }
+    }
+
+    fn pre_prepare(block: u8, view: u64, signer: u64) -> PeerMessage {
+        peer_message(
+            &PbftMessageType::PrePrepare,
+            &pre_prepare_msg(block, view, signer),
+            signer,
+        )
+    }
+
+    /// Nodes that commit the same pre-prepared blocks often enough pass every check
+    #[test]
+    fn no_violations() {
+        let mut checker = CommitChecker::new(mock_config(4).peers);
+        checker.observe_message(&pre_prepare(1, 0, 0));
+        checker.observe_message(&pre_prepare(2, 0, 0));
+
+        let histories = vec![
+            CommitHistory {
+                node: 0,
+                commits: vec![commit(1, 10), commit(2, 20)],
+                end: 25,
+            },
+            CommitHistory {
+                node: 1,
+                commits: vec![commit(1, 8)],
+                end: 15,
+            },
+        ];
+        let report = checker.check(&histories, Some(10));
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.commits.get(&0), Some(&2));
+        assert_eq!(report.commits.get(&1), Some(&1));
+    }
+
+    /// Nodes that commit different blocks at the same height are reported, along with which
+    /// blocks they committed
+    #[test]
+    fn disagreement() {
+        let mut checker = CommitChecker::new(mock_config(4).peers);
+        checker.observe_message(&pre_prepare(1, 0, 0));
+
+        let mut other = commit(1, 10);
+        other.block_id = BlockId::from(vec![9]);
+        let histories = vec![
+            CommitHistory {
+                node: 0,
+                commits: vec![commit(1, 10)],
+                end: 10,
+            },
+            CommitHistory {
+                node: 2,
+                commits: vec![other],
+                end: 10,
+            },
+        ];
+        let report = checker.check(&histories, None);
+        assert!(!report.is_safe());
+        assert!(report.is_live());
+        assert!(report.violations.contains(&Violation::Disagreement {
+            block_num: 1,
+            blocks: vec![(0, BlockId::from(vec![1])), (2, BlockId::from(vec![9]))],
+        }));
+    }
+
+    /// Blocks only count as pre-prepared if the primary of the view signed the `PrePrepare` or
+    /// `NewView` that they're in
+    #[test]
+    fn validity() {
+        let mut checker = CommitChecker::new(mock_config(4).peers);
+
+        // Node 1 isn't the primary in view 0
+        checker.observe_message(&pre_prepare(1, 0, 1));
+
+        // A PrePrepare with a bad signature
+        let mut forged = pre_prepare(2, 0, 0);
+        let mut signed: PbftSignedMessage = protobuf::parse_from_bytes(&forged.content).unwrap();
+        signed.set_signature(String::from("00"));
+        forged.content = signed.write_to_bytes().unwrap();
+        checker.observe_message(&forged);
+
+        // Node 1 is the primary in view 1, and reissues block 3 in a NewView
+        let mut new_view = PbftNewView::new();
+        new_view.set_info(make_msg_info(
+            &PbftMessageType::NewView,
+            1,
+            0,
+            mock_config(4).peers[1].clone(),
+        ));
+        new_view.set_pre_prepares(RepeatedField::from_vec(vec![pre_prepare_msg(3, 1, 1)]));
+        checker.observe_message(&peer_message(&PbftMessageType::NewView, &new_view, 1));
+
+        let histories = vec![CommitHistory {
+            node: 2,
+            commits: vec![commit(1, 1), commit(2, 2), commit(3, 3)],
+            end: 3,
+        }];
+        let report = checker.check(&histories, None);
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation::NotPrePrepared {
+                    node: 2,
+                    block_num: 1,
+                    block_id: BlockId::from(vec![1]),
+                },
+                Violation::NotPrePrepared {
+                    node: 2,
+                    block_num: 2,
+                    block_id: BlockId::from(vec![2]),
+                },
+            ]
+        );
+    }
+
+    /// Nodes that go too long without committing a block are reported, including at the start
+    /// and the end of their histories
+    #[test]
+    fn liveness() {
+        let mut checker = CommitChecker::new(mock_config(4).peers);
+        checker.observe_message(&pre_prepare(1, 0, 0));
+        checker.observe_message(&pre_prepare(2, 0, 0));
+
+        let histories = vec![
+            CommitHistory {
+                node: 0,
+                commits: vec![commit(1, 15), commit(2, 20)],
+                end: 20,
+            },
+            CommitHistory {
+                node: 3,
+                commits: vec![commit(1, 5), commit(2, 10)],
+                end: 30,
+            },
+        ];
+        let report = checker.check(&histories, Some(10));
+        assert!(report.is_safe());
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation::NoProgress {
+                    node: 0,
+                    from: 0,
+                    to: 15,
+                },
+                Violation::NoProgress {
+                    node: 3,
+                    from: 10,
+                    to: 30,
+                },
+            ]
+        );
+    }
+}
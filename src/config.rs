@@ -51,6 +51,13 @@ pub struct PbftConfig {
 
     /// How large the PbftLog is allowed to get
     pub max_log_size: u64,
+
+    /// How many blocks can be going through consensus at once
+    pub pipeline_depth: u64,
+
+    /// How many block durations a peer can go without sending anything before it's reported as
+    /// silent, or before a node that's waiting on the primary starts a view change
+    pub silent_peer_intervals: u32,
 }
 
 impl PbftConfig {
@@ -62,28 +69,24 @@ impl PbftConfig {
             view_change_timeout: Duration::from_millis(4000),
             checkpoint_period: 100,
             max_log_size: 1000,
+            pipeline_depth: 1,
+            silent_peer_intervals: 10,
         }
     }
+
+    /// How long a peer can go without sending anything before it's reported as silent
+    pub fn silence_threshold(&self) -> Duration {
+        self.block_duration * self.silent_peer_intervals
+    }
 }
 
-/// Load configuration from on-chain Sawtooth settings.
-///
-/// Configuration loads the following settings:
-/// + `sawtooth.consensus.pbft.peers` (required)
-/// + `sawtooth.consensus.pbft.block_duration` (optional, default 200 ms)
-/// + `sawtooth.consensus.pbft.checkpoint_period` (optional, default 10 ms)
-/// + `sawtooth.consensus.pbft.view_change_timeout` (optional, default 4000 ms)
-/// + `sawtooth.consensus.pbft.message_timeout` (optional, default 100 blocks)
-/// + `sawtooth.consensus.pbft.max_log_size` (optional, default 1000 messages)
+/// Load the on-chain Sawtooth settings that configure PBFT, as of the given block; see
+/// `pbft_config_from_settings` for the settings that are loaded.
 ///
 /// # Panics
-/// + If the `sawtooth.consensus.pbft.peers` setting is not provided
 /// + If settings loading fails entirely
-/// + If block duration is greater than the view change timeout
-pub fn load_pbft_config(block_id: BlockId, service: &mut Service) -> PbftConfig {
-    let mut config = PbftConfig::default();
-
-    let sawtooth_settings: HashMap<String, String> = service
+pub fn load_pbft_settings(block_id: BlockId, service: &mut Service) -> HashMap<String, String> {
+    service
         .get_settings(
             block_id,
             vec![
@@ -93,9 +96,31 @@ pub fn load_pbft_config(block_id: BlockId, service: &mut Service) -> PbftConfig
                 String::from("sawtooth.consensus.pbft.view_change_timeout"),
                 String::from("sawtooth.consensus.pbft.message_timeout"),
                 String::from("sawtooth.consensus.pbft.max_log_size"),
+                String::from("sawtooth.consensus.pbft.pipeline_depth"),
+                String::from("sawtooth.consensus.pbft.silent_peer_intervals"),
             ],
         )
-        .expect("Failed to get on-chain settings");
+        .expect("Failed to get on-chain settings")
+}
+
+/// Create a configuration from on-chain Sawtooth settings.
+///
+/// Configuration uses the following settings:
+/// + `sawtooth.consensus.pbft.peers` (required)
+/// + `sawtooth.consensus.pbft.block_duration` (optional, default 200 ms)
+/// + `sawtooth.consensus.pbft.checkpoint_period` (optional, default 10 ms)
+/// + `sawtooth.consensus.pbft.view_change_timeout` (optional, default 4000 ms)
+/// + `sawtooth.consensus.pbft.message_timeout` (optional, default 100 blocks)
+/// + `sawtooth.consensus.pbft.max_log_size` (optional, default 1000 messages)
+/// + `sawtooth.consensus.pbft.pipeline_depth` (optional, default 1 block)
+/// + `sawtooth.consensus.pbft.silent_peer_intervals` (optional, default 10 block durations)
+///
+/// # Panics
+/// + If the `sawtooth.consensus.pbft.peers` setting is not provided
+/// + If block duration is greater than the view change timeout
+/// + If the pipeline depth is 0
+pub fn pbft_config_from_settings(sawtooth_settings: &HashMap<String, String>) -> PbftConfig {
+    let mut config = PbftConfig::default();
 
     // Get the peers associated with this node (including ourselves). Panic if it is not provided;
     // the network cannot function without this setting.
@@ -146,23 +171,39 @@ pub fn load_pbft_config(block_id: BlockId, service: &mut Service) -> PbftConfig
             config.max_log_size = max_log_size;
         }
     }
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.pipeline_depth") {
+        if let Ok(pipeline_depth) = s.parse() {
+            config.pipeline_depth = pipeline_depth;
+        }
+    }
+
+    if let Some(s) = sawtooth_settings.get("sawtooth.consensus.pbft.silent_peer_intervals") {
+        if let Ok(silent_peer_intervals) = s.parse() {
+            config.silent_peer_intervals = silent_peer_intervals;
+        }
+    }
+
+    // At least one block has to be able to go through consensus
+    if config.pipeline_depth == 0 {
+        panic!("Pipeline depth must be at least 1");
+    }
 
     config
 }
 
-/// Create a mock configuration, given a number of nodes. PeerIds are generated using a Sha256
-/// hash.
-#[cfg(test)]
+/// Create a mock configuration, given a number of nodes. PeerIds are the public keys of the
+/// deterministic signers created by `signing::mock_signer`.
+#[cfg(any(test, feature = "simulation"))]
 pub fn mock_config(num_nodes: usize) -> PbftConfig {
-    use crypto::digest::Digest;
-    use crypto::sha2::Sha256;
-
-    let mut ids = Vec::new();
-    for i in 0..num_nodes {
-        let mut sha = Sha256::new();
-        sha.input_str(format!("I'm a node with ID {}", i).as_str());
-        ids.push(PeerId::from(sha.result_str().as_bytes().to_vec()));
-    }
+    use signing::mock_signer;
+
+    let ids: Vec<PeerId> = (0..num_nodes)
+        .map(|i| {
+            mock_signer(i as u64)
+                .get_public_key()
+                .expect("Mock signer has no public key")
+        })
+        .collect();
 
     let mut config = PbftConfig::default();
     config.peers = ids;
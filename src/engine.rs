@@ -17,23 +17,145 @@
 
 //! Entry point for the consensus algorithm, including the main event loop
 
+use std::rc::Rc;
 use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+
+use hex;
 
 use sawtooth_sdk::consensus::{engine::*, service::Service};
 
 use node::PbftNode;
 
+use admin::NodeStatus;
+
 use config;
-use timing;
+use evidence::EvidenceRecorder;
+use logging;
+use message_type::PbftMessageType;
+use metrics::{self, Metrics};
+use observer::PbftObserver;
+use signing::PbftSigner;
+use storage::PbftStorage;
+use timeline::TimelineWriter;
+use timing::{Clock, Ticker};
+use trace::TraceRecorder;
 
 use error::PbftError;
 
-#[derive(Default)]
-pub struct PbftEngine {}
+pub struct PbftEngine {
+    /// Signer for this node's messages; handed off to the node when the engine starts
+    signer: Option<PbftSigner>,
+
+    /// Where the node keeps its log and state, so it can recover after a restart; handed off to
+    /// the node when the engine starts
+    storage: Option<Box<PbftStorage>>,
+
+    /// Where the node's timers and the engine's tickers get the time from
+    clock: Rc<Clock>,
+
+    /// Records every update the engine receives, if the engine is tracing
+    recorder: Option<TraceRecorder>,
+
+    /// Where the node reports its metrics, so they can be served while the engine runs
+    metrics: Arc<Metrics>,
+
+    /// Where the engine publishes snapshots of the node for the admin server, if it's running
+    status: Option<Arc<NodeStatus>>,
+
+    /// Where the engine keeps the evidence of faulty nodes that the node finds, if anywhere
+    evidence_recorder: Option<EvidenceRecorder>,
+
+    /// Where the engine writes the timelines of the rounds of consensus that the node completes,
+    /// if anywhere
+    timeline_writer: Option<TimelineWriter>,
+
+    /// Observers to add to the node when the engine starts
+    observers: Vec<Box<PbftObserver>>,
+}
 
 impl PbftEngine {
-    pub fn new() -> Self {
-        PbftEngine {}
+    pub fn new(
+        signer: PbftSigner,
+        storage: Box<PbftStorage>,
+        clock: Rc<Clock>,
+        recorder: Option<TraceRecorder>,
+        metrics: Arc<Metrics>,
+        status: Option<Arc<NodeStatus>>,
+        evidence_recorder: Option<EvidenceRecorder>,
+    ) -> Self {
+        PbftEngine {
+            signer: Some(signer),
+            storage: Some(storage),
+            clock,
+            recorder,
+            metrics,
+            status,
+            evidence_recorder,
+            timeline_writer: None,
+            observers: vec![],
+        }
+    }
+
+    /// Tell the observer about the node's consensus events once the engine starts
+    pub fn add_observer(&mut self, observer: Box<PbftObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Write the timeline of each round of consensus that the node completes with `writer`
+    pub fn set_timeline_writer(&mut self, writer: TimelineWriter) {
+        self.timeline_writer = Some(writer);
+    }
+
+    // Add to the trace, if the engine is tracing; if the trace can't be written to, stop tracing
+    // rather than stopping the engine
+    fn record<F>(&mut self, record: F)
+    where
+        F: FnOnce(&mut TraceRecorder) -> Result<(), PbftError>,
+    {
+        let res = match self.recorder {
+            Some(ref mut recorder) => record(recorder),
+            None => Ok(()),
+        };
+        if let Err(err) = res {
+            error!("{}; no longer recording a trace", err);
+            self.recorder = None;
+        }
+    }
+
+    // Write the evidence of faulty nodes that the node found to the evidence file, if there is one
+    fn record_evidence(&mut self, node: &mut PbftNode) {
+        let found = node.msg_log.take_evidence();
+        if let Some(ref mut evidence_recorder) = self.evidence_recorder {
+            for record in &found {
+                if let Err(err) = evidence_recorder.record(record) {
+                    error!("Couldn't record evidence: {}", err);
+                }
+            }
+        }
+    }
+
+    // Write the timelines of the rounds the node completed, if the engine is writing them; if the
+    // timeline file can't be written to, stop writing timelines rather than stopping the engine
+    fn write_timelines(&mut self, node: &mut PbftNode) {
+        let completed = node.state.timelines.take_completed();
+        let res = match self.timeline_writer {
+            Some(ref mut writer) => completed
+                .iter()
+                .try_for_each(|timeline| writer.write(timeline)),
+            None => Ok(()),
+        };
+        if let Err(err) = res {
+            error!("{}; no longer writing timelines", err);
+            self.timeline_writer = None;
+        }
+    }
+
+    // Let the admin server know what the node is doing now, if it's running
+    fn publish_status(&self, node: &PbftNode) {
+        if let Some(ref status) = self.status {
+            status.update(node);
+        }
     }
 }
 
@@ -44,72 +166,80 @@ impl Engine for PbftEngine {
         mut service: Box<Service>,
         startup_state: StartupState,
     ) {
+        // Load on-chain settings
+        let settings =
+            config::load_pbft_settings(startup_state.chain_head.block_id.clone(), &mut *service);
+        let config = config::pbft_config_from_settings(&settings);
+
+        self.record(|recorder| recorder.record_startup(&startup_state, &settings));
+
         let StartupState {
-            chain_head,
-            peers: _peers,
+            peers,
             local_peer_info,
+            ..
         } = startup_state;
 
-        // Load on-chain settings
-        let config = config::load_pbft_config(chain_head.block_id, &mut *service);
+        let signer = self
+            .signer
+            .take()
+            .expect("The PBFT engine can only be started once");
+
+        // Peers verify our messages against our PeerId, so the key had better match it
+        match signer.get_public_key() {
+            Ok(ref public_key) if public_key == &local_peer_info.peer_id => (),
+            Ok(_) => panic!("The signing key does not belong to this validator"),
+            Err(err) => panic!("Couldn't derive a public key from the signing key: {}", err),
+        }
 
-        let node_id = config
-            .peers
-            .iter()
-            .position(|ref id| id == &&local_peer_info.peer_id)
-            .expect("This node is not in the peers list, which is necessary")
-            as u64;
+        let mut working_ticker = Ticker::new(config.block_duration, self.clock.clone());
+        let mut backlog_ticker = Ticker::new(config.message_timeout, self.clock.clone());
 
-        let mut working_ticker = timing::Ticker::new(config.block_duration);
-        let mut backlog_ticker = timing::Ticker::new(config.message_timeout);
+        let storage = self
+            .storage
+            .take()
+            .expect("The PBFT engine can only be started once");
 
-        let mut node = PbftNode::new(node_id, &config, service);
+        // Picks up where the node left off, if it was running before
+        let mut node = PbftNode::new(
+            &local_peer_info.peer_id,
+            &config,
+            service,
+            signer,
+            storage,
+            self.clock.clone(),
+        );
+        node.set_metrics(self.metrics.clone());
+        for observer in self.observers.drain(..) {
+            node.add_observer(observer);
+        }
+        node.update_metrics();
+        logging::set_state(&node.state);
+        self.publish_status(&node);
 
         debug!("Starting state: {:#?}", node.state);
 
+        // Any peers that aren't in the on-chain list yet get proposed as a change to the network
+        for peer in peers {
+            let res = node.on_peer_connected(peer.peer_id);
+            handle_pbft_result(&node, None, res);
+        }
+
         // Event loop. Keep going until we receive a shutdown message.
         loop {
             let incoming_message = updates.recv_timeout(config.message_timeout);
+            self.record(|recorder| recorder.record_update(&incoming_message));
 
-            let res = match incoming_message {
-                Ok(Update::BlockNew(block)) => node.on_block_new(block),
-                Ok(Update::BlockValid(block_id)) => node.on_block_valid(block_id),
-                Ok(Update::BlockInvalid(_)) => {
-                    warn!(
-                        "{}: BlockInvalid received, starting view change",
-                        node.state
-                    );
-                    node.start_view_change()
-                }
-                Ok(Update::BlockCommit(block_id)) => node.on_block_commit(block_id),
-                Ok(Update::PeerMessage(message, _sender_id)) => node.on_peer_message(&message),
-                Ok(Update::Shutdown) => break,
-                Ok(Update::PeerConnected(_)) | Ok(Update::PeerDisconnected(_)) => {
-                    error!("PBFT currently only supports static networks");
-                    Ok(())
-                }
-                Err(RecvTimeoutError::Timeout) => Err(PbftError::Timeout),
-                Err(RecvTimeoutError::Disconnected) => {
-                    error!("Disconnected from validator");
-                    break;
-                }
-            };
-            handle_pbft_result(res);
-
-            working_ticker.tick(|| {
-                if let Err(e) = node.try_publish() {
-                    error!("{}", e);
-                }
-
-                // Every so often, check to see if timeout has expired; initiate ViewChange if necessary
-                if node.check_timeout_expired() {
-                    handle_pbft_result(node.start_view_change());
-                }
-            });
-
-            backlog_ticker.tick(|| {
-                handle_pbft_result(node.retry_backlog());
-            })
+            if !handle_update(
+                &mut node,
+                incoming_message,
+                &mut working_ticker,
+                &mut backlog_ticker,
+            ) {
+                break;
+            }
+            self.record_evidence(&mut node);
+            self.write_timelines(&mut node);
+            self.publish_status(&node);
         }
     }
 
@@ -122,11 +252,94 @@ impl Engine for PbftEngine {
     }
 }
 
-fn handle_pbft_result(res: Result<(), PbftError>) {
+/// Hand an update from the validator to the node (or let the node know that waiting for one timed
+/// out), then do whatever periodic work is due. Returns `false` once the engine should stop.
+pub fn handle_update(
+    node: &mut PbftNode,
+    incoming_message: Result<Update, RecvTimeoutError>,
+    working_ticker: &mut Ticker,
+    backlog_ticker: &mut Ticker,
+) -> bool {
+    logging::set_state(&node.state);
+    let mut sender = None;
+    let res = match incoming_message {
+        Ok(Update::BlockNew(block)) => node.on_block_new(block),
+        Ok(Update::BlockValid(block_id)) => node.on_block_valid(block_id),
+        Ok(Update::BlockInvalid(_)) => {
+            warn!(
+                "{}: BlockInvalid received, starting view change",
+                node.state
+            );
+            node.start_view_change()
+        }
+        Ok(Update::BlockCommit(block_id)) => node.on_block_commit(block_id),
+        Ok(Update::PeerMessage(message, sender_id)) => {
+            let peer = hex::encode(Vec::<u8>::from(sender_id.clone()));
+            // Any peer can send any message type, so only the types this node knows get their own
+            // label; the rest are all `Unset`
+            let msg_type = PbftMessageType::from(message.message_type.as_str());
+            let label = String::from(&msg_type);
+            node.metrics.inc(
+                &metrics::MESSAGES_RECEIVED,
+                &[peer.as_str(), label.as_str()],
+            );
+            logging::set_message(&label, Some(&peer));
+            node.on_peer_activity(&sender_id, &msg_type);
+            sender = Some(sender_id);
+            node.on_peer_message(&message)
+        }
+        Ok(Update::Shutdown) => return false,
+        Ok(Update::PeerConnected(info)) => node.on_peer_connected(info.peer_id),
+        Ok(Update::PeerDisconnected(peer_id)) => node.on_peer_disconnected(peer_id),
+        Err(RecvTimeoutError::Timeout) => Err(PbftError::Timeout),
+        Err(RecvTimeoutError::Disconnected) => {
+            error!("Disconnected from validator");
+            return false;
+        }
+    };
+    handle_pbft_result(node, sender.as_ref(), res);
+    logging::clear_message();
+    logging::set_state(&node.state);
+
+    working_ticker.tick(|| {
+        if let Err(e) = node.try_publish() {
+            error!("{}", e);
+        }
+
+        let res = node.check_peer_activity();
+        handle_pbft_result(node, None, res);
+
+        // Every so often, check to see if timeout has expired; initiate ViewChange if necessary
+        if node.check_timeout_expired() {
+            let res = node.start_view_change();
+            handle_pbft_result(node, None, res);
+        }
+    });
+
+    backlog_ticker.tick(|| {
+        let res = node.retry_backlog();
+        handle_pbft_result(node, None, res);
+    });
+
+    // Make sure a restart picks up from the node's latest state
+    let res = node.persist_state();
+    handle_pbft_result(node, None, res);
+    node.update_metrics();
+    true
+}
+
+/// Log the error from handling an update, if there was one. If the error shows that another node
+/// is faulty, the node's observers are told about it, along with the peer that sent the update
+/// (when it came from a peer).
+pub fn handle_pbft_result(node: &PbftNode, sender: Option<&PeerId>, res: Result<(), PbftError>) {
     if let Err(e) = res {
+        if e.is_fault() {
+            node.observers.peer_suspected(&node.state, sender, &e);
+        }
         match e {
             PbftError::Timeout => (),
             PbftError::WrongNumMessages(_, _, _) | PbftError::NotReadyForMessage => trace!("{}", e),
+            PbftError::OutsideWatermarks(_, _, _) | PbftError::BacklogFull(_) => warn!("{}", e),
             _ => error!("{}", e),
         }
     }
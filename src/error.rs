@@ -42,6 +42,10 @@ pub enum PbftError {
     /// The block in the message doesn't match the one this node was expecting
     BlockMismatch(PbftBlock, PbftBlock),
 
+    /// The block in the message has different batches (or a different order of batches) than the
+    /// one this node was expecting
+    BatchDigestMismatch(PbftBlock, PbftBlock),
+
     /// The message information doesn't match the one this node was expecting
     MessageMismatch(PbftMessageType),
 
@@ -65,6 +69,52 @@ pub enum PbftError {
 
     /// Not ready for this message type
     NotReadyForMessage,
+
+    /// The message's signature doesn't match its claimed signer (signer ID)
+    InvalidSignature(String),
+
+    /// A message that only the primary can send isn't from the primary of its view
+    NotFromPrimary(PbftMessageType),
+
+    /// A message was sent as a different type than the one it was signed as (description)
+    MessageTypeMismatch(String),
+
+    /// The message's signer isn't one of this node's peers (signer ID)
+    UnknownSigner(String),
+
+    /// A message couldn't be signed, or the signing key couldn't be loaded (description)
+    SigningError(String),
+
+    /// A block's consensus seal doesn't prove that the previous block was committed (description)
+    InvalidSeal(String),
+
+    /// A `ViewChange` or `NewView` message doesn't prove what it claims to (description)
+    InvalidViewChange(String),
+
+    /// The message's sequence number is outside of the log's watermarks (seq_num, low, high)
+    OutsideWatermarks(u64, u64, u64),
+
+    /// The backlog is full, so the message can't be saved for later (backlog length)
+    BacklogFull(usize),
+
+    /// The node's persistent storage couldn't be read or written (description)
+    StorageError(String),
+
+    /// A `NetworkChange` message isn't valid (description)
+    InvalidNetworkChange(String),
+
+    /// A `CatchUp` message doesn't prove what it claims to (description)
+    InvalidCatchUp(String),
+
+    /// A trace of the engine's updates couldn't be read or written (description)
+    TraceError(String),
+
+    /// Evidence of a faulty node doesn't prove what it claims to, or couldn't be read or written
+    /// (description)
+    InvalidEvidence(String),
+
+    /// Timelines of consensus rounds couldn't be written (description)
+    TimelineError(String),
 }
 
 impl Error for PbftError {
@@ -75,6 +125,7 @@ impl Error for PbftError {
             MessageExists(_) => "MessageExists",
             WrongNumMessages(_, _, _) => "WrongNumMessages",
             BlockMismatch(_, _) => "BlockMismatch",
+            BatchDigestMismatch(_, _) => "BatchDigestMismatch",
             MessageMismatch(_) => "MessageMismatch",
             ViewMismatch(_, _) => "ViewMismatch",
             InternalError(_) => "InternalError",
@@ -83,10 +134,44 @@ impl Error for PbftError {
             Timeout => "Timeout",
             NoWorkingBlock => "NoWorkingBlock",
             NotReadyForMessage => "NotReadyForMessage",
+            InvalidSignature(_) => "InvalidSignature",
+            NotFromPrimary(_) => "NotFromPrimary",
+            MessageTypeMismatch(_) => "MessageTypeMismatch",
+            UnknownSigner(_) => "UnknownSigner",
+            SigningError(_) => "SigningError",
+            InvalidSeal(_) => "InvalidSeal",
+            InvalidViewChange(_) => "InvalidViewChange",
+            OutsideWatermarks(_, _, _) => "OutsideWatermarks",
+            BacklogFull(_) => "BacklogFull",
+            StorageError(_) => "StorageError",
+            InvalidNetworkChange(_) => "InvalidNetworkChange",
+            InvalidCatchUp(_) => "InvalidCatchUp",
+            TraceError(_) => "TraceError",
+            InvalidEvidence(_) => "InvalidEvidence",
+            TimelineError(_) => "TimelineError",
         }
     }
 }
 
+impl PbftError {
+    /// Whether the error means that another node is faulty: it sent a message that isn't validly
+    /// signed, or a block, view change, network change, or catch-up that doesn't prove what it
+    /// claims to
+    pub fn is_fault(&self) -> bool {
+        matches!(
+            self,
+            PbftError::InvalidSignature(_)
+                | PbftError::NotFromPrimary(_)
+                | PbftError::MessageTypeMismatch(_)
+                | PbftError::BatchDigestMismatch(_, _)
+                | PbftError::InvalidSeal(_)
+                | PbftError::InvalidViewChange(_)
+                | PbftError::InvalidNetworkChange(_)
+                | PbftError::InvalidCatchUp(_)
+        )
+    }
+}
+
 impl fmt::Display for PbftError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}: ", self.description())?;
@@ -106,9 +191,16 @@ impl fmt::Display for PbftError {
             PbftError::ViewMismatch(exp, got) => write!(f, "View mismatch: {} != {}", exp, got),
             PbftError::BlockMismatch(exp, got) => write!(
                 f,
-                "{:?} != {:?}",
-                &hex::encode(exp.get_block_id())[..6],
-                &hex::encode(got.get_block_id())[..6]
+                "{:.6} != {:.6}",
+                hex::encode(exp.get_block_id()),
+                hex::encode(got.get_block_id())
+            ),
+            PbftError::BatchDigestMismatch(exp, got) => write!(
+                f,
+                "Batches in block {:.6} don't match: {:.6} != {:.6}",
+                hex::encode(exp.get_block_id()),
+                hex::encode(exp.get_batches_digest()),
+                hex::encode(got.get_batches_digest())
             ),
             PbftError::NodeNotFound => write!(f, "Couldn't find node in the network"),
             PbftError::WrongNumBlocks => write!(f, "Incorrect number of blocks"),
@@ -116,6 +208,33 @@ impl fmt::Display for PbftError {
             PbftError::InternalError(description) => write!(f, "{}", description),
             PbftError::NoWorkingBlock => write!(f, "There is no working block"),
             PbftError::NotReadyForMessage => write!(f, "Not ready"),
+            PbftError::InvalidSignature(signer) => {
+                write!(f, "Message is not validly signed by {}", signer)
+            }
+            PbftError::NotFromPrimary(t) => {
+                write!(f, "{:?} message isn't from the primary of its view", t)
+            }
+            PbftError::MessageTypeMismatch(description) => write!(f, "{}", description),
+            PbftError::UnknownSigner(signer) => {
+                write!(f, "Message is signed by {}, which isn't a peer", signer)
+            }
+            PbftError::SigningError(description) => write!(f, "{}", description),
+            PbftError::InvalidSeal(description) => write!(f, "{}", description),
+            PbftError::InvalidViewChange(description) => write!(f, "{}", description),
+            PbftError::OutsideWatermarks(seq_num, low, high) => write!(
+                f,
+                "Sequence number {} is outside of the log bounds ({}, {})",
+                seq_num, low, high
+            ),
+            PbftError::BacklogFull(len) => {
+                write!(f, "Backlog is full ({} messages); dropping message", len)
+            }
+            PbftError::StorageError(description) => write!(f, "{}", description),
+            PbftError::InvalidNetworkChange(description) => write!(f, "{}", description),
+            PbftError::InvalidCatchUp(description) => write!(f, "{}", description),
+            PbftError::TraceError(description) => write!(f, "{}", description),
+            PbftError::InvalidEvidence(description) => write!(f, "{}", description),
+            PbftError::TimelineError(description) => write!(f, "{}", description),
         }
     }
 }
@@ -0,0 +1,312 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Evidence that a node equivocated, which anyone can check without trusting the node that found
+//! it
+//!
+//! A node that signs two votes (`PrePrepare`, `Prepare`, or `Commit` messages) for different
+//! blocks with the same view and sequence number is faulty. The log notices this when it adds the
+//! second vote, and keeps both signed messages as a `PbftEvidence` record. The engine appends the
+//! records to an evidence file, written the same way `FileStorage` writes its entries, and the
+//! `pbft-evidence` binary checks every record in the file with `verify_evidence`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use hex;
+use protobuf;
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+use error::PbftError;
+use message_type::PbftMessageType;
+use protos::pbft_message::{PbftEvidence, PbftMessage, PbftSignedMessage};
+use signing;
+use storage::{decode_entries, encode_entry};
+
+/// Tell if messages of this type are votes, which an honest node only ever sends for one block
+/// in each view and sequence number
+pub fn is_vote(msg_type: &PbftMessageType) -> bool {
+    match msg_type {
+        PbftMessageType::PrePrepare | PbftMessageType::Prepare | PbftMessageType::Commit => true,
+        _ => false,
+    }
+}
+
+/// Tell if two votes conflict: the same node sent them with the same type, view, and sequence
+/// number, but for different blocks (including the same block with different batches)
+pub fn conflicts(first: &PbftMessage, second: &PbftMessage) -> bool {
+    let (first_info, second_info) = (first.get_info(), second.get_info());
+    is_vote(&PbftMessageType::from(first_info.get_msg_type()))
+        && first_info.get_msg_type() == second_info.get_msg_type()
+        && first_info.get_view() == second_info.get_view()
+        && first_info.get_seq_num() == second_info.get_seq_num()
+        && first_info.get_signer_id() == second_info.get_signer_id()
+        && first.get_block() != second.get_block()
+}
+
+/// Create a record of two conflicting votes, from the signed messages they arrived in
+pub fn make_evidence(
+    msg_type: &PbftMessageType,
+    first: PbftSignedMessage,
+    second: PbftSignedMessage,
+) -> PbftEvidence {
+    let mut evidence = PbftEvidence::new();
+    evidence.set_message_type(String::from(msg_type));
+    evidence.set_first(first);
+    evidence.set_second(second);
+    evidence
+}
+
+/// Check that a record proves that a node equivocated: both messages are validly signed by the
+/// same node, and they're votes of the record's type for different blocks with the same view and
+/// sequence number. Returns the node that signed them.
+pub fn verify_evidence(evidence: &PbftEvidence) -> Result<PeerId, PbftError> {
+    let msg_type = PbftMessageType::from(evidence.get_message_type());
+    if !is_vote(&msg_type) {
+        return Err(PbftError::InvalidEvidence(format!(
+            "{} messages aren't votes",
+            evidence.get_message_type()
+        )));
+    }
+
+    let first = parse_vote(&msg_type, evidence.get_first())?;
+    let second = parse_vote(&msg_type, evidence.get_second())?;
+    if !conflicts(&first, &second) {
+        return Err(PbftError::InvalidEvidence(String::from(
+            "Messages don't conflict with each other",
+        )));
+    }
+
+    Ok(PeerId::from(first.get_info().get_signer_id().to_vec()))
+}
+
+// Parse a signed vote, and make sure it's of the given type and validly signed
+fn parse_vote(
+    msg_type: &PbftMessageType,
+    signed_msg: &PbftSignedMessage,
+) -> Result<PbftMessage, PbftError> {
+    signing::verify(msg_type, signed_msg)
+        .map_err(|err| PbftError::InvalidEvidence(format!("{}", err)))?;
+    let msg = protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message())
+        .map_err(|err| PbftError::InvalidEvidence(format!("Invalid message: {}", err)))?;
+    if msg.get_info().get_msg_type() != String::from(msg_type) {
+        return Err(PbftError::InvalidEvidence(format!(
+            "Message is a {}, not a {}",
+            msg.get_info().get_msg_type(),
+            String::from(msg_type)
+        )));
+    }
+    Ok(msg)
+}
+
+/// Describe a record of two conflicting votes in one line, for logs and reports
+pub fn describe(evidence: &PbftEvidence) -> String {
+    let parse = |signed_msg: &PbftSignedMessage| {
+        protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message()).unwrap_or_default()
+    };
+    let (first, second) = (parse(evidence.get_first()), parse(evidence.get_second()));
+    format!(
+        "Node {:.8} sent {} messages for blocks {:.6} and {:.6} (view {}, seq {})",
+        hex::encode(first.get_info().get_signer_id()),
+        evidence.get_message_type(),
+        hex::encode(first.get_block().get_block_id()),
+        hex::encode(second.get_block().get_block_id()),
+        first.get_info().get_view(),
+        first.get_info().get_seq_num(),
+    )
+}
+
+/// Appends evidence records to a file, which keeps the records from earlier runs
+pub struct EvidenceRecorder {
+    path: PathBuf,
+    file: File,
+}
+
+impl EvidenceRecorder {
+    /// Open the evidence file at `path`, creating it (and its directory) if it doesn't exist yet
+    pub fn open(path: &Path) -> Result<Self, PbftError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| evidence_error(path, &err))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| evidence_error(path, &err))?;
+        Ok(EvidenceRecorder {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Write a record; once this returns, the record has to survive a crash
+    pub fn record(&mut self, evidence: &PbftEvidence) -> Result<(), PbftError> {
+        self.file
+            .write_all(&encode_entry(evidence)?)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|err| evidence_error(&self.path, &err))
+    }
+}
+
+/// Read back every record in an evidence file, oldest first; a record that was only partially
+/// written is ignored
+pub fn load_evidence(path: &Path) -> Result<Vec<PbftEvidence>, PbftError> {
+    let bytes = fs::read(path).map_err(|err| evidence_error(path, &err))?;
+    let (records, _) = decode_entries(&bytes).map_err(|err| {
+        PbftError::InvalidEvidence(format!("Corrupt record in {}: {}", path.display(), err))
+    })?;
+    Ok(records)
+}
+
+fn evidence_error(path: &Path, err: &io::Error) -> PbftError {
+    PbftError::InvalidEvidence(format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlers::make_msg_info;
+    use protobuf::Message;
+    use protos::pbft_message::PbftBlock;
+    use signing::mock_signer;
+    use std::env;
+
+    fn mock_vote(
+        msg_type: &PbftMessageType,
+        seq_num: u64,
+        block_id: u8,
+        signer: u64,
+    ) -> PbftSignedMessage {
+        let mut block = PbftBlock::new();
+        block.set_block_id(vec![block_id]);
+        block.set_block_num(seq_num);
+
+        let mut msg = PbftMessage::new();
+        msg.set_info(make_msg_info(
+            msg_type,
+            0,
+            seq_num,
+            mock_signer(signer).get_public_key().unwrap(),
+        ));
+        msg.set_block(block);
+        mock_signer(signer)
+            .sign(&msg.write_to_bytes().unwrap())
+            .unwrap()
+    }
+
+    /// Votes for two different blocks in the same view and sequence number prove that the node
+    /// that signed them is faulty
+    #[test]
+    fn valid_evidence() {
+        let evidence = make_evidence(
+            &PbftMessageType::Commit,
+            mock_vote(&PbftMessageType::Commit, 3, 1, 2),
+            mock_vote(&PbftMessageType::Commit, 3, 2, 2),
+        );
+        assert_eq!(
+            verify_evidence(&evidence).unwrap(),
+            mock_signer(2).get_public_key().unwrap()
+        );
+        assert!(describe(&evidence).contains("Commit messages for blocks 01 and 02"));
+    }
+
+    /// A node that votes for the same block twice, but with its batches reordered the second time,
+    /// is just as faulty
+    #[test]
+    fn reordered_batches_evidence() {
+        let first = mock_vote(&PbftMessageType::Prepare, 3, 1, 2);
+        let mut msg = protobuf::parse_from_bytes::<PbftMessage>(first.get_message()).unwrap();
+        msg.mut_block().set_batches_digest(b"reordered".to_vec());
+        let second = mock_signer(2).sign(&msg.write_to_bytes().unwrap()).unwrap();
+
+        let evidence = make_evidence(&PbftMessageType::Prepare, first, second);
+        assert_eq!(
+            verify_evidence(&evidence).unwrap(),
+            mock_signer(2).get_public_key().unwrap()
+        );
+    }
+
+    /// Evidence doesn't verify unless the messages really conflict, and are really signed by the
+    /// node they're from
+    #[test]
+    fn invalid_evidence() {
+        let commit = |seq_num, block_id, signer| {
+            mock_vote(&PbftMessageType::Commit, seq_num, block_id, signer)
+        };
+        let invalid = vec![
+            // Same block
+            make_evidence(&PbftMessageType::Commit, commit(3, 1, 2), commit(3, 1, 2)),
+            // Different sequence numbers
+            make_evidence(&PbftMessageType::Commit, commit(3, 1, 2), commit(4, 2, 2)),
+            // Different nodes
+            make_evidence(&PbftMessageType::Commit, commit(3, 1, 2), commit(3, 2, 1)),
+            // Different types
+            make_evidence(
+                &PbftMessageType::Commit,
+                commit(3, 1, 2),
+                mock_vote(&PbftMessageType::Prepare, 3, 2, 2),
+            ),
+            // Not votes
+            make_evidence(
+                &PbftMessageType::Checkpoint,
+                mock_vote(&PbftMessageType::Checkpoint, 3, 1, 2),
+                mock_vote(&PbftMessageType::Checkpoint, 3, 2, 2),
+            ),
+        ];
+        for evidence in invalid {
+            match verify_evidence(&evidence) {
+                Err(PbftError::InvalidEvidence(_)) => (),
+                res => panic!("Invalid evidence was accepted: {:?}", res),
+            }
+        }
+
+        // A forged signature
+        let mut forged = commit(3, 2, 2);
+        forged.set_signature(commit(3, 1, 2).get_signature().to_string());
+        let evidence = make_evidence(&PbftMessageType::Commit, commit(3, 1, 2), forged);
+        assert!(verify_evidence(&evidence).is_err());
+    }
+
+    /// Records written to an evidence file can be read back, along with records from before the
+    /// file was reopened
+    #[test]
+    fn evidence_file() {
+        let path = env::temp_dir().join(format!("pbft-evidence-{}", ::std::process::id()));
+        fs::remove_file(&path).unwrap_or(());
+
+        let evidence = make_evidence(
+            &PbftMessageType::Prepare,
+            mock_vote(&PbftMessageType::Prepare, 1, 1, 0),
+            mock_vote(&PbftMessageType::Prepare, 1, 2, 0),
+        );
+        EvidenceRecorder::open(&path)
+            .unwrap()
+            .record(&evidence)
+            .unwrap();
+        EvidenceRecorder::open(&path)
+            .unwrap()
+            .record(&evidence)
+            .unwrap();
+
+        let records = load_evidence(&path).unwrap();
+        assert_eq!(records, vec![evidence.clone(), evidence]);
+        assert!(verify_evidence(&records[0]).is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+}
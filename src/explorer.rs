@@ -0,0 +1,397 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Systematic exploration of the ways a small simulated network can run
+//!
+//! Random runs of the `simulator` only try the orders of events that their seeds happen to pick.
+//! The `Explorer` tries every execution that strays from a fixed default schedule in at most a
+//! given number of places, where straying means delivering a different update, letting a
+//! different node do its periodic work, losing an update, or making a node's view change timeout
+//! expire. (Most ordering bugs only need a few events to happen out of order to show up.) After
+//! every step of every execution, it checks that:
+//!
+//! + No two honest nodes have committed different blocks at the same height (sequence number)
+//! + No honest node's view has gone down
+//!
+//! Nodes can't be copied, so each execution is replayed from the start. When an invariant is
+//! broken, the events that led up to it are shrunk until leaving out any one of them would keep
+//! the invariant from being broken, and returned as a `Counterexample`.
+
+use std::fmt;
+
+use config::PbftConfig;
+use simulator::{SimEvent, SimulatedNetwork};
+
+/// A property of the network that must hold after every step
+pub type Invariant = fn(&SimulatedNetwork) -> bool;
+
+/// Explores the executions of a network of nodes with the given configuration
+pub struct Explorer {
+    config: PbftConfig,
+
+    /// An execution is over once every honest node has committed this many blocks
+    pub height: u64,
+
+    /// The most steps an execution can take
+    pub max_steps: usize,
+
+    /// How many times an execution can stray from the default schedule
+    pub max_deviations: usize,
+
+    /// How many updates can be lost in an execution
+    pub max_drops: usize,
+
+    /// How many view change timeouts can expire in an execution
+    pub max_timeouts: usize,
+
+    /// Stop exploring after this many executions
+    pub max_executions: usize,
+
+    /// More invariants to check after every step, by name
+    invariants: Vec<(&'static str, Invariant)>,
+}
+
+/// How much of the network's behavior an exploration covered
+#[derive(Debug)]
+pub struct Exploration {
+    /// How many executions were tried
+    pub executions: usize,
+
+    /// Whether every execution within the bounds was tried, or exploration stopped at
+    /// `max_executions`
+    pub complete: bool,
+}
+
+/// The events that lead to an invariant being broken, and which invariant it was
+#[derive(Debug)]
+pub struct Counterexample {
+    pub violation: String,
+    pub events: Vec<SimEvent>,
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}, after these events:", self.violation)?;
+        for (i, event) in self.events.iter().enumerate() {
+            writeln!(f, "{:>4}: {:?}", i, event)?;
+        }
+        Ok(())
+    }
+}
+
+// An execution that ran to the end without breaking any invariants
+struct Run {
+    events: Vec<SimEvent>,
+
+    // The other events that could have happened at each step (only filled in after the prefix
+    // that the execution was told to follow)
+    alternatives: Vec<Vec<SimEvent>>,
+}
+
+// Keeps track of what the invariants need to know about an execution so far
+struct Checker<'a> {
+    explorer: &'a Explorer,
+    views: Vec<u64>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(explorer: &'a Explorer, sim: &SimulatedNetwork) -> Self {
+        Checker {
+            explorer,
+            views: sim.nodes.iter().map(|node| node.state.view).collect(),
+        }
+    }
+
+    // Describe the first invariant that doesn't hold for the network, if there is one
+    fn check(&mut self, sim: &SimulatedNetwork) -> Option<String> {
+        if !sim.honest_nodes_agree() {
+            return Some(String::from(
+                "Honest nodes committed different blocks at the same height",
+            ));
+        }
+        for i in sim.honest_nodes() {
+            let view = sim.nodes[i].state.view;
+            if view < self.views[i] {
+                return Some(format!(
+                    "Node {}'s view went down from {} to {}",
+                    i, self.views[i], view
+                ));
+            }
+            self.views[i] = view;
+        }
+        self.explorer
+            .invariants
+            .iter()
+            .find(|(_, holds)| !holds(sim))
+            .map(|(name, _)| format!("Invariant \"{}\" doesn't hold", name))
+    }
+}
+
+impl Explorer {
+    /// Create an explorer with small default bounds: one block, and executions that stray from
+    /// the default schedule once
+    pub fn new(config: PbftConfig) -> Self {
+        Explorer {
+            config,
+            height: 1,
+            max_steps: 200,
+            max_deviations: 1,
+            max_drops: 1,
+            max_timeouts: 1,
+            max_executions: 10_000,
+            invariants: vec![],
+        }
+    }
+
+    /// Check another invariant after every step
+    pub fn add_invariant(&mut self, name: &'static str, holds: Invariant) {
+        self.invariants.push((name, holds));
+    }
+
+    /// Try every execution within the explorer's bounds, depth first, and return the first
+    /// counterexample found (shrunk), if any
+    pub fn explore(&self) -> Result<Exploration, Counterexample> {
+        let mut executions = 0;
+        let mut pending: Vec<(Vec<SimEvent>, usize)> = vec![(vec![], self.max_deviations)];
+        while let Some((prefix, deviations_left)) = pending.pop() {
+            if executions == self.max_executions {
+                return Ok(Exploration {
+                    executions,
+                    complete: false,
+                });
+            }
+            executions += 1;
+
+            let run = self
+                .run(&prefix)
+                .map_err(|counterexample| self.shrink(counterexample))?;
+            if deviations_left == 0 {
+                continue;
+            }
+
+            // Stray from this execution at each step after the last time it strayed
+            for step in (prefix.len()..run.events.len()).rev() {
+                for alternative in &run.alternatives[step] {
+                    let mut events = run.events[..step].to_vec();
+                    events.push(*alternative);
+                    pending.push((events, deviations_left - 1));
+                }
+            }
+        }
+
+        Ok(Exploration {
+            executions,
+            complete: true,
+        })
+    }
+
+    // Follow `prefix`, then the default schedule, until every honest node reaches the target
+    // height, the network has nothing left to do, or the execution has taken `max_steps` steps
+    fn run(&self, prefix: &[SimEvent]) -> Result<Run, Counterexample> {
+        let mut sim = SimulatedNetwork::with_config(&self.config, 0);
+        let mut checker = Checker::new(self, &sim);
+        let mut run = Run {
+            events: vec![],
+            alternatives: vec![],
+        };
+        let mut idle_ticks = 0;
+
+        while run.events.len() < self.max_steps && !self.done(&sim) {
+            let step = run.events.len();
+            let event = if step < prefix.len() {
+                run.alternatives.push(vec![]);
+                prefix[step]
+            } else {
+                let default = match self.default_event(&sim, step) {
+                    Some(event) => event,
+                    None => break,
+                };
+                let alternatives = self
+                    .enabled_events(&sim, &run.events)
+                    .into_iter()
+                    .filter(|event| *event != default)
+                    .collect();
+                run.alternatives.push(alternatives);
+                default
+            };
+
+            if !sim.apply(event) {
+                panic!("Replayed event {:?} can't happen at step {}", event, step);
+            }
+            run.events.push(event);
+
+            if let Some(violation) = checker.check(&sim) {
+                return Err(Counterexample {
+                    violation,
+                    events: run.events,
+                });
+            }
+
+            // Stop once the nodes have nothing left to do on their own
+            if step >= prefix.len() && sim.pending_links().is_empty() {
+                idle_ticks += 1;
+                if idle_ticks > sim.live_nodes().len() {
+                    break;
+                }
+            } else {
+                idle_ticks = 0;
+            }
+        }
+
+        Ok(run)
+    }
+
+    // Deliver the update on the first link that has one; if there aren't any, let the nodes do
+    // their periodic work in turn
+    fn default_event(&self, sim: &SimulatedNetwork, step: usize) -> Option<SimEvent> {
+        if let Some(&(from, to)) = sim.pending_links().first() {
+            return Some(SimEvent::Deliver(from, to));
+        }
+        let live = sim.live_nodes();
+        if live.is_empty() {
+            None
+        } else {
+            Some(SimEvent::Tick(live[step % live.len()]))
+        }
+    }
+
+    // Everything that could happen next, given the events that already have
+    fn enabled_events(&self, sim: &SimulatedNetwork, history: &[SimEvent]) -> Vec<SimEvent> {
+        let drops = history
+            .iter()
+            .filter(|event| matches!(event, SimEvent::Drop(_, _)))
+            .count();
+        let timeouts = history
+            .iter()
+            .filter(|event| matches!(event, SimEvent::Timeout(_)))
+            .count();
+
+        let mut events = vec![];
+        for (from, to) in sim.pending_links() {
+            events.push(SimEvent::Deliver(from, to));
+            // Updates from a node's own validator don't go over the network
+            if from != to && drops < self.max_drops {
+                events.push(SimEvent::Drop(from, to));
+            }
+        }
+        for node in sim.live_nodes() {
+            events.push(SimEvent::Tick(node));
+            if timeouts < self.max_timeouts {
+                events.push(SimEvent::Timeout(node));
+            }
+        }
+        events
+    }
+
+    fn done(&self, sim: &SimulatedNetwork) -> bool {
+        sim.honest_nodes()
+            .iter()
+            .all(|node| sim.height(*node) >= self.height)
+    }
+
+    // Replay exactly the given events, skipping any that can't happen; returns the events that
+    // happened up to the point that an invariant was broken, if one was
+    fn replay(&self, events: &[SimEvent]) -> Option<Counterexample> {
+        let mut sim = SimulatedNetwork::with_config(&self.config, 0);
+        let mut checker = Checker::new(self, &sim);
+        let mut happened = vec![];
+        for event in events {
+            if !sim.apply(*event) {
+                continue;
+            }
+            happened.push(*event);
+            if let Some(violation) = checker.check(&sim) {
+                return Some(Counterexample {
+                    violation,
+                    events: happened,
+                });
+            }
+        }
+        None
+    }
+
+    // Leave out events one at a time for as long as an invariant is still broken without them;
+    // leaving out a later event can make an earlier one unnecessary, so keep going until nothing
+    // more can be left out
+    fn shrink(&self, mut counterexample: Counterexample) -> Counterexample {
+        let mut shrunk = true;
+        while shrunk {
+            shrunk = false;
+            let mut i = 0;
+            while i < counterexample.events.len() {
+                let mut events = counterexample.events.clone();
+                events.remove(i);
+                match self.replay(&events) {
+                    Some(smaller) => {
+                        counterexample = smaller;
+                        shrunk = true;
+                    }
+                    None => i += 1,
+                }
+            }
+        }
+        counterexample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+
+    /// Four nodes can't be made to disagree on their first block, or to go back to an earlier
+    /// view, by reordering, losing, or timing out on any single event
+    #[test]
+    fn safe_with_one_deviation() {
+        let mut explorer = Explorer::new(mock_config(4));
+        explorer.max_executions = 300;
+        match explorer.explore() {
+            Ok(exploration) => assert!(exploration.executions > 1),
+            Err(counterexample) => panic!("{}", counterexample),
+        }
+    }
+
+    /// A broken invariant is reported with a counterexample that can't be made any smaller
+    #[test]
+    fn minimal_counterexample() {
+        fn nothing_committed(sim: &SimulatedNetwork) -> bool {
+            sim.honest_nodes().iter().all(|node| sim.height(*node) == 0)
+        }
+
+        let mut explorer = Explorer::new(mock_config(4));
+        explorer.max_deviations = 0;
+        explorer.add_invariant("nothing committed", nothing_committed);
+
+        let counterexample = match explorer.explore() {
+            Ok(_) => panic!("A block was never committed"),
+            Err(counterexample) => counterexample,
+        };
+        assert_eq!(
+            counterexample.violation,
+            "Invariant \"nothing committed\" doesn't hold"
+        );
+
+        // The node that commits first has to see the block, so the primary has to publish it
+        assert!(counterexample.events.contains(&SimEvent::Tick(0)));
+        assert!(explorer.replay(&counterexample.events).is_some());
+        for i in 0..counterexample.events.len() {
+            let mut events = counterexample.events.clone();
+            events.remove(i);
+            assert!(explorer.replay(&events).is_none());
+        }
+    }
+}
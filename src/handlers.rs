@@ -17,27 +17,41 @@
 
 //! Handlers for individual message types
 
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use hex;
+use protobuf;
+use protobuf::RepeatedField;
 
+use std::collections::{BTreeMap, HashSet};
 use std::convert::From;
 use std::error::Error;
 
 use sawtooth_sdk::consensus::engine::{Block, BlockId, PeerId, PeerMessage};
 use sawtooth_sdk::consensus::service::Service;
 
-use protos::pbft_message::{PbftBlock, PbftMessage, PbftMessageInfo, PbftViewChange};
+use protos::pbft_message::{
+    PbftBlock, PbftCatchUp, PbftMessage, PbftMessageInfo, PbftNetworkChange, PbftNewView,
+    PbftPreparedCertificate, PbftSeal, PbftSignedMessage, PbftViewChange,
+};
 
 use error::PbftError;
 use message_log::PbftLog;
 use message_type::{PbftHint, PbftMessageType};
-use state::{PbftMode, PbftPhase, PbftState, WorkingBlockOption};
+use observer::PbftObservers;
+use signing;
+use state::{PbftMode, PbftPhase, PbftPipelinedBlock, PbftState, WorkingBlockOption};
+use timeline::TimelineEvent;
 
 /// Take action based on a `PbftHint`
-/// Either push to backlog or add message to log, depending on which type of hint
+/// Either push to backlog or add message to log, depending on which type of hint. Past messages
+/// are added along with the signed message they arrived in, so that the log can still catch
+/// conflicting votes among them.
 pub fn action_from_hint(
     msg_log: &mut PbftLog,
     hint: &PbftHint,
     pbft_message: &PbftMessage,
+    signed_msg: &PbftSignedMessage,
     msg_content: Vec<u8>,
 ) -> Result<(), PbftError> {
     let msg = PeerMessage {
@@ -46,21 +60,23 @@ pub fn action_from_hint(
     };
     match hint {
         PbftHint::FutureMessage => {
-            msg_log.push_backlog(msg);
+            msg_log.push_backlog(msg, pbft_message.get_info().get_seq_num())?;
             Err(PbftError::NotReadyForMessage)
         }
         PbftHint::PastMessage => {
-            msg_log.add_message(pbft_message.clone());
+            msg_log.add_signed_message(pbft_message.clone(), signed_msg.clone())?;
             Err(PbftError::NotReadyForMessage)
         }
-        PbftHint::PresentMessage => Ok(()),
+        PbftHint::PresentMessage | PbftHint::PipelinedMessage => Ok(()),
     }
 }
 
 /// Handle a `PrePrepare` message
-/// A `PrePrepare` message with this view and sequence number must not already exist in the log. If
-/// this node is a primary, make sure there's a corresponding BlockNew message. If this node is a
-/// secondary, then it takes the sequence number from this message as its own.
+/// The message has to be from the primary of its view, and a `PrePrepare` message with this view
+/// and sequence number must not already exist in the log. If this node is a primary, make sure
+/// there's a corresponding BlockNew message. If this node is a secondary, then it checks that the
+/// primary didn't change the batches in the block, and takes the sequence number from this message
+/// as its own.
 pub fn pre_prepare(
     state: &mut PbftState,
     msg_log: &mut PbftLog,
@@ -75,6 +91,8 @@ pub fn pre_prepare(
         ));
     }
 
+    check_from_primary(state, pbft_message)?;
+
     // Immutably borrow msg_log for a bit, in a context
     {
         // Check that this PrePrepare doesn't already exist
@@ -91,27 +109,17 @@ pub fn pre_prepare(
 
     if state.is_primary() {
         // Check that incoming PrePrepare matches original BlockNew
-        let block_new_msgs = msg_log.get_messages_of_type(
-            &PbftMessageType::BlockNew,
-            info.get_seq_num(),
-            info.get_view(),
-        );
-
-        if block_new_msgs.len() != 1 {
-            return Err(PbftError::WrongNumMessages(
-                PbftMessageType::BlockNew,
-                1,
-                block_new_msgs.len(),
-            ));
+        check_block_new(msg_log, pbft_message)?;
+    } else {
+        // The primary can't reorder the batches in the block it's proposing
+        if let Some(block_new) = msg_log
+            .get_messages_of_type(&PbftMessageType::BlockNew, 0, info.get_view())
+            .iter()
+            .find(|msg| msg.get_block().get_block_id() == pbft_message.get_block().get_block_id())
+        {
+            check_batches_digest(block_new.get_block(), pbft_message.get_block())?;
         }
 
-        if block_new_msgs[0].get_block() != pbft_message.get_block() {
-            return Err(PbftError::BlockMismatch(
-                block_new_msgs[0].get_block().clone(),
-                pbft_message.get_block().clone(),
-            ));
-        }
-    } else {
         // Set this secondary's sequence number from the PrePrepare message
         // (this was originally set by the primary)...
         state.seq_num = info.get_seq_num();
@@ -147,6 +155,145 @@ pub fn pre_prepare(
     Ok(())
 }
 
+/// Handle a `PrePrepare` message for a block in the pipeline (one that goes through consensus
+/// ahead of the working block)
+/// The checks are the same as in `pre_prepare`, except that a secondary takes the block from its
+/// block backlog instead of from a `BlockNew` message, since it only gets a `BlockNew` message for
+/// its working block. The block has to build on the last block this node is working on. Once the
+/// message checks out, consensus on the block moves on to the `Preparing` phase.
+pub fn pipelined_pre_prepare(
+    state: &mut PbftState,
+    msg_log: &mut PbftLog,
+    pbft_message: &PbftMessage,
+) -> Result<(), PbftError> {
+    let info = pbft_message.get_info();
+
+    if info.get_view() != state.view {
+        return Err(PbftError::ViewMismatch(
+            info.get_view() as usize,
+            state.view as usize,
+        ));
+    }
+
+    check_from_primary(state, pbft_message)?;
+
+    if !msg_log
+        .get_messages_of_type(
+            &PbftMessageType::PrePrepare,
+            info.get_seq_num(),
+            info.get_view(),
+        )
+        .is_empty()
+    {
+        return Err(PbftError::MessageExists(PbftMessageType::PrePrepare));
+    }
+
+    if state.is_primary() {
+        check_block_new(msg_log, pbft_message)?;
+    } else {
+        let previous_id = match state.pipeline.values().next_back() {
+            Some(pipelined) => pipelined.block.get_block_id().to_vec(),
+            None => match state.working_block {
+                WorkingBlockOption::WorkingBlock(ref block) => block.get_block_id().to_vec(),
+                _ => return Err(PbftError::NoWorkingBlock),
+            },
+        };
+
+        let block_id = BlockId::from(pbft_message.get_block().get_block_id().to_vec());
+        let block = msg_log
+            .take_from_block_backlog(&block_id)
+            .ok_or(PbftError::NotReadyForMessage)?;
+        let pbft_block = pbft_block_from_block(block.clone());
+
+        let checked = check_batches_digest(&pbft_block, pbft_message.get_block()).and_then(|_| {
+            if Vec::<u8>::from(block.previous_id.clone()) == previous_id {
+                Ok(())
+            } else {
+                Err(PbftError::BlockMismatch(
+                    pbft_block.clone(),
+                    pbft_message.get_block().clone(),
+                ))
+            }
+        });
+        if let Err(err) = checked {
+            msg_log.push_block_backlog(block);
+            return Err(err);
+        }
+
+        let mut block_new = PbftMessage::new();
+        block_new.set_info(make_msg_info(
+            &PbftMessageType::BlockNew,
+            info.get_view(),
+            info.get_seq_num(),
+            state.get_own_peer_id(),
+        ));
+        block_new.set_block(pbft_block);
+        msg_log.add_message(block_new)?;
+    }
+
+    state.pipeline.insert(
+        info.get_seq_num(),
+        PbftPipelinedBlock {
+            phase: PbftPhase::Preparing,
+            block: pbft_message.get_block().clone(),
+        },
+    );
+    state.record_pipelined_step(info.get_seq_num(), TimelineEvent::PrePrepare);
+
+    Ok(())
+}
+
+// Only the primary of a view can propose blocks in it; this is checked before anything else about
+// a PrePrepare, so that other nodes can't pass off a tampered block as the primary's
+fn check_from_primary(state: &PbftState, pbft_message: &PbftMessage) -> Result<(), PbftError> {
+    let info = pbft_message.get_info();
+    let primary = state.get_primary_peer_id_for_view(info.get_view());
+    if info.get_signer_id() != &Vec::<u8>::from(primary)[..] {
+        return Err(PbftError::NotFromPrimary(PbftMessageType::from(
+            info.get_msg_type(),
+        )));
+    }
+    Ok(())
+}
+
+// Check that a PrePrepare is for the same block as this node's BlockNew with the same view and
+// sequence number
+fn check_block_new(msg_log: &PbftLog, pbft_message: &PbftMessage) -> Result<(), PbftError> {
+    let info = pbft_message.get_info();
+    let block_new_msgs = msg_log.get_messages_of_type(
+        &PbftMessageType::BlockNew,
+        info.get_seq_num(),
+        info.get_view(),
+    );
+
+    if block_new_msgs.len() != 1 {
+        return Err(PbftError::WrongNumMessages(
+            PbftMessageType::BlockNew,
+            1,
+            block_new_msgs.len(),
+        ));
+    }
+
+    if block_new_msgs[0].get_block() != pbft_message.get_block() {
+        return Err(PbftError::BlockMismatch(
+            block_new_msgs[0].get_block().clone(),
+            pbft_message.get_block().clone(),
+        ));
+    }
+    Ok(())
+}
+
+// Check that a block has the same batches as the one the validator gave this node
+fn check_batches_digest(expected: &PbftBlock, block: &PbftBlock) -> Result<(), PbftError> {
+    if expected.get_batches_digest() != block.get_batches_digest() {
+        return Err(PbftError::BatchDigestMismatch(
+            expected.clone(),
+            block.clone(),
+        ));
+    }
+    Ok(())
+}
+
 /// Handle a `Commit` message
 /// Once a `2f + 1` `Commit` messages are received, the primary node can commit the block to the
 /// chain. If the block in the message isn't the one that belongs on top of the current chain head,
@@ -200,7 +347,7 @@ pub fn commit(
             message_type: String::from(pbft_message.get_info().get_msg_type()),
             content: msg_content,
         };
-        msg_log.push_backlog(msg);
+        msg_log.push_backlog(msg, pbft_message.get_info().get_seq_num())?;
         return Err(PbftError::BlockMismatch(
             pbft_message.get_block().clone(),
             working_block.clone(),
@@ -229,6 +376,16 @@ pub fn commit(
 /// (`PrePrepare`, `Prepare`, and `Commit`)
 pub fn multicast_hint(state: &PbftState, pbft_message: &PbftMessage) -> PbftHint {
     let msg_type = PbftMessageType::from(pbft_message.get_info().get_msg_type());
+    let seq_num = pbft_message.get_info().get_seq_num();
+
+    // Messages for blocks in the pipeline, or a PrePrepare that starts a new one
+    let pipelined = state.pipeline.contains_key(&seq_num)
+        || (msg_type == PbftMessageType::PrePrepare
+            && state.next_pipelined_seq_num() == Some(seq_num));
+    if pipelined && state.mode != PbftMode::ViewChanging {
+        debug!("{}: seq {} is in the pipeline", state, seq_num);
+        return PbftHint::PipelinedMessage;
+    }
 
     if pbft_message.get_info().get_seq_num() > state.seq_num {
         debug!(
@@ -284,52 +441,145 @@ pub fn multicast_hint(state: &PbftState, pbft_message: &PbftMessage) -> PbftHint
 }
 
 /// Handle a `ViewChange` message
-/// Once a node receives `2f + 1` `ViewChange` messages, the node enters view `v + 1` and changes
-/// itself into the appropriate role for that view (i.e. if `v = 1` and this is node 1, then this
-/// node is now the primary).
+/// Once a node receives `2f + 1` `ViewChange` messages for the view it is changing to, the primary
+/// of that view builds a `NewView` message to announce it, and returns it so it can be broadcast.
+/// Secondaries keep waiting in `ViewChanging` mode until the `NewView` arrives. The observers are
+/// told about the view change once, when its `2f + 1`th `ViewChange` arrives.
 pub fn view_change(
+    state: &PbftState,
+    msg_log: &PbftLog,
+    vc_message: &PbftViewChange,
+    observers: &PbftObservers,
+) -> Result<Option<PbftNewView>, PbftError> {
+    let view = vc_message.get_info().get_view();
+    let signed_vcs = msg_log.get_signed_view_changes(view);
+    if (signed_vcs.len() as u64) < 2 * state.f + 1 {
+        return Err(PbftError::WrongNumMessages(
+            PbftMessageType::ViewChange,
+            (2 * state.f + 1) as usize,
+            signed_vcs.len(),
+        ));
+    }
+
+    // Only the message that completes the quorum is news
+    if signed_vcs.len() as u64 == 2 * state.f + 1 {
+        observers.view_changed(state, view);
+    }
+
+    if state.get_own_peer_id() != state.get_primary_peer_id_for_view(view) {
+        debug!("{}: Waiting for NewView for view {}", state, view);
+        return Ok(None);
+    }
+
+    let view_changes = signed_vcs
+        .iter()
+        .map(|signed| protobuf::parse_from_bytes::<PbftViewChange>(signed.get_message()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(PbftError::SerializationError)?;
+    let stable_seq_num = view_changes
+        .iter()
+        .map(|vc| vc.get_info().get_seq_num())
+        .max()
+        .unwrap_or(0);
+    let pre_prepares = reissued_pre_prepares(&view_changes, view, state.get_own_peer_id())?;
+
+    let mut new_view = PbftNewView::new();
+    new_view.set_info(make_msg_info(
+        &PbftMessageType::NewView,
+        view,
+        stable_seq_num,
+        state.get_own_peer_id(),
+    ));
+    new_view.set_view_changes(RepeatedField::from_vec(signed_vcs));
+    new_view.set_pre_prepares(RepeatedField::from_vec(pre_prepares));
+    Ok(Some(new_view))
+}
+
+/// Handle a `NewView` message
+/// After checking that the message is valid, the node enters the new view and changes itself into
+/// the appropriate role for that view (i.e. if `v = 1` and this is node 1, then this node is now
+/// the primary). Any blocks in the pipeline are dropped. Returns the re-issued `PrePrepare`
+/// messages for the blocks that still have to be committed, in order.
+pub fn new_view(
     state: &mut PbftState,
-    msg_log: &mut PbftLog,
     service: &mut Service,
-    vc_message: &PbftViewChange,
-) -> Result<(), PbftError> {
-    msg_log.check_msg_against_log(&vc_message, true, 2 * state.f + 1)?;
+    new_view: &PbftNewView,
+) -> Result<Vec<PbftMessage>, PbftError> {
+    verify_new_view(state, new_view)?;
 
     // Update current view and stop timeout
-    state.view = vc_message.get_info().get_view();
+    state.view = new_view.get_info().get_view();
     warn!("{}: Updating to view {}", state, state.view);
 
+    // Blocks that have made it onto the chain are already taken care of
+    let head = service
+        .get_chain_head()
+        .map_err(|e| PbftError::InternalError(e.description().to_string()))?;
+    let reissued: Vec<PbftMessage> = new_view
+        .get_pre_prepares()
+        .iter()
+        .filter(|msg| msg.get_block().get_block_num() > head.block_num)
+        .cloned()
+        .collect();
+    let pipelined = state.abort_pipeline();
+
     // Upgrade this node to primary, if its ID is correct
     if state.get_own_peer_id() == state.get_primary_peer_id() {
         state.upgrade_role();
         warn!("{}: I'm now a primary", state);
 
         // If we're the new primary, need to clean up the block mess from the view change and
-        // initialize a new block.
-        if let WorkingBlockOption::WorkingBlock(ref working_block) = state.working_block {
-            info!(
-                "{}: Ignoring block {}",
-                state,
-                &hex::encode(working_block.get_block_id())
-            );
-            service
-                .ignore_block(BlockId::from(working_block.get_block_id().to_vec()))
-                .unwrap_or_else(|e| error!("Couldn't ignore block: {}", e));
-        } else if let WorkingBlockOption::TentativeWorkingBlock(ref block_id) = state.working_block
-        {
-            info!("{}: Ignoring block {}", state, &hex::encode(block_id));
+        // initialize a new block, unless a block from the old view still has to be committed.
+        let reissued_ids: Vec<BlockId> = reissued
+            .iter()
+            .map(|msg| BlockId::from(msg.get_block().get_block_id().to_vec()))
+            .collect();
+        let mut stale_ids: Vec<BlockId> = match state.working_block {
+            WorkingBlockOption::WorkingBlock(ref working_block) => {
+                vec![BlockId::from(working_block.get_block_id().to_vec())]
+            }
+            WorkingBlockOption::TentativeWorkingBlock(ref block_id) => vec![block_id.clone()],
+            WorkingBlockOption::NoWorkingBlock => vec![],
+        };
+        stale_ids.extend(
+            pipelined
+                .iter()
+                .map(|block| BlockId::from(block.get_block_id().to_vec())),
+        );
+        for block_id in stale_ids {
+            if !reissued_ids.contains(&block_id) {
+                info!(
+                    "{}: Ignoring block {}",
+                    state,
+                    &hex::encode(Vec::<u8>::from(block_id.clone()))
+                );
+                service
+                    .ignore_block(block_id)
+                    .unwrap_or_else(|e| error!("Couldn't ignore block: {}", e));
+            }
+        }
+        if reissued.is_empty() {
+            info!("{}: Initializing block", state);
             service
-                .ignore_block(block_id.clone())
-                .unwrap_or_else(|e| error!("Couldn't ignore block: {}", e));
+                .initialize_block(None)
+                .unwrap_or_else(|err| error!("Couldn't initialize block: {}", err));
         }
-        info!("{}: Initializing block", state);
-        service
-            .initialize_block(None)
-            .unwrap_or_else(|err| error!("Couldn't initialize block: {}", err));
     } else {
         warn!("{}: I'm now a secondary", state);
         state.downgrade_role();
     }
+
+    // The working block's round of consensus only goes on if its block was reissued
+    let continues = state.timelines.working.as_ref().map_or(false, |timeline| {
+        reissued
+            .iter()
+            .any(|msg| msg.get_block().get_block_id() == &timeline.block_id[..])
+    });
+    if continues {
+        state.record_step(TimelineEvent::ViewChange);
+    } else {
+        state.timelines.working = None;
+    }
     state.working_block = WorkingBlockOption::NoWorkingBlock;
     state.phase = PbftPhase::NotStarted;
     state.mode = PbftMode::Normal;
@@ -338,11 +588,525 @@ pub fn view_change(
         "{}: Entered normal mode in new view {} and stopped timeout",
         state, state.view
     );
+    Ok(reissued)
+}
+
+/// Check a prepared certificate, and return the `PrePrepare` message from it
+/// The `PrePrepare` has to be signed by the primary of its view, and the certificate needs valid
+/// `Prepare` messages for the same block, view, and sequence number from `2f + 1` different nodes
+/// in the network.
+pub fn verify_prepared_certificate(
+    state: &PbftState,
+    cert: &PbftPreparedCertificate,
+) -> Result<PbftMessage, PbftError> {
+    signing::verify(&PbftMessageType::PrePrepare, cert.get_pre_prepare())?;
+    let pre_prepare =
+        protobuf::parse_from_bytes::<PbftMessage>(cert.get_pre_prepare().get_message())
+            .map_err(PbftError::SerializationError)?;
+    let pp_info = pre_prepare.get_info();
+
+    if PbftMessageType::from(pp_info.get_msg_type()) != PbftMessageType::PrePrepare
+        || pp_info.get_signer_id()
+            != &Vec::<u8>::from(state.get_primary_peer_id_for_view(pp_info.get_view()))[..]
+    {
+        return Err(PbftError::InvalidViewChange(String::from(
+            "Prepared certificate doesn't contain a PrePrepare from the primary",
+        )));
+    }
+
+    let mut signers: HashSet<Vec<u8>> = HashSet::new();
+    for signed_msg in cert.get_prepare_messages() {
+        signing::verify(&PbftMessageType::Prepare, signed_msg)?;
+        let prepare = protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message())
+            .map_err(PbftError::SerializationError)?;
+        let info = prepare.get_info();
+
+        if PbftMessageType::from(info.get_msg_type()) != PbftMessageType::Prepare
+            || info.get_view() != pp_info.get_view()
+            || info.get_seq_num() != pp_info.get_seq_num()
+            || prepare.get_block().get_block_id() != pre_prepare.get_block().get_block_id()
+        {
+            return Err(PbftError::InvalidViewChange(String::from(
+                "Prepared certificate contains a message that doesn't match its PrePrepare",
+            )));
+        }
+
+        state
+            .get_node_id_from_bytes(info.get_signer_id())
+            .map_err(|_| {
+                PbftError::InvalidViewChange(format!(
+                    "Prepared certificate contains a Prepare from unknown node {}",
+                    hex::encode(info.get_signer_id())
+                ))
+            })?;
+        signers.insert(info.get_signer_id().to_vec());
+    }
+
+    if (signers.len() as u64) < 2 * state.f + 1 {
+        return Err(PbftError::InvalidViewChange(format!(
+            "Prepared certificate only has Prepares from {} nodes; expected {}",
+            signers.len(),
+            2 * state.f + 1
+        )));
+    }
+
+    Ok(pre_prepare)
+}
+
+/// Check that the prepared certificates in a `ViewChange` message are valid, and that they're all
+/// for blocks after the message's stable checkpoint and from views before the one being changed to
+pub fn verify_view_change(state: &PbftState, vc_message: &PbftViewChange) -> Result<(), PbftError> {
+    let info = vc_message.get_info();
+    for cert in vc_message.get_prepared_certificates() {
+        let pre_prepare = verify_prepared_certificate(state, cert)?;
+        if pre_prepare.get_info().get_seq_num() <= info.get_seq_num()
+            || pre_prepare.get_info().get_view() >= info.get_view()
+        {
+            return Err(PbftError::InvalidViewChange(String::from(
+                "ViewChange contains a prepared certificate from outside of its range",
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Check a `NewView` message
+/// It has to come from the primary of the new view, contain valid `ViewChange` messages for that
+/// view from `2f + 1` different nodes in the network, and re-issue exactly the `PrePrepare`
+/// messages that those `ViewChange` messages call for.
+pub fn verify_new_view(state: &PbftState, new_view: &PbftNewView) -> Result<(), PbftError> {
+    let info = new_view.get_info();
+    let view = info.get_view();
+
+    if PbftMessageType::from(info.get_msg_type()) != PbftMessageType::NewView
+        || info.get_signer_id() != &Vec::<u8>::from(state.get_primary_peer_id_for_view(view))[..]
+    {
+        return Err(PbftError::InvalidViewChange(format!(
+            "NewView for view {} isn't from that view's primary",
+            view
+        )));
+    }
+
+    let mut signers: HashSet<Vec<u8>> = HashSet::new();
+    let mut view_changes: Vec<PbftViewChange> = Vec::new();
+    for signed_msg in new_view.get_view_changes() {
+        signing::verify(&PbftMessageType::ViewChange, signed_msg)?;
+        let vc_message = protobuf::parse_from_bytes::<PbftViewChange>(signed_msg.get_message())
+            .map_err(PbftError::SerializationError)?;
+        let vc_info = vc_message.get_info();
+
+        if PbftMessageType::from(vc_info.get_msg_type()) != PbftMessageType::ViewChange
+            || vc_info.get_view() != view
+        {
+            return Err(PbftError::InvalidViewChange(format!(
+                "NewView contains a message that isn't a ViewChange for view {}",
+                view
+            )));
+        }
+        state
+            .get_node_id_from_bytes(vc_info.get_signer_id())
+            .map_err(|_| {
+                PbftError::InvalidViewChange(format!(
+                    "NewView contains a ViewChange from unknown node {}",
+                    hex::encode(vc_info.get_signer_id())
+                ))
+            })?;
+        verify_view_change(state, &vc_message)?;
+
+        signers.insert(vc_info.get_signer_id().to_vec());
+        view_changes.push(vc_message);
+    }
+
+    if (signers.len() as u64) < 2 * state.f + 1 {
+        return Err(PbftError::InvalidViewChange(format!(
+            "NewView only has ViewChanges from {} nodes; expected {}",
+            signers.len(),
+            2 * state.f + 1
+        )));
+    }
+
+    let expected = reissued_pre_prepares(
+        &view_changes,
+        view,
+        PeerId::from(info.get_signer_id().to_vec()),
+    )?;
+    if new_view.get_pre_prepares() != &expected[..] {
+        return Err(PbftError::InvalidViewChange(String::from(
+            "NewView doesn't re-issue the blocks that were prepared in the previous view",
+        )));
+    }
+
     Ok(())
 }
 
-// There should only be one block with a matching ID
-fn get_block_by_id(service: &mut Service, block_id: &BlockId) -> Option<Block> {
+/// Decide which blocks the primary of a new view has to propose again
+/// For every sequence number after the latest stable checkpoint in `view_changes`, the block from
+/// the prepared certificate with the highest view is re-issued in a `PrePrepare` for the new view.
+/// The certificates must already have been checked.
+pub fn reissued_pre_prepares(
+    view_changes: &[PbftViewChange],
+    view: u64,
+    primary: PeerId,
+) -> Result<Vec<PbftMessage>, PbftError> {
+    let stable_seq_num = view_changes
+        .iter()
+        .map(|vc| vc.get_info().get_seq_num())
+        .max()
+        .unwrap_or(0);
+
+    let mut prepared: BTreeMap<u64, PbftMessage> = BTreeMap::new();
+    for vc_message in view_changes {
+        for cert in vc_message.get_prepared_certificates() {
+            let pre_prepare =
+                protobuf::parse_from_bytes::<PbftMessage>(cert.get_pre_prepare().get_message())
+                    .map_err(PbftError::SerializationError)?;
+            let seq_num = pre_prepare.get_info().get_seq_num();
+            if seq_num <= stable_seq_num {
+                continue;
+            }
+            let newer = prepared.get(&seq_num).map_or(true, |msg| {
+                msg.get_info().get_view() < pre_prepare.get_info().get_view()
+            });
+            if newer {
+                prepared.insert(seq_num, pre_prepare);
+            }
+        }
+    }
+
+    Ok(prepared
+        .into_iter()
+        .map(|(seq_num, old_pre_prepare)| {
+            let mut msg = PbftMessage::new();
+            msg.set_info(make_msg_info(
+                &PbftMessageType::PrePrepare,
+                view,
+                seq_num,
+                primary.clone(),
+            ));
+            msg.set_block(old_pre_prepare.get_block().clone());
+            msg
+        })
+        .collect())
+}
+
+/// Build the consensus seal for a committed block, from the signed `Commit` messages in the log.
+/// Returns `None` if the log doesn't hold `2f + 1` of them.
+pub fn make_consensus_seal(
+    state: &PbftState,
+    msg_log: &PbftLog,
+    block: &Block,
+) -> Option<PbftSeal> {
+    let commit_messages = msg_log
+        .get_commit_certificate(&Vec::<u8>::from(block.block_id.clone()), 2 * state.f + 1)?;
+
+    let mut seal = PbftSeal::new();
+    seal.set_block(pbft_block_from_block(block.clone()));
+    seal.set_commit_messages(RepeatedField::from_vec(commit_messages));
+    Some(seal)
+}
+
+/// Check the consensus seal in a new block's payload
+/// The seal must prove that the block `pipeline_depth` blocks before this one was committed (see
+/// `verify_commit_certificate`); that's `sealed_id`, the block's ancestor on its own chain (without
+/// pipelining, its predecessor). The first `pipeline_depth` blocks after the genesis block carry no
+/// seal.
+pub fn verify_consensus_seal(
+    state: &PbftState,
+    msg_log: &PbftLog,
+    block: &Block,
+    sealed_id: &BlockId,
+) -> Result<(), PbftError> {
+    if block.block_num <= state.pipeline_depth {
+        return if block.payload.is_empty() {
+            Ok(())
+        } else {
+            Err(PbftError::InvalidSeal(String::from(
+                "Blocks this close to genesis should not be sealed",
+            )))
+        };
+    }
+
+    let seal = protobuf::parse_from_bytes::<PbftSeal>(&block.payload)
+        .map_err(|_| PbftError::InvalidSeal(String::from("Seal could not be parsed")))?;
+
+    if seal.get_block().get_block_num() != block.block_num - state.pipeline_depth
+        || seal.get_block().get_block_id() != &Vec::<u8>::from(sealed_id.clone())[..]
+    {
+        return Err(PbftError::InvalidSeal(String::from(
+            "Seal is missing or is not for the right block",
+        )));
+    }
+
+    verify_commit_certificate(state, msg_log, &seal).map(|_| ())
+}
+
+/// Check that a seal proves its block was committed
+/// It needs valid signatures on `Commit` messages for the block from `2f + 1` different nodes in
+/// the network, all in the same view and sequence number. None of the `Commit` messages can
+/// contradict one that this node has in its own log. Returns the information from one of the
+/// `Commit` messages, which has the view and sequence number the block was committed in.
+pub fn verify_commit_certificate(
+    state: &PbftState,
+    msg_log: &PbftLog,
+    seal: &PbftSeal,
+) -> Result<PbftMessageInfo, PbftError> {
+    let mut signers: HashSet<Vec<u8>> = HashSet::new();
+    let mut seal_info: Option<PbftMessageInfo> = None;
+    for signed_msg in seal.get_commit_messages() {
+        signing::verify(&PbftMessageType::Commit, signed_msg)?;
+        let commit = protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message())
+            .map_err(PbftError::SerializationError)?;
+        let info = commit.get_info();
+
+        if PbftMessageType::from(info.get_msg_type()) != PbftMessageType::Commit
+            || commit.get_block().get_block_id() != seal.get_block().get_block_id()
+        {
+            return Err(PbftError::InvalidSeal(String::from(
+                "Seal contains a message that isn't a Commit for the sealed block",
+            )));
+        }
+
+        // All Commits have to be from the same round of consensus
+        let first_info = seal_info.get_or_insert_with(|| info.clone());
+        if first_info.get_view() != info.get_view()
+            || first_info.get_seq_num() != info.get_seq_num()
+        {
+            return Err(PbftError::InvalidSeal(String::from(
+                "Seal contains Commits from different views or sequence numbers",
+            )));
+        }
+
+        state
+            .get_node_id_from_bytes(info.get_signer_id())
+            .map_err(|_| {
+                PbftError::InvalidSeal(format!(
+                    "Seal contains a Commit from unknown node {}",
+                    hex::encode(info.get_signer_id())
+                ))
+            })?;
+
+        // A signer can't have voted for something different in our own log
+        let contradicted = msg_log
+            .get_messages_of_type(
+                &PbftMessageType::Commit,
+                info.get_seq_num(),
+                info.get_view(),
+            )
+            .iter()
+            .any(|msg| {
+                msg.get_info().get_signer_id() == info.get_signer_id()
+                    && msg.get_block().get_block_id() != commit.get_block().get_block_id()
+            });
+        if contradicted {
+            return Err(PbftError::InvalidSeal(String::from(
+                "Seal is inconsistent with this node's log",
+            )));
+        }
+
+        signers.insert(info.get_signer_id().to_vec());
+    }
+
+    if (signers.len() as u64) < 2 * state.f + 1 {
+        return Err(PbftError::InvalidSeal(format!(
+            "Seal only has Commits from {} nodes; expected {}",
+            signers.len(),
+            2 * state.f + 1
+        )));
+    }
+
+    Ok(seal_info.expect("Seal has Commits from 2f + 1 nodes"))
+}
+
+/// Check a `NetworkChange` message
+/// It has to be signed by a node in the network; tentative proposals have to be signed by the
+/// primary of the view they were sent in. The proposed peers can't be empty or contain duplicates.
+pub fn verify_network_change(state: &PbftState, nc: &PbftNetworkChange) -> Result<(), PbftError> {
+    let info = nc.get_info();
+
+    if PbftMessageType::from(info.get_msg_type()) != PbftMessageType::NetworkChange {
+        return Err(PbftError::InvalidNetworkChange(String::from(
+            "Message isn't a NetworkChange",
+        )));
+    }
+    state
+        .get_node_id_from_bytes(info.get_signer_id())
+        .map_err(|_| {
+            PbftError::InvalidNetworkChange(format!(
+                "NetworkChange is from unknown node {}",
+                hex::encode(info.get_signer_id())
+            ))
+        })?;
+    if nc.get_tentative()
+        && info.get_signer_id()
+            != &Vec::<u8>::from(state.get_primary_peer_id_for_view(info.get_view()))[..]
+    {
+        return Err(PbftError::InvalidNetworkChange(format!(
+            "Tentative NetworkChange isn't from the primary of view {}",
+            info.get_view()
+        )));
+    }
+
+    let peers: HashSet<&Vec<u8>> = nc.get_peers().iter().collect();
+    if peers.is_empty() || peers.len() != nc.get_peers().len() {
+        return Err(PbftError::InvalidNetworkChange(String::from(
+            "NetworkChange has no peers, or the same peer more than once",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Handle a final `NetworkChange` message
+/// Once `2f + 1` different nodes in the current network agree on the same peers for the same view
+/// and sequence number, the change is accepted, and the new peers are returned. A network that
+/// isn't Byzantine fault tolerant yet (`f == 0`) needs every one of its nodes to agree.
+pub fn network_change(
+    state: &PbftState,
+    msg_log: &PbftLog,
+    nc: &PbftNetworkChange,
+) -> Result<Vec<PeerId>, PbftError> {
+    let num_agreeing = msg_log
+        .get_final_network_changes(nc)
+        .iter()
+        .filter(|other| {
+            state
+                .get_node_id_from_bytes(other.get_info().get_signer_id())
+                .is_ok()
+        })
+        .count();
+    let required = if state.f == 0 {
+        state.get_peer_ids().len()
+    } else {
+        (2 * state.f + 1) as usize
+    };
+    if num_agreeing < required {
+        return Err(PbftError::WrongNumMessages(
+            PbftMessageType::NetworkChange,
+            required,
+            num_agreeing,
+        ));
+    }
+
+    Ok(nc
+        .get_peers()
+        .iter()
+        .map(|id| PeerId::from(id.clone()))
+        .collect())
+}
+
+/// Tell if two lists of peers contain the same peers, regardless of order
+pub fn same_peers(peers: &[PeerId], other: &[PeerId]) -> bool {
+    peers.len() == other.len() && peers.iter().all(|id| other.contains(id))
+}
+
+/// Handle a `CatchUpRequest` message
+/// Builds proof of how far this node has gotten, for a node whose chain head is the block in the
+/// request: this node's latest stable checkpoint, and seals for the blocks it has committed after
+/// the requester's chain head. Returns `None` if this node can't prove anything the requester
+/// doesn't already have.
+pub fn make_catch_up(
+    state: &PbftState,
+    msg_log: &PbftLog,
+    request: &PbftMessage,
+) -> Option<PbftCatchUp> {
+    let head_num = request.get_block().get_block_num();
+    let seals: Vec<PbftSeal> = msg_log
+        .get_commit_certificates(2 * state.f + 1)
+        .into_iter()
+        .filter(|(block, _)| block.get_block_num() > head_num)
+        .map(|(block, commit_messages)| {
+            let mut seal = PbftSeal::new();
+            seal.set_block(block);
+            seal.set_commit_messages(RepeatedField::from_vec(commit_messages));
+            seal
+        })
+        .collect();
+    if seals.is_empty() {
+        return None;
+    }
+
+    let mut catch_up = PbftCatchUp::new();
+    catch_up.set_info(make_msg_info(
+        &PbftMessageType::CatchUp,
+        state.view,
+        state.seq_num,
+        state.get_own_peer_id(),
+    ));
+    catch_up.set_checkpoint_messages(RepeatedField::from_vec(
+        msg_log.get_stable_checkpoint_proof(),
+    ));
+    catch_up.set_seals(RepeatedField::from_vec(seals));
+    Some(catch_up)
+}
+
+/// Check a `CatchUp` message, and return the `Checkpoint` messages from it
+/// If there are `Checkpoint` messages, they need valid signatures from `2f + 1` different nodes in
+/// the network, all for the same view and sequence number. Every seal has to prove that its block
+/// was committed (see `verify_commit_certificate`), and the seals have to be for consecutive
+/// blocks.
+pub fn verify_catch_up(
+    state: &PbftState,
+    msg_log: &PbftLog,
+    catch_up: &PbftCatchUp,
+) -> Result<Vec<PbftMessage>, PbftError> {
+    let mut checkpoints: Vec<PbftMessage> = Vec::new();
+    let mut signers: HashSet<Vec<u8>> = HashSet::new();
+    for signed_msg in catch_up.get_checkpoint_messages() {
+        signing::verify(&PbftMessageType::Checkpoint, signed_msg)?;
+        let checkpoint = protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message())
+            .map_err(PbftError::SerializationError)?;
+
+        {
+            let info = checkpoint.get_info();
+            let matches_first = checkpoints.first().map_or(true, |first| {
+                first.get_info().get_view() == info.get_view()
+                    && first.get_info().get_seq_num() == info.get_seq_num()
+            });
+            if PbftMessageType::from(info.get_msg_type()) != PbftMessageType::Checkpoint
+                || !matches_first
+            {
+                return Err(PbftError::InvalidCatchUp(String::from(
+                    "CatchUp contains a message that isn't a Checkpoint for its stable checkpoint",
+                )));
+            }
+
+            state
+                .get_node_id_from_bytes(info.get_signer_id())
+                .map_err(|_| {
+                    PbftError::InvalidCatchUp(format!(
+                        "CatchUp contains a Checkpoint from unknown node {}",
+                        hex::encode(info.get_signer_id())
+                    ))
+                })?;
+            signers.insert(info.get_signer_id().to_vec());
+        }
+        checkpoints.push(checkpoint);
+    }
+    if !checkpoints.is_empty() && (signers.len() as u64) < 2 * state.f + 1 {
+        return Err(PbftError::InvalidCatchUp(format!(
+            "Stable checkpoint only has Checkpoints from {} nodes; expected {}",
+            signers.len(),
+            2 * state.f + 1
+        )));
+    }
+
+    let mut prev_block_num: Option<u64> = None;
+    for seal in catch_up.get_seals() {
+        verify_commit_certificate(state, msg_log, seal)?;
+        let block_num = seal.get_block().get_block_num();
+        if prev_block_num.map_or(false, |num| num + 1 != block_num) {
+            return Err(PbftError::InvalidCatchUp(String::from(
+                "CatchUp contains seals for blocks that aren't consecutive",
+            )));
+        }
+        prev_block_num = Some(block_num);
+    }
+
+    Ok(checkpoints)
+}
+
+/// There should only be one block with a matching ID
+pub fn get_block_by_id(service: &mut Service, block_id: &BlockId) -> Option<Block> {
     let blocks: Vec<Block> = service
         .get_blocks(vec![block_id.clone()])
         .unwrap_or_default()
@@ -378,21 +1142,32 @@ pub fn pbft_block_from_block(block: Block) -> PbftBlock {
     pbft_block.set_block_id(Vec::<u8>::from(block.block_id));
     pbft_block.set_signer_id(Vec::<u8>::from(block.signer_id));
     pbft_block.set_block_num(block.block_num);
+    pbft_block.set_batches_digest(batches_digest(&block.summary));
     pbft_block.set_summary(block.summary);
     pbft_block
 }
 
+/// Compute the digest that pins down the order of the batches in a block, from its summary
+pub fn batches_digest(summary: &[u8]) -> Vec<u8> {
+    let mut sha = Sha256::new();
+    sha.input(summary);
+    let mut digest = vec![0; sha.output_bytes()];
+    sha.result(&mut digest);
+    digest
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use config;
-    use crypto::digest::Digest;
-    use crypto::sha2::Sha256;
+    use protobuf::Message;
+    use signing::mock_signer;
+    use std::ops::Range;
+    use timing::mock_clock;
 
+    /// The ID of node `num` in the network from `config::mock_config`
     fn mock_peer_id(num: u64) -> PeerId {
-        let mut sha = Sha256::new();
-        sha.input_str(format!("I'm a peer (number {})", num).as_str());
-        PeerId::from(sha.result_str().as_bytes().to_vec())
+        mock_signer(num).get_public_key().unwrap()
     }
 
     fn mock_block_id(num: u64) -> BlockId {
@@ -426,11 +1201,472 @@ mod tests {
         pbft_msg
     }
 
+    /// Sign a `Commit` for `block` as node `from` (using its real key), and add it to the log
+    fn add_signed_commit(log: &mut PbftLog, view: u64, seq_num: u64, block: Block, from: u64) {
+        let signer = mock_signer(from);
+        let mut commit = PbftMessage::new();
+        commit.set_info(make_msg_info(
+            &PbftMessageType::Commit,
+            view,
+            seq_num,
+            signer.get_public_key().unwrap(),
+        ));
+        commit.set_block(pbft_block_from_block(block));
+        let signed = signer.sign(&commit.write_to_bytes().unwrap()).unwrap();
+        log.add_signed_message(commit, signed).unwrap();
+    }
+
+    /// Put a serialized seal in a block's payload
+    fn seal_block(mut block: Block, seal: &PbftSeal) -> Block {
+        block.payload = seal.write_to_bytes().unwrap();
+        block
+    }
+
+    /// Sign a message as node `from`
+    fn sign_msg<M: Message>(msg: &M, from: u64) -> PbftSignedMessage {
+        mock_signer(from)
+            .sign(&msg.write_to_bytes().unwrap())
+            .unwrap()
+    }
+
+    /// Create a prepared certificate for `block`, with a `PrePrepare` from the primary of `view`
+    /// (in a network of 4 nodes) and `Prepare` messages from each of the `preparers`
+    fn mock_prepared_cert(
+        view: u64,
+        seq_num: u64,
+        block: Block,
+        preparers: Range<u64>,
+    ) -> PbftPreparedCertificate {
+        let primary = view % 4;
+        let mut pre_prepare = PbftMessage::new();
+        pre_prepare.set_info(make_msg_info(
+            &PbftMessageType::PrePrepare,
+            view,
+            seq_num,
+            mock_signer(primary).get_public_key().unwrap(),
+        ));
+        pre_prepare.set_block(pbft_block_from_block(block.clone()));
+
+        let mut cert = PbftPreparedCertificate::new();
+        cert.set_pre_prepare(sign_msg(&pre_prepare, primary));
+        for peer in preparers {
+            let mut prepare = pre_prepare.clone();
+            prepare.set_info(make_msg_info(
+                &PbftMessageType::Prepare,
+                view,
+                seq_num,
+                mock_signer(peer).get_public_key().unwrap(),
+            ));
+            cert.mut_prepare_messages().push(sign_msg(&prepare, peer));
+        }
+        cert
+    }
+
+    /// Create a `ViewChange` for `view` from node `from`, along with its signed copy
+    fn mock_view_change(
+        view: u64,
+        seq_num: u64,
+        certs: Vec<PbftPreparedCertificate>,
+        from: u64,
+    ) -> (PbftViewChange, PbftSignedMessage) {
+        let mut vc = PbftViewChange::new();
+        vc.set_info(make_msg_info(
+            &PbftMessageType::ViewChange,
+            view,
+            seq_num,
+            mock_signer(from).get_public_key().unwrap(),
+        ));
+        vc.set_prepared_certificates(RepeatedField::from_vec(certs));
+        let signed = sign_msg(&vc, from);
+        (vc, signed)
+    }
+
+    /// Create a `NetworkChange` proposing `peers` from node `from`, along with its signed copy
+    fn mock_network_change(
+        view: u64,
+        seq_num: u64,
+        peers: &[PeerId],
+        tentative: bool,
+        from: u64,
+    ) -> (PbftNetworkChange, PbftSignedMessage) {
+        let mut nc = PbftNetworkChange::new();
+        nc.set_info(make_msg_info(
+            &PbftMessageType::NetworkChange,
+            view,
+            seq_num,
+            mock_signer(from).get_public_key().unwrap(),
+        ));
+        nc.set_peers(RepeatedField::from_vec(
+            peers.iter().cloned().map(Vec::<u8>::from).collect(),
+        ));
+        nc.set_tentative(tentative);
+        let signed = sign_msg(&nc, from);
+        (nc, signed)
+    }
+
+    /// Test that `NetworkChange` messages are checked properly, and that a change is only
+    /// accepted once `2f + 1` nodes in the current network agree on it
+    #[test]
+    fn test_network_change() {
+        let cfg = config::mock_config(4);
+        let new_peers = config::mock_config(5).peers;
+        let state = PbftState::new(&cfg.peers[1], &cfg, mock_clock());
+        let mut log = PbftLog::new(&cfg);
+
+        // Only the primary can make a tentative proposal, and only nodes in the network can agree
+        // to it
+        let (nc, _) = mock_network_change(0, 3, &new_peers, true, 0);
+        assert!(verify_network_change(&state, &nc).is_ok());
+        let (nc, _) = mock_network_change(0, 3, &new_peers, true, 2);
+        assert!(verify_network_change(&state, &nc).is_err());
+        let (nc, _) = mock_network_change(0, 3, &new_peers, false, 2);
+        assert!(verify_network_change(&state, &nc).is_ok());
+        let (nc, _) = mock_network_change(0, 3, &new_peers, false, 4);
+        assert!(verify_network_change(&state, &nc).is_err());
+
+        let duplicated = vec![new_peers[0].clone(), new_peers[0].clone()];
+        let (nc, _) = mock_network_change(0, 3, &duplicated, false, 2);
+        assert!(verify_network_change(&state, &nc).is_err());
+
+        // Agreement from a node that isn't in the network, or on different peers, doesn't count
+        for &(peers, from) in &[
+            (&new_peers[..], 0),
+            (&new_peers[..], 4),
+            (&cfg.peers[1..], 2),
+        ] {
+            let (nc, signed) = mock_network_change(0, 3, peers, false, from);
+            log.add_network_change(nc, signed).unwrap();
+        }
+        for from in 0..2 {
+            let (nc, signed) = mock_network_change(0, 3, &new_peers, false, from);
+            log.add_network_change(nc.clone(), signed).unwrap();
+            assert!(network_change(&state, &log, &nc).is_err());
+        }
+
+        let (nc, signed) = mock_network_change(0, 3, &new_peers, false, 2);
+        log.add_network_change(nc.clone(), signed).unwrap();
+        assert_eq!(network_change(&state, &log, &nc).unwrap(), new_peers);
+        assert!(same_peers(
+            &new_peers,
+            &new_peers.iter().rev().cloned().collect::<Vec<_>>()
+        ));
+        assert!(!same_peers(&new_peers, &cfg.peers));
+    }
+
+    /// Test that the new primary builds a valid `NewView` once it has `2f + 1` `ViewChange`
+    /// messages, and that the blocks prepared in the old view are carried over to the new one
+    #[test]
+    fn test_view_change() {
+        let cfg = config::mock_config(4);
+        let state1 = PbftState::new(&cfg.peers[1], &cfg, mock_clock());
+        let state2 = PbftState::new(&cfg.peers[2], &cfg, mock_clock());
+        let mut log = PbftLog::new(&cfg);
+        let observers = PbftObservers::new();
+
+        // A certificate without 2f + 1 Prepares doesn't prove anything
+        let (bad_vc, _) =
+            mock_view_change(1, 0, vec![mock_prepared_cert(0, 2, mock_block(2), 0..2)], 0);
+        assert!(verify_view_change(&state1, &bad_vc).is_err());
+
+        // Neither does one from the view that's being changed to
+        let (bad_vc, _) =
+            mock_view_change(1, 0, vec![mock_prepared_cert(1, 2, mock_block(2), 0..3)], 0);
+        assert!(verify_view_change(&state1, &bad_vc).is_err());
+
+        let cert = mock_prepared_cert(0, 2, mock_block(2), 0..3);
+        let mut vc = PbftViewChange::new();
+        for peer in 0..3 {
+            let certs = if peer == 0 {
+                vec![cert.clone()]
+            } else {
+                vec![]
+            };
+            let (peer_vc, signed) = mock_view_change(1, 0, certs, peer);
+            assert!(verify_view_change(&state1, &peer_vc).is_ok());
+            log.add_signed_view_change(peer_vc.clone(), signed, state1.view)
+                .unwrap();
+            vc = peer_vc;
+            if peer < 2 {
+                assert!(view_change(&state1, &log, &vc, &observers).is_err());
+            }
+        }
+
+        // Secondaries in the new view wait for the NewView
+        assert!(view_change(&state2, &log, &vc, &observers)
+            .unwrap()
+            .is_none());
+
+        let new_view = view_change(&state1, &log, &vc, &observers)
+            .unwrap()
+            .unwrap();
+        assert_eq!(new_view.get_view_changes().len(), 3);
+        assert_eq!(new_view.get_pre_prepares().len(), 1);
+        let pre_prepare = &new_view.get_pre_prepares()[0];
+        assert_eq!(pre_prepare.get_info().get_view(), 1);
+        assert_eq!(pre_prepare.get_info().get_seq_num(), 2);
+        assert_eq!(
+            pre_prepare.get_block().get_block_id(),
+            &Vec::<u8>::from(mock_block_id(2))[..]
+        );
+        assert!(verify_new_view(&state2, &new_view).is_ok());
+
+        // NewView from a node that isn't the new primary
+        let mut forged = new_view.clone();
+        forged
+            .mut_info()
+            .set_signer_id(Vec::<u8>::from(mock_signer(2).get_public_key().unwrap()));
+        assert!(verify_new_view(&state2, &forged).is_err());
+
+        // Not enough ViewChanges
+        let mut short = new_view.clone();
+        short.mut_view_changes().pop();
+        assert!(verify_new_view(&state2, &short).is_err());
+
+        // The block that was prepared in the old view is left out
+        let mut dropped = new_view.clone();
+        dropped.mut_pre_prepares().clear();
+        assert!(verify_new_view(&state2, &dropped).is_err());
+    }
+
+    /// Make sure that only blocks after the latest stable checkpoint are re-issued, and that the
+    /// certificate from the latest view wins
+    #[test]
+    fn test_reissued_pre_prepares() {
+        let mut other_block = mock_block(3);
+        other_block.block_id = mock_block_id(100);
+
+        let (vc0, _) = mock_view_change(
+            2,
+            1,
+            vec![
+                mock_prepared_cert(0, 2, mock_block(2), 0..3),
+                mock_prepared_cert(0, 3, mock_block(3), 0..3),
+            ],
+            0,
+        );
+        let (vc1, _) = mock_view_change(2, 2, vec![mock_prepared_cert(1, 3, other_block, 0..3)], 1);
+        let (vc2, _) = mock_view_change(2, 0, vec![], 2);
+
+        let primary = mock_signer(2).get_public_key().unwrap();
+        let pre_prepares = reissued_pre_prepares(&[vc0, vc1, vc2], 2, primary.clone()).unwrap();
+        assert_eq!(pre_prepares.len(), 1);
+        assert_eq!(pre_prepares[0].get_info().get_seq_num(), 3);
+        assert_eq!(pre_prepares[0].get_info().get_view(), 2);
+        assert_eq!(
+            pre_prepares[0].get_info().get_signer_id(),
+            &Vec::<u8>::from(primary)[..]
+        );
+        assert_eq!(
+            pre_prepares[0].get_block().get_block_id(),
+            &Vec::<u8>::from(mock_block_id(100))[..]
+        );
+    }
+
+    #[test]
+    fn test_consensus_seal() {
+        let cfg = config::mock_config(4);
+        let state = PbftState::new(&cfg.peers[1], &cfg, mock_clock());
+        let mut log = PbftLog::new(&cfg);
+
+        // Blocks on top of genesis don't need a seal
+        assert!(verify_consensus_seal(&state, &log, &mock_block(1), &mock_block_id(0)).is_ok());
+
+        // A seal can't be made until there are 2f + 1 Commits
+        for peer in 0..2 {
+            add_signed_commit(&mut log, 0, 1, mock_block(1), peer);
+        }
+        assert!(make_consensus_seal(&state, &log, &mock_block(1)).is_none());
+        add_signed_commit(&mut log, 0, 1, mock_block(1), 2);
+        let seal = make_consensus_seal(&state, &log, &mock_block(1)).unwrap();
+        assert_eq!(seal.get_commit_messages().len(), 3);
+
+        assert!(verify_consensus_seal(
+            &state,
+            &log,
+            &seal_block(mock_block(2), &seal),
+            &mock_block_id(1)
+        )
+        .is_ok());
+
+        // Missing seal
+        assert!(verify_consensus_seal(&state, &log, &mock_block(2), &mock_block_id(1)).is_err());
+
+        // Seal for a block other than the previous one
+        assert!(verify_consensus_seal(
+            &state,
+            &log,
+            &seal_block(mock_block(3), &seal),
+            &mock_block_id(2)
+        )
+        .is_err());
+
+        // Not enough Commits, even if one is repeated
+        let mut short_seal = seal.clone();
+        short_seal.mut_commit_messages().pop();
+        assert!(verify_consensus_seal(
+            &state,
+            &log,
+            &seal_block(mock_block(2), &short_seal),
+            &mock_block_id(1)
+        )
+        .is_err());
+        let repeated = short_seal.get_commit_messages()[0].clone();
+        short_seal.mut_commit_messages().push(repeated);
+        assert!(verify_consensus_seal(
+            &state,
+            &log,
+            &seal_block(mock_block(2), &short_seal),
+            &mock_block_id(1)
+        )
+        .is_err());
+
+        // Commit with a tampered signature
+        let mut forged_seal = seal.clone();
+        forged_seal.mut_commit_messages()[0].set_signature(String::from("00"));
+        assert!(verify_consensus_seal(
+            &state,
+            &log,
+            &seal_block(mock_block(2), &forged_seal),
+            &mock_block_id(1)
+        )
+        .is_err());
+
+        // Commit from a node that isn't in the network
+        let mut outsider_log = PbftLog::new(&cfg);
+        for peer in 3..6 {
+            add_signed_commit(&mut outsider_log, 0, 1, mock_block(1), peer);
+        }
+        let outsider_seal = make_consensus_seal(&state, &outsider_log, &mock_block(1)).unwrap();
+        assert!(verify_consensus_seal(
+            &state,
+            &log,
+            &seal_block(mock_block(2), &outsider_seal),
+            &mock_block_id(1)
+        )
+        .is_err());
+
+        // A node whose log shows node 0 committing a different block rejects the seal
+        let mut other_log = PbftLog::new(&cfg);
+        let mut other_block = mock_block(1);
+        other_block.block_id = mock_block_id(100);
+        add_signed_commit(&mut other_log, 0, 1, other_block, 0);
+        assert!(verify_consensus_seal(
+            &state,
+            &other_log,
+            &seal_block(mock_block(2), &seal),
+            &mock_block_id(1)
+        )
+        .is_err());
+
+        // With a pipeline two blocks deep, a block's seal is for the block two blocks before it
+        let mut pipelined_state = PbftState::new(&cfg.peers[1], &cfg, mock_clock());
+        pipelined_state.pipeline_depth = 2;
+        assert!(
+            verify_consensus_seal(&pipelined_state, &log, &mock_block(2), &mock_block_id(0))
+                .is_ok()
+        );
+        assert!(verify_consensus_seal(
+            &pipelined_state,
+            &log,
+            &seal_block(mock_block(2), &seal),
+            &mock_block_id(1)
+        )
+        .is_err());
+        assert!(verify_consensus_seal(
+            &pipelined_state,
+            &log,
+            &seal_block(mock_block(3), &seal),
+            &mock_block_id(1)
+        )
+        .is_ok());
+
+        // The sealed block has to be the one on the new block's chain, not just at the same height
+        assert!(verify_consensus_seal(
+            &pipelined_state,
+            &log,
+            &seal_block(mock_block(3), &seal),
+            &mock_block_id(100)
+        )
+        .is_err());
+    }
+
+    /// Make sure that a `CatchUp` message only includes what the requester is missing, and that it
+    /// has to prove its stable checkpoint and blocks
+    #[test]
+    fn test_catch_up() {
+        let cfg = config::mock_config(4);
+        let state = PbftState::new(&cfg.peers[1], &cfg, mock_clock());
+        let mut log = PbftLog::new(&cfg);
+
+        for num in 1..4 {
+            for peer in 0..3 {
+                add_signed_commit(&mut log, 0, num, mock_block(num), peer);
+            }
+        }
+
+        // Stable checkpoint at sequence number 2
+        for peer in 0..3 {
+            let signer = mock_signer(peer);
+            let mut checkpoint = PbftMessage::new();
+            checkpoint.set_info(make_msg_info(
+                &PbftMessageType::Checkpoint,
+                0,
+                2,
+                signer.get_public_key().unwrap(),
+            ));
+            let signed = signer.sign(&checkpoint.write_to_bytes().unwrap()).unwrap();
+            log.add_signed_message(checkpoint, signed).unwrap();
+        }
+        log.garbage_collect(2, 0);
+
+        // The newest block before the checkpoint can still be sealed
+        assert!(make_consensus_seal(&state, &log, &mock_block(1)).is_some());
+
+        // Only blocks after the requester's chain head are included, and only the ones that are
+        // still in the log
+        let mut request = mock_msg(&PbftMessageType::CatchUpRequest, 0, 0, mock_block(2), 2);
+        let catch_up = make_catch_up(&state, &log, &request).unwrap();
+        assert_eq!(catch_up.get_seals().len(), 1);
+        assert_eq!(catch_up.get_seals()[0].get_block().get_block_num(), 3);
+        assert_eq!(catch_up.get_checkpoint_messages().len(), 3);
+        assert_eq!(verify_catch_up(&state, &log, &catch_up).unwrap().len(), 3);
+
+        request.mut_block().set_block_num(3);
+        assert!(make_catch_up(&state, &log, &request).is_none());
+
+        // Not enough Checkpoints
+        let mut short = catch_up.clone();
+        short.mut_checkpoint_messages().pop();
+        assert!(verify_catch_up(&state, &log, &short).is_err());
+
+        // Tampered seal
+        let mut forged = catch_up.clone();
+        forged.mut_seals()[0].mut_commit_messages()[0].set_signature(String::from("00"));
+        assert!(verify_catch_up(&state, &log, &forged).is_err());
+
+        // Seals with a gap between them
+        let mut gap = catch_up.clone();
+        let mut seal = PbftSeal::new();
+        seal.set_block(pbft_block_from_block(mock_block(5)));
+        for peer in 0..3 {
+            add_signed_commit(&mut log, 0, 5, mock_block(5), peer);
+        }
+        seal.set_commit_messages(RepeatedField::from_vec(
+            log.get_commit_certificate(&Vec::<u8>::from(mock_block_id(5)), 3)
+                .unwrap(),
+        ));
+        gap.mut_seals().push(seal);
+        match verify_catch_up(&state, &log, &gap) {
+            Err(PbftError::InvalidCatchUp(_)) => (),
+            res => panic!("CatchUp with a gap was accepted: {:?}", res),
+        }
+    }
+
     #[test]
     fn test_pre_prepare() {
         let cfg = config::mock_config(4);
-        let mut state0 = PbftState::new(0, &cfg);
-        let mut state1 = PbftState::new(1, &cfg);
+        let mut state0 = PbftState::new(&cfg.peers[0], &cfg, mock_clock());
+        let mut state1 = PbftState::new(&cfg.peers[1], &cfg, mock_clock());
         let mut log0 = PbftLog::new(&cfg);
         let mut log1 = PbftLog::new(&cfg);
 
@@ -441,23 +1677,38 @@ mod tests {
 
         // Put the block new in the log
         let block_new0 = mock_msg(&PbftMessageType::BlockNew, 0, 1, mock_block(1), 0);
-        log0.add_message(block_new0);
+        log0.add_message(block_new0).unwrap();
         state0.seq_num = 1;
 
         let block_new1 = mock_msg(&PbftMessageType::BlockNew, 0, 0, mock_block(1), 0);
-        log1.add_message(block_new1);
+        log1.add_message(block_new1).unwrap();
 
         assert!(pre_prepare(&mut state0, &mut log0, &pre_prep_msg).is_ok());
         assert!(pre_prepare(&mut state1, &mut log1, &pre_prep_msg).is_ok());
 
         assert_eq!(state0.seq_num, 1);
         assert_eq!(state1.seq_num, 1);
+
+        // A PrePrepare for a block with different batches than the validator has is refused
+        let mut state2 = PbftState::new(&cfg.peers[2], &cfg, mock_clock());
+        let mut log2 = PbftLog::new(&cfg);
+        let block_new2 = mock_msg(&PbftMessageType::BlockNew, 0, 0, mock_block(1), 2);
+        log2.add_message(block_new2).unwrap();
+        let mut reordered = pre_prep_msg.clone();
+        reordered
+            .mut_block()
+            .set_batches_digest(batches_digest(b"reordered"));
+        match pre_prepare(&mut state2, &mut log2, &reordered) {
+            Err(PbftError::BatchDigestMismatch(_, _)) => (),
+            res => panic!("Reordered block was accepted: {:?}", res),
+        }
+        assert_eq!(state2.seq_num, 0);
     }
 
     #[test]
     fn test_multicast_hint() {
         let cfg = config::mock_config(4);
-        let mut state = PbftState::new(0, &cfg);
+        let mut state = PbftState::new(&cfg.peers[0], &cfg, mock_clock());
         state.seq_num = 5;
 
         // Past (past sequence number)
@@ -0,0 +1,91 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! A minimal HTTP server for the engine's local endpoints, which only answers `GET` requests
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// How long the server waits for a client to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answer `GET` requests on the given address from a background thread named `name`. `handler`
+/// gets the path of each request, and returns the content type and body of the response, or
+/// `None` if there's nothing at that path. Returns the address the server is listening on, which
+/// is useful when binding to port 0.
+pub fn serve<F>(address: &str, name: &str, handler: F) -> io::Result<SocketAddr>
+where
+    F: Fn(&str) -> Option<(&'static str, String)> + Send + 'static,
+{
+    let listener = TcpListener::bind(address)?;
+    let local_addr = listener.local_addr()?;
+
+    let thread_name = String::from(name);
+    thread::Builder::new()
+        .name(thread_name.clone())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream.and_then(|stream| respond(&handler, stream));
+                if let Err(err) = res {
+                    debug!("Couldn't answer {} request: {}", thread_name, err);
+                }
+            }
+        })?;
+
+    Ok(local_addr)
+}
+
+// Answer one HTTP request
+fn respond<F>(handler: &F, stream: TcpStream) -> io::Result<()>
+where
+    F: Fn(&str) -> Option<(&'static str, String)>,
+{
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Read the rest of the request's headers, so the client sees the whole response
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => handler(path),
+        _ => None,
+    };
+    let (status, content_type, body) = match response {
+        Some((content_type, body)) => ("200 OK", content_type, body),
+        None => ("404 Not Found", "text/plain", String::from("Not found\n")),
+    };
+
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,63 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Implementation of the [PBFT consensus
+//! algorithm](https://www.usenix.org/legacy/events/osdi99/full_papers/castro/castro_html/castro.html),
+//! modified for use with Hyperledger Sawtooth.
+
+extern crate crypto;
+#[macro_use]
+extern crate log;
+extern crate hex;
+extern crate protobuf;
+extern crate sawtooth_sdk;
+#[macro_use]
+extern crate serde_json;
+#[cfg(test)]
+extern crate proptest;
+
+pub mod admin;
+#[cfg(any(test, feature = "simulation"))]
+pub mod byzantine;
+pub mod checker;
+pub mod config;
+pub mod engine;
+pub mod error;
+pub mod evidence;
+#[cfg(test)]
+pub mod explorer;
+pub mod handlers;
+mod http;
+pub mod logging;
+#[cfg(any(test, feature = "simulation"))]
+pub mod lossy;
+pub mod message_extensions;
+pub mod message_log;
+pub mod message_type;
+pub mod metrics;
+pub mod node;
+pub mod observer;
+pub mod protos;
+pub mod replay;
+pub mod signing;
+#[cfg(any(test, feature = "simulation"))]
+pub mod simulator;
+pub mod state;
+pub mod storage;
+pub mod timeline;
+pub mod timing;
+pub mod trace;
@@ -0,0 +1,238 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Structured logging, for log pipelines that can't parse the compact `Display` of `PbftState`
+//!
+//! The engine keeps a per-thread `LogContext` up to date with the node's state and the message it
+//! is handling. The `JsonLogger` writes each log record as a line of JSON, with the fields of the
+//! context alongside the record's level, target, and message.
+
+use std::cell::RefCell;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{self, Level, Log, Metadata, Record, SetLoggerError};
+use serde_json::{Map, Value};
+
+use state::{PbftMode, PbftPhase, PbftState};
+
+thread_local! {
+    static CONTEXT: RefCell<LogContext> = RefCell::new(LogContext::default());
+}
+
+/// What the node on this thread is doing, as of when the engine last updated it
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LogContext {
+    pub node_id: Option<u64>,
+    pub view: Option<u64>,
+    pub seq_num: Option<u64>,
+    pub phase: Option<PbftPhase>,
+    pub mode: Option<PbftMode>,
+
+    /// The type of the message being handled
+    pub msg_type: Option<String>,
+
+    /// The (hex-encoded) ID of the peer that sent the message being handled, if it's known
+    pub peer: Option<String>,
+}
+
+/// Record the node's state in this thread's context
+pub fn set_state(state: &PbftState) {
+    CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        context.node_id = Some(state.id);
+        context.view = Some(state.view);
+        context.seq_num = Some(state.seq_num);
+        context.phase = Some(state.phase.clone());
+        context.mode = Some(state.mode);
+    });
+}
+
+/// Record the message that the node is about to handle in this thread's context
+pub fn set_message(msg_type: &str, peer: Option<&str>) {
+    CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        context.msg_type = Some(msg_type.to_string());
+        context.peer = peer.map(String::from);
+    });
+}
+
+/// Remove the message from this thread's context, once the node is done with it
+pub fn clear_message() {
+    CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        context.msg_type = None;
+        context.peer = None;
+    });
+}
+
+/// A copy of this thread's context
+pub fn context() -> LogContext {
+    CONTEXT.with(|context| context.borrow().clone())
+}
+
+/// Writes every log record as a line of JSON, along with the context of the thread it came from
+pub struct JsonLogger {
+    level: Level,
+}
+
+impl JsonLogger {
+    pub fn new(level: Level) -> Self {
+        JsonLogger { level }
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", format_record(record, &context()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Use a `JsonLogger` for everything that's logged from now on
+pub fn init_json(level: Level) -> Result<(), SetLoggerError> {
+    log::set_logger(Box::leak(Box::new(JsonLogger::new(level))))?;
+    log::set_max_level(level.to_level_filter());
+    Ok(())
+}
+
+/// Render a log record and the context it was logged in as a single line of JSON. Fields that
+/// aren't in the context are `null`, so every record has the same fields.
+pub fn format_record(record: &Record, context: &LogContext) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs_f64())
+        .unwrap_or(0.0);
+
+    let mut fields = Map::new();
+    fields.insert(String::from("timestamp"), Value::from(timestamp));
+    fields.insert(
+        String::from("level"),
+        Value::from(record.level().to_string()),
+    );
+    fields.insert(String::from("target"), Value::from(record.target()));
+    fields.insert(
+        String::from("message"),
+        Value::from(record.args().to_string()),
+    );
+    fields.insert(String::from("node_id"), optional(context.node_id));
+    fields.insert(String::from("view"), optional(context.view));
+    fields.insert(String::from("seq_num"), optional(context.seq_num));
+    fields.insert(
+        String::from("phase"),
+        optional(context.phase.as_ref().map(|phase| format!("{:?}", phase))),
+    );
+    fields.insert(
+        String::from("mode"),
+        optional(context.mode.map(|mode| format!("{:?}", mode))),
+    );
+    fields.insert(String::from("msg_type"), optional(context.msg_type.clone()));
+    fields.insert(String::from("peer"), optional(context.peer.clone()));
+
+    Value::Object(fields).to_string()
+}
+
+fn optional<T: Into<Value>>(value: Option<T>) -> Value {
+    value.map_or(Value::Null, Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use serde_json;
+    use timing::mock_clock;
+
+    fn parse(line: &str) -> Value {
+        serde_json::from_str(line).expect("Record isn't valid JSON")
+    }
+
+    /// Records carry the node's state and the message it's handling as separate fields
+    #[test]
+    fn record_with_context() {
+        let config = mock_config(4);
+        let mut state = PbftState::new(&config.peers[1], &config, mock_clock());
+        state.view = 2;
+        state.seq_num = 7;
+        state.phase = PbftPhase::Preparing;
+        state.mode = PbftMode::ViewChanging;
+
+        set_state(&state);
+        set_message("Prepare", Some("02ab"));
+        let line = format_record(
+            &Record::builder()
+                .args(format_args!("Something \"happened\"\n"))
+                .level(Level::Warn)
+                .target("sawtooth_pbft::node")
+                .build(),
+            &context(),
+        );
+        assert!(!line.contains('\n'));
+
+        let record = parse(&line);
+        assert_eq!(record["level"], "WARN");
+        assert_eq!(record["target"], "sawtooth_pbft::node");
+        assert_eq!(record["message"], "Something \"happened\"\n");
+        assert_eq!(record["node_id"], 1);
+        assert_eq!(record["view"], 2);
+        assert_eq!(record["seq_num"], 7);
+        assert_eq!(record["phase"], "Preparing");
+        assert_eq!(record["mode"], "ViewChanging");
+        assert_eq!(record["msg_type"], "Prepare");
+        assert_eq!(record["peer"], "02ab");
+        assert!(record["timestamp"].as_f64().unwrap() > 0.0);
+
+        // Once the message is handled, it's no longer part of the context
+        clear_message();
+        let record = parse(&format_record(
+            &Record::builder()
+                .args(format_args!("Done"))
+                .level(Level::Info)
+                .build(),
+            &context(),
+        ));
+        assert_eq!(record["view"], 2);
+        assert!(record["msg_type"].is_null());
+        assert!(record["peer"].is_null());
+    }
+
+    /// Records from outside of a node (such as the metrics server's thread) have every field,
+    /// with no values for the context
+    #[test]
+    fn record_without_context() {
+        let record = parse(&format_record(
+            &Record::builder()
+                .args(format_args!("Hello"))
+                .level(Level::Debug)
+                .build(),
+            &LogContext::default(),
+        ));
+        assert_eq!(record["level"], "DEBUG");
+        assert_eq!(record["message"], "Hello");
+        for field in &[
+            "node_id", "view", "seq_num", "phase", "mode", "msg_type", "peer",
+        ] {
+            assert!(record[*field].is_null(), "{} isn't null", field);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! Unreliable links for the simulated network
+//!
+//! By default, the `simulator` delivers every update that a node sends, exactly once and in the
+//! order it was sent. A `LossyTransport` can instead lose, delay, duplicate, or reorder the
+//! updates on each link, and can split the network into partitions that heal later. Time is
+//! measured in the simulator's steps.
+//!
+//! Only updates between different nodes go over the transport (including the blocks that nodes
+//! publish, since validators gossip them over the same network); a node's own validator always
+//! reaches it.
+
+use std::collections::{HashMap, VecDeque};
+
+use sawtooth_sdk::consensus::engine::{PeerInfo, PeerMessage, Update};
+
+use simulator::SimRng;
+
+/// How a link treats the updates that are sent over it
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LinkConditions {
+    /// The chance that an update is lost
+    pub drop_rate: f64,
+
+    /// The fewest steps it takes for an update to arrive
+    pub min_delay: u64,
+
+    /// The most steps it takes for an update to arrive
+    pub max_delay: u64,
+
+    /// The chance that an update arrives twice
+    pub duplicate_rate: f64,
+
+    /// The chance that an update is put ahead of some of the updates that were sent before it
+    pub reorder_rate: f64,
+}
+
+impl Default for LinkConditions {
+    /// A link that delivers every update right away, in order
+    fn default() -> Self {
+        LinkConditions {
+            drop_rate: 0.0,
+            min_delay: 0,
+            max_delay: 0,
+            duplicate_rate: 0.0,
+            reorder_rate: 0.0,
+        }
+    }
+}
+
+/// A split of the network into groups of nodes that can only reach the other nodes in their own
+/// group, from step `start` until step `end` (or forever, if `end` is `None`). The nodes that
+/// aren't in any of the groups make up one more group.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub groups: Vec<Vec<usize>>,
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl Partition {
+    /// Cut `nodes` off from the rest of the network, starting at step `start`
+    pub fn isolate(nodes: &[usize], start: u64) -> Self {
+        Partition {
+            groups: vec![nodes.to_vec()],
+            start,
+            end: None,
+        }
+    }
+
+    /// Heal the partition at step `end`
+    pub fn until(self, end: u64) -> Self {
+        Partition {
+            end: Some(end),
+            ..self
+        }
+    }
+
+    fn separates(&self, a: usize, b: usize, step: u64) -> bool {
+        if step < self.start || self.end.map_or(false, |end| step >= end) {
+            return false;
+        }
+        let group = |node| self.groups.iter().position(|group| group.contains(&node));
+        group(a) != group(b)
+    }
+}
+
+/// Carries updates between the nodes of a simulated network, under the conditions of each link
+pub struct LossyTransport {
+    /// The conditions of every link that doesn't have its own
+    pub conditions: LinkConditions,
+
+    /// Conditions for particular links, by (sender, receiver)
+    links: HashMap<(usize, usize), LinkConditions>,
+
+    partitions: Vec<Partition>,
+    rng: SimRng,
+}
+
+impl LossyTransport {
+    /// Create a transport where every link has the same conditions
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        LossyTransport {
+            conditions,
+            links: HashMap::new(),
+            partitions: vec![],
+            rng: SimRng::new(seed),
+        }
+    }
+
+    /// Change the conditions of the link from one node to another
+    pub fn set_link(&mut self, from: usize, to: usize, conditions: LinkConditions) {
+        self.links.insert((from, to), conditions);
+    }
+
+    /// Split the network as the partition says, for as long as it says
+    pub fn add_partition(&mut self, partition: Partition) {
+        self.partitions.push(partition);
+    }
+
+    /// Whether a partition keeps updates from getting from one node to another at the given step
+    pub fn is_cut(&self, from: usize, to: usize, step: u64) -> bool {
+        from != to
+            && self
+                .partitions
+                .iter()
+                .any(|partition| partition.separates(from, to, step))
+    }
+
+    /// Put an update that was sent at `step` onto the link's queue, along with the step it can be
+    /// delivered at; it might not make it onto the queue at all, or make it on twice
+    pub fn send(
+        &mut self,
+        from: usize,
+        to: usize,
+        step: u64,
+        update: Update,
+        queue: &mut VecDeque<(u64, Update)>,
+    ) {
+        if from == to {
+            queue.push_back((step, update));
+            return;
+        }
+        if self.is_cut(from, to, step) {
+            return;
+        }
+
+        let conditions = *self.links.get(&(from, to)).unwrap_or(&self.conditions);
+        if self.rng.chance(conditions.drop_rate) {
+            return;
+        }
+        if self.rng.chance(conditions.duplicate_rate) {
+            let duplicate = copy_update(&update);
+            self.enqueue(&conditions, step, duplicate, queue);
+        }
+        self.enqueue(&conditions, step, update, queue);
+    }
+
+    fn enqueue(
+        &mut self,
+        conditions: &LinkConditions,
+        step: u64,
+        update: Update,
+        queue: &mut VecDeque<(u64, Update)>,
+    ) {
+        let mut ready = step + conditions.min_delay;
+        if conditions.max_delay > conditions.min_delay {
+            ready += self
+                .rng
+                .below((conditions.max_delay - conditions.min_delay + 1) as usize)
+                as u64;
+        }
+
+        if !queue.is_empty() && self.rng.chance(conditions.reorder_rate) {
+            let position = self.rng.below(queue.len());
+            queue.insert(position, (ready, update));
+        } else {
+            queue.push_back((ready, update));
+        }
+    }
+}
+
+// Updates can't be cloned, so build a new one with the same contents
+fn copy_update(update: &Update) -> Update {
+    match update {
+        Update::BlockNew(block) => Update::BlockNew(block.clone()),
+        Update::BlockValid(block_id) => Update::BlockValid(block_id.clone()),
+        Update::BlockInvalid(block_id) => Update::BlockInvalid(block_id.clone()),
+        Update::BlockCommit(block_id) => Update::BlockCommit(block_id.clone()),
+        Update::PeerMessage(message, sender_id) => Update::PeerMessage(
+            PeerMessage {
+                message_type: message.message_type.clone(),
+                content: message.content.clone(),
+            },
+            sender_id.clone(),
+        ),
+        Update::PeerConnected(info) => Update::PeerConnected(PeerInfo {
+            peer_id: info.peer_id.clone(),
+        }),
+        Update::PeerDisconnected(peer_id) => Update::PeerDisconnected(peer_id.clone()),
+        Update::Shutdown => Update::Shutdown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use simulator::SimulatedNetwork;
+    use state::PbftMode;
+
+    const PATIENCE: usize = 200;
+    const MAX_STEPS: usize = 20_000;
+
+    /// Create a network of `num_nodes` nodes that reach a checkpoint every two blocks
+    fn network(num_nodes: usize, seed: u64) -> SimulatedNetwork {
+        let mut config = mock_config(num_nodes);
+        config.checkpoint_period = 2;
+        SimulatedNetwork::with_config(&config, seed)
+    }
+
+    /// Take steps until every one of the given nodes has committed at least `height` blocks
+    fn run_until_height_of(
+        sim: &mut SimulatedNetwork,
+        nodes: &[usize],
+        height: u64,
+        max_steps: usize,
+    ) -> bool {
+        sim.run_until(max_steps, |sim| {
+            nodes.iter().all(|node| sim.height(*node) >= height)
+        })
+    }
+
+    /// Updates that are delayed, duplicated, and reordered still get blocks committed, and
+    /// checkpoints made stable
+    #[test]
+    fn unreliable_links() {
+        let mut sim = network(4, 21);
+        sim.set_transport(LossyTransport::new(
+            LinkConditions {
+                min_delay: 1,
+                max_delay: 20,
+                duplicate_rate: 0.1,
+                reorder_rate: 0.1,
+                ..Default::default()
+            },
+            21,
+        ));
+        assert!(sim.run_with_timeouts(4, PATIENCE, MAX_STEPS));
+        let report = sim.report(None);
+        assert!(report.is_safe(), "{}", report);
+        assert!(sim
+            .nodes
+            .iter()
+            .any(|node| node.msg_log.latest_stable_checkpoint.is_some()));
+    }
+
+    /// No matter how many updates are lost, and how many view changes that causes, the nodes never
+    /// commit different blocks
+    #[test]
+    fn lost_updates() {
+        let mut sim = network(4, 22);
+        sim.set_transport(LossyTransport::new(
+            LinkConditions {
+                drop_rate: 0.1,
+                max_delay: 5,
+                ..Default::default()
+            },
+            22,
+        ));
+        sim.run_with_timeouts(4, PATIENCE, MAX_STEPS / 4);
+        assert!(sim.honest_nodes_agree());
+        let report = sim.report(None);
+        assert!(report.is_safe(), "{}", report);
+    }
+
+    /// A primary that can't reach any of the other nodes gets replaced, and commits nothing on its
+    /// own
+    #[test]
+    fn isolated_primary() {
+        let mut sim = network(4, 23);
+        let mut transport = LossyTransport::new(Default::default(), 23);
+        transport.add_partition(Partition::isolate(&[0], 0));
+        sim.set_transport(transport);
+
+        for i in 1..4 {
+            sim.expire_timeout(i);
+        }
+        assert!(run_until_height_of(&mut sim, &[1, 2, 3], 3, MAX_STEPS));
+        assert!(sim.honest_nodes_agree());
+        assert_eq!(sim.height(0), 0);
+        for i in 1..4 {
+            assert_eq!(sim.nodes[i].state.view, 1);
+            assert_eq!(sim.nodes[i].state.mode, PbftMode::Normal);
+        }
+        assert_eq!(sim.nodes[0].state.view, 0);
+    }
+
+    /// The majority of a network keeps committing blocks without a minority that it can't reach.
+    /// The minority can't commit anything, and even when its view change timeouts expire, it can't
+    /// get the majority to change views.
+    #[test]
+    fn minority_partition() {
+        let mut sim = network(7, 24);
+        let mut transport = LossyTransport::new(Default::default(), 24);
+        transport.add_partition(Partition::isolate(&[5, 6], 0));
+        sim.set_transport(transport);
+        let majority = [0, 1, 2, 3, 4];
+
+        assert!(run_until_height_of(&mut sim, &majority, 2, 4 * MAX_STEPS));
+        sim.expire_timeout(5);
+        sim.expire_timeout(6);
+        assert!(run_until_height_of(&mut sim, &majority, 4, 4 * MAX_STEPS));
+
+        assert!(sim.honest_nodes_agree());
+        let report = sim.report(None);
+        assert!(report.is_safe(), "{}", report);
+        for i in majority.iter() {
+            assert_eq!(sim.nodes[*i].state.view, 0);
+            assert_eq!(sim.nodes[*i].state.mode, PbftMode::Normal);
+        }
+        for i in 5..7 {
+            assert_eq!(sim.height(i), 0);
+            assert_eq!(sim.nodes[i].state.mode, PbftMode::ViewChanging);
+        }
+    }
+
+    /// Once a partition that cut off the primary heals, the old primary catches up to the view
+    /// the rest of the network moved to, and takes part in consensus again
+    #[test]
+    fn healed_partition_rejoins() {
+        const HEAL: u64 = 5_000;
+
+        let mut sim = network(4, 25);
+        let mut transport = LossyTransport::new(Default::default(), 25);
+        transport.add_partition(Partition::isolate(&[0], 0).until(HEAL));
+        sim.set_transport(transport);
+
+        for i in 1..4 {
+            sim.expire_timeout(i);
+        }
+        assert!(run_until_height_of(&mut sim, &[1, 2, 3], 2, MAX_STEPS));
+        assert!(sim.steps() < HEAL);
+        assert_eq!(sim.height(0), 0);
+
+        assert!(sim.run_until_height(6, 2 * MAX_STEPS));
+        assert!(sim.honest_nodes_agree());
+        let report = sim.report(None);
+        assert!(report.is_safe(), "{}", report);
+        // Checkpoints come every other block, so a node may be in the middle of one
+        for i in 0..4 {
+            assert_eq!(sim.nodes[i].state.view, 1);
+            assert_ne!(sim.nodes[i].state.mode, PbftMode::ViewChanging);
+        }
+        assert!(!sim.nodes[0].state.is_primary());
+    }
+}
@@ -15,37 +15,28 @@
  * -----------------------------------------------------------------------------
  */
 
-//! Implementation of the [PBFT consensus
-//! algorithm](https://www.usenix.org/legacy/events/osdi99/full_papers/castro/castro_html/castro.html),
-//! modified for use with Hyperledger Sawtooth.
+//! Runs the PBFT consensus engine for a Sawtooth validator
 
 #[macro_use]
 extern crate clap;
-#[cfg(test)]
-extern crate crypto;
 #[macro_use]
 extern crate log;
-extern crate hex;
-extern crate protobuf;
+extern crate sawtooth_pbft;
 extern crate sawtooth_sdk;
-extern crate serde_json;
 extern crate simple_logger;
 
+use std::path::Path;
 use std::process;
+use std::rc::Rc;
+use std::sync::Arc;
 
 use sawtooth_sdk::consensus::zmq_driver::ZmqDriver;
 
-pub mod config;
-pub mod engine;
-pub mod error;
-pub mod handlers;
-pub mod message_extensions;
-pub mod message_log;
-pub mod message_type;
-pub mod node;
-mod protos;
-pub mod state;
-pub mod timing;
+use sawtooth_pbft::admin::{self, NodeStatus};
+use sawtooth_pbft::metrics::{self, Metrics};
+use sawtooth_pbft::timeline::{TimelineFormat, TimelineWriter};
+use sawtooth_pbft::timing::{Clock, SystemClock};
+use sawtooth_pbft::{engine, evidence, logging, signing, storage, trace};
 
 fn main() {
     let matches = clap_app!(sawtooth_pbft =>
@@ -53,6 +44,24 @@ fn main() {
         (about: "PBFT consensus for Sawtooth")
         (@arg connect: -C --connect +takes_value
          "connection endpoint for validator")
+        (@arg key: -k --key +takes_value
+         "path to this validator's private key (default /etc/sawtooth/keys/validator.priv)")
+        (@arg storage: -s --storage +takes_value
+         "path to the file where the node keeps its log and state (default /var/lib/sawtooth/pbft.log)")
+        (@arg evidence: -e --evidence +takes_value
+         "path to the file where the node keeps evidence of faulty nodes (default /var/lib/sawtooth/pbft-evidence.log)")
+        (@arg trace: -t --trace +takes_value
+         "path to a file to record a trace of the engine's updates to, for pbft-replay")
+        (@arg timelines: --timelines +takes_value
+         "path to a file to write the timeline of each round of consensus to (off by default)")
+        (@arg timeline_format: --("timeline-format") +takes_value
+         "format of the timeline file: json (the default), with one object per line, or csv")
+        (@arg metrics: -m --metrics +takes_value
+         "address to serve Prometheus metrics on, such as 127.0.0.1:9876 (off by default)")
+        (@arg admin: -a --admin +takes_value
+         "address to serve the node's status on as JSON, such as 127.0.0.1:9877 (off by default)")
+        (@arg log_format: --("log-format") +takes_value
+         "format of the log: text (the default) or json, with the node's state in separate fields")
         (@arg verbose: -v --verbose +multiple
          "increase output verbosity"))
         .get_matches();
@@ -70,11 +79,103 @@ fn main() {
             .unwrap_or("tcp://localhost:5050"),
     );
 
-    simple_logger::init_with_level(log_level).expect("Unable to initialize logger");
+    let logger = match matches.value_of("log_format").unwrap_or("text") {
+        "text" => simple_logger::init_with_level(log_level),
+        "json" => logging::init_json(log_level),
+        _ => {
+            eprintln!("--log-format must be text or json");
+            process::exit(1);
+        }
+    };
+    logger.expect("Unable to initialize logger");
+
+    let key_path = matches
+        .value_of("key")
+        .unwrap_or("/etc/sawtooth/keys/validator.priv");
+
+    let storage_path = matches
+        .value_of("storage")
+        .unwrap_or("/var/lib/sawtooth/pbft.log");
+
+    let evidence_path = matches
+        .value_of("evidence")
+        .unwrap_or("/var/lib/sawtooth/pbft-evidence.log");
 
     warn!("Sawtooth PBFT Engine ({})", env!("CARGO_PKG_VERSION"));
 
-    let pbft_engine = engine::PbftEngine::new();
+    let signer = signing::PbftSigner::from_key_file(Path::new(key_path)).unwrap_or_else(|err| {
+        error!("{}", err);
+        process::exit(1);
+    });
+
+    let storage = storage::FileStorage::open(Path::new(storage_path)).unwrap_or_else(|err| {
+        error!("{}", err);
+        process::exit(1);
+    });
+
+    let evidence_recorder = evidence::EvidenceRecorder::open(Path::new(evidence_path))
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        });
+
+    let clock: Rc<Clock> = Rc::new(SystemClock);
+
+    let recorder = matches.value_of("trace").map(|trace_path| {
+        trace::TraceRecorder::create(Path::new(trace_path), clock.clone()).unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        })
+    });
+
+    let timeline_format = match matches.value_of("timeline_format").unwrap_or("json") {
+        "json" => TimelineFormat::JsonLines,
+        "csv" => TimelineFormat::Csv,
+        _ => {
+            error!("--timeline-format must be json or csv");
+            process::exit(1);
+        }
+    };
+
+    let timeline_writer = matches.value_of("timelines").map(|timeline_path| {
+        TimelineWriter::open(Path::new(timeline_path), timeline_format).unwrap_or_else(|err| {
+            error!("{}", err);
+            process::exit(1);
+        })
+    });
+
+    let metrics = Arc::new(Metrics::new());
+
+    if let Some(address) = matches.value_of("metrics") {
+        let local_addr = metrics::serve(metrics.clone(), address).unwrap_or_else(|err| {
+            error!("Couldn't serve metrics on {}: {}", address, err);
+            process::exit(1);
+        });
+        info!("Serving metrics at http://{}/metrics", local_addr);
+    }
+
+    let status = matches.value_of("admin").map(|address| {
+        let status = Arc::new(NodeStatus::new());
+        let local_addr = admin::serve(status.clone(), address).unwrap_or_else(|err| {
+            error!("Couldn't serve the admin API on {}: {}", address, err);
+            process::exit(1);
+        });
+        info!("Serving the node's status at http://{}/status", local_addr);
+        status
+    });
+
+    let mut pbft_engine = engine::PbftEngine::new(
+        signer,
+        Box::new(storage),
+        clock,
+        recorder,
+        metrics,
+        status,
+        Some(evidence_recorder),
+    );
+    if let Some(timeline_writer) = timeline_writer {
+        pbft_engine.set_timeline_writer(timeline_writer);
+    }
 
     let (driver, _stop) = ZmqDriver::new();
 
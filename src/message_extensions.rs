@@ -23,7 +23,10 @@
 
 use std::hash::{Hash, Hasher};
 
-use protos::pbft_message::{PbftBlock, PbftMessage, PbftMessageInfo, PbftViewChange};
+use protos::pbft_message::{
+    PbftBlock, PbftMessage, PbftMessageInfo, PbftNetworkChange, PbftPreparedCertificate,
+    PbftSignedMessage, PbftViewChange,
+};
 
 // All message types that have "info" inside of them
 pub trait PbftGetInfo<'a> {
@@ -44,6 +47,8 @@ impl<'a> PbftGetInfo<'a> for &'a PbftViewChange {
 
 impl Eq for PbftMessage {}
 impl Eq for PbftViewChange {}
+impl Eq for PbftSignedMessage {}
+impl Eq for PbftNetworkChange {}
 
 impl Hash for PbftMessageInfo {
     fn hash<H: Hasher>(&self, state: &mut H) {
@@ -77,5 +82,33 @@ impl Hash for PbftViewChange {
             msg.get_info().hash(state);
             msg.get_block().hash(state);
         }
+        for cert in self.get_prepared_certificates().iter() {
+            cert.hash(state);
+        }
+    }
+}
+
+impl Hash for PbftNetworkChange {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.get_info().hash(state);
+        self.get_peers().hash(state);
+        self.get_head().hash(state);
+        self.get_tentative().hash(state);
+    }
+}
+
+impl Hash for PbftSignedMessage {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.get_message().hash(state);
+        self.get_signature().hash(state);
+    }
+}
+
+impl Hash for PbftPreparedCertificate {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.get_pre_prepare().hash(state);
+        for msg in self.get_prepare_messages().iter() {
+            msg.hash(state);
+        }
     }
 }
@@ -19,19 +19,27 @@
 
 #![allow(unknown_lints)]
 
-use std::collections::{HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt;
 
 use hex;
 
-use protos::pbft_message::{PbftBlock, PbftMessage, PbftMessageInfo, PbftViewChange};
+use protobuf::RepeatedField;
 
-use sawtooth_sdk::consensus::engine::{Block, PeerMessage};
+use protos::pbft_message::{
+    PbftBlock, PbftEvidence, PbftMessage, PbftMessageInfo, PbftNetworkChange,
+    PbftPreparedCertificate, PbftSignedMessage, PbftViewChange,
+};
+
+use sawtooth_sdk::consensus::engine::{Block, BlockId, PeerMessage};
 
 use config::PbftConfig;
 use error::PbftError;
+use evidence;
 use message_extensions::PbftGetInfo;
 use message_type::PbftMessageType;
+use metrics::{self, Metrics};
+use observer::PbftObservers;
 
 /// The log keeps track of the last stable checkpoint
 #[derive(Clone)]
@@ -45,9 +53,22 @@ pub struct PbftLog {
     /// Generic messages (BlockNew, PrePrepare, Prepare, Commit, Checkpoint)
     messages: HashSet<PbftMessage>,
 
+    /// Signed copies of the generic messages received from peers, kept as proof of their votes
+    signed_messages: HashMap<PbftMessage, PbftSignedMessage>,
+
+    /// Signed `Commit` messages for the newest blocks that garbage collection took out of the log,
+    /// kept so that the primary can still seal the blocks after them
+    sealing_commits: HashMap<PbftMessage, PbftSignedMessage>,
+
     /// View change messages
     view_changes: HashSet<PbftViewChange>,
 
+    /// Signed copies of the view change messages, needed to justify a new view
+    signed_view_changes: HashMap<PbftViewChange, PbftSignedMessage>,
+
+    /// Network change messages, along with the signed messages they arrived in
+    network_changes: HashMap<PbftNetworkChange, PbftSignedMessage>,
+
     /// Watermarks (minimum/maximum sequence numbers)
     /// Ensure that log does not get too large
     low_water_mark: u64,
@@ -62,14 +83,27 @@ pub struct PbftLog {
     /// How many cycles in between checkpoints
     checkpoint_period: u64,
 
-    /// Backlog of messages (from peers)
-    backlog: VecDeque<PeerMessage>,
+    /// How many blocks back a new block's consensus seal is for
+    pipeline_depth: u64,
+
+    /// Backlog of messages (from peers), along with their sequence numbers
+    backlog: VecDeque<(u64, PeerMessage)>,
 
     /// Backlog of blocks (from BlockNews messages)
     block_backlog: VecDeque<Block>,
 
     /// The most recent checkpoint that contains proof
     pub latest_stable_checkpoint: Option<PbftStableCheckpoint>,
+
+    /// Told whenever a checkpoint becomes stable
+    observers: PbftObservers,
+
+    /// Proof of nodes that sent conflicting votes, which hasn't been taken out of the log yet
+    evidence: Vec<PbftEvidence>,
+
+    /// The votes (signer, type, view, and sequence number) that evidence has already been found
+    /// for, so that a node can only be caught once for each of them
+    equivocations: HashSet<(Vec<u8>, String, u64, u64)>,
 }
 
 impl fmt::Display for PbftLog {
@@ -111,24 +145,39 @@ impl PbftLog {
     pub fn new(config: &PbftConfig) -> Self {
         PbftLog {
             messages: HashSet::new(),
+            signed_messages: HashMap::new(),
+            sealing_commits: HashMap::new(),
             view_changes: HashSet::new(),
+            signed_view_changes: HashMap::new(),
+            network_changes: HashMap::new(),
             low_water_mark: 0,
             cycles: 0,
             checkpoint_period: config.checkpoint_period,
+            pipeline_depth: config.pipeline_depth,
             high_water_mark: config.max_log_size,
             max_log_size: config.max_log_size,
             backlog: VecDeque::new(),
             block_backlog: VecDeque::new(),
             latest_stable_checkpoint: None,
+            observers: PbftObservers::new(),
+            evidence: vec![],
+            equivocations: HashSet::new(),
         }
     }
 
+    /// Tell the given observers (usually the node's) whenever a checkpoint becomes stable
+    pub fn set_observers(&mut self, observers: PbftObservers) {
+        self.observers = observers;
+    }
+
     /// `prepared` predicate
     /// `prepared` is true for this node if the following messages are present in its log:
     ///  + The original `BlockNew` message
     ///  + A `PrePrepare` message matching the original message (in the current view)
     ///  + `2f + 1` matching `Prepare` messages from different nodes that match
     ///    `PrePrepare` message above (including its own)
+    ///
+    /// The batches digests of all of these messages' blocks have to match as well.
     pub fn prepared(&self, deser_msg: &PbftMessage, f: u64) -> Result<(), PbftError> {
         if deser_msg.get_info().get_msg_type() != String::from(&PbftMessageType::Prepare) {
             return Err(PbftError::NotReadyForMessage);
@@ -160,42 +209,81 @@ impl PbftLog {
             ));
         }
 
+        // Everyone has to agree on the order of the batches in the block, too
+        let pre_prep_block = pre_prep_msgs[0].get_block();
+        if pre_prep_block.get_block_id() == block_new_msgs[0].get_block().get_block_id()
+            && pre_prep_block.get_batches_digest()
+                != block_new_msgs[0].get_block().get_batches_digest()
+        {
+            return Err(PbftError::BatchDigestMismatch(
+                block_new_msgs[0].get_block().clone(),
+                pre_prep_block.clone(),
+            ));
+        }
+
         let prep_msgs = self.get_messages_of_type(
             &PbftMessageType::Prepare,
             info.get_seq_num(),
             info.get_view(),
         );
-        for prep_msg in &prep_msgs {
-            // Make sure the contents match
-            if (!infos_match(prep_msg.get_info(), &pre_prep_msgs[0].get_info())
-                && prep_msg.get_block() != pre_prep_msgs[0].get_block())
-                || (!infos_match(prep_msg.get_info(), block_new_msgs[0].get_info())
-                    && prep_msg.get_block() != block_new_msgs[0].get_block())
-            {
-                return Err(PbftError::MessageMismatch(PbftMessageType::Prepare));
-            }
-        }
 
-        self.check_msg_against_log(&deser_msg, true, 2 * f + 1)?;
+        if pre_prep_block.get_block_id() != block_new_msgs[0].get_block().get_block_id() {
+            return Err(PbftError::MessageMismatch(PbftMessageType::PrePrepare));
+        }
 
-        Ok(())
+        // Only Prepares for the block in the PrePrepare count; a faulty node can send Prepares for
+        // a different block (or for the same block with its batches reordered), but that can't get
+        // either block prepared
+        count_for_block(
+            PbftMessageType::Prepare,
+            &prep_msgs,
+            pre_prep_block,
+            2 * f + 1,
+        )
     }
 
     /// "committed" predicate
     /// `committed` is true if for this node:
-    ///   + `prepared` is true
-    ///   + This node has accepted `2f + 1` `Commit` messages, including its own
+    ///   + `prepared` is true, for the block in the `Commit` message
+    ///   + This node has accepted `2f + 1` `Commit` messages for that block, including its own
     pub fn committed(&self, deser_msg: &PbftMessage, f: u64) -> Result<(), PbftError> {
         if deser_msg.get_info().get_msg_type() != String::from(&PbftMessageType::Commit) {
             return Err(PbftError::NotReadyForMessage);
         }
-        self.check_msg_against_log(&deser_msg, true, 2 * f + 1)?;
+        let info = deser_msg.get_info();
+        let commit_msgs = self.get_messages_of_type(
+            &PbftMessageType::Commit,
+            info.get_seq_num(),
+            info.get_view(),
+        );
+        count_for_block(
+            PbftMessageType::Commit,
+            &commit_msgs,
+            deser_msg.get_block(),
+            2 * f + 1,
+        )?;
 
         let mut prep_msg = deser_msg.clone();
-        let mut info = prep_msg.get_info().clone();
-        info.set_msg_type(String::from(&PbftMessageType::Prepare));
-        prep_msg.set_info(info);
+        let mut prep_info = info.clone();
+        prep_info.set_msg_type(String::from(&PbftMessageType::Prepare));
+        prep_msg.set_info(prep_info);
         self.prepared(&prep_msg, f)?;
+
+        // A quorum of Commits for some other block doesn't commit the block that was prepared
+        let pre_prep_msgs = self.get_messages_of_type(
+            &PbftMessageType::PrePrepare,
+            info.get_seq_num(),
+            info.get_view(),
+        );
+        let pre_prep_block = pre_prep_msgs[0].get_block();
+        if pre_prep_block.get_block_id() != deser_msg.get_block().get_block_id()
+            || pre_prep_block.get_batches_digest() != deser_msg.get_block().get_batches_digest()
+        {
+            return Err(PbftError::BlockMismatch(
+                pre_prep_block.clone(),
+                deser_msg.get_block().clone(),
+            ));
+        }
         Ok(())
     }
 
@@ -235,28 +323,233 @@ impl PbftLog {
         Ok(())
     }
 
-    /// Add a generic PBFT message to the log
-    pub fn add_message(&mut self, msg: PbftMessage) {
-        if msg.get_info().get_seq_num() < self.high_water_mark
-            || msg.get_info().get_seq_num() >= self.low_water_mark
-        {
-            // If the message wasn't already in the log, increment cycles
-            let msg_type = PbftMessageType::from(msg.get_info().get_msg_type());
-            let inserted = self.messages.insert(msg);
-            if msg_type == PbftMessageType::BlockNew && inserted {
-                self.cycles += 1;
-            }
-            trace!("{}", self);
-        } else {
-            warn!(
-                "Not adding message with sequence number {}; outside of log bounds ({}, {})",
-                msg.get_info().get_seq_num(),
+    /// Make sure that a sequence number is inside of the log's watermarks; the low water mark is
+    /// the latest stable checkpoint, and the high water mark is `max_log_size` past it
+    pub fn check_watermarks(&self, seq_num: u64) -> Result<(), PbftError> {
+        if seq_num < self.low_water_mark || seq_num >= self.high_water_mark {
+            Err(PbftError::OutsideWatermarks(
+                seq_num,
                 self.low_water_mark,
                 self.high_water_mark,
-            );
+            ))
+        } else {
+            Ok(())
         }
     }
 
+    /// Make sure that a message's view isn't too far past the node's current view; like sequence
+    /// numbers, views can't be more than `max_log_size` ahead
+    pub fn check_view(&self, msg_view: u64, view: u64) -> Result<(), PbftError> {
+        if msg_view > view + self.max_log_size {
+            Err(PbftError::ViewMismatch(msg_view as usize, view as usize))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Add a generic PBFT message to the log
+    /// Messages outside of the log's watermarks are rejected, except for `BlockNew` messages that
+    /// haven't been assigned a sequence number yet.
+    pub fn add_message(&mut self, msg: PbftMessage) -> Result<(), PbftError> {
+        let msg_type = PbftMessageType::from(msg.get_info().get_msg_type());
+        let seq_num = msg.get_info().get_seq_num();
+        if msg_type != PbftMessageType::BlockNew || seq_num != 0 {
+            self.check_watermarks(seq_num)?;
+        }
+
+        // If the message wasn't already in the log, increment cycles
+        let inserted = self.messages.insert(msg);
+        if msg_type == PbftMessageType::BlockNew && inserted {
+            self.cycles += 1;
+        }
+        trace!("{}", self);
+        Ok(())
+    }
+
+    /// Add a generic PBFT message to the log, along with the signed message it arrived in. If the
+    /// message is a vote that conflicts with a vote already in the log, both signed messages are
+    /// kept as evidence that the node that sent them is faulty.
+    pub fn add_signed_message(
+        &mut self,
+        msg: PbftMessage,
+        signed_msg: PbftSignedMessage,
+    ) -> Result<(), PbftError> {
+        if !self.messages.contains(&msg) {
+            self.check_for_equivocation(&msg, &signed_msg);
+        }
+        self.add_message(msg.clone())?;
+        self.signed_messages.insert(msg, signed_msg);
+        Ok(())
+    }
+
+    // Keep evidence if the new vote conflicts with a signed vote in the log. One record is enough
+    // to prove that the signer equivocated, so a node that keeps sending conflicting votes for the
+    // same view and sequence number only gets recorded once.
+    fn check_for_equivocation(&mut self, msg: &PbftMessage, signed_msg: &PbftSignedMessage) {
+        let info = msg.get_info();
+        let msg_type = PbftMessageType::from(info.get_msg_type());
+        if !evidence::is_vote(&msg_type) {
+            return;
+        }
+        let key = (
+            info.get_signer_id().to_vec(),
+            info.get_msg_type().to_string(),
+            info.get_view(),
+            info.get_seq_num(),
+        );
+        if self.equivocations.contains(&key) {
+            return;
+        }
+
+        let signed_messages = &self.signed_messages;
+        let found = self
+            .messages
+            .iter()
+            .filter(|existing| evidence::conflicts(existing, msg))
+            .filter_map(|existing| signed_messages.get(existing))
+            .next()
+            .map(|first| evidence::make_evidence(&msg_type, first.clone(), signed_msg.clone()));
+        if let Some(record) = found {
+            error!("Equivocation: {}", evidence::describe(&record));
+            self.equivocations.insert(key);
+            self.evidence.push(record);
+        }
+    }
+
+    /// Take the evidence of conflicting votes that the log has found since the last time
+    pub fn take_evidence(&mut self) -> Vec<PbftEvidence> {
+        ::std::mem::replace(&mut self.evidence, vec![])
+    }
+
+    /// Obtain the signed message that a generic PBFT message arrived in, if there is one
+    pub fn get_signed_message(&self, msg: &PbftMessage) -> Option<&PbftSignedMessage> {
+        self.signed_messages.get(msg)
+    }
+
+    /// Obtain proof that a block was committed: signed `Commit` messages for the block from at
+    /// least `num_cutoff` different nodes, all in the same view and sequence number. This includes
+    /// the newest blocks that were garbage collected, so that their successors can be sealed.
+    pub fn get_commit_certificate(
+        &self,
+        block_id: &[u8],
+        num_cutoff: u64,
+    ) -> Option<Vec<PbftSignedMessage>> {
+        let mut candidates: HashMap<(u64, u64), Vec<&PbftMessage>> = HashMap::new();
+        for msg in self.messages.iter().chain(self.sealing_commits.keys()) {
+            let info = msg.get_info();
+            if info.get_msg_type() == String::from(&PbftMessageType::Commit)
+                && msg.get_block().get_block_id() == block_id
+            {
+                candidates
+                    .entry((info.get_view(), info.get_seq_num()))
+                    .or_insert_with(Vec::new)
+                    .push(msg);
+            }
+        }
+
+        for commits in candidates.values() {
+            let mut signers: HashSet<&[u8]> = HashSet::new();
+            let certificate: Vec<PbftSignedMessage> = commits
+                .iter()
+                .filter_map(|msg| {
+                    self.get_signed_message(msg)
+                        .or_else(|| self.sealing_commits.get(*msg))
+                        .map(|signed| (msg, signed))
+                })
+                .filter(|(msg, _)| signers.insert(msg.get_info().get_signer_id()))
+                .map(|(_, signed)| signed.clone())
+                .collect();
+            if certificate.len() as u64 >= num_cutoff {
+                return Some(certificate);
+            }
+        }
+        None
+    }
+
+    /// Obtain proof of every committed block in the log that has it (see
+    /// `get_commit_certificate`), in order of block number
+    pub fn get_commit_certificates(
+        &self,
+        num_cutoff: u64,
+    ) -> Vec<(PbftBlock, Vec<PbftSignedMessage>)> {
+        let mut blocks: Vec<&PbftBlock> = Vec::new();
+        for msg in &self.messages {
+            if msg.get_info().get_msg_type() == String::from(&PbftMessageType::Commit)
+                && !blocks.contains(&msg.get_block())
+            {
+                blocks.push(msg.get_block());
+            }
+        }
+        blocks.sort_by_key(|block| block.get_block_num());
+
+        blocks
+            .into_iter()
+            .filter_map(|block| {
+                self.get_commit_certificate(block.get_block_id(), num_cutoff)
+                    .map(|certificate| (block.clone(), certificate))
+            })
+            .collect()
+    }
+
+    /// Obtain proof of every block that was prepared after sequence number `low_seq_num`: the
+    /// signed `PrePrepare` for the block, along with signed `Prepare` messages for it from at least
+    /// `num_cutoff` different nodes. If a sequence number was prepared in more than one view, only
+    /// the certificate from the latest view is included.
+    pub fn get_prepared_certificates(
+        &self,
+        low_seq_num: u64,
+        num_cutoff: u64,
+    ) -> Vec<PbftPreparedCertificate> {
+        let mut certificates: HashMap<u64, (u64, PbftPreparedCertificate)> = HashMap::new();
+        for pre_prep in &self.messages {
+            let info = pre_prep.get_info();
+            if info.get_msg_type() != String::from(&PbftMessageType::PrePrepare)
+                || info.get_seq_num() <= low_seq_num
+            {
+                continue;
+            }
+            let signed_pre_prep = match self.get_signed_message(pre_prep) {
+                Some(signed) => signed,
+                None => continue,
+            };
+
+            let mut signers: HashSet<&[u8]> = HashSet::new();
+            let prepares: Vec<PbftSignedMessage> = self
+                .get_messages_of_type(
+                    &PbftMessageType::Prepare,
+                    info.get_seq_num(),
+                    info.get_view(),
+                )
+                .into_iter()
+                .filter(|msg| msg.get_block().get_block_id() == pre_prep.get_block().get_block_id())
+                .filter_map(|msg| self.get_signed_message(msg).map(|signed| (msg, signed)))
+                .filter(|(msg, _)| signers.insert(msg.get_info().get_signer_id()))
+                .map(|(_, signed)| signed.clone())
+                .collect();
+            if (prepares.len() as u64) < num_cutoff {
+                continue;
+            }
+
+            let newer = certificates
+                .get(&info.get_seq_num())
+                .map_or(true, |(view, _)| *view < info.get_view());
+            if newer {
+                let mut cert = PbftPreparedCertificate::new();
+                cert.set_pre_prepare(signed_pre_prep.clone());
+                cert.set_prepare_messages(RepeatedField::from_vec(prepares));
+                certificates.insert(info.get_seq_num(), (info.get_view(), cert));
+            }
+        }
+
+        let mut seq_nums: Vec<u64> = certificates.keys().cloned().collect();
+        seq_nums.sort();
+        seq_nums
+            .iter()
+            .filter_map(|seq_num| certificates.remove(seq_num))
+            .map(|(_, cert)| cert)
+            .collect()
+    }
+
     /// Obtain messages from the log that match a given type, sequence number, and view
     pub fn get_messages_of_type(
         &self,
@@ -307,7 +600,8 @@ impl PbftLog {
 
     /// Fix sequence numbers of generic PBFT messages that are defaulted to zero
     /// This is used to fix the `BlockNew` messages in secondary nodes, once they receive a
-    /// `PrePrepare` message with the proper sequence number.
+    /// `PrePrepare` message with the proper sequence number. Messages for other blocks are left
+    /// alone, since their `PrePrepare`s may still be on the way.
     pub fn fix_seq_nums(
         &mut self,
         msg_type: &PbftMessageType,
@@ -319,6 +613,7 @@ impl PbftLog {
         let zero_seq_msgs: Vec<PbftMessage> = self
             .get_messages_of_type(msg_type, 0, view)
             .iter()
+            .filter(|msg| msg.get_block().get_block_id() == block.get_block_id())
             .map(|&msg| msg.clone())
             .collect();
 
@@ -326,30 +621,142 @@ impl PbftLog {
             self.messages.remove(m);
         }
 
-        let mut fixed_msgs = Vec::<PbftMessage>::new();
+        let changed_msgs = zero_seq_msgs.len();
         for mut m in zero_seq_msgs {
-            if m.get_info().get_msg_type() == String::from(msg_type)
-                && m.get_info().get_seq_num() == 0
-                && m.get_block().get_block_id() == block.get_block_id()
-            {
-                let mut info: PbftMessageInfo = m.get_info().clone();
-                let mut new_msg = m.clone();
-                info.set_seq_num(new_sequence_number);
-                new_msg.set_info(info);
-                fixed_msgs.push(new_msg.clone());
-            }
-        }
-
-        let changed_msgs = fixed_msgs.len();
-        for m in fixed_msgs {
+            m.mut_info().set_seq_num(new_sequence_number);
             self.messages.insert(m);
         }
         changed_msgs
     }
 
     /// Add a `ViewChange` message to the log
-    pub fn add_view_change(&mut self, vc: PbftViewChange) {
+    /// The message's sequence number (the sender's stable checkpoint) has to be inside of the
+    /// log's watermarks, and its view has to be past the node's current `view`, but not too far
+    /// past it. Each node only gets one `ViewChange` for each view.
+    pub fn add_view_change(&mut self, vc: PbftViewChange, view: u64) -> Result<(), PbftError> {
+        let info = vc.get_info();
+        self.check_watermarks(info.get_seq_num())?;
+        self.check_view(info.get_view(), view)?;
+        if info.get_view() <= view {
+            return Err(PbftError::ViewMismatch(
+                info.get_view() as usize,
+                view as usize,
+            ));
+        }
+
+        if self.view_changes.contains(&vc) {
+            return Ok(());
+        }
+        if self.view_changes.iter().any(|existing| {
+            existing.get_info().get_view() == info.get_view()
+                && existing.get_info().get_signer_id() == info.get_signer_id()
+        }) {
+            return Err(PbftError::MessageExists(PbftMessageType::ViewChange));
+        }
+
         self.view_changes.insert(vc);
+        Ok(())
+    }
+
+    /// Add a `ViewChange` message to the log, along with the signed message it arrived in
+    pub fn add_signed_view_change(
+        &mut self,
+        vc: PbftViewChange,
+        signed_vc: PbftSignedMessage,
+        view: u64,
+    ) -> Result<(), PbftError> {
+        self.add_view_change(vc.clone(), view)?;
+        self.signed_view_changes.insert(vc, signed_vc);
+        Ok(())
+    }
+
+    /// Obtain the `ViewChange` messages for a view, one from each node that sent one
+    pub fn get_view_changes(&self, view: u64) -> Vec<&PbftViewChange> {
+        let mut signers: HashSet<&[u8]> = HashSet::new();
+        self.view_changes
+            .iter()
+            .filter(|vc| vc.get_info().get_view() == view)
+            .filter(|vc| signers.insert(vc.get_info().get_signer_id()))
+            .collect()
+    }
+
+    /// Obtain the signed `ViewChange` messages for a view, one from each node that sent one
+    pub fn get_signed_view_changes(&self, view: u64) -> Vec<PbftSignedMessage> {
+        let mut signers: HashSet<&[u8]> = HashSet::new();
+        self.signed_view_changes
+            .iter()
+            .filter(|(vc, _)| vc.get_info().get_view() == view)
+            .filter(|(vc, _)| signers.insert(vc.get_info().get_signer_id()))
+            .map(|(_, signed)| signed.clone())
+            .collect()
+    }
+
+    /// Add a `NetworkChange` message to the log, along with the signed message it arrived in
+    pub fn add_network_change(
+        &mut self,
+        nc: PbftNetworkChange,
+        signed_nc: PbftSignedMessage,
+    ) -> Result<(), PbftError> {
+        self.check_watermarks(nc.get_info().get_seq_num())?;
+        self.network_changes.insert(nc, signed_nc);
+        Ok(())
+    }
+
+    /// Obtain the latest tentative `NetworkChange` (the one with the highest sequence number) sent
+    /// in a view by the given node
+    pub fn get_tentative_network_change(
+        &self,
+        view: u64,
+        signer_id: &[u8],
+    ) -> Option<&PbftNetworkChange> {
+        self.network_changes
+            .keys()
+            .filter(|nc| {
+                nc.get_tentative()
+                    && nc.get_info().get_view() == view
+                    && nc.get_info().get_signer_id() == signer_id
+            })
+            .max_by_key(|nc| nc.get_info().get_seq_num())
+    }
+
+    /// Obtain the final `NetworkChange` messages that agree with the given one (same view,
+    /// sequence number, and peers), one from each node that sent one
+    pub fn get_final_network_changes(&self, nc: &PbftNetworkChange) -> Vec<&PbftNetworkChange> {
+        let mut signers: HashSet<&[u8]> = HashSet::new();
+        self.network_changes
+            .keys()
+            .filter(|other| {
+                !other.get_tentative()
+                    && other.get_info().get_view() == nc.get_info().get_view()
+                    && other.get_info().get_seq_num() == nc.get_info().get_seq_num()
+                    && other.get_peers() == nc.get_peers()
+            })
+            .filter(|other| signers.insert(other.get_info().get_signer_id()))
+            .collect()
+    }
+
+    /// Obtain every signed message in the log (including `ViewChange` and `NetworkChange`
+    /// messages), along with its type
+    pub fn get_all_signed_messages(&self) -> Vec<(PbftMessageType, PbftSignedMessage)> {
+        self.signed_messages
+            .iter()
+            .map(|(msg, signed)| {
+                (
+                    PbftMessageType::from(msg.get_info().get_msg_type()),
+                    signed.clone(),
+                )
+            })
+            .chain(
+                self.signed_view_changes
+                    .values()
+                    .map(|signed| (PbftMessageType::ViewChange, signed.clone())),
+            )
+            .chain(
+                self.network_changes
+                    .values()
+                    .map(|signed| (PbftMessageType::NetworkChange, signed.clone())),
+            )
+            .collect()
     }
 
     /// Get the latest stable checkpoint
@@ -361,17 +768,36 @@ impl PbftLog {
         }
     }
 
+    /// Obtain the signed `Checkpoint` messages that prove the latest stable checkpoint
+    pub fn get_stable_checkpoint_proof(&self) -> Vec<PbftSignedMessage> {
+        match self.latest_stable_checkpoint {
+            Some(ref cp) => cp
+                .checkpoint_messages
+                .iter()
+                .filter_map(|msg| self.get_signed_message(msg))
+                .cloned()
+                .collect(),
+            None => vec![],
+        }
+    }
+
     /// Is this node ready for a checkpoint?
     pub fn at_checkpoint(&self) -> bool {
         self.cycles >= self.checkpoint_period
     }
 
     /// Garbage collect the log, and create a stable checkpoint
+    /// The watermarks slide up so that the log accepts the `max_log_size` sequence numbers starting
+    /// at the checkpoint; anything in the backlog from before the checkpoint is dropped.
     pub fn garbage_collect(&mut self, stable_checkpoint: u64, view: u64) {
         self.low_water_mark = stable_checkpoint;
         self.high_water_mark = self.low_water_mark + self.max_log_size;
         self.cycles = 0;
 
+        let low_water_mark = self.low_water_mark;
+        self.backlog
+            .retain(|(seq_num, _)| *seq_num >= low_water_mark);
+
         // Update the stable checkpoint
         #[allow(map_clone)]
         let cp_msgs: Vec<PbftMessage> = self
@@ -385,6 +811,8 @@ impl PbftLog {
         };
         self.latest_stable_checkpoint = Some(cp);
 
+        self.keep_sealing_commits(stable_checkpoint);
+
         // Garbage collect logs, filter out all old messages (up to but not including the
         // checkpoint)
         self.messages = self
@@ -396,6 +824,8 @@ impl PbftLog {
             })
             .cloned()
             .collect();
+        let messages = &self.messages;
+        self.signed_messages.retain(|msg, _| messages.contains(msg));
         self.view_changes = self
             .view_changes
             .iter()
@@ -405,14 +835,97 @@ impl PbftLog {
             })
             .cloned()
             .collect();
+        let view_changes = &self.view_changes;
+        self.signed_view_changes
+            .retain(|vc, _| view_changes.contains(vc));
+        self.network_changes
+            .retain(|nc, _| nc.get_info().get_seq_num() >= stable_checkpoint);
+        self.equivocations
+            .retain(|(_, _, _, seq_num)| *seq_num >= stable_checkpoint);
+
+        self.observers
+            .checkpoint_stabilized(stable_checkpoint, view);
     }
 
-    pub fn push_backlog(&mut self, msg: PeerMessage) {
-        self.backlog.push_back(msg);
+    /// Hold on to the signed `Commit` messages from before the checkpoint for the newest
+    /// `pipeline_depth` blocks, since the next blocks to be published have to be sealed with them;
+    /// the ones kept from earlier checkpoints are replaced once there are newer blocks
+    fn keep_sealing_commits(&mut self, stable_checkpoint: u64) {
+        let messages = &self.messages;
+        let signed_messages = &self.signed_messages;
+        let commits: Vec<(PbftMessage, PbftSignedMessage)> = self
+            .sealing_commits
+            .drain()
+            .chain(
+                messages
+                    .iter()
+                    .filter(|msg| {
+                        msg.get_info().get_msg_type() == String::from(&PbftMessageType::Commit)
+                            && msg.get_info().get_seq_num() < stable_checkpoint
+                    })
+                    .filter_map(|msg| {
+                        signed_messages
+                            .get(msg)
+                            .map(|signed| (msg.clone(), signed.clone()))
+                    }),
+            )
+            .collect();
+
+        let mut block_nums: Vec<u64> = commits
+            .iter()
+            .map(|(msg, _)| msg.get_block().get_block_num())
+            .collect();
+        block_nums.sort_unstable();
+        block_nums.dedup();
+        let newest = &block_nums[block_nums
+            .len()
+            .saturating_sub(self.pipeline_depth as usize)..];
+
+        self.sealing_commits = commits
+            .into_iter()
+            .filter(|(msg, _)| newest.contains(&msg.get_block().get_block_num()))
+            .collect();
+    }
+
+    /// Take a stable checkpoint that other nodes proved to this node with their signed `Checkpoint`
+    /// messages, and garbage collect the log up to it
+    pub fn adopt_stable_checkpoint(
+        &mut self,
+        stable_checkpoint: u64,
+        view: u64,
+        checkpoint_messages: Vec<(PbftMessage, PbftSignedMessage)>,
+    ) {
+        // The messages may be past the current watermarks; garbage collection moves them
+        for (msg, signed_msg) in checkpoint_messages {
+            self.messages.insert(msg.clone());
+            self.signed_messages.insert(msg, signed_msg);
+        }
+        self.garbage_collect(stable_checkpoint, view);
+    }
+
+    /// Save a message with the given sequence number to retry later, as long as the sequence
+    /// number is inside of the log's watermarks. A message that's already in the backlog isn't
+    /// saved again, and the backlog holds at most `max_log_size` messages.
+    pub fn push_backlog(&mut self, msg: PeerMessage, seq_num: u64) -> Result<(), PbftError> {
+        self.check_watermarks(seq_num)?;
+        let exists = self.backlog.iter().any(|(existing_seq_num, existing)| {
+            *existing_seq_num == seq_num
+                && existing.message_type == msg.message_type
+                && existing.content == msg.content
+        });
+        if exists {
+            return Ok(());
+        }
+        if self.backlog.len() as u64 >= self.max_log_size {
+            return Err(PbftError::BacklogFull(self.backlog.len()));
+        }
+
+        self.backlog.push_back((seq_num, msg));
+        Ok(())
     }
 
     pub fn pop_backlog(&mut self) -> Option<PeerMessage> {
-        self.backlog.pop_front()
+        self.backlog.pop_front().map(|(_, msg)| msg)
     }
 
     pub fn push_block_backlog(&mut self, msg: Block) {
@@ -422,6 +935,77 @@ impl PbftLog {
     pub fn pop_block_backlog(&mut self) -> Option<Block> {
         self.block_backlog.pop_front()
     }
+
+    /// Take the block with the given ID out of the backlog, if it's there
+    pub fn take_from_block_backlog(&mut self, block_id: &BlockId) -> Option<Block> {
+        let index = self
+            .block_backlog
+            .iter()
+            .position(|block| &block.block_id == block_id)?;
+        self.block_backlog.remove(index)
+    }
+
+    /// Drop the blocks in the backlog that are already on the chain (block number `block_num` or
+    /// below)
+    pub fn trim_block_backlog(&mut self, block_num: u64) {
+        self.block_backlog
+            .retain(|block| block.block_num > block_num);
+    }
+
+    /// How many messages of each type are in the log (not counting the backlog)
+    pub fn message_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for msg in &self.messages {
+            *counts
+                .entry(msg.get_info().get_msg_type().to_string())
+                .or_insert(0) += 1;
+        }
+        if !self.view_changes.is_empty() {
+            counts.insert(
+                String::from(&PbftMessageType::ViewChange),
+                self.view_changes.len(),
+            );
+        }
+        if !self.network_changes.is_empty() {
+            counts.insert(
+                String::from(&PbftMessageType::NetworkChange),
+                self.network_changes.len(),
+            );
+        }
+        counts
+    }
+
+    /// The log's watermarks; it accepts messages from the low watermark up to (but not including)
+    /// the high one
+    pub fn get_watermarks(&self) -> (u64, u64) {
+        (self.low_water_mark, self.high_water_mark)
+    }
+
+    /// How many messages and blocks are waiting in the backlogs
+    pub fn backlog_lengths(&self) -> (usize, usize) {
+        (self.backlog.len(), self.block_backlog.len())
+    }
+
+    /// Report how big the log and its backlogs are, and its latest stable checkpoint
+    pub fn report_metrics(&self, metrics: &Metrics) {
+        metrics.set(&metrics::LOG_MESSAGES, &[], self.messages.len() as f64);
+        metrics.set(
+            &metrics::LOG_VIEW_CHANGES,
+            &[],
+            self.view_changes.len() as f64,
+        );
+        metrics.set(&metrics::BACKLOG_SIZE, &[], self.backlog.len() as f64);
+        metrics.set(
+            &metrics::BLOCK_BACKLOG_SIZE,
+            &[],
+            self.block_backlog.len() as f64,
+        );
+        let stable_checkpoint = self
+            .latest_stable_checkpoint
+            .as_ref()
+            .map_or(0, |cp| cp.seq_num);
+        metrics.set(&metrics::STABLE_CHECKPOINT, &[], stable_checkpoint as f64);
+    }
 }
 
 // Make sure messages are all from different nodes
@@ -437,6 +1021,33 @@ fn num_unique_signers(msg_info_list: &[&PbftMessageInfo]) -> u64 {
     diff_msgs as u64
 }
 
+// Make sure that at least `num_cutoff` different nodes sent a message for the given block, with the
+// same batches
+fn count_for_block(
+    msg_type: PbftMessageType,
+    msgs: &[&PbftMessage],
+    block: &PbftBlock,
+    num_cutoff: u64,
+) -> Result<(), PbftError> {
+    let infos: Vec<&PbftMessageInfo> = msgs
+        .iter()
+        .filter(|msg| {
+            msg.get_block().get_block_id() == block.get_block_id()
+                && msg.get_block().get_batches_digest() == block.get_batches_digest()
+        })
+        .map(|msg| msg.get_info())
+        .collect();
+    let num_msgs = num_unique_signers(&infos);
+    if num_msgs < num_cutoff {
+        return Err(PbftError::WrongNumMessages(
+            msg_type,
+            num_cutoff as usize,
+            num_msgs as usize,
+        ));
+    }
+    Ok(())
+}
+
 // Check that the views and sequence numbers of two messages match
 fn infos_match(m1: &PbftMessageInfo, m2: &PbftMessageInfo) -> bool {
     m1.get_view() == m2.get_view() && m1.get_seq_num() == m2.get_seq_num()
@@ -446,7 +1057,9 @@ fn infos_match(m1: &PbftMessageInfo, m2: &PbftMessageInfo) -> bool {
 mod tests {
     use super::*;
     use config;
+    use proptest::prelude::*;
     use sawtooth_sdk::consensus::engine::PeerId;
+    use std::ops::Range;
 
     /// Create a PbftMessage, given its type, view, sequence number, and who it's from
     fn make_msg(
@@ -490,7 +1103,7 @@ mod tests {
 
         let msg = make_msg(&PbftMessageType::PrePrepare, 0, 1, get_peer_id(&cfg, 0));
 
-        log.add_message(msg.clone());
+        log.add_message(msg.clone()).unwrap();
 
         let gotten_msgs = log.get_messages_of_type(&PbftMessageType::PrePrepare, 1, 0);
 
@@ -498,6 +1111,43 @@ mod tests {
         assert_eq!(&msg, gotten_msgs[0]);
     }
 
+    /// Test that the log's statistics count its messages by type, and its backlogs
+    #[test]
+    fn statistics() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+        assert!(log.message_counts().is_empty());
+        assert_eq!(log.get_watermarks(), (0, cfg.max_log_size));
+
+        log.add_message(make_msg(
+            &PbftMessageType::PrePrepare,
+            0,
+            1,
+            get_peer_id(&cfg, 0),
+        ))
+        .unwrap();
+        for which in 0..3 {
+            log.add_message(make_msg(
+                &PbftMessageType::Prepare,
+                0,
+                1,
+                get_peer_id(&cfg, which),
+            ))
+            .unwrap();
+        }
+        let peer_msg = PeerMessage {
+            message_type: String::from(&PbftMessageType::Commit),
+            content: vec![],
+        };
+        log.push_backlog(peer_msg, 1).unwrap();
+
+        let counts = log.message_counts();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["PrePrepare"], 1);
+        assert_eq!(counts["Prepare"], 3);
+        assert_eq!(log.backlog_lengths(), (1, 0));
+    }
+
     /// Test that `prepared` and `committed` predicates work properly
     #[test]
     fn prepared_committed() {
@@ -505,21 +1155,21 @@ mod tests {
         let mut log = PbftLog::new(&cfg);
 
         let msg = make_msg(&PbftMessageType::BlockNew, 0, 1, get_peer_id(&cfg, 1));
-        log.add_message(msg.clone());
+        log.add_message(msg.clone()).unwrap();
 
         assert_eq!(log.cycles, 1);
         assert!(log.prepared(&msg, 1 as u64).is_err());
         assert!(log.committed(&msg, 1 as u64).is_err());
 
         let msg = make_msg(&PbftMessageType::PrePrepare, 0, 1, get_peer_id(&cfg, 0));
-        log.add_message(msg.clone());
+        log.add_message(msg.clone()).unwrap();
         assert!(log.prepared(&msg, 1 as u64).is_err());
         assert!(log.committed(&msg, 1 as u64).is_err());
 
         for peer in 0..4 {
             let msg = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, peer));
 
-            log.add_message(msg.clone());
+            log.add_message(msg.clone()).unwrap();
             if peer < 2 {
                 assert!(log.prepared(&msg, 1 as u64).is_err());
                 assert!(log.committed(&msg, 1 as u64).is_err());
@@ -532,7 +1182,7 @@ mod tests {
         for peer in 0..4 {
             let msg = make_msg(&PbftMessageType::Commit, 0, 1, get_peer_id(&cfg, peer));
 
-            log.add_message(msg.clone());
+            log.add_message(msg.clone()).unwrap();
             if peer < 2 {
                 assert!(log.committed(&msg, 1 as u64).is_err());
             } else {
@@ -541,6 +1191,92 @@ mod tests {
         }
     }
 
+    /// Test that `Prepare` messages for a block with the same ID, but different batches, don't
+    /// count towards it being `prepared`, and that a `PrePrepare` whose batches don't match the
+    /// `BlockNew` is a mismatch
+    #[test]
+    fn prepared_batch_mismatch() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        let msg = make_msg(&PbftMessageType::BlockNew, 0, 1, get_peer_id(&cfg, 1));
+        log.add_message(msg.clone()).unwrap();
+        let msg = make_msg(&PbftMessageType::PrePrepare, 0, 1, get_peer_id(&cfg, 0));
+        log.add_message(msg.clone()).unwrap();
+
+        for peer in 0..2 {
+            let msg = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, peer));
+            log.add_message(msg.clone()).unwrap();
+        }
+        let mut msg = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, 3));
+        msg.mut_block().set_batches_digest(b"reordered".to_vec());
+        log.add_message(msg.clone()).unwrap();
+
+        match log.prepared(&msg, 1) {
+            Err(PbftError::WrongNumMessages(PbftMessageType::Prepare, 3, 2)) => (),
+            res => panic!("Prepared with mismatched batches: {:?}", res),
+        }
+
+        let msg = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, 2));
+        log.add_message(msg.clone()).unwrap();
+        assert!(log.prepared(&msg, 1).is_ok());
+
+        // The primary reordered the batches in the block
+        let mut log = PbftLog::new(&cfg);
+        let msg = make_msg(&PbftMessageType::BlockNew, 0, 1, get_peer_id(&cfg, 1));
+        log.add_message(msg.clone()).unwrap();
+        let mut msg = make_msg(&PbftMessageType::PrePrepare, 0, 1, get_peer_id(&cfg, 0));
+        msg.mut_block().set_batches_digest(b"reordered".to_vec());
+        log.add_message(msg.clone()).unwrap();
+        let msg = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, 1));
+        log.add_message(msg.clone()).unwrap();
+        match log.prepared(&msg, 1) {
+            Err(PbftError::BatchDigestMismatch(_, _)) => (),
+            res => panic!("Prepared with mismatched batches: {:?}", res),
+        }
+    }
+
+    /// Test that `Prepare` and `Commit` messages for a different block than the one in the
+    /// `PrePrepare` don't count towards being `prepared` or `committed`
+    #[test]
+    fn conflicting_blocks() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        let msg = make_msg(&PbftMessageType::BlockNew, 0, 1, get_peer_id(&cfg, 1));
+        log.add_message(msg.clone()).unwrap();
+        let msg = make_msg(&PbftMessageType::PrePrepare, 0, 1, get_peer_id(&cfg, 0));
+        log.add_message(msg.clone()).unwrap();
+
+        // An equivocating primary sent nodes 2 and 3 a different block
+        for peer in 0..4 {
+            let mut prepare = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, peer));
+            let mut commit = make_msg(&PbftMessageType::Commit, 0, 1, get_peer_id(&cfg, peer));
+            if peer >= 2 {
+                prepare.mut_block().set_block_id(b"other block".to_vec());
+                commit.mut_block().set_block_id(b"other block".to_vec());
+            }
+            log.add_message(prepare).unwrap();
+            log.add_message(commit).unwrap();
+        }
+
+        let prepare = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, 1));
+        match log.prepared(&prepare, 1) {
+            Err(PbftError::WrongNumMessages(PbftMessageType::Prepare, 3, 2)) => (),
+            res => panic!("Prepared without enough matching Prepares: {:?}", res),
+        }
+        let commit = make_msg(&PbftMessageType::Commit, 0, 1, get_peer_id(&cfg, 1));
+        match log.committed(&commit, 1) {
+            Err(PbftError::WrongNumMessages(PbftMessageType::Commit, 3, 2)) => (),
+            res => panic!("Committed without enough matching Commits: {:?}", res),
+        }
+
+        // One more Prepare for the right block is enough
+        let msg = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, 2));
+        log.add_message(msg).unwrap();
+        assert!(log.prepared(&prepare, 1).is_ok());
+    }
+
     /// Test that sequence number adjustments work as expected
     /// (This is used by secondary nodes to adjust the sequence number of their `BlockNew`, when
     /// they receive a `PrePrepare` from the primary)
@@ -550,16 +1286,129 @@ mod tests {
         let mut log = PbftLog::new(&cfg);
 
         let msg0 = make_msg(&PbftMessageType::BlockNew, 0, 0, get_peer_id(&cfg, 1));
-        log.add_message(msg0.clone());
+        log.add_message(msg0.clone()).unwrap();
 
         let msg = make_msg(&PbftMessageType::PrePrepare, 0, 1, get_peer_id(&cfg, 0));
-        log.add_message(msg.clone());
+        log.add_message(msg.clone()).unwrap();
 
         let num_updated = log.fix_seq_nums(&PbftMessageType::BlockNew, 1, 0, msg0.get_block());
 
         assert_eq!(num_updated, 1);
     }
 
+    /// Add a message to the log, signed by node `which`
+    fn add_signed(log: &mut PbftLog, msg: PbftMessage, which: u64) {
+        use protobuf::Message;
+        use signing::mock_signer;
+
+        let signed = mock_signer(which)
+            .sign(&msg.write_to_bytes().unwrap())
+            .unwrap();
+        log.add_signed_message(msg, signed).unwrap();
+    }
+
+    /// Test that a node that signs votes for two different blocks in the same view and sequence
+    /// number is caught, with evidence that anyone can verify
+    #[test]
+    fn equivocation() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        let commit = make_msg(&PbftMessageType::Commit, 0, 1, get_peer_id(&cfg, 2));
+        add_signed(&mut log, commit.clone(), 2);
+        let mut conflicting = commit.clone();
+        conflicting
+            .mut_block()
+            .set_block_id(b"another block".to_vec());
+
+        // Votes for the same block, or for different sequence numbers, are fine
+        add_signed(&mut log, commit.clone(), 2);
+        let other = make_msg(&PbftMessageType::Commit, 0, 2, get_peer_id(&cfg, 2));
+        add_signed(&mut log, other, 2);
+        assert!(log.take_evidence().is_empty());
+
+        add_signed(&mut log, conflicting.clone(), 2);
+        let found = log.take_evidence();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].get_message_type(), "Commit");
+        assert_eq!(
+            evidence::verify_evidence(&found[0]).unwrap(),
+            get_peer_id(&cfg, 2)
+        );
+
+        // The same conflict is only reported once
+        add_signed(&mut log, conflicting, 2);
+        assert!(log.take_evidence().is_empty());
+    }
+
+    /// Test that a node that sends several conflicting votes for the same view and sequence number
+    /// only gets one evidence record, instead of one for every pair of them
+    #[test]
+    fn repeated_equivocation() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        for block_id in &[&b"first block"[..], b"second block", b"third block"] {
+            let mut prepare = make_msg(&PbftMessageType::Prepare, 0, 1, get_peer_id(&cfg, 2));
+            prepare.mut_block().set_block_id(block_id.to_vec());
+            add_signed(&mut log, prepare, 2);
+        }
+
+        let found = log.take_evidence();
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            evidence::verify_evidence(&found[0]).unwrap(),
+            get_peer_id(&cfg, 2)
+        );
+    }
+
+    /// Test that prepared certificates are only made for blocks with a signed `PrePrepare` and
+    /// `2f + 1` signed `Prepare` messages after the given sequence number, and that only the
+    /// latest view's certificate is kept for each sequence number
+    #[test]
+    fn prepared_certificates() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        for seq in 1..3 {
+            let msg = make_msg(&PbftMessageType::PrePrepare, 0, seq, get_peer_id(&cfg, 0));
+            add_signed(&mut log, msg, 0);
+            for peer in 0..2 {
+                let msg = make_msg(&PbftMessageType::Prepare, 0, seq, get_peer_id(&cfg, peer));
+                add_signed(&mut log, msg, peer);
+            }
+        }
+        assert!(log.get_prepared_certificates(0, 3).is_empty());
+
+        // An unsigned Prepare doesn't count
+        let msg = make_msg(&PbftMessageType::Prepare, 0, 2, get_peer_id(&cfg, 3));
+        log.add_message(msg).unwrap();
+        assert!(log.get_prepared_certificates(0, 3).is_empty());
+
+        for seq in 1..3 {
+            let msg = make_msg(&PbftMessageType::Prepare, 0, seq, get_peer_id(&cfg, 2));
+            add_signed(&mut log, msg, 2);
+        }
+        let certs = log.get_prepared_certificates(0, 3);
+        assert_eq!(certs.len(), 2);
+        assert_eq!(certs[1].get_prepare_messages().len(), 3);
+        assert_eq!(log.get_prepared_certificates(1, 3).len(), 1);
+
+        // Sequence number 2 gets prepared again in view 1
+        let msg = make_msg(&PbftMessageType::PrePrepare, 1, 2, get_peer_id(&cfg, 1));
+        add_signed(&mut log, msg.clone(), 1);
+        for peer in 0..3 {
+            let msg = make_msg(&PbftMessageType::Prepare, 1, 2, get_peer_id(&cfg, peer));
+            add_signed(&mut log, msg, peer);
+        }
+        let certs = log.get_prepared_certificates(1, 3);
+        assert_eq!(certs.len(), 1);
+        assert_eq!(
+            certs[0].get_pre_prepare(),
+            log.get_signed_message(&msg).unwrap()
+        );
+    }
+
     /// Make sure that the log doesn't start out checkpointing
     #[test]
     fn checkpoint_basics() {
@@ -579,28 +1428,28 @@ mod tests {
 
         for seq in 1..5 {
             let msg = make_msg(&PbftMessageType::BlockNew, 0, seq, get_peer_id(&cfg, 1));
-            log.add_message(msg.clone());
+            log.add_message(msg.clone()).unwrap();
 
             let msg = make_msg(&PbftMessageType::PrePrepare, 0, seq, get_peer_id(&cfg, 0));
-            log.add_message(msg.clone());
+            log.add_message(msg.clone()).unwrap();
 
             for peer in 0..4 {
                 let msg = make_msg(&PbftMessageType::Prepare, 0, seq, get_peer_id(&cfg, peer));
 
-                log.add_message(msg.clone());
+                log.add_message(msg.clone()).unwrap();
             }
 
             for peer in 0..4 {
                 let msg = make_msg(&PbftMessageType::Commit, 0, seq, get_peer_id(&cfg, peer));
 
-                log.add_message(msg.clone());
+                log.add_message(msg.clone()).unwrap();
             }
         }
 
         for peer in 0..4 {
             let msg = make_msg(&PbftMessageType::Checkpoint, 0, 4, get_peer_id(&cfg, peer));
 
-            log.add_message(msg.clone());
+            log.add_message(msg.clone()).unwrap();
         }
 
         log.garbage_collect(4, 0);
@@ -623,5 +1472,384 @@ mod tests {
         for msg_type in &[PbftMessageType::Prepare, PbftMessageType::Commit] {
             assert_eq!(log.get_messages_of_type(&msg_type, 4, 0).len(), 4);
         }
+
+        // The watermarks slide up to the checkpoint
+        assert!(log.check_watermarks(3).is_err());
+        assert!(log.check_watermarks(4).is_ok());
+        assert!(log.check_watermarks(4 + cfg.max_log_size - 1).is_ok());
+        assert!(log.check_watermarks(4 + cfg.max_log_size).is_err());
+        let msg = make_msg(&PbftMessageType::Commit, 0, 2, get_peer_id(&cfg, 0));
+        assert!(log.add_message(msg).is_err());
+    }
+
+    /// Make sure that messages outside of the watermarks are rejected everywhere they could be
+    /// stored, so that a flood of them leaves the log's memory usage bounded
+    #[test]
+    fn watermarks() {
+        let cfg = config::mock_config(4);
+        let mut log = PbftLog::new(&cfg);
+
+        let high = cfg.max_log_size;
+        for seq in high..high + 10_000 {
+            let msg = make_msg(&PbftMessageType::Prepare, 0, seq, get_peer_id(&cfg, 2));
+            match log.add_message(msg) {
+                Err(PbftError::OutsideWatermarks(s, 0, h)) if s == seq && h == high => (),
+                res => panic!("Out-of-window message was accepted: {:?}", res),
+            }
+
+            let peer_msg = PeerMessage {
+                message_type: String::from(&PbftMessageType::Prepare),
+                content: vec![],
+            };
+            assert!(log.push_backlog(peer_msg, seq).is_err());
+
+            let mut vc = PbftViewChange::new();
+            vc.set_info(
+                make_msg(&PbftMessageType::ViewChange, 1, seq, get_peer_id(&cfg, 2)).take_info(),
+            );
+            assert!(log.add_view_change(vc, 0).is_err());
+        }
+        assert!(log.messages.is_empty());
+        assert!(log.backlog.is_empty());
+        assert!(log.view_changes.is_empty());
+
+        // The same goes for views: ViewChanges for views that are too far ahead, or that the node
+        // is already in, are rejected
+        let view_change = |view, seq, which| {
+            let mut vc = PbftViewChange::new();
+            vc.set_info(
+                make_msg(
+                    &PbftMessageType::ViewChange,
+                    view,
+                    seq,
+                    get_peer_id(&cfg, which),
+                )
+                .take_info(),
+            );
+            vc
+        };
+        for view in high + 1..high + 10_000 {
+            match log.add_view_change(view_change(view, 1, 2), 0) {
+                Err(PbftError::ViewMismatch(v, 0)) if v as u64 == view => (),
+                res => panic!("Far-off ViewChange was accepted: {:?}", res),
+            }
+            assert!(log.check_view(view, 0).is_err());
+        }
+        assert!(log.add_view_change(view_change(3, 1, 2), 3).is_err());
+        assert!(log.view_changes.is_empty());
+
+        // Each node only gets one ViewChange for each view
+        assert!(log.add_view_change(view_change(high, 1, 2), 0).is_ok());
+        assert!(log.add_view_change(view_change(high, 1, 2), 0).is_ok());
+        match log.add_view_change(view_change(high, 2, 2), 0) {
+            Err(PbftError::MessageExists(PbftMessageType::ViewChange)) => (),
+            res => panic!("Second ViewChange for a view was accepted: {:?}", res),
+        }
+        assert!(log.add_view_change(view_change(high, 2, 3), 0).is_ok());
+        assert_eq!(log.view_changes.len(), 2);
+
+        // Messages inside of the window are still accepted
+        let msg = make_msg(&PbftMessageType::Prepare, 0, high - 1, get_peer_id(&cfg, 2));
+        assert!(log.add_message(msg).is_ok());
+
+        // After a checkpoint, messages from before it are rejected, and old messages are dropped
+        // from the backlog
+        let peer_msg = PeerMessage {
+            message_type: String::from(&PbftMessageType::Commit),
+            content: vec![],
+        };
+        assert!(log.push_backlog(peer_msg.clone(), 5).is_ok());
+        assert!(log.push_backlog(peer_msg, 15).is_ok());
+        log.garbage_collect(10, 0);
+        assert_eq!(log.backlog.len(), 1);
+        let msg = make_msg(&PbftMessageType::Prepare, 0, 9, get_peer_id(&cfg, 2));
+        assert!(log.add_message(msg).is_err());
+        let msg = make_msg(&PbftMessageType::Prepare, 0, high + 9, get_peer_id(&cfg, 2));
+        assert!(log.add_message(msg).is_ok());
+
+        // BlockNew messages that haven't been given a sequence number are always accepted
+        let msg = make_msg(&PbftMessageType::BlockNew, 0, 0, get_peer_id(&cfg, 1));
+        assert!(log.add_message(msg).is_ok());
+
+        // The backlog doesn't keep the same message twice, and only holds so many messages
+        let peer_msg = |num: u64| PeerMessage {
+            message_type: String::from(&PbftMessageType::Commit),
+            content: num.to_string().into_bytes(),
+        };
+        for _ in 0..3 {
+            assert!(log.push_backlog(peer_msg(0), 20).is_ok());
+        }
+        assert_eq!(log.backlog.len(), 2);
+        for num in 1..cfg.max_log_size - 1 {
+            assert!(log.push_backlog(peer_msg(num), 20).is_ok());
+        }
+        match log.push_backlog(peer_msg(cfg.max_log_size), 20) {
+            Err(PbftError::BacklogFull(len)) if len as u64 == cfg.max_log_size => (),
+            res => panic!("Message was added to a full backlog: {:?}", res),
+        }
+        assert!(log.push_backlog(peer_msg(0), 20).is_ok());
+        assert_eq!(log.backlog.len() as u64, cfg.max_log_size);
+    }
+
+    /// Which message type a generated number stands for; votes come up the most, since the quorum
+    /// predicates need a lot of them
+    fn msg_type_of(num: u8) -> PbftMessageType {
+        match num {
+            0 => PbftMessageType::BlockNew,
+            1 => PbftMessageType::PrePrepare,
+            2..=4 => PbftMessageType::Prepare,
+            5..=7 => PbftMessageType::Commit,
+            _ => PbftMessageType::Checkpoint,
+        }
+    }
+
+    /// The numbers that `msg_type_of` turns into each type
+    const BLOCK_NEW: u8 = 0;
+    const PREPARE: u8 = 2;
+    const COMMIT: u8 = 5;
+    const ANY_TYPE: Range<u8> = 0..9;
+
+    /// Which block a message is for (its ID and batches digest): usually the same block,
+    /// sometimes a different one, and once in a while the same block with its batches in a
+    /// different order
+    fn arb_block() -> impl Strategy<Value = (&'static str, &'static str)> {
+        prop_oneof![
+            6 => Just(("A", "batches")),
+            1 => Just(("B", "batches")),
+            1 => Just(("A", "reordered")),
+        ]
+    }
+
+    /// A message with a type from `types`, from one of four nodes, in view 0 or 1 and sequence
+    /// number 0 through 2
+    fn arb_msg(types: Range<u8>) -> impl Strategy<Value = PbftMessage> {
+        let peers = config::mock_config(4).peers;
+        (types, 0..2u64, 0..3u64, 0..4usize, arb_block()).prop_map(
+            move |(msg_type, view, seq_num, signer, (block_id, batches_digest))| {
+                let mut msg =
+                    make_msg(&msg_type_of(msg_type), view, seq_num, peers[signer].clone());
+                msg.mut_block().set_block_id(block_id.as_bytes().to_vec());
+                // Every block was published by the same validator
+                msg.mut_block()
+                    .set_signer_id(Vec::<u8>::from(peers[0].clone()));
+                msg.mut_block()
+                    .set_batches_digest(batches_digest.as_bytes().to_vec());
+                msg
+            },
+        )
+    }
+
+    /// A set of random messages, which can end up with any number of proposals and votes for each
+    /// view and sequence number
+    fn arb_msgs() -> impl Strategy<Value = HashSet<PbftMessage>> {
+        prop::collection::hash_set(arb_msg(ANY_TYPE), 0..80)
+    }
+
+    /// A log with all of the given messages in it
+    fn make_log(msgs: &HashSet<PbftMessage>) -> PbftLog {
+        let mut log = PbftLog::new(&config::mock_config(4));
+        for msg in msgs {
+            log.add_message(msg.clone()).unwrap();
+        }
+        log
+    }
+
+    /// The messages of the given type in the given view and sequence number
+    fn msgs_at<'a>(
+        msgs: &'a HashSet<PbftMessage>,
+        msg_type: &PbftMessageType,
+        view: u64,
+        seq_num: u64,
+    ) -> Vec<&'a PbftMessage> {
+        msgs.iter()
+            .filter(|msg| {
+                PbftMessageType::from(msg.get_info().get_msg_type()) == *msg_type
+                    && msg.get_info().get_view() == view
+                    && msg.get_info().get_seq_num() == seq_num
+            })
+            .collect()
+    }
+
+    /// How many different nodes sent one of the messages
+    fn num_signers(msgs: &[&PbftMessage]) -> u64 {
+        msgs.iter()
+            .map(|msg| msg.get_info().get_signer_id())
+            .collect::<HashSet<_>>()
+            .len() as u64
+    }
+
+    /// How many different nodes voted for exactly this block, with a message of the given type in
+    /// the given view and sequence number
+    fn votes_for(
+        msgs: &HashSet<PbftMessage>,
+        msg_type: &PbftMessageType,
+        view: u64,
+        seq_num: u64,
+        block: &PbftBlock,
+    ) -> u64 {
+        let votes: Vec<&PbftMessage> = msgs_at(msgs, msg_type, view, seq_num)
+            .into_iter()
+            .filter(|msg| msg.get_block() == block)
+            .collect();
+        num_signers(&votes)
+    }
+
+    /// The block that's prepared in a view and sequence number, if there is one, straight from the
+    /// definition: the node has exactly one block for it (one `BlockNew`), the primary proposed
+    /// exactly that block (one `PrePrepare`), and a quorum of `2f + 1` different nodes sent a
+    /// `Prepare` for it
+    fn reference_prepared(
+        msgs: &HashSet<PbftMessage>,
+        view: u64,
+        seq_num: u64,
+        f: u64,
+    ) -> Option<PbftBlock> {
+        let block_news = msgs_at(msgs, &PbftMessageType::BlockNew, view, seq_num);
+        let pre_prepares = msgs_at(msgs, &PbftMessageType::PrePrepare, view, seq_num);
+        if block_news.len() != 1 || pre_prepares.len() != 1 {
+            return None;
+        }
+
+        let block = pre_prepares[0].get_block();
+        let quorum = votes_for(msgs, &PbftMessageType::Prepare, view, seq_num, block) > 2 * f;
+        if block_news[0].get_block() == block && quorum {
+            Some(block.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Whether the block a `Commit` is for is committed, straight from the definition: it's the
+    /// prepared block, and a quorum of `2f + 1` different nodes sent a `Commit` for it
+    fn reference_committed(msgs: &HashSet<PbftMessage>, commit: &PbftMessage, f: u64) -> bool {
+        let info = commit.get_info();
+        let (view, seq_num) = (info.get_view(), info.get_seq_num());
+        let block = commit.get_block();
+        reference_prepared(msgs, view, seq_num, f).as_ref() == Some(block)
+            && votes_for(msgs, &PbftMessageType::Commit, view, seq_num, block) > 2 * f
+    }
+
+    proptest! {
+        /// `prepared` and `committed` agree with their definitions for random logs
+        #[test]
+        fn quorum_predicates_match_reference(
+            msgs in arb_msgs(),
+            prepare in arb_msg(PREPARE..PREPARE + 1),
+            commit in arb_msg(COMMIT..COMMIT + 1)
+        ) {
+            let log = make_log(&msgs);
+            let f = 1;
+
+            let info = prepare.get_info();
+            let expected = reference_prepared(&msgs, info.get_view(), info.get_seq_num(), f);
+            prop_assert_eq!(log.prepared(&prepare, f).is_ok(), expected.is_some());
+            prop_assert_eq!(
+                log.committed(&commit, f).is_ok(),
+                reference_committed(&msgs, &commit, f)
+            );
+
+            // Only Prepares and Commits can be prepared or committed
+            prop_assert!(log.prepared(&commit, f).is_err());
+            prop_assert!(log.committed(&prepare, f).is_err());
+        }
+
+        /// `check_msg_against_log` counts the different nodes that sent a message of the same type,
+        /// view, and sequence number
+        #[test]
+        fn check_msg_against_log_matches_reference(
+            msgs in arb_msgs(),
+            msg in arb_msg(ANY_TYPE),
+            cutoff in 0..5u64
+        ) {
+            let log = make_log(&msgs);
+            let info = msg.get_info();
+            let msg_type = PbftMessageType::from(info.get_msg_type());
+            let same = msgs_at(&msgs, &msg_type, info.get_view(), info.get_seq_num());
+
+            prop_assert_eq!(
+                log.check_msg_against_log(&&msg, true, cutoff).is_ok(),
+                num_signers(&same) >= cutoff
+            );
+        }
+
+        /// `fix_seq_nums` gives the new sequence number to exactly the messages of the given type
+        /// and view that are for the block and don't have one yet, and leaves the rest of the log
+        /// alone
+        #[test]
+        fn fix_seq_nums_matches_reference(
+            msgs in arb_msgs(),
+            block_new in arb_msg(BLOCK_NEW..BLOCK_NEW + 1),
+            seq_num in 1..3u64
+        ) {
+            let mut log = make_log(&msgs);
+            let view = block_new.get_info().get_view();
+            let block = block_new.get_block();
+
+            let mut num_fixed = 0;
+            let expected: HashSet<PbftMessage> = msgs
+                .iter()
+                .map(|msg| {
+                    let mut msg = msg.clone();
+                    if PbftMessageType::from(msg.get_info().get_msg_type())
+                        == PbftMessageType::BlockNew
+                        && msg.get_info().get_view() == view
+                        && msg.get_info().get_seq_num() == 0
+                        && msg.get_block().get_block_id() == block.get_block_id()
+                    {
+                        msg.mut_info().set_seq_num(seq_num);
+                        num_fixed += 1;
+                    }
+                    msg
+                })
+                .collect();
+
+            prop_assert_eq!(
+                log.fix_seq_nums(&PbftMessageType::BlockNew, seq_num, view, block),
+                num_fixed
+            );
+            prop_assert_eq!(&log.messages, &expected);
+        }
+
+        /// Garbage collection keeps exactly the messages from the checkpoint on, and keeps the
+        /// checkpoint's `Checkpoint` messages as proof
+        #[test]
+        fn garbage_collect_matches_reference(
+            msgs in arb_msgs(),
+            checkpoint in 0..3u64,
+            view in 0..2u64
+        ) {
+            let cfg = config::mock_config(4);
+            let mut log = make_log(&msgs);
+            log.garbage_collect(checkpoint, view);
+
+            let expected: HashSet<PbftMessage> = msgs
+                .iter()
+                .filter(|msg| {
+                    let seq_num = msg.get_info().get_seq_num();
+                    seq_num >= checkpoint && seq_num > 0
+                })
+                .cloned()
+                .collect();
+            prop_assert_eq!(&log.messages, &expected);
+
+            let proof: HashSet<&PbftMessage> = log
+                .latest_stable_checkpoint
+                .as_ref()
+                .unwrap()
+                .checkpoint_messages
+                .iter()
+                .collect();
+            let expected_proof: HashSet<&PbftMessage> =
+                msgs_at(&msgs, &PbftMessageType::Checkpoint, view, checkpoint)
+                    .into_iter()
+                    .collect();
+            prop_assert_eq!(proof, expected_proof);
+
+            prop_assert!(log.check_watermarks(checkpoint).is_ok());
+            prop_assert!(log.check_watermarks(checkpoint + cfg.max_log_size).is_err());
+            if checkpoint > 0 {
+                prop_assert!(log.check_watermarks(checkpoint - 1).is_err());
+            }
+        }
     }
 }
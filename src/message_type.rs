@@ -30,6 +30,10 @@ pub enum PbftHint {
 
     /// A present message. The node is ready to process this message immediately.
     PresentMessage,
+
+    /// A message for a block that is going through consensus ahead of the working block. The node
+    /// processes it separately from the working block's messages.
+    PipelinedMessage,
 }
 
 // Messages related to PBFT consensus
@@ -44,6 +48,10 @@ pub enum PbftMessageType {
     BlockNew,
     Checkpoint,
     ViewChange,
+    NewView,
+    NetworkChange,
+    CatchUpRequest,
+    CatchUp,
 
     Unset,
 }
@@ -57,6 +65,10 @@ impl fmt::Display for PbftMessageType {
             PbftMessageType::BlockNew => "BN",
             PbftMessageType::Checkpoint => "CP",
             PbftMessageType::ViewChange => "VC",
+            PbftMessageType::NewView => "NV",
+            PbftMessageType::NetworkChange => "NC",
+            PbftMessageType::CatchUpRequest => "CR",
+            PbftMessageType::CatchUp => "CU",
             PbftMessageType::Unset => "Un",
         };
         write!(f, "{}", txt)
@@ -84,6 +96,10 @@ impl<'a> From<&'a str> for PbftMessageType {
             "BlockNew" => PbftMessageType::BlockNew,
             "ViewChange" => PbftMessageType::ViewChange,
             "Checkpoint" => PbftMessageType::Checkpoint,
+            "NewView" => PbftMessageType::NewView,
+            "NetworkChange" => PbftMessageType::NetworkChange,
+            "CatchUpRequest" => PbftMessageType::CatchUpRequest,
+            "CatchUp" => PbftMessageType::CatchUp,
             _ => {
                 warn!("Unhandled PBFT message type: {}", s);
                 PbftMessageType::Unset
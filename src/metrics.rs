@@ -0,0 +1,482 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Metrics about the node's progress through the consensus algorithm
+//!
+//! The node and its log keep a set of counters, gauges, and histograms up to date as they handle
+//! updates. The metrics can be rendered in the Prometheus text format, and served over HTTP (at
+//! `/metrics`) for Prometheus to scrape.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use http;
+
+/// The kinds of metrics that Prometheus understands
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MetricKind {
+    /// A count that only goes up
+    Counter,
+
+    /// A value that can go up or down
+    Gauge,
+
+    /// A distribution of observed values, counted into buckets
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+/// The definition of a metric: its name, description, kind, and the names of its labels
+#[derive(Debug)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    pub labels: &'static [&'static str],
+}
+
+pub static VIEW: Metric = Metric {
+    name: "pbft_view",
+    help: "The view the node is in",
+    kind: MetricKind::Gauge,
+    labels: &[],
+};
+
+pub static SEQ_NUM: Metric = Metric {
+    name: "pbft_seq_num",
+    help: "The sequence number of the block the node is working on",
+    kind: MetricKind::Gauge,
+    labels: &[],
+};
+
+pub static PHASE: Metric = Metric {
+    name: "pbft_phase",
+    help: "Whether the node is in each phase (1 for the current phase, 0 for the others)",
+    kind: MetricKind::Gauge,
+    labels: &["phase"],
+};
+
+pub static MODE: Metric = Metric {
+    name: "pbft_mode",
+    help: "Whether the node is in each mode (1 for the current mode, 0 for the others)",
+    kind: MetricKind::Gauge,
+    labels: &["mode"],
+};
+
+pub static PHASE_DURATION: Metric = Metric {
+    name: "pbft_phase_duration_seconds",
+    help: "How long the node spent in each phase before moving on to the next one",
+    kind: MetricKind::Histogram,
+    labels: &["phase"],
+};
+
+pub static VIEW_CHANGES: Metric = Metric {
+    name: "pbft_view_changes_total",
+    help: "How many view changes the node has started",
+    kind: MetricKind::Counter,
+    labels: &[],
+};
+
+pub static BLOCKS_COMMITTED: Metric = Metric {
+    name: "pbft_blocks_committed_total",
+    help: "How many blocks the validator has committed",
+    kind: MetricKind::Counter,
+    labels: &[],
+};
+
+pub static MESSAGES_RECEIVED: Metric = Metric {
+    name: "pbft_messages_received_total",
+    help: "How many messages the node has received, by the peer that sent them and their type",
+    kind: MetricKind::Counter,
+    labels: &["peer", "type"],
+};
+
+pub static MESSAGES_SENT: Metric = Metric {
+    name: "pbft_messages_sent_total",
+    help: "How many messages the node has sent, by type",
+    kind: MetricKind::Counter,
+    labels: &["type"],
+};
+
+pub static PEER_SILENCE: Metric = Metric {
+    name: "pbft_peer_silence_seconds",
+    help: "How long it's been since each peer sent a message",
+    kind: MetricKind::Gauge,
+    labels: &["peer"],
+};
+
+pub static SILENT_PEER: Metric = Metric {
+    name: "pbft_peer_silent",
+    help: "Whether each peer has gone quiet (1 if it has, 0 if it hasn't)",
+    kind: MetricKind::Gauge,
+    labels: &["peer"],
+};
+
+pub static LOG_MESSAGES: Metric = Metric {
+    name: "pbft_log_messages",
+    help: "How many consensus messages are in the node's log",
+    kind: MetricKind::Gauge,
+    labels: &[],
+};
+
+pub static LOG_VIEW_CHANGES: Metric = Metric {
+    name: "pbft_log_view_changes",
+    help: "How many view change messages are in the node's log",
+    kind: MetricKind::Gauge,
+    labels: &[],
+};
+
+pub static BACKLOG_SIZE: Metric = Metric {
+    name: "pbft_backlog_messages",
+    help: "How many messages are in the backlog, waiting until the node is ready for them",
+    kind: MetricKind::Gauge,
+    labels: &[],
+};
+
+pub static BLOCK_BACKLOG_SIZE: Metric = Metric {
+    name: "pbft_backlog_blocks",
+    help: "How many blocks are in the backlog, waiting until the node is ready for them",
+    kind: MetricKind::Gauge,
+    labels: &[],
+};
+
+pub static STABLE_CHECKPOINT: Metric = Metric {
+    name: "pbft_stable_checkpoint",
+    help: "The sequence number of the latest stable checkpoint",
+    kind: MetricKind::Gauge,
+    labels: &[],
+};
+
+/// Upper bounds of the histogram buckets, in seconds
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+enum Series {
+    Value(f64),
+    Histogram {
+        /// How many observations fell into each bucket (not cumulative)
+        buckets: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+struct Family {
+    metric: &'static Metric,
+
+    /// The values of the metric, by the values of its labels
+    series: BTreeMap<Vec<String>, Series>,
+}
+
+/// The current values of every metric that has been updated; safe to share between the node and
+/// the thread that serves the metrics
+#[derive(Default)]
+pub struct Metrics {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Add one to a counter
+    pub fn inc(&self, metric: &'static Metric, labels: &[&str]) {
+        self.add(metric, labels, 1.0);
+    }
+
+    /// Add to a counter or gauge
+    pub fn add(&self, metric: &'static Metric, labels: &[&str], value: f64) {
+        self.update(metric, labels, |series| {
+            if let Series::Value(ref mut current) = *series {
+                *current += value;
+            }
+        });
+    }
+
+    /// Set a gauge
+    pub fn set(&self, metric: &'static Metric, labels: &[&str], value: f64) {
+        self.update(metric, labels, |series| {
+            if let Series::Value(ref mut current) = *series {
+                *current = value;
+            }
+        });
+    }
+
+    /// Record an observation in a histogram
+    pub fn observe(&self, metric: &'static Metric, labels: &[&str], value: f64) {
+        self.update(metric, labels, |series| {
+            if let Series::Histogram {
+                ref mut buckets,
+                ref mut sum,
+                ref mut count,
+            } = *series
+            {
+                if let Some(bucket) = BUCKETS.iter().position(|bound| value <= *bound) {
+                    buckets[bucket] += 1;
+                }
+                *sum += value;
+                *count += 1;
+            }
+        });
+    }
+
+    /// The value of a counter or gauge, or the number of observations in a histogram; `None` if
+    /// it was never updated
+    pub fn get(&self, metric: &'static Metric, labels: &[&str]) -> Option<f64> {
+        let families = self.lock();
+        let key: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        families
+            .get(metric.name)
+            .and_then(|family| family.series.get(&key))
+            .map(|series| match *series {
+                Series::Value(value) => value,
+                Series::Histogram { count, .. } => count as f64,
+            })
+    }
+
+    /// Render every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let families = self.lock();
+        let mut out = String::new();
+        for family in families.values() {
+            let metric = family.metric;
+            // Writing to a String can't fail
+            let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+            let _ = writeln!(out, "# TYPE {} {}", metric.name, metric.kind.as_str());
+
+            for (values, series) in &family.series {
+                let labels = format_labels(metric.labels, values);
+                match series {
+                    Series::Value(value) => {
+                        let _ = writeln!(out, "{}{} {}", metric.name, labels, value);
+                    }
+                    Series::Histogram {
+                        buckets,
+                        sum,
+                        count,
+                    } => {
+                        let mut cumulative = 0;
+                        for (bound, observations) in BUCKETS.iter().zip(buckets) {
+                            cumulative += observations;
+                            let labels = format_bucket_labels(metric.labels, values, bound);
+                            let _ =
+                                writeln!(out, "{}_bucket{} {}", metric.name, labels, cumulative);
+                        }
+                        let labels = format_bucket_labels(metric.labels, values, "+Inf");
+                        let _ = writeln!(out, "{}_bucket{} {}", metric.name, labels, count);
+                        let labels = format_labels(metric.labels, values);
+                        let _ = writeln!(out, "{}_sum{} {}", metric.name, labels, sum);
+                        let _ = writeln!(out, "{}_count{} {}", metric.name, labels, count);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn update<F>(&self, metric: &'static Metric, labels: &[&str], update: F)
+    where
+        F: FnOnce(&mut Series),
+    {
+        debug_assert_eq!(
+            metric.labels.len(),
+            labels.len(),
+            "Wrong number of labels for {}",
+            metric.name
+        );
+
+        let mut families = self.lock();
+        let family = families.entry(metric.name).or_insert_with(|| Family {
+            metric,
+            series: BTreeMap::new(),
+        });
+        let series = family
+            .series
+            .entry(labels.iter().map(|label| label.to_string()).collect())
+            .or_insert_with(|| match metric.kind {
+                MetricKind::Histogram => Series::Histogram {
+                    buckets: vec![0; BUCKETS.len()],
+                    sum: 0.0,
+                    count: 0,
+                },
+                _ => Series::Value(0.0),
+            });
+        update(series);
+    }
+
+    // The metrics are only ever updated one value at a time, so they're still consistent if a
+    // thread panicked while holding the lock
+    fn lock(&self) -> MutexGuard<BTreeMap<&'static str, Family>> {
+        self.families
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn format_labels(names: &[&str], values: &[String]) -> String {
+    if names.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn format_bucket_labels<T: ToString>(names: &[&str], values: &[String], bound: T) -> String {
+    let mut names = names.to_vec();
+    names.push("le");
+    let mut values = values.to_vec();
+    values.push(bound.to_string());
+    format_labels(&names, &values)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve the metrics at `/metrics` on the given address, from a background thread. Returns the
+/// address the server is listening on, which is useful when binding to port 0.
+pub fn serve(metrics: Arc<Metrics>, address: &str) -> io::Result<SocketAddr> {
+    http::serve(address, "metrics", move |path| match path {
+        "/metrics" => Some(("text/plain; version=0.0.4", metrics.render())),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write as IoWrite};
+    use std::net::TcpStream;
+
+    /// Counters and gauges are rendered with their help, type, and labels, and only once they've
+    /// been updated
+    #[test]
+    fn render_values() {
+        let metrics = Metrics::new();
+        assert_eq!(metrics.render(), "");
+        assert_eq!(metrics.get(&VIEW, &[]), None);
+
+        metrics.set(&VIEW, &[], 3.0);
+        metrics.inc(&MESSAGES_RECEIVED, &["02ab", "Prepare"]);
+        metrics.inc(&MESSAGES_RECEIVED, &["02ab", "Prepare"]);
+        metrics.inc(&MESSAGES_RECEIVED, &["03cd", "Commit"]);
+
+        assert_eq!(metrics.get(&VIEW, &[]), Some(3.0));
+        assert_eq!(
+            metrics.get(&MESSAGES_RECEIVED, &["02ab", "Prepare"]),
+            Some(2.0)
+        );
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("# HELP pbft_view The view the node is in\n"));
+        assert!(rendered.contains("# TYPE pbft_view gauge\npbft_view 3\n"));
+        assert!(rendered.contains("# TYPE pbft_messages_received_total counter\n"));
+        assert!(
+            rendered.contains("pbft_messages_received_total{peer=\"02ab\",type=\"Prepare\"} 2\n")
+        );
+        assert!(
+            rendered.contains("pbft_messages_received_total{peer=\"03cd\",type=\"Commit\"} 1\n")
+        );
+    }
+
+    /// Histogram buckets are cumulative, and end with a `+Inf` bucket that counts everything
+    #[test]
+    fn render_histogram() {
+        let metrics = Metrics::new();
+        metrics.observe(&PHASE_DURATION, &["Preparing"], 0.003_906_25);
+        metrics.observe(&PHASE_DURATION, &["Preparing"], 0.125);
+        metrics.observe(&PHASE_DURATION, &["Preparing"], 100.0);
+        assert_eq!(metrics.get(&PHASE_DURATION, &["Preparing"]), Some(3.0));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("# TYPE pbft_phase_duration_seconds histogram\n"));
+        for (bound, count) in &[
+            ("0.005", 1),
+            ("0.1", 1),
+            ("0.25", 2),
+            ("60", 2),
+            ("+Inf", 3),
+        ] {
+            let line = format!(
+                "pbft_phase_duration_seconds_bucket{{phase=\"Preparing\",le=\"{}\"}} {}\n",
+                bound, count
+            );
+            assert!(rendered.contains(&line), "Missing {}", line);
+        }
+        assert!(rendered
+            .contains("pbft_phase_duration_seconds_sum{phase=\"Preparing\"} 100.12890625\n"));
+        assert!(rendered.contains("pbft_phase_duration_seconds_count{phase=\"Preparing\"} 3\n"));
+    }
+
+    /// Label values can't break out of their quotes
+    #[test]
+    fn escaped_labels() {
+        let metrics = Metrics::new();
+        metrics.inc(&MESSAGES_SENT, &["a\"b\\c\nd"]);
+        assert!(metrics
+            .render()
+            .contains("pbft_messages_sent_total{type=\"a\\\"b\\\\c\\nd\"} 1\n"));
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).expect("Failed to connect");
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    /// The server answers scrapes of `/metrics` with the current metrics, and anything else with
+    /// a 404
+    #[test]
+    fn http_server() {
+        let metrics = Arc::new(Metrics::new());
+        let addr = serve(metrics.clone(), "127.0.0.1:0").expect("Failed to serve");
+
+        metrics.set(&SEQ_NUM, &[], 7.0);
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.ends_with("pbft_seq_num 7\n"));
+
+        metrics.set(&SEQ_NUM, &[], 8.0);
+        assert!(get(addr, "/metrics").ends_with("pbft_seq_num 8\n"));
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
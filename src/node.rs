@@ -25,18 +25,31 @@ use protobuf::{Message, ProtobufError};
 
 use std::convert::From;
 use std::error::Error;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
 
-use sawtooth_sdk::consensus::engine::{Block, BlockId, Error as EngineError, PeerMessage};
+use sawtooth_sdk::consensus::engine::{Block, BlockId, Error as EngineError, PeerId, PeerMessage};
 use sawtooth_sdk::consensus::service::Service;
 
-use protos::pbft_message::{PbftBlock, PbftMessage, PbftMessageInfo, PbftViewChange};
+use protos::pbft_message::{
+    PbftBlock, PbftCatchUp, PbftMessage, PbftMessageInfo, PbftNetworkChange, PbftNewView,
+    PbftSignedMessage, PbftStateSnapshot, PbftStorageEntry, PbftViewChange,
+};
 
 use config::PbftConfig;
 use error::PbftError;
-use handlers;
+use handlers::{self, pbft_block_from_block};
+use logging;
 use message_log::{PbftLog, PbftStableCheckpoint};
 use message_type::{PbftHint, PbftMessageType};
-use state::{PbftMode, PbftPhase, PbftState, WorkingBlockOption};
+use metrics::{self, Metrics};
+use observer::{PbftObserver, PbftObservers};
+use signing::{self, PbftSigner};
+use state::{PbftMode, PbftPhase, PbftPipelinedBlock, PbftState, WorkingBlockOption};
+use storage::PbftStorage;
+use timeline::TimelineEvent;
+use timing::Clock;
 
 /// Contains all of the components for operating a PBFT node.
 pub struct PbftNode {
@@ -48,20 +61,80 @@ pub struct PbftNode {
 
     /// Messages this node has received
     pub msg_log: PbftLog,
+
+    /// Signs every message this node sends to its peers
+    signer: PbftSigner,
+
+    /// Durable record of this node's messages and state, for recovering from a crash
+    storage: Box<PbftStorage>,
+
+    /// The state snapshot that was most recently written to storage
+    persisted_state: PbftStateSnapshot,
+
+    /// The sequence number this node most recently asked its peers to help it catch up to
+    catch_up_target: Option<u64>,
+
+    /// The last block this node is committing to catch up with the network, until it's committed
+    catch_up_head: Option<BlockId>,
+
+    /// Metrics about this node's progress, which may be shared with a metrics server
+    pub metrics: Arc<Metrics>,
+
+    /// Told about consensus events as they happen; shared with the log
+    pub observers: PbftObservers,
+
+    /// Where the time spent in each phase is measured from
+    clock: Rc<Clock>,
+
+    /// The phase the node was in when its metrics were last updated, and when it entered it
+    metrics_phase: (PbftPhase, Instant),
 }
 
 impl PbftNode {
     /// Construct a new PBFT node.
-    /// After the node is created, if the node is primary, it initializes a new block on the chain.
-    pub fn new(id: u64, config: &PbftConfig, service: Box<Service>) -> Self {
+    /// If the node's storage has anything in it (the node is restarting), the node picks up where
+    /// it left off. After the node is created, if the node is primary and isn't in the middle of
+    /// a block (or waiting for more peers), it initializes a new block on the chain. The node's
+    /// timers keep time with `clock`.
+    /// # Panics
+    /// Panics if the storage can't be read, since the node could otherwise contradict messages
+    /// it sent before it restarted.
+    pub fn new(
+        peer_id: &PeerId,
+        config: &PbftConfig,
+        service: Box<Service>,
+        signer: PbftSigner,
+        storage: Box<PbftStorage>,
+        clock: Rc<Clock>,
+    ) -> Self {
         let mut n = PbftNode {
-            state: PbftState::new(id, config),
+            state: PbftState::new(peer_id, config, clock.clone()),
             service,
             msg_log: PbftLog::new(config),
+            signer,
+            storage,
+            persisted_state: PbftStateSnapshot::new(),
+            catch_up_target: None,
+            catch_up_head: None,
+            metrics: Arc::new(Metrics::new()),
+            metrics_phase: (PbftPhase::NotStarted, clock.now()),
+            clock,
+            observers: PbftObservers::new(),
         };
+        n.msg_log.set_observers(n.observers.clone());
+
+        n.restore()
+            .unwrap_or_else(|err| panic!("Couldn't restore from storage: {}", err));
+        // Conflicting votes in storage were found (and recorded) before the restart
+        n.msg_log.take_evidence();
+        n.persisted_state = n.make_snapshot();
+        n.metrics_phase = (n.state.phase.clone(), n.clock.now());
 
         // Primary initializes a block
-        if n.state.is_primary() {
+        if n.state.is_primary()
+            && n.state.phase == PbftPhase::NotStarted
+            && n.state.mode != PbftMode::Connecting
+        {
             debug!("{}: Initializing block", n.state);
             n.service
                 .initialize_block(None)
@@ -72,38 +145,95 @@ impl PbftNode {
 
     // ---------- Methods for handling Updates from the validator ----------
 
+    /// Record that a message arrived from a peer, before it's handled. This only keeps track of
+    /// when each peer was last heard from and what type of message it sent; the view and sequence
+    /// number the peer is at are recorded once the message is verified, when it's handled.
+    pub fn on_peer_activity(&mut self, sender_id: &PeerId, msg_type: &PbftMessageType) {
+        let was_silent = self
+            .state
+            .peer_activity
+            .get(sender_id)
+            .map_or(false, |activity| activity.silent);
+        if was_silent {
+            info!(
+                "{}: Heard from silent peer {:.6} again",
+                self.state,
+                hex::encode(Vec::<u8>::from(sender_id.clone()))
+            );
+        }
+        self.state.record_peer_message(sender_id, msg_type);
+    }
+
     /// Handle a peer message from another PbftNode
     /// This method handles all messages from other nodes. Such messages may include `PrePrepare`,
-    /// `Prepare`, `Commit`, `Checkpoint`, or `ViewChange`. If a node receives a type of message
-    /// before it is ready to do so, the message is pushed into a backlog queue.
+    /// `Prepare`, `Commit`, `Checkpoint`, `ViewChange`, or `NewView`. If a node receives a type of
+    /// message before it is ready to do so, the message is pushed into a backlog queue.
+    ///
+    /// Every message arrives wrapped in a `PbftSignedMessage`; messages whose signature doesn't
+    /// match their `signer_id`, that aren't the type they were sent as, or that are signed by a
+    /// node that isn't one of this node's peers are rejected before anything else is done with
+    /// them.
     pub fn on_peer_message(&mut self, msg: &PeerMessage) -> Result<(), PbftError> {
         let msg_type = msg.message_type.clone();
         let msg_type = PbftMessageType::from(msg_type.as_str());
 
+        // Make sure the message actually came from the node it claims to be from
+        let signed_msg = protobuf::parse_from_bytes::<PbftSignedMessage>(&msg.content)
+            .map_err(PbftError::SerializationError)?;
+        let info = signing::verify(&msg_type, &signed_msg)?;
+        let signer_id = PeerId::from(info.get_signer_id().to_vec());
+        if !self.state.get_peer_ids().contains(&signer_id) {
+            return Err(PbftError::UnknownSigner(hex::encode(info.get_signer_id())));
+        }
+        self.state.record_peer_progress(&info);
+        let content = signed_msg.get_message();
+
+        // Until the network is Byzantine fault tolerant (and includes this node), the only thing
+        // to do is agree on changes to its peers
+        if self.state.mode == PbftMode::Connecting && msg_type != PbftMessageType::NetworkChange {
+            debug!("{}: Connecting; ignoring {} message", self.state, msg_type);
+            return Ok(());
+        }
+
         // Handle a multicast protocol message
         let multicast_hint = if msg_type.is_multicast() {
-            let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
+            let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(content)
                 .map_err(PbftError::SerializationError)?;
 
             debug!(
-                "{}: <<<<<< {} [Node {:02}] (v {}, seq {}, b {})",
+                "{}: <<<<<< {} [Node {:02}] (v {}, seq {}, b {:.6})",
                 self.state,
                 msg_type,
                 self.state
                     .get_node_id_from_bytes(pbft_message.get_info().get_signer_id())?,
                 pbft_message.get_info().get_view(),
                 pbft_message.get_info().get_seq_num(),
-                &hex::encode(pbft_message.get_block().get_block_id())[..6],
+                hex::encode(pbft_message.get_block().get_block_id()),
             );
 
+            // A faulty node could otherwise fill up the log and backlog with messages for views
+            // that are never going to happen
+            self.msg_log
+                .check_view(pbft_message.get_info().get_view(), self.state.view)?;
+
+            let is_vote =
+                msg_type == PbftMessageType::Prepare || msg_type == PbftMessageType::Commit;
+            if is_vote && !self.is_own_message(pbft_message.get_info()) {
+                self.state.record_vote(pbft_message.get_info());
+            }
+
             handlers::multicast_hint(&self.state, &pbft_message)
         } else {
             PbftHint::PresentMessage
         };
 
+        if multicast_hint == PbftHint::PipelinedMessage {
+            return self.on_pipelined_message(msg, &signed_msg);
+        }
+
         match msg_type {
             PbftMessageType::PrePrepare => {
-                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
+                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(content)
                     .map_err(PbftError::SerializationError)?;
 
                 // If we've got a BlockNew ready and the sequence number is our current plus one,
@@ -119,10 +249,10 @@ impl PbftNode {
                         ignore_hint = true;
                     } else {
                         debug!(
-                            "{}: Not starting multicast; ({} != {} or {} != {} + 1)",
+                            "{}: Not starting multicast; ({:.6} != {:.6} or {} != {} + 1)",
                             self.state,
-                            &hex::encode(Vec::<u8>::from(block_id.clone()))[..6],
-                            &hex::encode(pbft_message.get_block().get_block_id())[..6],
+                            hex::encode(Vec::<u8>::from(block_id.clone())),
+                            hex::encode(pbft_message.get_block().get_block_id()),
                             pbft_message.get_info().get_seq_num(),
                             self.state.seq_num,
                         );
@@ -134,15 +264,17 @@ impl PbftNode {
                         &mut self.msg_log,
                         &multicast_hint,
                         &pbft_message,
+                        &signed_msg,
                         msg.content.clone(),
                     )?;
                 }
 
-                handlers::pre_prepare(&mut self.state, &mut self.msg_log, &pbft_message)?;
+                let res = handlers::pre_prepare(&mut self.state, &mut self.msg_log, &pbft_message);
+                self.view_change_on_batch_mismatch(res)?;
 
                 // NOTE: Putting log add here is necessary because on_peer_message gets
                 // called again inside of _broadcast_pbft_message
-                self.msg_log.add_message(pbft_message.clone());
+                self.log_signed_message(&msg_type, pbft_message.clone(), &signed_msg)?;
                 self.state.switch_phase(PbftPhase::Preparing);
 
                 info!(
@@ -159,19 +291,21 @@ impl PbftNode {
             }
 
             PbftMessageType::Prepare => {
-                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
+                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(content)
                     .map_err(PbftError::SerializationError)?;
 
                 handlers::action_from_hint(
                     &mut self.msg_log,
                     &multicast_hint,
                     &pbft_message,
+                    &signed_msg,
                     msg.content.clone(),
                 )?;
 
-                self.msg_log.add_message(pbft_message.clone());
+                self.log_signed_message(&msg_type, pbft_message.clone(), &signed_msg)?;
 
-                self.msg_log.prepared(&pbft_message, self.state.f)?;
+                let res = self.msg_log.prepared(&pbft_message, self.state.f);
+                self.view_change_on_batch_mismatch(res)?;
 
                 if self.state.phase != PbftPhase::Checking {
                     self.state.switch_phase(PbftPhase::Checking);
@@ -187,17 +321,18 @@ impl PbftNode {
             }
 
             PbftMessageType::Commit => {
-                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
+                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(content)
                     .map_err(PbftError::SerializationError)?;
 
                 handlers::action_from_hint(
                     &mut self.msg_log,
                     &multicast_hint,
                     &pbft_message,
+                    &signed_msg,
                     msg.content.clone(),
                 )?;
 
-                self.msg_log.add_message(pbft_message.clone());
+                self.log_signed_message(&msg_type, pbft_message.clone(), &signed_msg)?;
 
                 self.msg_log.committed(&pbft_message, self.state.f)?;
 
@@ -219,7 +354,7 @@ impl PbftNode {
             }
 
             PbftMessageType::Checkpoint => {
-                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(&msg.content)
+                let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(content)
                     .map_err(PbftError::SerializationError)?;
 
                 debug!(
@@ -229,6 +364,11 @@ impl PbftNode {
                         .get_node_id_from_bytes(pbft_message.get_info().get_signer_id())?
                 );
 
+                // The rest of the network got to a checkpoint that this node hasn't even started
+                if pbft_message.get_info().get_seq_num() > self.state.seq_num {
+                    self.request_catch_up(pbft_message.get_info().get_seq_num())?;
+                }
+
                 if self.msg_log.get_latest_checkpoint() >= pbft_message.get_info().get_seq_num() {
                     debug!(
                         "{}: Already at a stable checkpoint with this sequence number or past it!",
@@ -237,18 +377,24 @@ impl PbftNode {
                     return Ok(());
                 }
 
-                // Not ready to receive checkpoint yet; only acceptable in NotStarted
-                if self.state.phase != PbftPhase::NotStarted {
-                    self.msg_log.push_backlog(PeerMessage {
-                        message_type: msg.message_type.clone(),
-                        content: msg.content.clone(),
-                    });
+                // Not ready to receive checkpoint yet; only acceptable once the block at the
+                // checkpoint is on the chain
+                if self.state.phase != PbftPhase::NotStarted
+                    && pbft_message.get_info().get_seq_num() >= self.state.seq_num
+                {
+                    self.msg_log.push_backlog(
+                        PeerMessage {
+                            message_type: msg.message_type.clone(),
+                            content: msg.content.clone(),
+                        },
+                        pbft_message.get_info().get_seq_num(),
+                    )?;
                     debug!("{}: Not in NotStarted; not handling checkpoint yet", self.state);
                     return Ok(());
                 }
 
                 // Add message to the log
-                self.msg_log.add_message(pbft_message.clone());
+                self.log_signed_message(&msg_type, pbft_message.clone(), &signed_msg)?;
 
                 // If we're a secondary, forward the message to everyone else in the network (resign it)
                 if !self.state.is_primary() && self.state.mode != PbftMode::Checkpointing {
@@ -275,11 +421,12 @@ impl PbftNode {
                     );
 
                     self.state.mode = self.state.pre_checkpoint_mode;
+                    self.compact_storage()?;
                 }
             }
 
             PbftMessageType::ViewChange => {
-                let vc_message = protobuf::parse_from_bytes::<PbftViewChange>(&msg.content)
+                let vc_message = protobuf::parse_from_bytes::<PbftViewChange>(content)
                     .map_err(PbftError::SerializationError)?;
 
                 debug!(
@@ -291,40 +438,259 @@ impl PbftNode {
                     vc_message.get_info().get_seq_num(),
                 );
 
-                self.msg_log.add_view_change(vc_message.clone());
+                handlers::verify_view_change(&self.state, &vc_message)?;
+                self.msg_log.add_signed_view_change(
+                    vc_message.clone(),
+                    signed_msg.clone(),
+                    self.state.view,
+                )?;
+                if !self.is_own_message(vc_message.get_info()) {
+                    self.persist_peer_message(&msg_type, &signed_msg)?;
+                }
 
-                if self.state.mode != PbftMode::ViewChanging {
+                let vc_view = vc_message.get_info().get_view();
+                let joining = vc_view > self.state.view
+                    && (self.state.mode != PbftMode::ViewChanging
+                        || vc_view > self.state.pending_view);
+                if joining {
                     // Even if our own timer hasn't expired, still do a ViewChange if we've received
                     // f + 1 VC messages to prevent being late to the new view party
-                    if self
-                        .msg_log
-                        .check_msg_against_log(&&vc_message, true, self.state.f + 1)
-                        .is_ok()
-                        && vc_message.get_info().get_view() > self.state.view
-                    {
+                    if self.msg_log.get_view_changes(vc_view).len() as u64 > self.state.f {
                         warn!(
                             "{}: Starting ViewChange from a ViewChange message",
                             self.state
                         );
-                        self.start_view_change()?;
+                        self.send_view_change(vc_view)?;
+                    } else {
+                        return Ok(());
+                    }
+                }
+
+                if self.state.mode != PbftMode::ViewChanging || vc_view != self.state.pending_view {
+                    return Ok(());
+                }
+
+                // Once there are 2f + 1 ViewChanges, the new primary announces the new view
+                if let Some(new_view) =
+                    handlers::view_change(&self.state, &self.msg_log, &vc_message, &self.observers)?
+                {
+                    let msg_bytes = new_view
+                        .write_to_bytes()
+                        .map_err(PbftError::SerializationError)?;
+                    self._broadcast_message(&PbftMessageType::NewView, &msg_bytes)?;
+                    self.enter_new_view(&new_view)?;
+                }
+            }
+
+            PbftMessageType::NewView => {
+                let new_view = protobuf::parse_from_bytes::<PbftNewView>(content)
+                    .map_err(PbftError::SerializationError)?;
+
+                debug!(
+                    "{}: Received NewView message from Node {:02} (v {})",
+                    self.state,
+                    self.state
+                        .get_node_id_from_bytes(new_view.get_info().get_signer_id())?,
+                    new_view.get_info().get_view(),
+                );
+
+                self.enter_new_view(&new_view)?;
+            }
+
+            PbftMessageType::NetworkChange => {
+                let nc_message = protobuf::parse_from_bytes::<PbftNetworkChange>(content)
+                    .map_err(PbftError::SerializationError)?;
+
+                debug!(
+                    "{}: Received {} NetworkChange message from Node {:02} (v {}, seq {}, {} peers)",
+                    self.state,
+                    if nc_message.get_tentative() {
+                        "tentative"
                     } else {
+                        "final"
+                    },
+                    self.state
+                        .get_node_id_from_bytes(nc_message.get_info().get_signer_id())?,
+                    nc_message.get_info().get_view(),
+                    nc_message.get_info().get_seq_num(),
+                    nc_message.get_peers().len(),
+                );
+
+                handlers::verify_network_change(&self.state, &nc_message)?;
+                self.msg_log
+                    .add_network_change(nc_message.clone(), signed_msg.clone())?;
+                if !self.is_own_message(nc_message.get_info()) {
+                    self.persist_peer_message(&msg_type, &signed_msg)?;
+                }
+
+                if nc_message.get_tentative() {
+                    self.endorse_network_change()?;
+                } else {
+                    let peers = handlers::network_change(&self.state, &self.msg_log, &nc_message)?;
+                    if handlers::same_peers(&peers, self.state.get_peer_ids())
+                        || self.state.pending_network_change.is_some()
+                    {
                         return Ok(());
                     }
+                    info!(
+                        "{}: Network agreed to change to {} peers after sequence number {}",
+                        self.state,
+                        peers.len(),
+                        nc_message.get_info().get_seq_num()
+                    );
+                    self.state.pending_network_change = Some(nc_message);
+                    self.apply_network_change()?;
+                }
+            }
+
+            PbftMessageType::CatchUpRequest => {
+                let request = protobuf::parse_from_bytes::<PbftMessage>(content)
+                    .map_err(PbftError::SerializationError)?;
+                if self.is_own_message(request.get_info()) {
+                    return Ok(());
+                }
+
+                debug!(
+                    "{}: Received CatchUpRequest from Node {:02} (seq {}, block {})",
+                    self.state,
+                    self.state
+                        .get_node_id_from_bytes(request.get_info().get_signer_id())?,
+                    request.get_info().get_seq_num(),
+                    request.get_block().get_block_num(),
+                );
+
+                if let Some(catch_up) =
+                    handlers::make_catch_up(&self.state, &self.msg_log, &request)
+                {
+                    let msg_bytes = catch_up
+                        .write_to_bytes()
+                        .map_err(PbftError::SerializationError)?;
+                    let requester = PeerId::from(request.get_info().get_signer_id().to_vec());
+                    self._send_message_to(&requester, &PbftMessageType::CatchUp, &msg_bytes)?;
                 }
+            }
+
+            PbftMessageType::CatchUp => {
+                let catch_up = protobuf::parse_from_bytes::<PbftCatchUp>(content)
+                    .map_err(PbftError::SerializationError)?;
+
+                debug!(
+                    "{}: Received CatchUp from Node {:02} ({} seals)",
+                    self.state,
+                    self.state
+                        .get_node_id_from_bytes(catch_up.get_info().get_signer_id())?,
+                    catch_up.get_seals().len(),
+                );
+
+                self.catch_up(&catch_up)?;
+            }
+
+            _ => warn!("Message type not implemented"),
+        }
+        Ok(())
+    }
+
+    // Handle a PrePrepare, Prepare, or Commit message for a block in the pipeline. Consensus on the
+    // block goes through the same phases as it would for the working block, except that once the
+    // network has committed the block, it waits in `Finished` until the blocks before it are on the
+    // chain.
+    fn on_pipelined_message(
+        &mut self,
+        msg: &PeerMessage,
+        signed_msg: &PbftSignedMessage,
+    ) -> Result<(), PbftError> {
+        let msg_type = PbftMessageType::from(msg.message_type.as_str());
+        let pbft_message = protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message())
+            .map_err(PbftError::SerializationError)?;
+        let seq_num = pbft_message.get_info().get_seq_num();
 
-                handlers::view_change(
+        match msg_type {
+            PbftMessageType::PrePrepare => {
+                let res = handlers::pipelined_pre_prepare(
                     &mut self.state,
                     &mut self.msg_log,
-                    &mut *self.service,
-                    &vc_message,
+                    &pbft_message,
+                );
+                if let Err(PbftError::NotReadyForMessage) = res {
+                    // The block hasn't arrived from the validator yet
+                    self.msg_log.push_backlog(
+                        PeerMessage {
+                            message_type: msg.message_type.clone(),
+                            content: msg.content.clone(),
+                        },
+                        seq_num,
+                    )?;
+                }
+                self.view_change_on_batch_mismatch(res)?;
+
+                self.log_signed_message(&msg_type, pbft_message.clone(), signed_msg)?;
+                info!(
+                    "{}: PrePrepare, sequence number {} (pipelined)",
+                    self.state, seq_num
+                );
+
+                self._broadcast_pbft_message(
+                    seq_num,
+                    &PbftMessageType::Prepare,
+                    pbft_message.get_block().clone(),
                 )?;
             }
 
-            _ => warn!("Message type not implemented"),
+            PbftMessageType::Prepare => {
+                self.log_signed_message(&msg_type, pbft_message.clone(), signed_msg)?;
+                if self.pipelined_phase(seq_num) != Some(PbftPhase::Preparing) {
+                    return Ok(());
+                }
+
+                let res = self.msg_log.prepared(&pbft_message, self.state.f);
+                self.view_change_on_batch_mismatch(res)?;
+
+                self.set_pipelined_phase(seq_num, PbftPhase::Checking);
+                debug!("{}: Checking pipelined block {}", self.state, seq_num);
+                self.service
+                    .check_blocks(vec![BlockId::from(
+                        pbft_message.get_block().get_block_id().to_vec(),
+                    )])
+                    .map_err(|_| {
+                        PbftError::InternalError(String::from("Failed to check blocks"))
+                    })?;
+            }
+
+            PbftMessageType::Commit => {
+                self.log_signed_message(&msg_type, pbft_message.clone(), signed_msg)?;
+                if self.pipelined_phase(seq_num) != Some(PbftPhase::Committing) {
+                    return Ok(());
+                }
+
+                self.msg_log.committed(&pbft_message, self.state.f)?;
+
+                self.set_pipelined_phase(seq_num, PbftPhase::Finished);
+                info!(
+                    "{}: Network committed pipelined block {}; waiting for the blocks before it",
+                    self.state, seq_num
+                );
+            }
+
+            _ => warn!("{} messages aren't pipelined", msg_type),
         }
         Ok(())
     }
 
+    fn pipelined_phase(&self, seq_num: u64) -> Option<PbftPhase> {
+        self.state
+            .pipeline
+            .get(&seq_num)
+            .map(|pipelined| pipelined.phase.clone())
+    }
+
+    fn set_pipelined_phase(&mut self, seq_num: u64, phase: PbftPhase) {
+        let event = TimelineEvent::for_phase(&phase);
+        if let Some(pipelined) = self.state.pipeline.get_mut(&seq_num) {
+            pipelined.phase = phase;
+            self.state.record_pipelined_step(seq_num, event);
+        }
+    }
+
     /// Creates a new working block on the working block queue and kicks off the consensus algorithm
     /// by broadcasting a `PrePrepare` message to peers. Starts a view change timer, just in case
     /// the primary decides not to commit this block. If a `BlockCommit` update doesn't happen in a
@@ -332,6 +698,60 @@ impl PbftNode {
     pub fn on_block_new(&mut self, block: Block) -> Result<(), PbftError> {
         info!("{}: Got BlockNew: {:?}", self.state, block.block_id);
 
+        // The primary has to prove that the previous block was committed; if it can't, then it's
+        // faulty.
+        if let Err(err) = self.verify_consensus_seal(&block) {
+            error!(
+                "{}: Block {:?} has an invalid consensus seal; starting view change",
+                self.state, block.block_id
+            );
+            self.service
+                .fail_block(block.block_id.clone())
+                .unwrap_or_else(|e| error!("Couldn't fail block: {}", e));
+            self.start_view_change()?;
+            return Err(err);
+        }
+
+        if self.state.mode == PbftMode::Connecting {
+            debug!(
+                "{}: Connecting; pushing block {} to backlog",
+                self.state,
+                &hex::encode(Vec::<u8>::from(block.block_id.clone()))[..6]
+            );
+            self.msg_log.push_block_backlog(block);
+            return Ok(());
+        }
+
+        let head = self
+            .service
+            .get_chain_head()
+            .map_err(|e| PbftError::InternalError(e.description().to_string()))?;
+
+        // A block that isn't past the chain head lost out to a different block at its height; for
+        // instance, one that a replaced primary published before the view change
+        if block.block_num <= head.block_num {
+            debug!(
+                "{}: Block {} is not past the chain head; ignoring it",
+                self.state,
+                &hex::encode(Vec::<u8>::from(block.block_id.clone()))[..6]
+            );
+            self.service
+                .ignore_block(block.block_id)
+                .unwrap_or_else(|e| error!("Couldn't ignore block: {}", e));
+            return Ok(());
+        }
+
+        // The primary can start on a block that builds on the last one it's working on while the
+        // blocks before it are still going through consensus, if the pipeline has room for it.
+        // Secondaries wait for the primary's PrePrepare to tell them the block's sequence number.
+        if self.state.is_primary() && self.state.phase != PbftPhase::NotStarted {
+            if let Some(seq_num) = self.state.next_pipelined_seq_num() {
+                if block.block_num == head.block_num + self.state.num_in_flight() + 1 {
+                    return self.start_pipelined_block(block, seq_num);
+                }
+            }
+        }
+
         let pbft_block = pbft_block_from_block(block.clone());
 
         let mut msg = PbftMessage::new();
@@ -354,11 +774,6 @@ impl PbftNode {
 
         msg.set_block(pbft_block.clone());
 
-        let head = self
-            .service
-            .get_chain_head()
-            .map_err(|e| PbftError::InternalError(e.description().to_string()))?;
-
         if block.block_num > head.block_num + 1
             || self.state.switch_phase(PbftPhase::PrePreparing).is_none()
         {
@@ -371,17 +786,66 @@ impl PbftNode {
             return Ok(());
         }
 
-        self.msg_log.add_message(msg);
-        self.state.working_block = WorkingBlockOption::TentativeWorkingBlock(block.block_id);
+        self.msg_log.add_message(msg)?;
+        self.state.working_block =
+            WorkingBlockOption::TentativeWorkingBlock(block.block_id.clone());
         self.state.timeout.start();
 
         if self.state.is_primary() {
             let s = self.state.seq_num;
             self._broadcast_pbft_message(s, &PbftMessageType::PrePrepare, pbft_block)?;
+            self.build_on(block.block_id);
         }
         Ok(())
     }
 
+    // As the primary, start consensus on a block in the pipeline, ahead of the blocks before it
+    fn start_pipelined_block(&mut self, block: Block, seq_num: u64) -> Result<(), PbftError> {
+        let pbft_block = pbft_block_from_block(block.clone());
+
+        let mut msg = PbftMessage::new();
+        msg.set_info(handlers::make_msg_info(
+            &PbftMessageType::BlockNew,
+            self.state.view,
+            seq_num,
+            self.state.get_own_peer_id(),
+        ));
+        msg.set_block(pbft_block.clone());
+        self.msg_log.add_message(msg)?;
+
+        self.state.pipeline.insert(
+            seq_num,
+            PbftPipelinedBlock {
+                phase: PbftPhase::PrePreparing,
+                block: pbft_block.clone(),
+            },
+        );
+        self.state
+            .record_pipelined_step(seq_num, TimelineEvent::BlockNew);
+        info!(
+            "{}: Pipelining block {} with sequence number {}",
+            self.state, block.block_num, seq_num
+        );
+
+        self._broadcast_pbft_message(seq_num, &PbftMessageType::PrePrepare, pbft_block)?;
+        self.build_on(block.block_id);
+        Ok(())
+    }
+
+    // If the pipeline still has room, the primary starts building the next block on top of the one
+    // it just proposed, instead of waiting for it to be committed
+    fn build_on(&mut self, block_id: BlockId) {
+        if self.state.is_primary() && !self.state.pipeline_full() {
+            info!(
+                "{}: Initializing block with previous ID {:?}",
+                self.state, block_id
+            );
+            self.service
+                .initialize_block(Some(block_id))
+                .unwrap_or_else(|err| error!("Couldn't initialize block: {}", err));
+        }
+    }
+
     /// Handle a `BlockCommit` update from the Validator
     /// Since the block was successfully committed, the primary is not faulty and the view change
     /// timer can be stopped. If this node is a primary, then initialize a new block. Both node
@@ -390,31 +854,94 @@ impl PbftNode {
     /// checkpoint), then start a checkpoint.
     pub fn on_block_commit(&mut self, block_id: BlockId) -> Result<(), PbftError> {
         debug!("{}: <<<<<< BlockCommit: {:?}", self.state, block_id);
+        self.metrics.inc(&metrics::BLOCKS_COMMITTED, &[]);
+        self.observers.block_committed(&self.state, &block_id);
 
         if self.state.phase == PbftPhase::Finished {
-            if self.state.is_primary() {
+            // The primary is already building on the last block in the pipeline, unless the
+            // pipeline was full
+            if self.state.is_primary() && self.state.pipeline_full() {
+                let previous_id = self
+                    .state
+                    .pipeline
+                    .values()
+                    .next_back()
+                    .map_or(block_id.clone(), |pipelined| {
+                        BlockId::from(pipelined.block.get_block_id().to_vec())
+                    });
                 info!(
                     "{}: Initializing block with previous ID {:?}",
-                    self.state, block_id
+                    self.state, previous_id
                 );
                 self.service
-                    .initialize_block(Some(block_id))
+                    .initialize_block(Some(previous_id))
                     .unwrap_or_else(|err| error!("Couldn't initialize block: {}", err));
             }
 
             self.state.switch_phase(PbftPhase::NotStarted);
 
+            // The network may have agreed to change its peers after this block
+            self.apply_network_change()?;
+
             // Start a checkpoint in NotStarted, if we're at one
             if self.msg_log.at_checkpoint() {
                 self.start_checkpoint()?;
             }
+
+            self.advance_pipeline()?;
+        } else if self.catch_up_head.as_ref() == Some(&block_id) {
+            // The last of the blocks from catching up is on the chain
+            self.catch_up_head = None;
+            if self.state.is_primary() {
+                info!(
+                    "{}: Caught up; initializing block with previous ID {:?}",
+                    self.state, block_id
+                );
+                self.service
+                    .initialize_block(Some(block_id))
+                    .unwrap_or_else(|err| error!("Couldn't initialize block: {}", err));
+            }
         } else {
             debug!("{}: Not doing anything with BlockCommit", self.state);
         }
 
-        // The primary processessed this block in a timely manner, so stop the timeout.
-        self.state.timeout.stop();
+        // The primary processessed this block in a timely manner, so stop the timeout (or give
+        // it a fresh one for the next block in the pipeline).
+        if self.state.num_in_flight() > 0 {
+            self.state.timeout.start();
+        } else {
+            self.state.timeout.stop();
+        }
+
+        Ok(())
+    }
+
+    // Once the working block is on the chain, the next block in the pipeline becomes the working
+    // block. If the network already committed it, it goes to the validator right away.
+    fn advance_pipeline(&mut self) -> Result<(), PbftError> {
+        if !self.state.promote_pipelined_block() {
+            return Ok(());
+        }
+        info!(
+            "{}: Continuing with pipelined block {}",
+            self.state, self.state.seq_num
+        );
 
+        if self.state.phase == PbftPhase::Finished {
+            if let WorkingBlockOption::WorkingBlock(ref block) = self.state.working_block {
+                info!(
+                    "{}: Committing block {:?}",
+                    self.state,
+                    BlockId::from(block.get_block_id().to_vec())
+                );
+                self.service
+                    .commit_block(BlockId::from(block.get_block_id().to_vec()))
+                    .map_err(|_| {
+                        PbftError::InternalError(String::from("Failed to commit block"))
+                    })?;
+            }
+            self.state.working_block = WorkingBlockOption::NoWorkingBlock;
+        }
         Ok(())
     }
 
@@ -424,7 +951,23 @@ impl PbftNode {
     /// Once a `BlockValid` is received, transition to committing blocks.
     pub fn on_block_valid(&mut self, block_id: BlockId) -> Result<(), PbftError> {
         debug!("{}: <<<<<< BlockValid: {:?}", self.state, block_id);
-        self.state.switch_phase(PbftPhase::Committing);
+
+        // The block may be one in the pipeline instead of the working block
+        let pipelined_seq_num = self
+            .state
+            .pipeline
+            .iter()
+            .find(|(_, pipelined)| {
+                pipelined.phase == PbftPhase::Checking
+                    && pipelined.block.get_block_id() == &Vec::<u8>::from(block_id.clone())[..]
+            })
+            .map(|(seq_num, _)| *seq_num);
+        match pipelined_seq_num {
+            Some(seq_num) => self.set_pipelined_phase(seq_num, PbftPhase::Committing),
+            None => {
+                self.state.switch_phase(PbftPhase::Committing);
+            }
+        }
 
         debug!("{}: Getting blocks", self.state);
         let valid_blocks: Vec<Block> = self
@@ -439,11 +982,12 @@ impl PbftNode {
             return Err(PbftError::WrongNumBlocks);
         }
 
-        let s = self.state.seq_num; // By now, secondaries have the proper seq number
+        // By now, secondaries have the proper seq number
+        let s = pipelined_seq_num.unwrap_or(self.state.seq_num);
         self._broadcast_pbft_message(
             s,
             &PbftMessageType::Commit,
-            handlers::pbft_block_from_block(valid_blocks[0].clone()),
+            pbft_block_from_block(valid_blocks[0].clone()),
         )?;
         Ok(())
     }
@@ -451,12 +995,17 @@ impl PbftNode {
     // ---------- Methods for periodically checking on and updating the state, called by the engine ----------
 
     /// The primary tries to finalize a block every so often
+    /// The block is finalized with a consensus seal proving that the current chain head was
+    /// committed; if this node can't prove that yet, it waits.
     /// # Panics
     /// Panics if `finalize_block` fails. This is necessary because it means the validator wasn't
     /// able to publish the new block.
     pub fn try_publish(&mut self) -> Result<(), PbftError> {
         // Try to finalize a block
-        if self.state.is_primary() && self.state.phase == PbftPhase::NotStarted {
+        if self.state.is_primary()
+            && !self.state.pipeline_full()
+            && self.state.mode != PbftMode::Connecting
+        {
             debug!("{}: Summarizing block", self.state);
             if let Err(e) = self.service.summarize_block() {
                 info!(
@@ -464,9 +1013,9 @@ impl PbftNode {
                     self.state,
                     e.description().to_string()
                 );
-            } else {
+            } else if let Some(seal) = self.make_seal()? {
                 debug!("{}: Trying to finalize block", self.state);
-                match self.service.finalize_block(vec![]) {
+                match self.service.finalize_block(seal) {
                     Ok(block_id) => {
                         info!("{}: Publishing block {:?}", self.state, block_id);
                     }
@@ -480,11 +1029,103 @@ impl PbftNode {
         Ok(())
     }
 
+    // Serialize the consensus seal for the block `pipeline_depth` blocks before the one being
+    // published; without pipelining, that's the current chain head. The blocks in the pipeline are
+    // the ones after the chain head, so the sealed block is always already on the chain. The
+    // first `pipeline_depth` blocks after genesis aren't sealed. Returns `None` if this node
+    // doesn't have proof that the block was committed, so it can't publish yet.
+    fn make_seal(&mut self) -> Result<Option<Vec<u8>>, PbftError> {
+        let mut sealed = self
+            .service
+            .get_chain_head()
+            .map_err(|e| PbftError::InternalError(e.description().to_string()))?;
+        let block_num = sealed.block_num + self.state.num_in_flight() + 1;
+        if block_num <= self.state.pipeline_depth {
+            return Ok(Some(vec![]));
+        }
+        while sealed.block_num > block_num - self.state.pipeline_depth {
+            sealed = handlers::get_block_by_id(&mut *self.service, &sealed.previous_id)
+                .ok_or(PbftError::WrongNumBlocks)?;
+        }
+
+        match handlers::make_consensus_seal(&self.state, &self.msg_log, &sealed) {
+            Some(seal) => seal
+                .write_to_bytes()
+                .map(Some)
+                .map_err(PbftError::SerializationError),
+            None => {
+                // Peers would reject an unsealed block, so wait for the Commits to arrive
+                warn!(
+                    "{}: No proof that block {:?} was committed; not publishing yet",
+                    self.state, sealed.block_id
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    // Check a new block's consensus seal against the block it has to be for: the block's ancestor
+    // `pipeline_depth` blocks back on its own chain
+    fn verify_consensus_seal(&mut self, block: &Block) -> Result<(), PbftError> {
+        let mut sealed_id = block.previous_id.clone();
+        // Don't look past genesis; blocks that close to it aren't sealed anyway
+        for _ in 1..self.state.pipeline_depth.min(block.block_num) {
+            sealed_id = handlers::get_block_by_id(&mut *self.service, &sealed_id)
+                .ok_or(PbftError::WrongNumBlocks)?
+                .previous_id;
+        }
+        handlers::verify_consensus_seal(&self.state, &self.msg_log, block, &sealed_id)
+    }
+
     /// Check to see if the view change timeout has expired
     pub fn check_timeout_expired(&mut self) -> bool {
         self.state.timeout.check_expired()
     }
 
+    /// Check for peers that haven't sent anything in a while, and report the ones that went quiet
+    /// since the last check. If this node is waiting on the primary and the primary has been silent
+    /// for the whole silence threshold since the wait started, start the view change without
+    /// waiting for the view change timeout.
+    pub fn check_peer_activity(&mut self) -> Result<(), PbftError> {
+        for peer_id in self.state.update_silent_peers() {
+            let role = if peer_id == self.state.get_primary_peer_id() {
+                "Primary"
+            } else {
+                "Peer"
+            };
+            warn!(
+                "{}: {} {:.6} has sent nothing for {:?}",
+                self.state,
+                role,
+                hex::encode(Vec::<u8>::from(peer_id.clone())),
+                self.state.peer_silence(&peer_id).unwrap_or_default(),
+            );
+        }
+
+        if self.state.mode == PbftMode::Normal && self.primary_silent_while_waiting() {
+            warn!(
+                "{}: Primary has been silent for {:?} while this node waits on it",
+                self.state, self.state.silence_threshold
+            );
+            return self.start_view_change();
+        }
+        Ok(())
+    }
+
+    // A network with nothing to commit is quiet, so the primary's silence only counts once this
+    // node's view change timeout is running
+    fn primary_silent_while_waiting(&self) -> bool {
+        let waited = match self.state.timeout.remaining() {
+            Some(remaining) => self.state.timeout.duration() - remaining,
+            None => return false,
+        };
+        let silence = self
+            .state
+            .peer_silence(&self.state.get_primary_peer_id())
+            .unwrap_or_default();
+        waited.min(silence) >= self.state.silence_threshold
+    }
+
     /// Start the checkpoint process
     /// Primaries start the checkpoint to ensure sequence number correctness
     pub fn start_checkpoint(&mut self) -> Result<(), PbftError> {
@@ -507,9 +1148,13 @@ impl PbftNode {
         let mut peer_res = Ok(());
         if let Some(msg) = self.msg_log.pop_backlog() {
             debug!("{}: Popping from backlog {}", self.state, msg.message_type);
+            logging::set_message(&msg.message_type, None);
             peer_res = self.on_peer_message(&msg);
+            logging::clear_message();
         }
-        if self.state.mode == PbftMode::Normal && self.state.phase == PbftPhase::NotStarted {
+        let ready_for_block = self.state.phase == PbftPhase::NotStarted
+            || (self.state.is_primary() && self.state.next_pipelined_seq_num().is_some());
+        if self.state.mode == PbftMode::Normal && ready_for_block {
             if let Some(msg) = self.msg_log.pop_block_backlog() {
                 debug!("{}: Popping BlockNew from backlog", self.state);
                 self.on_block_new(msg)?;
@@ -520,17 +1165,68 @@ impl PbftNode {
 
     /// Initiate a view change (this node suspects that the primary is faulty)
     /// Nodes drop everything when they're doing a view change - will not process any peer messages
-    /// other than `ViewChanges` until the view change is complete.
+    /// other than `ViewChanges` and `NewViews` until the view change is complete. If the view
+    /// change timer runs out before a valid `NewView` arrives, the new primary is considered faulty
+    /// as well, and the node moves on to the view after it.
     pub fn start_view_change(&mut self) -> Result<(), PbftError> {
-        if self.state.mode == PbftMode::ViewChanging {
+        // There's no primary to replace until the network is Byzantine fault tolerant
+        if self.state.mode == PbftMode::Connecting {
             return Ok(());
         }
-        warn!("{}: Starting view change", self.state);
-        self.state.mode = PbftMode::ViewChanging;
 
-        let PbftStableCheckpoint {
-            seq_num: stable_seq_num,
-            checkpoint_messages,
+        let view = if self.state.mode == PbftMode::ViewChanging {
+            if !self.state.timeout.check_expired() {
+                return Ok(());
+            }
+            self.state.pending_view + 1
+        } else {
+            self.state.view + 1
+        };
+        self.send_view_change(view)
+    }
+
+    // A block whose batches don't match what the validator has means that the primary is tampering
+    // with the block, so replace the primary. Only the primary's PrePrepare can get here: a
+    // PrePrepare from any other node is refused before its batches are checked, and Prepares with
+    // different batches just don't count towards the block being prepared.
+    fn view_change_on_batch_mismatch(
+        &mut self,
+        res: Result<(), PbftError>,
+    ) -> Result<(), PbftError> {
+        if let Err(ref err @ PbftError::BatchDigestMismatch(_, _)) = res {
+            error!("{}: {}", self.state, err);
+            self.start_view_change()?;
+        }
+        res
+    }
+
+    // Enter ViewChanging mode, and broadcast a ViewChange message for the given view, along with
+    // proof of the stable checkpoint and of every block prepared since then
+    fn send_view_change(&mut self, view: u64) -> Result<(), PbftError> {
+        warn!("{}: Starting view change to view {}", self.state, view);
+
+        // What the primary has been up to is evidence of whether it needs replacing
+        let primary = self.state.get_primary_peer_id();
+        if let Some(activity) = self.state.peer_activity.get(&primary) {
+            warn!(
+                "{}: Primary last sent a message {:?} ago (view {}, seq {}){}",
+                self.state,
+                self.clock.now() - activity.last_message,
+                activity.last_view,
+                activity.last_seq_num,
+                if activity.silent { "; it's silent" } else { "" },
+            );
+        }
+        self.metrics.inc(&metrics::VIEW_CHANGES, &[]);
+        self.state.mode = PbftMode::ViewChanging;
+        self.state.pending_view = view;
+
+        // Give the new primary some time to send a NewView
+        self.state.timeout.start();
+
+        let PbftStableCheckpoint {
+            seq_num: stable_seq_num,
+            checkpoint_messages,
         } = if let Some(ref cp) = self.msg_log.latest_stable_checkpoint {
             debug!("{}: No stable checkpoint", self.state);
             cp.clone()
@@ -543,7 +1239,7 @@ impl PbftNode {
 
         let info = handlers::make_msg_info(
             &PbftMessageType::ViewChange,
-            self.state.view + 1,
+            view,
             stable_seq_num,
             self.state.get_own_peer_id(),
         );
@@ -551,6 +1247,10 @@ impl PbftNode {
         let mut vc_msg = PbftViewChange::new();
         vc_msg.set_info(info);
         vc_msg.set_checkpoint_messages(RepeatedField::from_vec(checkpoint_messages.to_vec()));
+        vc_msg.set_prepared_certificates(RepeatedField::from_vec(
+            self.msg_log
+                .get_prepared_certificates(stable_seq_num, 2 * self.state.f + 1),
+        ));
 
         let msg_bytes = vc_msg
             .write_to_bytes()
@@ -559,6 +1259,777 @@ impl PbftNode {
         self._broadcast_message(&PbftMessageType::ViewChange, &msg_bytes)
     }
 
+    // Move to the view announced by a NewView message, if this node isn't already in it, and pick
+    // the consensus process back up for any block that was prepared in an earlier view
+    fn enter_new_view(&mut self, new_view: &PbftNewView) -> Result<(), PbftError> {
+        if new_view.get_info().get_view() <= self.state.view {
+            debug!(
+                "{}: Already in view {}; ignoring NewView",
+                self.state,
+                new_view.get_info().get_view()
+            );
+            return Ok(());
+        }
+
+        let reissued = handlers::new_view(&mut self.state, &mut *self.service, new_view)?;
+        for (i, pre_prepare) in reissued.into_iter().enumerate() {
+            let info = pre_prepare.get_info().clone();
+
+            // The first block becomes the working block, and the rest go in the pipeline
+            if i > 0 && self.state.next_pipelined_seq_num() != Some(info.get_seq_num()) {
+                warn!(
+                    "{}: No room in the pipeline to prepare block {:.6} again",
+                    self.state,
+                    hex::encode(pre_prepare.get_block().get_block_id())
+                );
+                break;
+            }
+            info!(
+                "{}: Preparing block {:.6} again in view {}, sequence number {}",
+                self.state,
+                hex::encode(pre_prepare.get_block().get_block_id()),
+                info.get_view(),
+                info.get_seq_num()
+            );
+
+            // The block already arrived in an earlier view; record it for this view
+            let mut block_new = pre_prepare.clone();
+            block_new.set_info(handlers::make_msg_info(
+                &PbftMessageType::BlockNew,
+                info.get_view(),
+                info.get_seq_num(),
+                self.state.get_own_peer_id(),
+            ));
+            self.msg_log.add_message(block_new)?;
+            self.msg_log.add_message(pre_prepare.clone())?;
+
+            if i == 0 {
+                self.state.seq_num = info.get_seq_num();
+                self.state.working_block =
+                    WorkingBlockOption::WorkingBlock(pre_prepare.get_block().clone());
+                self.state.switch_phase(PbftPhase::PrePreparing);
+                self.state.switch_phase(PbftPhase::Preparing);
+                self.state.timeout.start();
+            } else {
+                self.state.pipeline.insert(
+                    info.get_seq_num(),
+                    PbftPipelinedBlock {
+                        phase: PbftPhase::Preparing,
+                        block: pre_prepare.get_block().clone(),
+                    },
+                );
+                self.state
+                    .record_pipelined_step(info.get_seq_num(), TimelineEvent::PrePrepare);
+            }
+
+            self._broadcast_pbft_message(
+                info.get_seq_num(),
+                &PbftMessageType::Prepare,
+                pre_prepare.get_block().clone(),
+            )?;
+        }
+
+        // The old primary may not have finished proposing a change to the network's peers
+        self.propose_network_change()
+    }
+
+    // ---------- Methods for changing the peers in the network ----------
+
+    /// Handle a `PeerConnected` update
+    /// A peer that isn't part of the network yet becomes a candidate to join it. The primary
+    /// proposes adding it, and the other nodes agree once they're connected to it as well.
+    pub fn on_peer_connected(&mut self, peer_id: PeerId) -> Result<(), PbftError> {
+        if self.state.candidate_peers.contains(&peer_id) {
+            return Ok(());
+        }
+        info!(
+            "{}: Peer {} connected",
+            self.state,
+            &hex::encode(Vec::<u8>::from(peer_id.clone()))[..6]
+        );
+        self.state.candidate_peers.push(peer_id);
+
+        self.propose_network_change()?;
+        self.endorse_network_change()
+    }
+
+    /// Handle a `PeerDisconnected` update
+    /// The peer stops being a candidate to be in the network; if it's already part of it, the
+    /// primary proposes removing it.
+    pub fn on_peer_disconnected(&mut self, peer_id: PeerId) -> Result<(), PbftError> {
+        if peer_id == self.state.get_own_peer_id() || !self.state.candidate_peers.contains(&peer_id)
+        {
+            return Ok(());
+        }
+        info!(
+            "{}: Peer {} disconnected",
+            self.state,
+            &hex::encode(Vec::<u8>::from(peer_id.clone()))[..6]
+        );
+        self.state.candidate_peers.retain(|id| id != &peer_id);
+
+        self.propose_network_change()?;
+        self.endorse_network_change()
+    }
+
+    // As the primary, send a tentative NetworkChange if the peers this node is connected to aren't
+    // the same as the ones in the network. The change takes effect after the last block the
+    // primary is working on, including the ones in the pipeline.
+    fn propose_network_change(&mut self) -> Result<(), PbftError> {
+        if !self.state.is_primary()
+            || self.state.mode == PbftMode::ViewChanging
+            || handlers::same_peers(&self.state.candidate_peers, self.state.get_peer_ids())
+        {
+            return Ok(());
+        }
+
+        info!(
+            "{}: Proposing a change to {} peers",
+            self.state,
+            self.state.candidate_peers.len()
+        );
+        let peers = self.state.candidate_peers.clone();
+        let seq_num = self.state.last_seq_num();
+        self._broadcast_network_change(peers, seq_num, true)
+    }
+
+    // Agree to the current primary's latest proposal for the network's peers, if this node is
+    // connected to the same peers
+    fn endorse_network_change(&mut self) -> Result<(), PbftError> {
+        if !self.state.is_member() {
+            return Ok(());
+        }
+
+        let proposal = match self.msg_log.get_tentative_network_change(
+            self.state.view,
+            &Vec::<u8>::from(self.state.get_primary_peer_id()),
+        ) {
+            Some(nc) => nc.clone(),
+            None => return Ok(()),
+        };
+        let peers: Vec<PeerId> = proposal
+            .get_peers()
+            .iter()
+            .map(|id| PeerId::from(id.clone()))
+            .collect();
+
+        if handlers::same_peers(&peers, self.state.get_peer_ids()) {
+            return Ok(());
+        }
+        if !handlers::same_peers(&peers, &self.state.candidate_peers) {
+            debug!(
+                "{}: Not agreeing to NetworkChange; not connected to the same peers",
+                self.state
+            );
+            return Ok(());
+        }
+
+        self._broadcast_network_change(peers, proposal.get_info().get_seq_num(), false)
+    }
+
+    // Switch to the peers the network agreed on, once this node has finished the block that the
+    // change takes effect after. A node that is still connecting catches up to the view and
+    // sequence number of the rest of the network instead.
+    fn apply_network_change(&mut self) -> Result<(), PbftError> {
+        let ready = match self.state.pending_network_change {
+            Some(ref nc) => {
+                self.state.mode == PbftMode::Connecting
+                    || (self.state.phase == PbftPhase::NotStarted
+                        && self.state.seq_num >= nc.get_info().get_seq_num())
+            }
+            None => false,
+        };
+        if !ready {
+            return Ok(());
+        }
+        let nc = self
+            .state
+            .pending_network_change
+            .take()
+            .expect("Pending NetworkChange disappeared");
+
+        let was_primary = self.state.is_primary();
+        let was_connecting = self.state.mode == PbftMode::Connecting;
+        if was_connecting {
+            self.state.view = ::std::cmp::max(self.state.view, nc.get_info().get_view());
+            self.state.seq_num = ::std::cmp::max(self.state.seq_num, nc.get_info().get_seq_num());
+        }
+
+        self.state.set_peers(
+            nc.get_peers()
+                .iter()
+                .map(|id| PeerId::from(id.clone()))
+                .collect(),
+        );
+        info!(
+            "{}: Network changed to {} peers (f = {})",
+            self.state,
+            self.state.get_peer_ids().len(),
+            self.state.f
+        );
+
+        if was_connecting && self.state.mode == PbftMode::Normal {
+            let head = self
+                .service
+                .get_chain_head()
+                .map_err(|e| PbftError::InternalError(e.description().to_string()))?;
+            if head.block_num < nc.get_head().get_block_num() {
+                warn!(
+                    "{}: Chain head {} is behind the network's ({})",
+                    self.state,
+                    head.block_num,
+                    nc.get_head().get_block_num()
+                );
+                self.request_catch_up(nc.get_info().get_seq_num())?;
+            }
+        }
+
+        // The primary may have moved to a different node
+        if self.state.is_primary() && self.state.mode == PbftMode::Normal {
+            if !was_primary || was_connecting {
+                info!("{}: Initializing block", self.state);
+                self.service
+                    .initialize_block(None)
+                    .unwrap_or_else(|err| error!("Couldn't initialize block: {}", err));
+            }
+        } else if was_primary && !was_connecting {
+            self.service
+                .cancel_block()
+                .unwrap_or_else(|err| debug!("Couldn't cancel block: {}", err));
+        }
+
+        // The new primary might not be connected to the same peers
+        self.propose_network_change()
+    }
+
+    // ---------- Methods for catching up with the rest of the network ----------
+
+    // Ask the other nodes for proof of how far the network has gotten, unless this node already
+    // asked when the network was at `seq_num`
+    fn request_catch_up(&mut self, seq_num: u64) -> Result<(), PbftError> {
+        if self
+            .catch_up_target
+            .map_or(false, |target| target >= seq_num)
+        {
+            return Ok(());
+        }
+        self.catch_up_target = Some(seq_num);
+
+        let head = self
+            .service
+            .get_chain_head()
+            .map_err(|e| PbftError::InternalError(e.description().to_string()))?;
+        warn!(
+            "{}: Fell behind the network (seq num {}); asking to catch up from block {}",
+            self.state, seq_num, head.block_num
+        );
+
+        let msg_bytes = make_msg_bytes(
+            handlers::make_msg_info(
+                &PbftMessageType::CatchUpRequest,
+                self.state.view,
+                self.state.seq_num,
+                self.state.get_own_peer_id(),
+            ),
+            pbft_block_from_block(head),
+        )
+        .map_err(PbftError::SerializationError)?;
+
+        // Catch-up messages aren't kept in the log, so they aren't written to storage either
+        let signed_bytes = self
+            .signer
+            .sign(&msg_bytes)?
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)?;
+        self._send_signed_message(&PbftMessageType::CatchUpRequest, &signed_bytes)
+    }
+
+    // Commit the blocks that a `CatchUp` message proves the network has committed, without going
+    // through consensus for them again, and adopt the stable checkpoint, view, and sequence number
+    // from it. Blocks before the first seal in the message are proven by the seals in the blocks
+    // after them.
+    fn catch_up(&mut self, catch_up: &PbftCatchUp) -> Result<(), PbftError> {
+        let checkpoint_messages = handlers::verify_catch_up(&self.state, &self.msg_log, catch_up)?;
+
+        let head = self
+            .service
+            .get_chain_head()
+            .map_err(|e| PbftError::InternalError(e.description().to_string()))?;
+        let last_seal = match catch_up.get_seals().last() {
+            Some(seal) if seal.get_block().get_block_num() > head.block_num => seal,
+            _ => {
+                debug!("{}: Nothing to catch up on", self.state);
+                return Ok(());
+            }
+        };
+
+        // Work backwards from the last sealed block to this node's chain head
+        let sealed_ids: Vec<&[u8]> = catch_up
+            .get_seals()
+            .iter()
+            .map(|seal| seal.get_block().get_block_id())
+            .collect();
+        let last_block = handlers::get_block_by_id(
+            &mut *self.service,
+            &BlockId::from(last_seal.get_block().get_block_id().to_vec()),
+        )
+        .ok_or(PbftError::WrongNumBlocks)?;
+        let mut blocks = vec![last_block];
+        while blocks[0].block_num > head.block_num + 1 {
+            if !sealed_ids.contains(&&Vec::<u8>::from(blocks[0].previous_id.clone())[..]) {
+                self.verify_consensus_seal(&blocks[0])?;
+            }
+            let previous = handlers::get_block_by_id(&mut *self.service, &blocks[0].previous_id)
+                .ok_or(PbftError::WrongNumBlocks)?;
+            blocks.insert(0, previous);
+        }
+        if blocks[0].previous_id != head.block_id {
+            return Err(PbftError::InvalidCatchUp(String::from(
+                "CatchUp blocks don't build on this node's chain head",
+            )));
+        }
+
+        // Adopt the stable checkpoint first, so that the messages after it fit in the log
+        if let Some(cp_info) = checkpoint_messages
+            .first()
+            .map(|msg| msg.get_info().clone())
+        {
+            if cp_info.get_seq_num() > self.msg_log.get_latest_checkpoint() {
+                info!(
+                    "{}: Adopting stable checkpoint (seq num {})",
+                    self.state,
+                    cp_info.get_seq_num()
+                );
+                let proof = checkpoint_messages
+                    .into_iter()
+                    .zip(catch_up.get_checkpoint_messages().iter().cloned())
+                    .collect();
+                self.msg_log.adopt_stable_checkpoint(
+                    cp_info.get_seq_num(),
+                    cp_info.get_view(),
+                    proof,
+                );
+            }
+        }
+
+        let commit_info =
+            handlers::verify_commit_certificate(&self.state, &self.msg_log, last_seal)?;
+        for block in &blocks {
+            info!(
+                "{}: Catching up; committing block {} ({})",
+                self.state,
+                block.block_num,
+                &hex::encode(Vec::<u8>::from(block.block_id.clone()))[..6]
+            );
+            self.service
+                .commit_block(block.block_id.clone())
+                .map_err(|err| {
+                    PbftError::InternalError(format!("Couldn't commit block: {}", err))
+                })?;
+        }
+        let last_block = blocks
+            .pop()
+            .expect("There is at least one block to catch up on");
+        self.msg_log.trim_block_backlog(last_block.block_num);
+
+        // Keep the proof that the last block was committed, in case this node has to seal the block
+        // after it
+        for signed_msg in last_seal.get_commit_messages() {
+            let commit = protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message())
+                .map_err(PbftError::SerializationError)?;
+            if let Err(err) = self.log_signed_message(&PbftMessageType::Commit, commit, signed_msg)
+            {
+                debug!("{}: Not keeping Commit from catch-up: {}", self.state, err);
+            }
+        }
+
+        // Whatever this node was working on (including the blocks in the pipeline) is either on
+        // the chain now, or still has to go through consensus
+        let mut unfinished_ids: Vec<BlockId> = match self.state.working_block {
+            WorkingBlockOption::WorkingBlock(ref block) => {
+                vec![BlockId::from(block.get_block_id().to_vec())]
+            }
+            WorkingBlockOption::TentativeWorkingBlock(ref block_id) => vec![block_id.clone()],
+            WorkingBlockOption::NoWorkingBlock => vec![],
+        };
+        unfinished_ids.extend(
+            self.state
+                .abort_pipeline()
+                .iter()
+                .map(|block| BlockId::from(block.get_block_id().to_vec())),
+        );
+        for block_id in unfinished_ids {
+            if let Some(block) = handlers::get_block_by_id(&mut *self.service, &block_id) {
+                if block.block_num > last_block.block_num {
+                    self.msg_log.push_block_backlog(block);
+                }
+            }
+        }
+
+        let was_primary = self.state.is_primary();
+        self.state.seq_num = ::std::cmp::max(self.state.seq_num, commit_info.get_seq_num());
+        self.state.view = ::std::cmp::max(self.state.view, commit_info.get_view());
+        if self.state.mode == PbftMode::ViewChanging && self.state.view >= self.state.pending_view {
+            self.state.mode = PbftMode::Normal;
+        }
+        if self.state.get_own_peer_id() == self.state.get_primary_peer_id() {
+            self.state.upgrade_role();
+        } else {
+            self.state.downgrade_role();
+        }
+        if was_primary {
+            self.service
+                .cancel_block()
+                .unwrap_or_else(|err| debug!("Couldn't cancel block: {}", err));
+        }
+        self.state.working_block = WorkingBlockOption::NoWorkingBlock;
+        self.state.phase = PbftPhase::NotStarted;
+        self.state.timelines.abandon();
+        self.state.timeout.stop();
+        self.catch_up_head = Some(last_block.block_id);
+        self.catch_up_target = None;
+
+        warn!(
+            "{}: Caught up to block {}",
+            self.state, last_block.block_num
+        );
+        self.compact_storage()
+    }
+
+    // ---------- Methods for reporting metrics ----------
+
+    /// Tell an observer about this node's consensus events from now on
+    pub fn add_observer(&mut self, observer: Box<PbftObserver>) {
+        self.observers.add(observer);
+    }
+
+    /// Share this node's metrics with whatever serves them, such as the metrics server
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Bring the metrics that describe this node's state up to date. If the node moved on to a
+    /// different phase since the last time, the time it spent in the old phase is recorded.
+    pub fn update_metrics(&mut self) {
+        let now = self.clock.now();
+        if self.state.phase != self.metrics_phase.0 {
+            let (ref phase, since) = self.metrics_phase;
+            let duration = now - since;
+            self.metrics.observe(
+                &metrics::PHASE_DURATION,
+                &[format!("{:?}", phase).as_str()],
+                duration.as_secs_f64(),
+            );
+            self.metrics_phase = (self.state.phase.clone(), now);
+        }
+
+        self.metrics
+            .set(&metrics::VIEW, &[], self.state.view as f64);
+        self.metrics
+            .set(&metrics::SEQ_NUM, &[], self.state.seq_num as f64);
+        for phase in &[
+            PbftPhase::NotStarted,
+            PbftPhase::PrePreparing,
+            PbftPhase::Preparing,
+            PbftPhase::Checking,
+            PbftPhase::Committing,
+            PbftPhase::Finished,
+        ] {
+            let current = if *phase == self.state.phase { 1.0 } else { 0.0 };
+            self.metrics
+                .set(&metrics::PHASE, &[format!("{:?}", phase).as_str()], current);
+        }
+        for mode in &[
+            PbftMode::Normal,
+            PbftMode::ViewChanging,
+            PbftMode::Checkpointing,
+            PbftMode::Connecting,
+        ] {
+            let current = if *mode == self.state.mode { 1.0 } else { 0.0 };
+            self.metrics
+                .set(&metrics::MODE, &[format!("{:?}", mode).as_str()], current);
+        }
+
+        for (peer_id, activity) in &self.state.peer_activity {
+            let peer = hex::encode(Vec::<u8>::from(peer_id.clone()));
+            let silence = now - activity.last_message;
+            self.metrics.set(
+                &metrics::PEER_SILENCE,
+                &[peer.as_str()],
+                silence.as_secs_f64(),
+            );
+            let silent = if activity.silent { 1.0 } else { 0.0 };
+            self.metrics
+                .set(&metrics::SILENT_PEER, &[peer.as_str()], silent);
+        }
+
+        self.msg_log.report_metrics(&self.metrics);
+    }
+
+    // ---------- Methods for persisting and restoring the node's log and state ----------
+
+    /// Write this node's state to storage, if it has changed since it was last written
+    pub fn persist_state(&mut self) -> Result<(), PbftError> {
+        let snapshot = self.make_snapshot();
+        if snapshot == self.persisted_state {
+            return Ok(());
+        }
+
+        let mut entry = PbftStorageEntry::new();
+        entry.set_state(snapshot.clone());
+        self.storage.append(&entry)?;
+        self.persisted_state = snapshot;
+        Ok(())
+    }
+
+    // Snapshot this node's state, along with its latest stable checkpoint
+    fn make_snapshot(&self) -> PbftStateSnapshot {
+        let mut snapshot = self.state.snapshot();
+        if let Some(ref cp) = self.msg_log.latest_stable_checkpoint {
+            snapshot.set_checkpoint_seq_num(cp.seq_num);
+            snapshot.set_checkpoint_view(
+                cp.checkpoint_messages
+                    .first()
+                    .map_or(self.state.view, |msg| msg.get_info().get_view()),
+            );
+        }
+        snapshot
+    }
+
+    // Write a signed message that this node is sending to storage, along with the node's current
+    // state; both have to be durable before the message goes out
+    fn persist_message(
+        &mut self,
+        msg_type: &PbftMessageType,
+        signed_msg: &PbftSignedMessage,
+    ) -> Result<(), PbftError> {
+        let snapshot = self.make_snapshot();
+
+        let mut entry = PbftStorageEntry::new();
+        entry.set_message_type(String::from(msg_type));
+        entry.set_message(signed_msg.clone());
+        entry.set_state(snapshot.clone());
+        self.storage.append(&entry)?;
+        self.persisted_state = snapshot;
+        Ok(())
+    }
+
+    // Write a signed message that this node has accepted from a peer to storage. Peer messages
+    // aren't synced to disk one at a time; they become durable along with the next state snapshot
+    // or message this node sends, and any that are lost in a crash can be sent again by the peers
+    // or recovered by catching up.
+    fn persist_peer_message(
+        &mut self,
+        msg_type: &PbftMessageType,
+        signed_msg: &PbftSignedMessage,
+    ) -> Result<(), PbftError> {
+        let mut entry = PbftStorageEntry::new();
+        entry.set_message_type(String::from(msg_type));
+        entry.set_message(signed_msg.clone());
+        self.storage.append_unsynced(&entry)
+    }
+
+    // Add a signed message to the log and write it to storage. This node's own messages were
+    // already written to storage before they were broadcast.
+    fn log_signed_message(
+        &mut self,
+        msg_type: &PbftMessageType,
+        pbft_message: PbftMessage,
+        signed_msg: &PbftSignedMessage,
+    ) -> Result<(), PbftError> {
+        let own = self.is_own_message(pbft_message.get_info());
+        self.msg_log
+            .add_signed_message(pbft_message, signed_msg.clone())?;
+        if !own {
+            self.persist_peer_message(msg_type, signed_msg)?;
+        }
+        Ok(())
+    }
+
+    fn is_own_message(&self, info: &PbftMessageInfo) -> bool {
+        info.get_signer_id() == Vec::<u8>::from(self.state.get_own_peer_id()).as_slice()
+    }
+
+    // After a stable checkpoint, rewrite storage with only what's left in the log
+    fn compact_storage(&mut self) -> Result<(), PbftError> {
+        let snapshot = self.make_snapshot();
+
+        let mut entries: Vec<PbftStorageEntry> = self
+            .msg_log
+            .get_all_signed_messages()
+            .into_iter()
+            .map(|(msg_type, signed_msg)| {
+                let mut entry = PbftStorageEntry::new();
+                entry.set_message_type(String::from(&msg_type));
+                entry.set_message(signed_msg);
+                entry
+            })
+            .collect();
+        let mut entry = PbftStorageEntry::new();
+        entry.set_state(snapshot.clone());
+        entries.push(entry);
+
+        self.storage.compact(&entries)?;
+        self.persisted_state = snapshot;
+        Ok(())
+    }
+
+    // Rebuild the log and state from storage, after a restart. The log's watermarks are moved to
+    // the stored checkpoint before the messages are replayed (so messages past the default
+    // watermarks aren't rejected), and the checkpoint is taken again afterwards so that it
+    // includes the replayed `Checkpoint` messages.
+    fn restore(&mut self) -> Result<(), PbftError> {
+        let entries = self.storage.load()?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "{}: Restoring {} entries from storage",
+            self.state,
+            entries.len()
+        );
+
+        let snapshot = entries
+            .iter()
+            .rev()
+            .find(|entry| entry.has_state())
+            .map(|entry| entry.get_state().clone());
+        let checkpoint = snapshot
+            .as_ref()
+            .map(|s| (s.get_checkpoint_seq_num(), s.get_checkpoint_view()))
+            .filter(|(seq_num, _)| *seq_num > 0);
+
+        if let Some((seq_num, view)) = checkpoint {
+            self.msg_log.garbage_collect(seq_num, view);
+        }
+        // ViewChanges are only kept for views past the one the node is restored to
+        let view = snapshot
+            .as_ref()
+            .map_or(self.state.view, |snapshot| snapshot.get_view());
+        for entry in entries.iter().filter(|entry| entry.has_message()) {
+            let msg_type = entry.get_message_type();
+            if let Err(err) = self.restore_message(msg_type, entry.get_message(), view) {
+                warn!("{}: Not restoring message: {}", self.state, err);
+            }
+        }
+        if let Some((seq_num, view)) = checkpoint {
+            self.msg_log.garbage_collect(seq_num, view);
+        }
+
+        if let Some(snapshot) = snapshot {
+            self.state.restore(&snapshot)?;
+        }
+        self.restore_block_new()?;
+
+        info!("{}: Restored from storage", self.state);
+
+        // There's no telling how long this node was down, so give the rest of the network a full
+        // timeout before suspecting the primary
+        if self.state.phase != PbftPhase::NotStarted || self.state.mode == PbftMode::ViewChanging {
+            self.state.timeout.start();
+        }
+
+        // The validator's answer to a block check may have been lost in the crash
+        let mut unchecked: Vec<BlockId> = self
+            .state
+            .pipeline
+            .values()
+            .filter(|pipelined| pipelined.phase == PbftPhase::Checking)
+            .map(|pipelined| BlockId::from(pipelined.block.get_block_id().to_vec()))
+            .collect();
+        if self.state.phase == PbftPhase::Checking {
+            if let WorkingBlockOption::WorkingBlock(ref block) = self.state.working_block {
+                unchecked.insert(0, BlockId::from(block.get_block_id().to_vec()));
+            }
+        }
+        if !unchecked.is_empty() {
+            self.service
+                .check_blocks(unchecked)
+                .unwrap_or_else(|err| error!("Couldn't check block: {}", err));
+        }
+        Ok(())
+    }
+
+    // Add a stored message back into the log, for a node that's in the given view
+    fn restore_message(
+        &mut self,
+        msg_type: &str,
+        signed_msg: &PbftSignedMessage,
+        view: u64,
+    ) -> Result<(), PbftError> {
+        match PbftMessageType::from(msg_type) {
+            PbftMessageType::ViewChange => {
+                let vc_message =
+                    protobuf::parse_from_bytes::<PbftViewChange>(signed_msg.get_message())
+                        .map_err(PbftError::SerializationError)?;
+                self.msg_log
+                    .add_signed_view_change(vc_message, signed_msg.clone(), view)
+            }
+            // NewView messages aren't kept in the log; the restored state is already in the view
+            PbftMessageType::NewView => Ok(()),
+            PbftMessageType::NetworkChange => {
+                let nc_message =
+                    protobuf::parse_from_bytes::<PbftNetworkChange>(signed_msg.get_message())
+                        .map_err(PbftError::SerializationError)?;
+                self.msg_log
+                    .add_network_change(nc_message, signed_msg.clone())
+            }
+            _ => {
+                let pbft_message =
+                    protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message())
+                        .map_err(PbftError::SerializationError)?;
+                self.msg_log
+                    .add_signed_message(pbft_message, signed_msg.clone())
+            }
+        }
+    }
+
+    // `BlockNew` messages come from the validator instead of peers, so they aren't stored; recreate
+    // the ones for the restored working block and the blocks in the pipeline
+    fn restore_block_new(&mut self) -> Result<(), PbftError> {
+        let pipelined: Vec<(PbftBlock, u64)> = self
+            .state
+            .pipeline
+            .iter()
+            .map(|(seq_num, pipelined)| (pipelined.block.clone(), *seq_num))
+            .collect();
+        for (block, seq_num) in pipelined {
+            self.add_block_new(block, seq_num)?;
+        }
+
+        let (block, seq_num) = match self.state.working_block {
+            WorkingBlockOption::WorkingBlock(ref block) => (block.clone(), self.state.seq_num),
+            WorkingBlockOption::TentativeWorkingBlock(ref block_id) => {
+                let block = self
+                    .service
+                    .get_blocks(vec![block_id.clone()])
+                    .map_err(|e| PbftError::InternalError(e.description().to_string()))?
+                    .remove(block_id)
+                    .ok_or(PbftError::WrongNumBlocks)?;
+                let seq_num = if self.state.is_primary() {
+                    self.state.seq_num
+                } else {
+                    0
+                };
+                (pbft_block_from_block(block), seq_num)
+            }
+            WorkingBlockOption::NoWorkingBlock => return Ok(()),
+        };
+        self.add_block_new(block, seq_num)
+    }
+
+    fn add_block_new(&mut self, block: PbftBlock, seq_num: u64) -> Result<(), PbftError> {
+        let mut block_new = PbftMessage::new();
+        block_new.set_info(handlers::make_msg_info(
+            &PbftMessageType::BlockNew,
+            self.state.view,
+            seq_num,
+            self.state.get_own_peer_id(),
+        ));
+        block_new.set_block(block);
+        self.msg_log.add_message(block_new)
+    }
+
     // ---------- Methods for communication between nodes ----------
 
     // Broadcast a message to this node's peers, and itself
@@ -568,12 +2039,35 @@ impl PbftNode {
         msg_type: &PbftMessageType,
         block: PbftBlock,
     ) -> Result<(), PbftError> {
-        let expected_type = self.state.check_msg_type();
+        let expected_type = self.state.check_msg_type_for(seq_num);
         // Make sure that we should be sending messages of this type
         if msg_type.is_multicast() && msg_type != &expected_type {
             return Ok(());
         }
 
+        // Never vote for two different blocks with the same view and sequence number, even if
+        // this node restarted in between
+        if msg_type.is_multicast() {
+            let own_id = Vec::<u8>::from(self.state.get_own_peer_id());
+            let conflicting = self
+                .msg_log
+                .get_messages_of_type(msg_type, seq_num, self.state.view)
+                .iter()
+                .any(|msg| {
+                    msg.get_info().get_signer_id() == own_id.as_slice()
+                        && msg.get_block().get_block_id() != block.get_block_id()
+                });
+            if conflicting {
+                error!(
+                    "{}: Refusing to send a {} for a different block than before",
+                    self.state, msg_type
+                );
+                return Err(PbftError::MessageExists(PbftMessageType::from(
+                    String::from(msg_type).as_str(),
+                )));
+            }
+        }
+
         let msg_bytes = make_msg_bytes(
             handlers::make_msg_info(
                 &msg_type,
@@ -587,11 +2081,83 @@ impl PbftNode {
         self._broadcast_message(&msg_type, &msg_bytes)
     }
 
-    #[cfg(not(test))]
+    // Broadcast a NetworkChange proposing the given peers, along with this node's chain head
+    fn _broadcast_network_change(
+        &mut self,
+        peers: Vec<PeerId>,
+        seq_num: u64,
+        tentative: bool,
+    ) -> Result<(), PbftError> {
+        let head = self
+            .service
+            .get_chain_head()
+            .map_err(|e| PbftError::InternalError(e.description().to_string()))?;
+
+        let mut nc_msg = PbftNetworkChange::new();
+        nc_msg.set_info(handlers::make_msg_info(
+            &PbftMessageType::NetworkChange,
+            self.state.view,
+            seq_num,
+            self.state.get_own_peer_id(),
+        ));
+        nc_msg.set_peers(RepeatedField::from_vec(
+            peers.into_iter().map(Vec::<u8>::from).collect(),
+        ));
+        nc_msg.set_head(pbft_block_from_block(head));
+        nc_msg.set_tentative(tentative);
+
+        let msg_bytes = nc_msg
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)?;
+        self._broadcast_message(&PbftMessageType::NetworkChange, &msg_bytes)
+    }
+
+    // Sign a serialized message and write it to storage, then broadcast it to this node's peers
+    // and itself
     fn _broadcast_message(
         &mut self,
         msg_type: &PbftMessageType,
         msg_bytes: &[u8],
+    ) -> Result<(), PbftError> {
+        let signed_msg = self.signer.sign(msg_bytes)?;
+        self.persist_message(msg_type, &signed_msg)?;
+
+        let signed_bytes = signed_msg
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)?;
+
+        self.metrics
+            .inc(&metrics::MESSAGES_SENT, &[String::from(msg_type).as_str()]);
+        self._send_signed_message(msg_type, &signed_bytes)
+    }
+
+    // Sign a serialized message and send it to one peer
+    fn _send_message_to(
+        &mut self,
+        peer_id: &PeerId,
+        msg_type: &PbftMessageType,
+        msg_bytes: &[u8],
+    ) -> Result<(), PbftError> {
+        let signed_bytes = self
+            .signer
+            .sign(msg_bytes)?
+            .write_to_bytes()
+            .map_err(PbftError::SerializationError)?;
+
+        debug!("{}: Sending {:?}", self.state, msg_type);
+        self.metrics
+            .inc(&metrics::MESSAGES_SENT, &[String::from(msg_type).as_str()]);
+        self.service
+            .send_to(peer_id, String::from(msg_type).as_str(), signed_bytes)
+            .unwrap_or_else(|err| error!("Couldn't send to peer: {}", err));
+        Ok(())
+    }
+
+    #[cfg(not(test))]
+    fn _send_signed_message(
+        &mut self,
+        msg_type: &PbftMessageType,
+        msg_bytes: &[u8],
     ) -> Result<(), PbftError> {
         // Broadcast to peers
         debug!("{}: Broadcasting {:?}", self.state, msg_type);
@@ -607,14 +2173,19 @@ impl PbftNode {
         self.on_peer_message(&peer_msg)
     }
 
-    /// NOTE: Disabling self-sending for testing purposes
+    /// NOTE: Disabling self-sending for testing purposes; the message still goes to the service,
+    /// so the simulated network (see the `simulator` module) can send it back to this node
     #[cfg(test)]
-    fn _broadcast_message(
+    fn _send_signed_message(
         &mut self,
-        _msg_type: &PbftMessageType,
-        _msg_bytes: &[u8],
+        msg_type: &PbftMessageType,
+        msg_bytes: &[u8],
     ) -> Result<(), PbftError> {
-        return Ok(());
+        debug!("{}: Broadcasting {:?}", self.state, msg_type);
+        self.service
+            .broadcast(String::from(msg_type).as_str(), msg_bytes.to_vec())
+            .unwrap_or_else(|err| error!("Couldn't broadcast: {}", err));
+        Ok(())
     }
 }
 
@@ -626,17 +2197,6 @@ fn make_msg_bytes(info: PbftMessageInfo, block: PbftBlock) -> Result<Vec<u8>, Pr
     msg.write_to_bytes()
 }
 
-// Make a PbftBlock out of a consensus Block (PBFT doesn't need to use all the information about
-// the block - this keeps blocks lighter weight)
-fn pbft_block_from_block(block: Block) -> PbftBlock {
-    let mut pbft_block = PbftBlock::new();
-    pbft_block.set_block_id(Vec::<u8>::from(block.block_id));
-    pbft_block.set_signer_id(Vec::<u8>::from(block.signer_id));
-    pbft_block.set_block_num(block.block_num);
-    pbft_block.set_summary(block.summary);
-    pbft_block
-}
-
 /// NOTE: Testing the PbftNode is a bit strange. Due to missing functionality in the Service,
 /// a node calling `broadcast()` doesn't include sending a message to itself. In order to get around
 /// this, `on_peer_message()` is called, which sometimes causes unintended side effects when
@@ -647,25 +2207,32 @@ mod tests {
     use config::mock_config;
     use crypto::digest::Digest;
     use crypto::sha2::Sha256;
+    use evidence;
     use handlers::make_msg_info;
+    use protos::pbft_message::{PbftPreparedCertificate, PbftSeal};
     use sawtooth_sdk::consensus::engine::{Error, PeerId};
     use serde_json;
+    use signing::mock_signer;
     use std::collections::HashMap;
     use std::default::Default;
     use std::fs::{remove_file, File};
     use std::io::prelude::*;
+    use std::time::Duration;
+    use storage::MemoryStorage;
+    use timing::{mock_clock, Timeout};
 
     const BLOCK_FILE: &str = "blocks.txt";
 
     /// Mock service to roughly keep track of the blockchain
     pub struct MockService {
         pub chain: Vec<BlockId>,
+        pub block_file: &'static str,
     }
 
     impl MockService {
         /// Serialize the chain into JSON, and write to a file
         fn write_chain(&self) {
-            let mut block_file = File::create(BLOCK_FILE).unwrap();
+            let mut block_file = File::create(self.block_file).unwrap();
             let block_bytes: Vec<Vec<u8>> = self
                 .chain
                 .iter()
@@ -725,6 +2292,7 @@ mod tests {
                     .chain
                     .iter()
                     .position(|val| val == id)
+                    .or_else(|| (0..100).position(|num| &mock_block_id(num) == id))
                     .unwrap_or(self.chain.len());
                 res.insert(id.clone(), mock_block(index as u64));
             }
@@ -736,7 +2304,7 @@ mod tests {
                 block_id: self.chain.last().unwrap().clone(),
                 previous_id: self.chain.get(prev_num).unwrap().clone(),
                 signer_id: PeerId::from(vec![]),
-                block_num: self.chain.len() as u64,
+                block_num: self.chain.len() as u64 - 1,
                 payload: vec![],
                 summary: vec![],
             })
@@ -759,12 +2327,32 @@ mod tests {
 
     /// Create a node, based on a given ID
     fn mock_node(node_id: usize) -> PbftNode {
+        mock_node_with_storage(node_id, MemoryStorage::new())
+    }
+
+    /// Create a node that keeps its log and state in the given storage; if the storage isn't
+    /// empty, the node picks up from what's in it as if it restarted
+    fn mock_node_with_storage(node_id: usize, storage: MemoryStorage) -> PbftNode {
+        mock_node_in_network(node_id, 4, storage)
+    }
+
+    /// Create a node in a network that starts out with peers `0..num_nodes` (which may not include
+    /// the node itself)
+    fn mock_node_in_network(node_id: usize, num_nodes: usize, storage: MemoryStorage) -> PbftNode {
         let service: Box<MockService> = Box::new(MockService {
             // Create genesis block (but with actual ID)
             chain: vec![mock_block_id(0)],
+            block_file: BLOCK_FILE,
         });
-        let cfg = mock_config(4);
-        PbftNode::new(node_id as u64, &cfg, service)
+        let cfg = mock_config(num_nodes);
+        PbftNode::new(
+            &mock_peer_id(node_id as u64),
+            &cfg,
+            service,
+            mock_signer(node_id as u64),
+            Box::new(storage),
+            mock_clock(),
+        )
     }
 
     /// Create a deterministic BlockId hash based on a block number
@@ -774,26 +2362,69 @@ mod tests {
         BlockId::from(sha.result_str().as_bytes().to_vec())
     }
 
-    /// Create a deterministic PeerId hash based on a peer number
+    /// Obtain the PeerId (public key) of the deterministic mock signer for a peer number
     fn mock_peer_id(num: u64) -> PeerId {
-        let mut sha = Sha256::new();
-        sha.input_str(format!("I'm a peer (number {})", num).as_str());
-        PeerId::from(sha.result_str().as_bytes().to_vec())
+        mock_signer(num).get_public_key().unwrap()
+    }
+
+    /// Sign a serialized message as peer `from`, and wrap it in a PeerMessage
+    fn mock_signed_msg(msg_type: &PbftMessageType, msg_bytes: &[u8], from: u64) -> PeerMessage {
+        let content = mock_signer(from)
+            .sign(msg_bytes)
+            .unwrap()
+            .write_to_bytes()
+            .expect("SerializationError");
+        PeerMessage {
+            message_type: String::from(msg_type),
+            content,
+        }
     }
 
-    /// Create a mock Block, including only the BlockId, the BlockId of the previous block, and the
-    /// block number
+    /// Create a mock Block, including only the BlockId, the BlockId of the previous block, the
+    /// block number, and a consensus seal for the previous block (if it isn't genesis)
     fn mock_block(num: u64) -> Block {
         Block {
             block_id: mock_block_id(num),
             previous_id: mock_block_id(num - 1),
             signer_id: PeerId::from(vec![]),
             block_num: num,
-            payload: vec![],
+            payload: if num > 1 { mock_seal(num - 1) } else { vec![] },
             summary: vec![],
         }
     }
 
+    /// Create a serialized consensus seal for block `num`, with `Commit` messages from nodes 0, 1,
+    /// and 2
+    fn mock_seal(num: u64) -> Vec<u8> {
+        mock_seal_for(mock_block_id(num), num)
+    }
+
+    /// Create a serialized consensus seal like `mock_seal`, for the block with the given ID
+    fn mock_seal_for(block_id: BlockId, num: u64) -> Vec<u8> {
+        let mut pbft_block = PbftBlock::new();
+        pbft_block.set_block_id(Vec::<u8>::from(block_id));
+        pbft_block.set_block_num(num);
+
+        let mut seal = PbftSeal::new();
+        for peer in 0..3 {
+            let mut commit = PbftMessage::new();
+            commit.set_info(make_msg_info(
+                &PbftMessageType::Commit,
+                0,
+                num,
+                mock_peer_id(peer),
+            ));
+            commit.set_block(pbft_block.clone());
+            seal.mut_commit_messages().push(
+                mock_signer(peer)
+                    .sign(&commit.write_to_bytes().unwrap())
+                    .unwrap(),
+            );
+        }
+        seal.set_block(pbft_block);
+        seal.write_to_bytes().unwrap()
+    }
+
     /// Create a mock PeerMessage
     fn mock_msg(
         msg_type: &PbftMessageType,
@@ -808,11 +2439,35 @@ mod tests {
         pbft_msg.set_info(info);
         pbft_msg.set_block(pbft_block_from_block(block.clone()));
 
-        let content = pbft_msg.write_to_bytes().expect("SerializationError");
-        PeerMessage {
-            message_type: String::from(msg_type),
-            content,
-        }
+        let msg_bytes = pbft_msg.write_to_bytes().expect("SerializationError");
+        mock_signed_msg(msg_type, &msg_bytes, from)
+    }
+
+    /// Create a NetworkChange PeerMessage proposing peers `0..num_peers`
+    fn mock_network_change_msg(
+        view: u64,
+        seq_num: u64,
+        num_peers: u64,
+        tentative: bool,
+        from: u64,
+    ) -> PeerMessage {
+        let mut nc_msg = PbftNetworkChange::new();
+        nc_msg.set_info(make_msg_info(
+            &PbftMessageType::NetworkChange,
+            view,
+            seq_num,
+            mock_peer_id(from),
+        ));
+        nc_msg.set_peers(RepeatedField::from_vec(
+            (0..num_peers)
+                .map(|i| Vec::<u8>::from(mock_peer_id(i)))
+                .collect(),
+        ));
+        nc_msg.set_head(pbft_block_from_block(mock_block(1)));
+        nc_msg.set_tentative(tentative);
+
+        let msg_bytes = nc_msg.write_to_bytes().expect("SerializationError");
+        mock_signed_msg(&PbftMessageType::NetworkChange, &msg_bytes, from)
     }
 
     fn handle_pbft_err(e: PbftError) {
@@ -865,6 +2520,24 @@ mod tests {
         assert_eq!(node1.state.seq_num, 0);
     }
 
+    /// Make sure that a block without a valid consensus seal is rejected, and makes the node
+    /// suspect the primary
+    #[test]
+    fn block_new_invalid_seal() {
+        let mut node1 = mock_node(1);
+        let mut block = mock_block(2);
+        block.payload = mock_seal(3);
+        assert!(node1.on_block_new(block).is_err());
+        assert_eq!(node1.state.phase, PbftPhase::NotStarted);
+        assert_eq!(node1.state.mode, PbftMode::ViewChanging);
+
+        let mut node1 = mock_node(1);
+        let mut block = mock_block(2);
+        block.payload = vec![];
+        assert!(node1.on_block_new(block).is_err());
+        assert_eq!(node1.state.mode, PbftMode::ViewChanging);
+    }
+
     /// Make sure that receiving a `BlockValid` update works as expected
     #[test]
     fn block_valid() {
@@ -933,24 +2606,241 @@ mod tests {
         }
         assert_eq!(node1.state.phase, PbftPhase::Finished);
 
-        // Spoof the `commit_blocks()` call
-        assert!(node1.on_block_commit(mock_block_id(1)).is_ok());
-        assert_eq!(node1.state.phase, PbftPhase::NotStarted);
+        // Spoof the `commit_blocks()` call
+        assert!(node1.on_block_commit(mock_block_id(1)).is_ok());
+        assert_eq!(node1.state.phase, PbftPhase::NotStarted);
+
+        // Make sure the block was actually committed
+        let mut f = File::open(BLOCK_FILE).unwrap();
+        let mut buffer = String::new();
+        f.read_to_string(&mut buffer).unwrap();
+        let deser: Vec<Vec<u8>> = serde_json::from_str(&buffer).unwrap();
+        let blocks: Vec<BlockId> = deser
+            .iter()
+            .filter(|&block| !block.is_empty())
+            .map(|ref block| BlockId::from(block.clone().clone()))
+            .collect();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1], mock_block_id(1));
+
+        remove_file(BLOCK_FILE).unwrap();
+    }
+
+    /// Make sure that a node starts a view change if the primary reorders the batches in a block
+    #[test]
+    fn reordered_batches() {
+        let mut node = mock_node(1);
+        let block = mock_block(1);
+        node.on_block_new(block.clone())
+            .unwrap_or_else(handle_pbft_err);
+
+        let mut reordered = block.clone();
+        reordered.summary = b"reordered".to_vec();
+        let msg = mock_msg(&PbftMessageType::PrePrepare, 0, 1, reordered, 0);
+        match node.on_peer_message(&msg) {
+            Err(PbftError::BatchDigestMismatch(_, _)) => (),
+            res => panic!("Reordered block was accepted: {:?}", res),
+        }
+        assert_eq!(node.state.mode, PbftMode::ViewChanging);
+        assert_eq!(node.state.pending_view, 1);
+        assert_eq!(node.state.seq_num, 0);
+    }
+
+    /// A secondary can't propose blocks, so a tampered `PrePrepare` from one is refused without
+    /// starting a view change, and the primary's real `PrePrepare` still gets through
+    #[test]
+    fn reordered_batches_from_secondary() {
+        let mut node = mock_node(1);
+        let block = mock_block(1);
+        node.on_block_new(block.clone())
+            .unwrap_or_else(handle_pbft_err);
+
+        let mut reordered = block.clone();
+        reordered.summary = b"reordered".to_vec();
+        let msg = mock_msg(&PbftMessageType::PrePrepare, 0, 1, reordered, 2);
+        match node.on_peer_message(&msg) {
+            Err(PbftError::NotFromPrimary(PbftMessageType::PrePrepare)) => (),
+            res => panic!("PrePrepare from a secondary was accepted: {:?}", res),
+        }
+        assert_eq!(node.state.mode, PbftMode::Normal);
+        assert_eq!(node.state.seq_num, 0);
+
+        let msg = mock_msg(&PbftMessageType::PrePrepare, 0, 1, block, 0);
+        node.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.seq_num, 1);
+    }
+
+    /// Make sure that a secondary can work on a block in the pipeline ahead of the working block,
+    /// and that the blocks still go to the validator in order
+    #[test]
+    fn pipelined_consensus() {
+        const PIPELINE_BLOCK_FILE: &str = "pipeline_blocks.txt";
+
+        let mut node = mock_node(1);
+        node.service = Box::new(MockService {
+            chain: vec![mock_block_id(0)],
+            block_file: PIPELINE_BLOCK_FILE,
+        });
+        node.state.pipeline_depth = 2;
+
+        // With a pipeline two blocks deep, neither block is sealed
+        let block1 = mock_block(1);
+        let block2 = Block {
+            payload: vec![],
+            ..mock_block(2)
+        };
+
+        node.on_block_new(block1.clone())
+            .unwrap_or_else(handle_pbft_err);
+        let msg = mock_msg(&PbftMessageType::PrePrepare, 0, 1, block1.clone(), 0);
+        node.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.phase, PbftPhase::Preparing);
+
+        // Block 2 waits for the primary to give it a sequence number
+        node.on_block_new(block2.clone())
+            .unwrap_or_else(handle_pbft_err);
+        assert!(node.state.pipeline.is_empty());
+        let msg = mock_msg(&PbftMessageType::PrePrepare, 0, 2, block2.clone(), 0);
+        node.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.pipeline[&2].phase, PbftPhase::Preparing);
+        assert_eq!(node.state.seq_num, 1);
+
+        // Block 2 gets through consensus first, but isn't committed yet
+        for peer in 0..3 {
+            let msg = mock_msg(&PbftMessageType::Prepare, 0, 2, block2.clone(), peer);
+            node.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node.state.pipeline[&2].phase, PbftPhase::Checking);
+        assert_eq!(node.state.phase, PbftPhase::Preparing);
+        node.on_block_valid(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.pipeline[&2].phase, PbftPhase::Committing);
+        for peer in 0..3 {
+            let msg = mock_msg(&PbftMessageType::Commit, 0, 2, block2.clone(), peer);
+            node.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node.state.pipeline[&2].phase, PbftPhase::Finished);
+        assert_eq!(node.service.get_chain_head().unwrap().block_num, 0);
+
+        // Block 1 goes through consensus and is committed
+        for peer in 0..3 {
+            let msg = mock_msg(&PbftMessageType::Prepare, 0, 1, block1.clone(), peer);
+            node.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        node.on_block_valid(mock_block_id(1))
+            .unwrap_or_else(handle_pbft_err);
+        for peer in 0..3 {
+            let msg = mock_msg(&PbftMessageType::Commit, 0, 1, block1.clone(), peer);
+            node.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node.state.phase, PbftPhase::Finished);
+        assert_eq!(
+            node.service.get_chain_head().unwrap().block_id,
+            mock_block_id(1)
+        );
+
+        // Then block 2 goes straight to the validator
+        node.on_block_commit(mock_block_id(1))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.seq_num, 2);
+        assert_eq!(node.state.phase, PbftPhase::Finished);
+        assert!(node.state.pipeline.is_empty());
+        assert_eq!(
+            node.service.get_chain_head().unwrap().block_id,
+            mock_block_id(2)
+        );
+        node.on_block_commit(mock_block_id(2))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.phase, PbftPhase::NotStarted);
+
+        remove_file(PIPELINE_BLOCK_FILE).unwrap();
+    }
+
+    /// Make sure that the primary only starts as many blocks as fit in the pipeline
+    #[test]
+    fn pipelined_primary() {
+        let mut node = mock_node(0);
+        node.state.pipeline_depth = 2;
+
+        node.on_block_new(mock_block(1))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.seq_num, 1);
+        assert!(!node.state.pipeline_full());
+
+        let block2 = Block {
+            payload: vec![],
+            ..mock_block(2)
+        };
+        node.on_block_new(block2.clone())
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.seq_num, 1);
+        assert_eq!(node.state.pipeline[&2].phase, PbftPhase::PrePreparing);
+        assert!(node.state.pipeline_full());
+
+        // The primary's own PrePrepare starts consensus on the block
+        let msg = mock_msg(&PbftMessageType::PrePrepare, 0, 2, block2, 0);
+        node.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.pipeline[&2].phase, PbftPhase::Preparing);
+
+        // No more room; block 3 carries the seal for block 1
+        let block3 = Block {
+            payload: mock_block(2).payload,
+            ..mock_block(3)
+        };
+        node.on_block_new(block3).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.pipeline.len(), 1);
+    }
+
+    /// Make sure that with a pipeline, a block's seal has to be for its own ancestor
+    /// `pipeline_depth` blocks back, and not some other block at that height
+    #[test]
+    fn pipelined_seal() {
+        let mut node = mock_node(1);
+        node.state.pipeline_depth = 2;
+        let fork_block = Block {
+            payload: mock_seal_for(mock_block_id(100), 1),
+            ..mock_block(3)
+        };
+        assert!(node.on_block_new(fork_block).is_err());
+        assert_eq!(node.state.mode, PbftMode::ViewChanging);
+
+        let mut node = mock_node(1);
+        node.state.pipeline_depth = 2;
+        let block3 = Block {
+            payload: mock_seal(1),
+            ..mock_block(3)
+        };
+        node.on_block_new(block3).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.mode, PbftMode::Normal);
+    }
 
-        // Make sure the block was actually committed
-        let mut f = File::open(BLOCK_FILE).unwrap();
-        let mut buffer = String::new();
-        f.read_to_string(&mut buffer).unwrap();
-        let deser: Vec<Vec<u8>> = serde_json::from_str(&buffer).unwrap();
-        let blocks: Vec<BlockId> = deser
-            .iter()
-            .filter(|&block| !block.is_empty())
-            .map(|ref block| BlockId::from(block.clone().clone()))
-            .collect();
-        assert_eq!(blocks.len(), 2);
-        assert_eq!(blocks[1], mock_block_id(1));
+    /// Make sure that the primary only publishes a block once it can seal it, even if the proof
+    /// that the chain head was committed is from before the latest stable checkpoint
+    #[test]
+    fn seal_needs_proof() {
+        let mut node = mock_node(0);
+        node.service = Box::new(MockService {
+            chain: vec![mock_block_id(0), mock_block_id(1)],
+            block_file: BLOCK_FILE,
+        });
+        assert_eq!(node.make_seal().unwrap(), None);
+
+        let seal = protobuf::parse_from_bytes::<PbftSeal>(&mock_seal(1)).unwrap();
+        for signed_msg in seal.get_commit_messages() {
+            let commit =
+                protobuf::parse_from_bytes::<PbftMessage>(signed_msg.get_message()).unwrap();
+            node.msg_log
+                .add_signed_message(commit, signed_msg.clone())
+                .unwrap();
+        }
+        assert!(node.make_seal().unwrap().is_some());
 
-        remove_file(BLOCK_FILE).unwrap();
+        node.msg_log.garbage_collect(2, 0);
+        let sealed = protobuf::parse_from_bytes::<PbftSeal>(&node.make_seal().unwrap().unwrap());
+        assert_eq!(
+            sealed.unwrap().get_block().get_block_id(),
+            &Vec::<u8>::from(mock_block_id(1))[..]
+        );
     }
 
     /// Make sure that checkpointing works as expected:
@@ -998,18 +2888,132 @@ mod tests {
             vc_msg.set_checkpoint_messages(RepeatedField::default());
 
             let msg_bytes = vc_msg.write_to_bytes().unwrap();
-            let msg = PeerMessage {
-                message_type: String::from(&PbftMessageType::ViewChange),
-                content: msg_bytes,
-            };
+            let msg = mock_signed_msg(&PbftMessageType::ViewChange, &msg_bytes, peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+
+        assert!(node1.state.is_primary());
+        assert_eq!(node1.state.view, 1);
+    }
+
+    /// Create a signed `ViewChange` for `view` from node `from`, proving that block 2 was
+    /// prepared in view 0 with sequence number 2
+    fn mock_view_change(view: u64, from: u64) -> PeerMessage {
+        let mut pre_prepare = PbftMessage::new();
+        pre_prepare.set_info(make_msg_info(
+            &PbftMessageType::PrePrepare,
+            0,
+            2,
+            mock_peer_id(0),
+        ));
+        pre_prepare.set_block(pbft_block_from_block(mock_block(2)));
+
+        let mut cert = PbftPreparedCertificate::new();
+        cert.set_pre_prepare(
+            mock_signer(0)
+                .sign(&pre_prepare.write_to_bytes().unwrap())
+                .unwrap(),
+        );
+        for peer in 0..3 {
+            let mut prepare = pre_prepare.clone();
+            prepare.set_info(make_msg_info(
+                &PbftMessageType::Prepare,
+                0,
+                2,
+                mock_peer_id(peer),
+            ));
+            cert.mut_prepare_messages().push(
+                mock_signer(peer)
+                    .sign(&prepare.write_to_bytes().unwrap())
+                    .unwrap(),
+            );
+        }
+
+        let mut vc_msg = PbftViewChange::new();
+        vc_msg.set_info(make_msg_info(
+            &PbftMessageType::ViewChange,
+            view,
+            0,
+            mock_peer_id(from),
+        ));
+        vc_msg.set_prepared_certificates(RepeatedField::from_vec(vec![cert]));
+
+        let msg_bytes = vc_msg.write_to_bytes().unwrap();
+        mock_signed_msg(&PbftMessageType::ViewChange, &msg_bytes, from)
+    }
+
+    /// Test that secondaries only leave `ViewChanging` mode once they get a valid `NewView` from
+    /// the new primary, and that a block that was prepared in the old view gets prepared again in
+    /// the new one
+    #[test]
+    fn new_view() {
+        let mut node1 = mock_node(1);
+        let mut node2 = mock_node(2);
+
+        for peer in 0..3 {
+            let msg = mock_view_change(1, peer);
             node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+            node2.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
         }
 
+        // The new primary moves on right away, and proposes the prepared block again
         assert!(node1.state.is_primary());
         assert_eq!(node1.state.view, 1);
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+        assert_eq!(node1.state.phase, PbftPhase::Preparing);
+        assert_eq!(node1.state.seq_num, 2);
+
+        // The secondary waits for a NewView
+        assert_eq!(node2.state.mode, PbftMode::ViewChanging);
+        assert_eq!(node2.state.view, 0);
+
+        let vc_msg =
+            protobuf::parse_from_bytes::<PbftSignedMessage>(&mock_view_change(1, 0).content)
+                .and_then(|signed| {
+                    protobuf::parse_from_bytes::<PbftViewChange>(signed.get_message())
+                })
+                .unwrap();
+        let new_view =
+            handlers::view_change(&node1.state, &node1.msg_log, &vc_msg, &node1.observers)
+                .unwrap()
+                .unwrap();
+
+        // A NewView that isn't from the new primary is rejected
+        let mut forged = new_view.clone();
+        forged
+            .mut_info()
+            .set_signer_id(Vec::<u8>::from(mock_peer_id(3)));
+        let msg = mock_signed_msg(
+            &PbftMessageType::NewView,
+            &forged.write_to_bytes().unwrap(),
+            3,
+        );
+        assert!(node2.on_peer_message(&msg).is_err());
+        assert_eq!(node2.state.mode, PbftMode::ViewChanging);
+
+        let msg = mock_signed_msg(
+            &PbftMessageType::NewView,
+            &new_view.write_to_bytes().unwrap(),
+            1,
+        );
+        node2.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert!(!node2.state.is_primary());
+        assert_eq!(node2.state.view, 1);
+        assert_eq!(node2.state.mode, PbftMode::Normal);
+        assert_eq!(node2.state.phase, PbftPhase::Preparing);
+        assert_eq!(node2.state.seq_num, 2);
+        assert_eq!(
+            node2.state.working_block,
+            WorkingBlockOption::WorkingBlock(pbft_block_from_block(mock_block(2)))
+        );
+
+        // Receiving the NewView again doesn't change anything
+        node2.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node2.state.phase, PbftPhase::Preparing);
     }
 
-    /// Make sure that view changes start correctly
+    /// Make sure that view changes start correctly, and that a node moves on to the next view if
+    /// the new primary doesn't send a `NewView` in time
     #[test]
     fn start_view_change() {
         let mut node1 = mock_node(1);
@@ -1018,5 +3022,576 @@ mod tests {
         node1.start_view_change().unwrap_or_else(handle_pbft_err);
 
         assert_eq!(node1.state.mode, PbftMode::ViewChanging);
+        assert_eq!(node1.state.pending_view, 1);
+
+        // Timer hasn't expired yet
+        node1.start_view_change().unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.pending_view, 1);
+
+        let clock = mock_clock();
+        node1.state.timeout = Timeout::new(Duration::from_millis(0), clock.clone());
+        node1.state.timeout.start();
+        clock.advance(Duration::from_millis(1));
+        node1.start_view_change().unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.mode, PbftMode::ViewChanging);
+        assert_eq!(node1.state.pending_view, 2);
+    }
+
+    /// Make sure that a node reports its state and how long it spent in each phase, along with
+    /// the messages it sent and the view changes it started
+    #[test]
+    fn report_metrics() {
+        let mut node = mock_node(1);
+        let clock = mock_clock();
+        let node_clock: Rc<Clock> = clock.clone();
+        node.clock = node_clock;
+        node.metrics_phase = (node.state.phase.clone(), clock.now());
+
+        node.update_metrics();
+        assert_eq!(node.metrics.get(&metrics::VIEW, &[]), Some(0.0));
+        assert_eq!(node.metrics.get(&metrics::SEQ_NUM, &[]), Some(0.0));
+        assert_eq!(
+            node.metrics.get(&metrics::PHASE, &["NotStarted"]),
+            Some(1.0)
+        );
+        assert_eq!(node.metrics.get(&metrics::MODE, &["Normal"]), Some(1.0));
+        assert_eq!(
+            node.metrics.get(&metrics::PHASE_DURATION, &["NotStarted"]),
+            None
+        );
+
+        // The time spent in a phase is recorded once the node moves on from it
+        clock.advance(Duration::from_millis(250));
+        node.state.switch_phase(PbftPhase::PrePreparing);
+        node.update_metrics();
+        assert_eq!(
+            node.metrics.get(&metrics::PHASE, &["NotStarted"]),
+            Some(0.0)
+        );
+        assert_eq!(
+            node.metrics.get(&metrics::PHASE, &["PrePreparing"]),
+            Some(1.0)
+        );
+        assert_eq!(
+            node.metrics.get(&metrics::PHASE_DURATION, &["NotStarted"]),
+            Some(1.0)
+        );
+        assert!(node
+            .metrics
+            .render()
+            .contains("pbft_phase_duration_seconds_sum{phase=\"NotStarted\"} 0.25\n"));
+
+        node.start_view_change().unwrap_or_else(handle_pbft_err);
+        node.update_metrics();
+        assert_eq!(node.metrics.get(&metrics::VIEW_CHANGES, &[]), Some(1.0));
+        assert_eq!(
+            node.metrics.get(&metrics::MESSAGES_SENT, &["ViewChange"]),
+            Some(1.0)
+        );
+        assert_eq!(
+            node.metrics.get(&metrics::MODE, &["ViewChanging"]),
+            Some(1.0)
+        );
+        assert_eq!(node.metrics.get(&metrics::LOG_VIEW_CHANGES, &[]), Some(0.0));
+    }
+
+    /// Make sure that a node keeps track of the view and sequence number of what each of its peers
+    /// sent last, and how many messages of each type they sent
+    #[test]
+    fn peer_activity() {
+        let mut node = mock_node(1);
+        let mut deliver = |from: u64, msg: PeerMessage| {
+            let msg_type = PbftMessageType::from(msg.message_type.as_str());
+            node.on_peer_activity(&mock_peer_id(from), &msg_type);
+            node.on_peer_message(&msg).unwrap_or(());
+        };
+        for seq_num in 2..4 {
+            let block = mock_block(seq_num);
+            deliver(0, mock_msg(&PbftMessageType::Prepare, 0, seq_num, block, 0));
+        }
+        deliver(2, mock_view_change(1, 2));
+
+        // Nodes that aren't in the network aren't tracked
+        let outsider = mock_msg(&PbftMessageType::Prepare, 0, 2, mock_block(2), 5);
+        deliver(5, outsider);
+
+        // A message that isn't validly signed counts as activity, but doesn't say anything about
+        // where the node is
+        let mut forged = PbftMessage::new();
+        forged.set_info(make_msg_info(
+            &PbftMessageType::Commit,
+            7,
+            9,
+            mock_peer_id(3),
+        ));
+        let msg_bytes = forged.write_to_bytes().unwrap();
+        deliver(3, mock_signed_msg(&PbftMessageType::Commit, &msg_bytes, 2));
+
+        // Messages of types the node doesn't know are all counted together
+        for name in &["Bogus", "Nonsense"] {
+            let msg = PeerMessage {
+                message_type: String::from(*name),
+                content: vec![],
+            };
+            deliver(3, msg);
+        }
+
+        let activity = &node.state.peer_activity;
+        assert_eq!(activity.len(), 3);
+        assert_eq!(activity[&mock_peer_id(0)].last_view, 0);
+        assert_eq!(activity[&mock_peer_id(0)].last_seq_num, 3);
+        assert_eq!(activity[&mock_peer_id(0)].messages["Prepare"], 2);
+        assert_eq!(activity[&mock_peer_id(2)].last_view, 1);
+        assert_eq!(activity[&mock_peer_id(2)].messages["ViewChange"], 1);
+        assert_eq!(activity[&mock_peer_id(3)].last_view, 0);
+        assert_eq!(activity[&mock_peer_id(3)].last_seq_num, 0);
+        assert_eq!(activity[&mock_peer_id(3)].messages["Commit"], 1);
+        assert_eq!(activity[&mock_peer_id(3)].messages["Unset"], 2);
+        assert_eq!(activity[&mock_peer_id(3)].messages.len(), 2);
+
+        node.update_metrics();
+        let peer = hex::encode(Vec::<u8>::from(mock_peer_id(0)));
+        assert_eq!(
+            node.metrics.get(&metrics::SILENT_PEER, &[peer.as_str()]),
+            Some(0.0)
+        );
+    }
+
+    /// Make sure that a node starts a view change early when the primary goes silent while the
+    /// node is waiting on it, but not when the network is just quiet
+    #[test]
+    fn silent_primary() {
+        let clock = mock_clock();
+        let mut node = mock_node(1);
+        node.state = PbftState::new(&mock_peer_id(1), &mock_config(4), clock.clone());
+        let node_clock: Rc<Clock> = clock.clone();
+        node.clock = node_clock;
+        let threshold = node.state.silence_threshold;
+
+        clock.advance(threshold * 2);
+        node.check_peer_activity().unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.mode, PbftMode::Normal);
+
+        // Once the node is waiting on a block, the primary's silence counts from then, and
+        // hearing from the primary starts it over
+        node.on_block_new(mock_block(1))
+            .unwrap_or_else(handle_pbft_err);
+        clock.advance(threshold / 2);
+        node.on_peer_activity(&mock_peer_id(0), &PbftMessageType::PrePrepare);
+        clock.advance(threshold / 2);
+        node.check_peer_activity().unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.mode, PbftMode::Normal);
+
+        clock.advance(threshold / 2);
+        node.check_peer_activity().unwrap_or_else(handle_pbft_err);
+        assert_eq!(node.state.mode, PbftMode::ViewChanging);
+        assert_eq!(node.state.pending_view, 1);
+        assert!(node.state.timeout.remaining().is_some());
+    }
+
+    /// Obtain the messages of a type that a node has sent, from its storage
+    fn stored_own_messages(
+        storage: &MemoryStorage,
+        msg_type: &PbftMessageType,
+        node_id: u64,
+    ) -> Vec<PbftMessage> {
+        storage
+            .entries()
+            .iter()
+            .filter(|entry| entry.get_message_type() == String::from(msg_type))
+            .map(|entry| {
+                protobuf::parse_from_bytes::<PbftMessage>(entry.get_message().get_message())
+                    .unwrap()
+            })
+            .filter(|msg| {
+                msg.get_info().get_signer_id() == Vec::<u8>::from(mock_peer_id(node_id)).as_slice()
+            })
+            .collect()
+    }
+
+    /// Make sure that a node that crashes in the middle of a block picks up where it left off
+    /// when it restarts:
+    /// + Votes are written to storage when they're sent
+    /// + The restarted node's phase, sequence number, and working block are restored
+    /// + Votes sent before the crash count towards `prepared` and `committed` after it
+    /// + The restarted node won't vote for a different block with the same sequence number
+    #[test]
+    fn crash_recovery() {
+        let storage = MemoryStorage::new();
+        let block = mock_block(1);
+
+        let mut node1 = mock_node_with_storage(1, storage.clone());
+        node1
+            .on_block_new(block.clone())
+            .unwrap_or_else(handle_pbft_err);
+        let msg = mock_msg(&PbftMessageType::PrePrepare, 0, 1, block.clone(), 0);
+        node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Preparing);
+        assert_eq!(
+            stored_own_messages(&storage, &PbftMessageType::Prepare, 1).len(),
+            1
+        );
+
+        // Crash while preparing
+        drop(node1);
+        let mut node1 = mock_node_with_storage(1, storage.clone());
+        assert_eq!(node1.state.phase, PbftPhase::Preparing);
+        assert_eq!(node1.state.seq_num, 1);
+        assert_eq!(
+            node1.state.working_block,
+            WorkingBlockOption::WorkingBlock(pbft_block_from_block(block.clone()))
+        );
+
+        // The node's own Prepare from before the crash counts
+        for peer in &[0, 2] {
+            let msg = mock_msg(&PbftMessageType::Prepare, 0, 1, block.clone(), *peer);
+            node1.on_peer_message(&msg).unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node1.state.phase, PbftPhase::Checking);
+        node1
+            .on_block_valid(mock_block_id(1))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Committing);
+        let commits = stored_own_messages(&storage, &PbftMessageType::Commit, 1);
+        assert_eq!(commits.len(), 1);
+        assert_eq!(
+            commits[0].get_block().get_block_id(),
+            Vec::<u8>::from(mock_block_id(1)).as_slice()
+        );
+
+        // Crash while committing
+        drop(node1);
+        let mut node1 = mock_node_with_storage(1, storage.clone());
+        assert_eq!(node1.state.phase, PbftPhase::Committing);
+        assert_eq!(node1.state.seq_num, 1);
+
+        // Voting for a different block now would be equivocating
+        let num_entries = storage.entries().len();
+        match node1._broadcast_pbft_message(
+            1,
+            &PbftMessageType::Commit,
+            pbft_block_from_block(mock_block(2)),
+        ) {
+            Err(PbftError::MessageExists(PbftMessageType::Commit)) => (),
+            res => panic!("Conflicting Commit was not refused: {:?}", res),
+        }
+        assert_eq!(storage.entries().len(), num_entries);
+
+        // Sending the same vote again is fine
+        node1
+            ._broadcast_pbft_message(1, &PbftMessageType::Commit, pbft_block_from_block(block))
+            .unwrap_or_else(handle_pbft_err);
+    }
+
+    /// Make sure that conflicting votes are caught even when they arrive too late for the node to
+    /// act on them, and that a node that keeps equivocating is only recorded once
+    #[test]
+    fn past_equivocation() {
+        let mut node = mock_node(1);
+        node.state.seq_num = 1;
+
+        for block_num in 1..4 {
+            let msg = mock_msg(&PbftMessageType::Prepare, 0, 1, mock_block(block_num), 2);
+            match node.on_peer_message(&msg) {
+                Err(PbftError::NotReadyForMessage) => (),
+                res => panic!("Past message wasn't just logged: {:?}", res),
+            }
+        }
+
+        let evidence = node.msg_log.take_evidence();
+        assert_eq!(evidence.len(), 1);
+        assert_eq!(
+            evidence::verify_evidence(&evidence[0]).unwrap(),
+            mock_peer_id(2)
+        );
+    }
+
+    /// Make sure that messages signed by someone other than their claimed signer, sent as a
+    /// different type than they were signed as, or signed by a node outside the network are
+    /// rejected without affecting the node's state
+    #[test]
+    fn forged_message() {
+        let mut node1 = mock_node(1);
+        let block = mock_block(1);
+        node1
+            .on_block_new(block.clone())
+            .unwrap_or_else(handle_pbft_err);
+
+        // Node 2 pretends to be the primary
+        let mut pbft_msg = PbftMessage::new();
+        pbft_msg.set_info(make_msg_info(
+            &PbftMessageType::PrePrepare,
+            0,
+            1,
+            mock_peer_id(0),
+        ));
+        pbft_msg.set_block(pbft_block_from_block(block.clone()));
+        let msg_bytes = pbft_msg.write_to_bytes().unwrap();
+        let forged = mock_signed_msg(&PbftMessageType::PrePrepare, &msg_bytes, 2);
+
+        match node1.on_peer_message(&forged) {
+            Err(PbftError::InvalidSignature(_)) => (),
+            res => panic!("Forged message was not rejected: {:?}", res),
+        }
+        assert_eq!(node1.state.phase, PbftPhase::PrePreparing);
+        assert_eq!(node1.state.seq_num, 0);
+
+        // An unsigned message is rejected as well
+        let unsigned = PeerMessage {
+            message_type: String::from(&PbftMessageType::PrePrepare),
+            content: msg_bytes,
+        };
+        assert!(node1.on_peer_message(&unsigned).is_err());
+        assert_eq!(node1.state.phase, PbftPhase::PrePreparing);
+
+        // A message from the primary can't be passed off as a different type of message
+        let mut relabeled = mock_msg(&PbftMessageType::Commit, 0, 1, block.clone(), 0);
+        relabeled.message_type = String::from(&PbftMessageType::PrePrepare);
+        match node1.on_peer_message(&relabeled) {
+            Err(PbftError::MessageTypeMismatch(_)) => (),
+            res => panic!("Relabeled message was not rejected: {:?}", res),
+        }
+        assert_eq!(node1.state.phase, PbftPhase::PrePreparing);
+
+        // A validly signed message from a node that isn't in the network is rejected
+        let outsider = mock_msg(&PbftMessageType::PrePrepare, 0, 1, block.clone(), 5);
+        match node1.on_peer_message(&outsider) {
+            Err(PbftError::UnknownSigner(_)) => (),
+            res => panic!("Message from outside the network was accepted: {:?}", res),
+        }
+        assert_eq!(node1.state.phase, PbftPhase::PrePreparing);
+        assert_eq!(node1.state.seq_num, 0);
+
+        // The real primary's message goes through
+        let genuine = mock_msg(&PbftMessageType::PrePrepare, 0, 1, block, 0);
+        node1
+            .on_peer_message(&genuine)
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Preparing);
+    }
+
+    /// Make sure that a node that connects to the network gets added to it once `2f + 1` nodes
+    /// agree to the change, and that the change waits for the current block to be finished
+    #[test]
+    fn network_change() {
+        let storage = MemoryStorage::new();
+        let mut node1 = mock_node_with_storage(1, storage.clone());
+        node1
+            .on_peer_connected(mock_peer_id(4))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.candidate_peers.len(), 5);
+        assert_eq!(node1.state.get_peer_ids().len(), 4);
+
+        // Start working on a block
+        node1
+            .on_block_new(mock_block(1))
+            .unwrap_or_else(handle_pbft_err);
+        node1
+            .on_peer_message(&mock_msg(
+                &PbftMessageType::PrePrepare,
+                0,
+                1,
+                mock_block(1),
+                0,
+            ))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node1.state.phase, PbftPhase::Preparing);
+
+        // The primary proposes the change, and node 1 agrees to it
+        node1
+            .on_peer_message(&mock_network_change_msg(0, 1, 5, true, 0))
+            .unwrap_or_else(handle_pbft_err);
+        let finals: Vec<PbftNetworkChange> = storage
+            .entries()
+            .iter()
+            .filter(|entry| entry.get_message_type() == "NetworkChange")
+            .map(|entry| {
+                protobuf::parse_from_bytes::<PbftNetworkChange>(entry.get_message().get_message())
+                    .unwrap()
+            })
+            .filter(|nc| {
+                nc.get_info().get_signer_id() == Vec::<u8>::from(mock_peer_id(1)).as_slice()
+            })
+            .collect();
+        assert_eq!(finals.len(), 1);
+        assert!(!finals[0].get_tentative());
+        assert_eq!(finals[0].get_peers().len(), 5);
+
+        // A tentative proposal from someone other than the primary is rejected
+        match node1.on_peer_message(&mock_network_change_msg(0, 1, 5, true, 2)) {
+            Err(PbftError::InvalidNetworkChange(_)) => (),
+            res => panic!(
+                "Tentative NetworkChange from non-primary accepted: {:?}",
+                res
+            ),
+        }
+
+        // 2f + 1 final messages are required (including node 1's own)
+        for peer in 0..3 {
+            node1
+                .on_peer_message(&mock_network_change_msg(0, 1, 5, false, peer))
+                .unwrap_or_else(handle_pbft_err);
+        }
+        assert!(node1.state.pending_network_change.is_some());
+        assert_eq!(node1.state.get_peer_ids().len(), 4);
+
+        // The change takes effect once block 1 is committed
+        node1.state.phase = PbftPhase::Finished;
+        node1
+            .on_block_commit(mock_block_id(1))
+            .unwrap_or_else(handle_pbft_err);
+        assert!(node1.state.pending_network_change.is_none());
+        assert_eq!(node1.state.get_peer_ids().len(), 5);
+        assert_eq!(node1.state.f, 1);
+        assert_eq!(node1.state.mode, PbftMode::Normal);
+    }
+
+    /// Make sure that a node that isn't in the network waits in `Connecting` mode until the
+    /// network agrees to add it, then catches up to the network's view and sequence number
+    #[test]
+    fn joining_node() {
+        let mut node4 = mock_node_in_network(4, 4, MemoryStorage::new());
+        assert_eq!(node4.state.mode, PbftMode::Connecting);
+        assert!(!node4.state.is_member());
+
+        // Regular messages are ignored until the node is part of the network
+        node4
+            .on_peer_message(&mock_msg(
+                &PbftMessageType::PrePrepare,
+                0,
+                1,
+                mock_block(1),
+                0,
+            ))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node4.state.phase, PbftPhase::NotStarted);
+        assert_eq!(node4.state.seq_num, 0);
+
+        for peer in 0..3 {
+            node4
+                .on_peer_message(&mock_network_change_msg(2, 7, 5, false, peer))
+                .unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node4.state.mode, PbftMode::Normal);
+        assert!(node4.state.is_member());
+        assert_eq!(node4.state.get_peer_ids().len(), 5);
+        assert_eq!(node4.state.view, 2);
+        assert_eq!(node4.state.seq_num, 7);
+    }
+
+    /// Make sure that a network that is too small to be Byzantine fault tolerant waits for more
+    /// nodes to connect before it starts
+    #[test]
+    fn connecting_network() {
+        let mut node0 = mock_node_in_network(0, 3, MemoryStorage::new());
+        assert_eq!(node0.state.mode, PbftMode::Connecting);
+        assert_eq!(node0.state.f, 0);
+
+        // The primary doesn't publish blocks while it's connecting
+        node0
+            .on_block_new(mock_block(1))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node0.state.phase, PbftPhase::NotStarted);
+
+        // Once a fourth node connects and the others agree, the network can start
+        node0
+            .on_peer_connected(mock_peer_id(3))
+            .unwrap_or_else(handle_pbft_err);
+        for peer in 1..3 {
+            node0
+                .on_peer_message(&mock_network_change_msg(0, 0, 4, false, peer))
+                .unwrap_or_else(handle_pbft_err);
+        }
+        assert_eq!(node0.state.mode, PbftMode::Connecting);
+        node0
+            .on_peer_message(&mock_network_change_msg(0, 0, 4, false, 0))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(node0.state.mode, PbftMode::Normal);
+        assert_eq!(node0.state.f, 1);
+    }
+
+    /// Create a signed CatchUp PeerMessage from node 0, with the seals for the given blocks
+    fn mock_catch_up_msg(block_nums: &[u64]) -> PeerMessage {
+        let mut catch_up = PbftCatchUp::new();
+        catch_up.set_info(make_msg_info(
+            &PbftMessageType::CatchUp,
+            0,
+            *block_nums.last().unwrap(),
+            mock_peer_id(0),
+        ));
+        for num in block_nums {
+            catch_up
+                .mut_seals()
+                .push(protobuf::parse_from_bytes::<PbftSeal>(&mock_seal(*num)).unwrap());
+        }
+
+        let msg_bytes = catch_up.write_to_bytes().expect("SerializationError");
+        mock_signed_msg(&PbftMessageType::CatchUp, &msg_bytes, 0)
+    }
+
+    /// Make sure that a node that fell behind commits the blocks that a `CatchUp` message proves
+    /// were committed (including the ones before the first seal, which are proven by the seals in
+    /// the blocks after them), and takes the sequence number they were committed at
+    #[test]
+    fn catch_up() {
+        const CATCH_UP_BLOCK_FILE: &str = "catch_up_blocks.txt";
+
+        let mut node1 = mock_node(1);
+        node1.service = Box::new(MockService {
+            chain: vec![mock_block_id(0)],
+            block_file: CATCH_UP_BLOCK_FILE,
+        });
+
+        // A seal without enough Commits doesn't prove anything
+        let mut catch_up = PbftCatchUp::new();
+        catch_up.set_info(make_msg_info(
+            &PbftMessageType::CatchUp,
+            0,
+            3,
+            mock_peer_id(0),
+        ));
+        let mut seal = protobuf::parse_from_bytes::<PbftSeal>(&mock_seal(3)).unwrap();
+        seal.mut_commit_messages().pop();
+        catch_up.mut_seals().push(seal);
+        let msg = mock_signed_msg(
+            &PbftMessageType::CatchUp,
+            &catch_up.write_to_bytes().unwrap(),
+            0,
+        );
+        match node1.on_peer_message(&msg) {
+            Err(PbftError::InvalidSeal(_)) => (),
+            res => panic!("CatchUp with an invalid seal was accepted: {:?}", res),
+        }
+        assert_eq!(node1.service.get_chain_head().unwrap().block_num, 0);
+
+        node1
+            .on_peer_message(&mock_catch_up_msg(&[2, 3]))
+            .unwrap_or_else(handle_pbft_err);
+        let head = node1.service.get_chain_head().unwrap();
+        assert_eq!(head.block_id, mock_block_id(3));
+        assert_eq!(node1.state.seq_num, 3);
+        assert_eq!(node1.state.phase, PbftPhase::NotStarted);
+
+        // The node can prove that the last block was committed
+        assert!(node1
+            .msg_log
+            .get_commit_certificate(&Vec::<u8>::from(mock_block_id(3)), 3)
+            .is_some());
+
+        // Old news doesn't change anything
+        node1
+            .on_peer_message(&mock_catch_up_msg(&[2]))
+            .unwrap_or_else(handle_pbft_err);
+        assert_eq!(
+            node1.service.get_chain_head().unwrap().block_id,
+            mock_block_id(3)
+        );
+
+        node1
+            .on_block_commit(mock_block_id(3))
+            .unwrap_or_else(handle_pbft_err);
+        assert!(node1.catch_up_head.is_none());
+
+        remove_file(CATCH_UP_BLOCK_FILE).unwrap();
     }
 }
@@ -0,0 +1,230 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Hooks for reacting to consensus events without changing the engine
+//!
+//! Anything that implements `PbftObserver` can be added to a `PbftEngine` (or directly to a
+//! `PbftNode`), and is told when the node's validator commits a block, when enough nodes agree to
+//! change views, when a checkpoint becomes stable, and when a peer looks faulty.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
+
+use error::PbftError;
+use state::PbftState;
+
+/// Callbacks for consensus events; every callback does nothing unless it's overridden
+pub trait PbftObserver {
+    /// The validator committed a block
+    fn block_committed(&mut self, _state: &PbftState, _block_id: &BlockId) {}
+
+    /// `2f + 1` nodes (including this one) asked to change to `view`
+    fn view_changed(&mut self, _state: &PbftState, _view: u64) {}
+
+    /// The checkpoint at `seq_num` became stable, and everything before it was dropped from the
+    /// log
+    fn checkpoint_stabilized(&mut self, _seq_num: u64, _view: u64) {}
+
+    /// Handling an update failed in a way that means another node is faulty; `peer_id` is the
+    /// peer that sent the update, if it came from a peer
+    fn peer_suspected(&mut self, _state: &PbftState, _peer_id: Option<&PeerId>, _err: &PbftError) {}
+}
+
+/// A node's observers; clones share the same observers, so the node and its log can both tell
+/// them about events
+#[derive(Clone, Default)]
+pub struct PbftObservers(Rc<RefCell<Vec<Box<PbftObserver>>>>);
+
+impl PbftObservers {
+    pub fn new() -> Self {
+        PbftObservers::default()
+    }
+
+    pub fn add(&self, observer: Box<PbftObserver>) {
+        self.0.borrow_mut().push(observer);
+    }
+
+    pub fn block_committed(&self, state: &PbftState, block_id: &BlockId) {
+        self.each(|observer| observer.block_committed(state, block_id));
+    }
+
+    pub fn view_changed(&self, state: &PbftState, view: u64) {
+        self.each(|observer| observer.view_changed(state, view));
+    }
+
+    pub fn checkpoint_stabilized(&self, seq_num: u64, view: u64) {
+        self.each(|observer| observer.checkpoint_stabilized(seq_num, view));
+    }
+
+    pub fn peer_suspected(&self, state: &PbftState, peer_id: Option<&PeerId>, err: &PbftError) {
+        self.each(|observer| observer.peer_suspected(state, peer_id, err));
+    }
+
+    fn each<F: FnMut(&mut PbftObserver)>(&self, mut callback: F) {
+        for observer in self.0.borrow_mut().iter_mut() {
+            callback(&mut **observer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byzantine::Fault;
+    use config::mock_config;
+    use engine::handle_pbft_result;
+    use simulator::SimulatedNetwork;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Committed(BlockId),
+        ViewChanged(u64),
+        Stabilized(u64),
+        Suspected(Option<PeerId>),
+    }
+
+    /// Keeps every event it's told about, where the test can see them
+    struct Recorder(Rc<RefCell<Vec<Event>>>);
+
+    impl PbftObserver for Recorder {
+        fn block_committed(&mut self, _state: &PbftState, block_id: &BlockId) {
+            self.0.borrow_mut().push(Event::Committed(block_id.clone()));
+        }
+
+        fn view_changed(&mut self, _state: &PbftState, view: u64) {
+            self.0.borrow_mut().push(Event::ViewChanged(view));
+        }
+
+        fn checkpoint_stabilized(&mut self, seq_num: u64, _view: u64) {
+            self.0.borrow_mut().push(Event::Stabilized(seq_num));
+        }
+
+        fn peer_suspected(
+            &mut self,
+            _state: &PbftState,
+            peer_id: Option<&PeerId>,
+            _err: &PbftError,
+        ) {
+            self.0.borrow_mut().push(Event::Suspected(peer_id.cloned()));
+        }
+    }
+
+    fn observe(sim: &mut SimulatedNetwork, node: usize) -> Rc<RefCell<Vec<Event>>> {
+        let events = Rc::new(RefCell::new(vec![]));
+        sim.nodes[node].add_observer(Box::new(Recorder(events.clone())));
+        events
+    }
+
+    /// Observers hear about every block that the node commits, in order
+    #[test]
+    fn block_committed() {
+        let mut sim = SimulatedNetwork::new(4, 1);
+        let events = observe(&mut sim, 1);
+        assert!(sim.run_until_height(3, 20_000));
+
+        let chain: Vec<BlockId> = sim
+            .chain(1)
+            .into_iter()
+            .map(|block| block.block_id)
+            .collect();
+        let committed: Vec<BlockId> = events
+            .borrow()
+            .iter()
+            .filter_map(|event| match event {
+                Event::Committed(block_id) => Some(block_id.clone()),
+                _ => None,
+            })
+            .collect();
+        assert!(committed.len() >= 2);
+        let start = chain
+            .iter()
+            .position(|block_id| *block_id == committed[0])
+            .expect("Committed a block that isn't on the chain");
+        assert_eq!(&chain[start..start + committed.len()], &committed[..]);
+    }
+
+    /// Observers added to one node hear about the checkpoints that become stable in its log
+    #[test]
+    fn checkpoint_stabilized() {
+        let mut config = mock_config(4);
+        config.checkpoint_period = 2;
+        let mut sim = SimulatedNetwork::with_config(&config, 2);
+        let events = observe(&mut sim, 2);
+        assert!(sim.run_until_height(5, 20_000));
+
+        let checkpoints: Vec<u64> = events
+            .borrow()
+            .iter()
+            .filter_map(|event| match event {
+                Event::Stabilized(seq_num) => Some(*seq_num),
+                _ => None,
+            })
+            .collect();
+        let latest = sim.nodes[2]
+            .msg_log
+            .latest_stable_checkpoint
+            .as_ref()
+            .expect("No stable checkpoint")
+            .seq_num;
+        assert_eq!(checkpoints.last(), Some(&latest));
+    }
+
+    /// When the primary crashes, each of the other nodes' observers hears about the view change
+    /// exactly once
+    #[test]
+    fn view_changed() {
+        let mut sim = SimulatedNetwork::new(4, 3);
+        let observed: Vec<_> = (1..4).map(|i| observe(&mut sim, i)).collect();
+        assert!(sim.run_until_height(2, 20_000));
+
+        sim.crash(0);
+        for i in 1..4 {
+            sim.expire_timeout(i);
+        }
+        assert!(sim.run_until(20_000, |sim| (1..4).all(|i| sim.nodes[i].state.view == 1)));
+
+        for events in observed {
+            let events = events.borrow();
+            let view_changes: Vec<&Event> = events
+                .iter()
+                .filter(|event| match event {
+                    Event::ViewChanged(_) => true,
+                    _ => false,
+                })
+                .collect();
+            assert_eq!(view_changes, vec![&Event::ViewChanged(1)]);
+        }
+    }
+
+    /// Messages with forged signatures make the node suspect the peer that sent them; errors that
+    /// don't show a fault don't
+    #[test]
+    fn peer_suspected() {
+        let mut sim = SimulatedNetwork::new(4, 4);
+        let events = observe(&mut sim, 1);
+
+        handle_pbft_result(&sim.nodes[1], None, Err(PbftError::NotReadyForMessage));
+        assert!(events.borrow().is_empty());
+
+        sim.set_fault(0, Some(Fault::ForgeSigner));
+        let faulty_id = sim.nodes[0].state.get_own_peer_id();
+        assert!(sim.run_until(20_000, |_| !events.borrow().is_empty()));
+        assert_eq!(events.borrow()[0], Event::Suspected(Some(faulty_id)));
+    }
+}
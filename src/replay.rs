@@ -0,0 +1,434 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Replaying a trace that an engine recorded into a fresh `PbftNode`, to reproduce what happened
+//! to the node offline
+//!
+//! The replayed node gets the same updates at the same (simulated) times as the recorded one, and
+//! does its periodic work the same way the engine does. What it sends goes nowhere; the replies it
+//! got from its peers and its validator are already in the trace.
+//!
+//! The blocks the node commits, and the messages it sends and receives, are checked with a
+//! `CommitChecker`, in milliseconds since the trace started.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+use std::vec;
+
+use hex;
+use sawtooth_sdk::consensus::engine::{Block, BlockId, Error, PeerId, PeerMessage, Update};
+use sawtooth_sdk::consensus::service::Service;
+
+use checker::{CommitChecker, CommitHistory, CommittedBlock, Report};
+use config::pbft_config_from_settings;
+use engine::{handle_pbft_result, handle_update};
+use node::PbftNode;
+use signing::PbftSigner;
+use storage::MemoryStorage;
+use timing::{Clock, MockClock, Ticker};
+use trace::{Trace, TraceEvent};
+
+/// Stands in for the validator while a trace is replayed. It knows about the chain head the engine
+/// started with and every block in the trace's `BlockNew` updates, and keeps track of which one is
+/// the chain head as the node commits them.
+///
+/// The validator's replies to the node (`BlockNew`, `BlockValid`, `BlockCommit`, ...) are in the
+/// trace, so the service doesn't send any updates of its own. That includes the blocks the node
+/// published, so the service never finishes building a block.
+///
+/// The messages the node sends are shown to the `CommitChecker`, and the blocks it commits are
+/// recorded along with when they were committed by `clock`, since the service was created.
+pub struct ReplayService {
+    blocks: HashMap<BlockId, Block>,
+    chain_head: Block,
+    settings: HashMap<String, String>,
+    checker: Rc<RefCell<CommitChecker>>,
+    commits: Rc<RefCell<Vec<CommittedBlock>>>,
+    clock: Rc<MockClock>,
+    created: Instant,
+}
+
+impl ReplayService {
+    pub fn new(trace: &Trace, clock: Rc<MockClock>) -> Self {
+        let chain_head = trace.startup_state.chain_head.clone();
+        let mut blocks: HashMap<BlockId, Block> = trace
+            .events
+            .iter()
+            .filter_map(|event| match event.update {
+                Some(Update::BlockNew(ref block)) => Some((block.block_id.clone(), block.clone())),
+                _ => None,
+            })
+            .collect();
+        blocks.insert(chain_head.block_id.clone(), chain_head.clone());
+
+        let peers = pbft_config_from_settings(&trace.settings).peers;
+        ReplayService {
+            blocks,
+            chain_head,
+            settings: trace.settings.clone(),
+            checker: Rc::new(RefCell::new(CommitChecker::new(peers))),
+            commits: Rc::new(RefCell::new(vec![])),
+            created: clock.now(),
+            clock,
+        }
+    }
+
+    fn observe(&self, message_type: &str, payload: &[u8]) {
+        self.checker.borrow_mut().observe_message(&PeerMessage {
+            message_type: String::from(message_type),
+            content: payload.to_vec(),
+        });
+    }
+}
+
+impl Service for ReplayService {
+    fn send_to(
+        &mut self,
+        peer: &PeerId,
+        message_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.observe(message_type, &payload);
+        debug!(
+            "Node sent {} to {}",
+            message_type,
+            hex::encode(Vec::<u8>::from(peer.clone()))
+        );
+        Ok(())
+    }
+
+    fn broadcast(&mut self, message_type: &str, payload: Vec<u8>) -> Result<(), Error> {
+        self.observe(message_type, &payload);
+        debug!("Node broadcast {}", message_type);
+        Ok(())
+    }
+
+    fn initialize_block(&mut self, _previous_id: Option<BlockId>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+        Err(Error::BlockNotReady)
+    }
+
+    fn finalize_block(&mut self, _data: Vec<u8>) -> Result<BlockId, Error> {
+        Err(Error::BlockNotReady)
+    }
+
+    fn cancel_block(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_blocks(&mut self, _priority: Vec<BlockId>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn commit_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        self.chain_head = self
+            .blocks
+            .get(&block_id)
+            .cloned()
+            .ok_or_else(|| Error::UnknownBlock(format!("{:?}", block_id)))?;
+        self.commits.borrow_mut().push(CommittedBlock {
+            block_id,
+            block_num: self.chain_head.block_num,
+            at: millis(self.clock.now() - self.created),
+        });
+        Ok(())
+    }
+
+    fn ignore_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn fail_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn get_blocks(&mut self, block_ids: Vec<BlockId>) -> Result<HashMap<BlockId, Block>, Error> {
+        Ok(block_ids
+            .into_iter()
+            .filter_map(|id| self.blocks.get(&id).map(|block| (id, block.clone())))
+            .collect())
+    }
+
+    fn get_chain_head(&mut self) -> Result<Block, Error> {
+        Ok(self.chain_head.clone())
+    }
+
+    fn get_settings(
+        &mut self,
+        _block_id: BlockId,
+        keys: Vec<String>,
+    ) -> Result<HashMap<String, String>, Error> {
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| self.settings.get(&key).map(|value| (key, value.clone())))
+            .collect())
+    }
+
+    fn get_state(
+        &mut self,
+        _block_id: BlockId,
+        _addresses: Vec<String>,
+    ) -> Result<HashMap<String, Vec<u8>>, Error> {
+        Ok(Default::default())
+    }
+}
+
+/// A node that a trace is being replayed into, one event at a time
+pub struct Replay {
+    pub node: PbftNode,
+
+    /// How many events have been replayed so far
+    pub steps: usize,
+
+    events: vec::IntoIter<TraceEvent>,
+    next_event: Option<TraceEvent>,
+    checker: Rc<RefCell<CommitChecker>>,
+    commits: Rc<RefCell<Vec<CommittedBlock>>>,
+    clock: Rc<MockClock>,
+    elapsed: Duration,
+    working_ticker: Ticker,
+    backlog_ticker: Ticker,
+}
+
+impl Replay {
+    /// Start a node the same way the engine that recorded the trace did. The node signs its
+    /// messages with `signer`, which only matters if it has to verify its own messages; it should
+    /// be the recorded node's key if that's available.
+    pub fn new(trace: Trace, signer: PbftSigner) -> Self {
+        // Times are measured from when the trace started, so the service has to be created first
+        let clock = Rc::new(MockClock::new());
+        let service = Box::new(ReplayService::new(&trace, clock.clone()));
+        let checker = service.checker.clone();
+        let commits = service.commits.clone();
+        let start = trace.start;
+        clock.advance(start);
+
+        let config = pbft_config_from_settings(&trace.settings);
+        let Trace {
+            startup_state,
+            events,
+            ..
+        } = trace;
+
+        let mut node = PbftNode::new(
+            &startup_state.local_peer_info.peer_id,
+            &config,
+            service,
+            signer,
+            Box::new(MemoryStorage::new()),
+            clock.clone(),
+        );
+        for peer in startup_state.peers {
+            let res = node.on_peer_connected(peer.peer_id);
+            handle_pbft_result(&node, None, res);
+        }
+
+        let mut events = events.into_iter();
+        Replay {
+            node,
+            steps: 0,
+            next_event: events.next(),
+            events,
+            checker,
+            commits,
+            working_ticker: Ticker::new(config.block_duration, clock.clone()),
+            backlog_ticker: Ticker::new(config.message_timeout, clock.clone()),
+            elapsed: start,
+            clock,
+        }
+    }
+
+    /// The event that will be replayed next, if the trace isn't over
+    pub fn next_event(&self) -> Option<&TraceEvent> {
+        self.next_event.as_ref()
+    }
+
+    /// Replay the next event, after moving the clock forward to when it happened; returns `false`
+    /// once there's nothing left to replay, or the node has stopped
+    pub fn step(&mut self) -> bool {
+        let event = match self.next_event.take() {
+            Some(event) => event,
+            None => return false,
+        };
+        self.next_event = self.events.next();
+        self.steps += 1;
+
+        if event.elapsed > self.elapsed {
+            self.clock.advance(event.elapsed - self.elapsed);
+            self.elapsed = event.elapsed;
+        }
+
+        if let Some(Update::PeerMessage(ref message, _)) = event.update {
+            self.checker.borrow_mut().observe_message(message);
+        }
+        let keep_going = handle_update(
+            &mut self.node,
+            event.update.ok_or(RecvTimeoutError::Timeout),
+            &mut self.working_ticker,
+            &mut self.backlog_ticker,
+        );
+        keep_going && self.next_event.is_some()
+    }
+
+    /// Check the blocks the node has committed so far, allowing it to go `max_gap` milliseconds
+    /// without committing a block (or forever, if `max_gap` is `None`). Blocks only count as
+    /// pre-prepared if the node saw the `PrePrepare` or `NewView` for them, so blocks that the node
+    /// caught up on are reported as not pre-prepared.
+    pub fn report(&self, max_gap: Option<u64>) -> Report {
+        let history = CommitHistory {
+            node: self.node.state.id,
+            commits: self.commits.borrow().clone(),
+            end: millis(self.elapsed),
+        };
+        self.checker.borrow().check(&[history], max_gap)
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use sawtooth_sdk::consensus::engine::{PeerInfo, StartupState};
+    use serde_json;
+    use signing::mock_signer;
+
+    fn mock_block(num: u64) -> Block {
+        Block {
+            block_id: BlockId::from(vec![num as u8; 4]),
+            previous_id: BlockId::from(vec![num.saturating_sub(1) as u8; 4]),
+            signer_id: PeerId::from(vec![]),
+            block_num: num,
+            payload: vec![],
+            summary: vec![],
+        }
+    }
+
+    /// Create a trace of node 1 in a network of four nodes, with the given events
+    fn mock_trace(events: Vec<TraceEvent>) -> Trace {
+        let peers: Vec<String> = mock_config(4)
+            .peers
+            .into_iter()
+            .map(|peer_id| hex::encode(Vec::<u8>::from(peer_id)))
+            .collect();
+        let mut settings = HashMap::new();
+        settings.insert(
+            String::from("sawtooth.consensus.pbft.peers"),
+            serde_json::to_string(&peers).unwrap(),
+        );
+
+        Trace {
+            startup_state: StartupState {
+                chain_head: mock_block(0),
+                peers: vec![],
+                local_peer_info: PeerInfo {
+                    peer_id: mock_config(4).peers[1].clone(),
+                },
+            },
+            settings,
+            start: Duration::from_millis(10),
+            events,
+        }
+    }
+
+    fn timeout_at(millis: u64) -> TraceEvent {
+        TraceEvent {
+            elapsed: Duration::from_millis(millis),
+            update: None,
+        }
+    }
+
+    /// The service knows about every block in the trace, and moves the chain head (and records
+    /// when) when the node commits one
+    #[test]
+    fn replay_service() {
+        let trace = mock_trace(vec![TraceEvent {
+            elapsed: Duration::from_millis(20),
+            update: Some(Update::BlockNew(mock_block(1))),
+        }]);
+        let clock = Rc::new(MockClock::new());
+        let mut service = ReplayService::new(&trace, clock.clone());
+        clock.advance(Duration::from_millis(25));
+
+        let blocks = service
+            .get_blocks(vec![mock_block(0).block_id, mock_block(1).block_id])
+            .unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(service.get_chain_head().unwrap().block_num, 0);
+
+        service.commit_block(mock_block(1).block_id).unwrap();
+        assert_eq!(service.get_chain_head().unwrap().block_num, 1);
+        assert!(service.commit_block(mock_block(2).block_id).is_err());
+        assert_eq!(
+            *service.commits.borrow(),
+            vec![CommittedBlock {
+                block_id: mock_block(1).block_id,
+                block_num: 1,
+                at: 25,
+            }]
+        );
+
+        let settings = service
+            .get_settings(
+                mock_block(1).block_id,
+                vec![String::from("sawtooth.consensus.pbft.peers")],
+            )
+            .unwrap();
+        assert_eq!(settings, trace.settings);
+    }
+
+    /// Events are replayed one at a time, until the node shuts down
+    #[test]
+    fn replay_until_shutdown() {
+        let trace = mock_trace(vec![
+            timeout_at(20),
+            timeout_at(30),
+            TraceEvent {
+                elapsed: Duration::from_millis(40),
+                update: Some(Update::Shutdown),
+            },
+            timeout_at(50),
+        ]);
+        let mut replay = Replay::new(trace, mock_signer(1));
+        assert_eq!(replay.node.state.id, 1);
+
+        assert!(replay.step());
+        assert!(replay.step());
+        assert_eq!(
+            replay.next_event().map(|event| event.elapsed),
+            Some(Duration::from_millis(40))
+        );
+        assert!(!replay.step());
+        assert_eq!(replay.steps, 3);
+        assert_eq!(replay.elapsed, Duration::from_millis(40));
+
+        // Nothing was committed in the 40ms the trace covers
+        assert!(replay.report(Some(40)).is_ok());
+        let report = replay.report(Some(30));
+        assert!(report.is_safe());
+        assert!(!report.is_live());
+    }
+}
@@ -0,0 +1,837 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! Deterministic, in-process network of `PbftNode`s, for testing how nodes behave together
+//!
+//! Each node gets a `SimulatedService` in place of a validator. Messages that a node sends to its
+//! peers go into a queue for each (sender, receiver) link, and blocks that a node publishes are
+//! sent to every node as `BlockNew` updates the same way. On each step, the network uses a seeded
+//! random number generator to either deliver the next update on one of the links, or to let one
+//! of the nodes do its periodic work (publishing blocks and retrying its backlog), so the same
+//! seed always produces the same run.
+//!
+//! Tests can also make each of these things happen directly, as `SimEvent`s; the `explorer`
+//! does this to try every order of events in a small network.
+//!
+//! Each node has its own `MockClock`, which only moves forward when the node's view change timeout
+//! is made to expire: when a test calls `expire_timeout`, or when `run_with_timeouts` decides that
+//! the network has stalled.
+//!
+//! Links are reliable unless the network is given a `lossy::LossyTransport`, which can lose,
+//! delay, duplicate, and reorder updates, and partition the network.
+//!
+//! The network keeps track of when each node committed each block, and watches the messages that
+//! nodes send, so that `report` can check the run with a `checker::CommitChecker`.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::Duration;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use sawtooth_sdk::consensus::engine::{Block, BlockId, Error, PeerId, PeerMessage, Update};
+use sawtooth_sdk::consensus::service::Service;
+
+use byzantine::Fault;
+use checker::{CommitChecker, CommitHistory, CommittedBlock, Report};
+use config::{mock_config, PbftConfig};
+use engine::handle_pbft_result;
+use error::PbftError;
+use lossy::LossyTransport;
+use message_type::PbftMessageType;
+use node::PbftNode;
+use signing::mock_signer;
+use storage::MemoryStorage;
+use timing::MockClock;
+
+/// Pseudorandom number generator (SplitMix64), so that a seed always gives the same delivery
+/// order
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// `true` with probability `p`; never uses up a number if `p` is zero
+    pub fn chance(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        let sample = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        sample < p
+    }
+}
+
+/// Everything the simulated validators share: the links between them, and the blocks that have
+/// been published
+struct NetworkState {
+    peers: Vec<PeerId>,
+
+    /// Updates that are on their way from one node to another, by (sender, receiver), along
+    /// with the step they can be delivered at; a node's own validator sends it updates on the
+    /// (node, node) link
+    links: BTreeMap<(usize, usize), VecDeque<(u64, Update)>>,
+
+    /// Decides what happens to the updates that are sent over each link
+    transport: LossyTransport,
+
+    /// Messages that nodes broadcast, which they also have to handle themselves before doing
+    /// anything else
+    loopback: VecDeque<(usize, PeerMessage)>,
+
+    /// Every block that has been published, by ID
+    blocks: HashMap<BlockId, Block>,
+
+    /// The blocks each node has committed, starting with genesis
+    chains: Vec<Vec<Block>>,
+
+    /// The block each node's validator is building on, if it's building a block
+    building: Vec<Option<Block>>,
+
+    /// Nodes that have crashed, which don't send or receive anything
+    crashed: Vec<bool>,
+
+    /// The ways in which Byzantine nodes tamper with what they send to other nodes
+    faults: Vec<Option<Fault>>,
+
+    /// How many events have happened so far
+    steps: u64,
+
+    /// The blocks each node has committed, along with when
+    commits: Vec<Vec<CommittedBlock>>,
+
+    /// Watches every message that's sent, for evidence of which blocks were pre-prepared
+    checker: CommitChecker,
+}
+
+impl NetworkState {
+    fn send(&mut self, from: usize, to: usize, update: Update) {
+        if self.crashed[from] || self.crashed[to] {
+            return;
+        }
+        let update = match self.faults[from] {
+            Some(fault) if from != to => {
+                match fault.tamper(from, to, update, &self.peers, &mut self.blocks) {
+                    Some(update) => update,
+                    None => return,
+                }
+            }
+            _ => update,
+        };
+        if let Update::PeerMessage(ref message, _) = update {
+            self.checker.observe_message(message);
+        }
+        let queue = self.links.entry((from, to)).or_insert_with(VecDeque::new);
+        self.transport.send(from, to, self.steps, update, queue);
+    }
+
+    fn peer_message(&self, from: usize, message_type: &str, payload: Vec<u8>) -> Update {
+        Update::PeerMessage(
+            PeerMessage {
+                message_type: String::from(message_type),
+                content: payload,
+            },
+            self.peers[from].clone(),
+        )
+    }
+
+    fn chain_head(&self, node: usize) -> Block {
+        self.chains[node]
+            .last()
+            .expect("Simulated chain has no genesis block")
+            .clone()
+    }
+}
+
+/// Stands in for the validator of one node in the simulated network
+pub struct SimulatedService {
+    node: usize,
+    network: Rc<RefCell<NetworkState>>,
+}
+
+impl Service for SimulatedService {
+    fn send_to(
+        &mut self,
+        peer: &PeerId,
+        message_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        let mut network = self.network.borrow_mut();
+        let to = network
+            .peers
+            .iter()
+            .position(|id| id == peer)
+            .ok_or_else(|| Error::UnknownPeer(format!("{:?}", peer)))?;
+        let update = network.peer_message(self.node, message_type, payload);
+        network.send(self.node, to, update);
+        Ok(())
+    }
+
+    /// Sends the message to every other node, and (in tests) back to this one; see
+    /// `PbftNode::_send_signed_message`
+    fn broadcast(&mut self, message_type: &str, payload: Vec<u8>) -> Result<(), Error> {
+        let mut network = self.network.borrow_mut();
+        for to in (0..network.peers.len()).filter(|to| *to != self.node) {
+            let update = network.peer_message(self.node, message_type, payload.clone());
+            network.send(self.node, to, update);
+        }
+        let message = PeerMessage {
+            message_type: String::from(message_type),
+            content: payload,
+        };
+        network.checker.observe_message(&message);
+
+        // Outside of tests (in the benchmarks), nodes handle their own messages as soon as they
+        // send them
+        if cfg!(test) {
+            network.loopback.push_back((self.node, message));
+        }
+        Ok(())
+    }
+
+    fn initialize_block(&mut self, previous_id: Option<BlockId>) -> Result<(), Error> {
+        let mut network = self.network.borrow_mut();
+        let previous = match previous_id {
+            Some(id) => network
+                .blocks
+                .get(&id)
+                .cloned()
+                .ok_or_else(|| Error::UnknownBlock(format!("{:?}", id)))?,
+            None => network.chain_head(self.node),
+        };
+        network.building[self.node] = Some(previous);
+        Ok(())
+    }
+
+    fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+        match self.network.borrow().building[self.node] {
+            Some(ref previous) => Ok(block_summary(self.node, previous)),
+            None => Err(Error::BlockNotReady),
+        }
+    }
+
+    fn finalize_block(&mut self, data: Vec<u8>) -> Result<BlockId, Error> {
+        let mut network = self.network.borrow_mut();
+        let previous = network.building[self.node]
+            .take()
+            .ok_or_else(|| Error::InvalidState(String::from("No block initialized")))?;
+
+        let block = Block {
+            block_id: sim_block_id(self.node, previous.block_num + 1, &previous.block_id),
+            previous_id: previous.block_id.clone(),
+            signer_id: network.peers[self.node].clone(),
+            block_num: previous.block_num + 1,
+            payload: data,
+            summary: block_summary(self.node, &previous),
+        };
+        network.blocks.insert(block.block_id.clone(), block.clone());
+        for to in 0..network.peers.len() {
+            network.send(self.node, to, Update::BlockNew(block.clone()));
+        }
+        Ok(block.block_id)
+    }
+
+    fn cancel_block(&mut self) -> Result<(), Error> {
+        self.network.borrow_mut().building[self.node] = None;
+        Ok(())
+    }
+
+    fn check_blocks(&mut self, priority: Vec<BlockId>) -> Result<(), Error> {
+        let mut network = self.network.borrow_mut();
+        for block_id in priority {
+            network.send(self.node, self.node, Update::BlockValid(block_id));
+        }
+        Ok(())
+    }
+
+    fn commit_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        let mut network = self.network.borrow_mut();
+        let block = network
+            .blocks
+            .get(&block_id)
+            .cloned()
+            .ok_or_else(|| Error::UnknownBlock(format!("{:?}", block_id)))?;
+        if block.previous_id != network.chain_head(self.node).block_id {
+            return Err(Error::InvalidState(format!(
+                "Block {:?} doesn't build on the chain head",
+                block_id
+            )));
+        }
+        let commit = CommittedBlock {
+            block_id: block_id.clone(),
+            block_num: block.block_num,
+            at: network.steps,
+        };
+        network.commits[self.node].push(commit);
+        network.chains[self.node].push(block);
+        network.send(self.node, self.node, Update::BlockCommit(block_id));
+        Ok(())
+    }
+
+    fn ignore_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn fail_block(&mut self, _block_id: BlockId) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn get_blocks(&mut self, block_ids: Vec<BlockId>) -> Result<HashMap<BlockId, Block>, Error> {
+        let network = self.network.borrow();
+        Ok(block_ids
+            .into_iter()
+            .filter_map(|id| network.blocks.get(&id).map(|block| (id, block.clone())))
+            .collect())
+    }
+
+    fn get_chain_head(&mut self) -> Result<Block, Error> {
+        Ok(self.network.borrow().chain_head(self.node))
+    }
+
+    fn get_settings(
+        &mut self,
+        _block_id: BlockId,
+        _keys: Vec<String>,
+    ) -> Result<HashMap<String, String>, Error> {
+        Ok(Default::default())
+    }
+
+    fn get_state(
+        &mut self,
+        _block_id: BlockId,
+        _addresses: Vec<String>,
+    ) -> Result<HashMap<String, Vec<u8>>, Error> {
+        Ok(Default::default())
+    }
+}
+
+/// Something that can happen next in a simulated network
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SimEvent {
+    /// Deliver the next update on the link from the first node to the second
+    Deliver(usize, usize),
+
+    /// Lose the next update on the link from the first node to the second
+    Drop(usize, usize),
+
+    /// Let the node publish and retry its backlog
+    Tick(usize),
+
+    /// Make the node's view change timeout expire
+    Timeout(usize),
+}
+
+/// A network of `PbftNode`s that talk to each other through `SimulatedService`s
+pub struct SimulatedNetwork {
+    pub nodes: Vec<PbftNode>,
+    network: Rc<RefCell<NetworkState>>,
+    rng: SimRng,
+    clocks: Vec<Rc<MockClock>>,
+    view_change_timeout: Duration,
+}
+
+impl SimulatedNetwork {
+    /// Create a network of `num_nodes` nodes with the default configuration
+    pub fn new(num_nodes: usize, seed: u64) -> Self {
+        Self::with_config(&mock_config(num_nodes), seed)
+    }
+
+    /// Create a network with a node for each of the peers in the configuration; the peers have to
+    /// be the public keys of `signing::mock_signer(0..n)`, as in `config::mock_config`
+    pub fn with_config(config: &PbftConfig, seed: u64) -> Self {
+        let num_nodes = config.peers.len();
+        let genesis = Block {
+            block_id: sim_block_id(0, 0, &BlockId::from(vec![])),
+            previous_id: BlockId::from(vec![]),
+            signer_id: PeerId::from(vec![]),
+            block_num: 0,
+            payload: vec![],
+            summary: vec![],
+        };
+
+        let mut blocks = HashMap::new();
+        blocks.insert(genesis.block_id.clone(), genesis.clone());
+        let network = Rc::new(RefCell::new(NetworkState {
+            peers: config.peers.clone(),
+            links: BTreeMap::new(),
+            transport: LossyTransport::new(Default::default(), seed),
+            loopback: VecDeque::new(),
+            blocks,
+            chains: vec![vec![genesis]; num_nodes],
+            building: vec![None; num_nodes],
+            crashed: vec![false; num_nodes],
+            faults: vec![None; num_nodes],
+            steps: 0,
+            commits: vec![vec![]; num_nodes],
+            checker: CommitChecker::new(config.peers.clone()),
+        }));
+
+        let clocks: Vec<Rc<MockClock>> =
+            (0..num_nodes).map(|_| Rc::new(MockClock::new())).collect();
+        let nodes = (0..num_nodes)
+            .map(|i| {
+                PbftNode::new(
+                    &config.peers[i],
+                    config,
+                    Box::new(SimulatedService {
+                        node: i,
+                        network: network.clone(),
+                    }),
+                    mock_signer(i as u64),
+                    Box::new(MemoryStorage::new()),
+                    clocks[i].clone(),
+                )
+            })
+            .collect();
+
+        SimulatedNetwork {
+            nodes,
+            network,
+            rng: SimRng::new(seed),
+            clocks,
+            view_change_timeout: config.view_change_timeout,
+        }
+    }
+
+    /// Deliver the next update on a randomly chosen link, or let a random node publish and retry
+    /// its backlog; either way, any messages that nodes broadcast are then handled by the nodes
+    /// that sent them
+    pub fn step(&mut self) {
+        let links = self.pending_links();
+        let live = self.live_nodes();
+        if links.is_empty() && live.is_empty() {
+            return;
+        }
+
+        let choice = self.rng.below(links.len() + live.len());
+        let event = if choice < links.len() {
+            let (from, to) = links[choice];
+            SimEvent::Deliver(from, to)
+        } else {
+            SimEvent::Tick(live[choice - links.len()])
+        };
+        self.apply(event);
+    }
+
+    /// Make the event happen, then let the nodes handle any messages they broadcast to themselves.
+    /// Returns `false` (and does nothing) if the event can't happen, because there's nothing ready
+    /// on the link or the node has crashed. An update on a link that a partition has cut since it
+    /// was sent is lost instead of delivered.
+    pub fn apply(&mut self, event: SimEvent) -> bool {
+        match event {
+            SimEvent::Deliver(from, to) | SimEvent::Drop(from, to) => {
+                let (update, cut) = {
+                    let mut network = self.network.borrow_mut();
+                    let steps = network.steps;
+                    let cut = network.transport.is_cut(from, to, steps);
+                    let update = network
+                        .links
+                        .get_mut(&(from, to))
+                        .and_then(|queue| {
+                            if queue.front().map_or(false, |(ready, _)| *ready <= steps) {
+                                queue.pop_front()
+                            } else {
+                                None
+                            }
+                        })
+                        .map(|(_, update)| update);
+                    (update, cut)
+                };
+                match update {
+                    Some(update) => {
+                        if let SimEvent::Deliver(_, _) = event {
+                            if !cut {
+                                self.deliver(to, update);
+                            }
+                        }
+                    }
+                    None => return false,
+                }
+            }
+            SimEvent::Tick(node) | SimEvent::Timeout(node) => {
+                if self.is_crashed(node) {
+                    return false;
+                }
+                if let SimEvent::Tick(_) = event {
+                    self.tick(node);
+                } else {
+                    // Make sure the timeout has expired, in case the node is already changing
+                    // views and is waiting on it
+                    self.clocks[node].advance(self.view_change_timeout + Duration::from_millis(1));
+                    let res = self.nodes[node].start_view_change();
+                    handle_pbft_result(&self.nodes[node], None, res);
+                }
+            }
+        }
+
+        self.deliver_loopback();
+        self.network.borrow_mut().steps += 1;
+        true
+    }
+
+    /// The links that have an update ready to be delivered, in order
+    pub fn pending_links(&self) -> Vec<(usize, usize)> {
+        let steps = self.steps();
+        self.network
+            .borrow()
+            .links
+            .iter()
+            .filter(|(_, queue)| queue.front().map_or(false, |(ready, _)| *ready <= steps))
+            .map(|(link, _)| *link)
+            .collect()
+    }
+
+    /// The nodes that haven't crashed
+    pub fn live_nodes(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|i| !self.is_crashed(*i))
+            .collect()
+    }
+
+    /// Take steps until `done` is true for the network, or until `max_steps` steps have been
+    /// taken; returns whether `done` became true
+    pub fn run_until<F>(&mut self, max_steps: usize, done: F) -> bool
+    where
+        F: Fn(&SimulatedNetwork) -> bool,
+    {
+        for _ in 0..max_steps {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+        done(self)
+    }
+
+    /// Take steps until every node that hasn't crashed has committed at least `height` blocks
+    pub fn run_until_height(&mut self, height: u64, max_steps: usize) -> bool {
+        self.run_until(max_steps, |sim| {
+            (0..sim.nodes.len())
+                .filter(|i| !sim.is_crashed(*i))
+                .all(|i| sim.height(i) >= height)
+        })
+    }
+
+    /// Take steps until every honest node has committed at least `height` blocks, or until
+    /// `max_steps` steps have been taken. Whenever the honest nodes go `patience` steps without
+    /// committing a block or changing views, their view change timeouts expire.
+    pub fn run_with_timeouts(&mut self, height: u64, patience: usize, max_steps: usize) -> bool {
+        let progress = |sim: &SimulatedNetwork| -> Vec<(u64, u64, u64)> {
+            sim.honest_nodes()
+                .into_iter()
+                .map(|i| {
+                    let state = &sim.nodes[i].state;
+                    (sim.height(i), state.view, state.pending_view)
+                })
+                .collect()
+        };
+
+        let mut last_progress = progress(self);
+        let mut idle = 0;
+        for _ in 0..max_steps {
+            if self
+                .honest_nodes()
+                .iter()
+                .all(|i| self.height(*i) >= height)
+            {
+                return true;
+            }
+            self.step();
+
+            let current = progress(self);
+            if current != last_progress {
+                last_progress = current;
+                idle = 0;
+            } else {
+                idle += 1;
+            }
+            if idle >= patience {
+                for i in self.honest_nodes() {
+                    self.expire_timeout(i);
+                }
+                idle = 0;
+            }
+        }
+        false
+    }
+
+    /// Make the node's view change timeout expire
+    pub fn expire_timeout(&mut self, node: usize) {
+        self.apply(SimEvent::Timeout(node));
+    }
+
+    /// Stop the node from sending or receiving anything; updates that are on their way to or from
+    /// it are lost
+    pub fn crash(&mut self, node: usize) {
+        let mut network = self.network.borrow_mut();
+        network.crashed[node] = true;
+        network
+            .links
+            .retain(|(from, to), _| *from != node && *to != node);
+    }
+
+    pub fn is_crashed(&self, node: usize) -> bool {
+        self.network.borrow().crashed[node]
+    }
+
+    /// Send updates between nodes over the transport from now on, instead of over reliable links
+    pub fn set_transport(&mut self, transport: LossyTransport) {
+        self.network.borrow_mut().transport = transport;
+    }
+
+    /// How many events have happened so far
+    pub fn steps(&self) -> u64 {
+        self.network.borrow().steps
+    }
+
+    /// Make the node Byzantine (or honest again, with `None`); see the `byzantine` module
+    pub fn set_fault(&mut self, node: usize, fault: Option<Fault>) {
+        self.network.borrow_mut().faults[node] = fault;
+    }
+
+    /// The nodes that haven't crashed and aren't Byzantine
+    pub fn honest_nodes(&self) -> Vec<usize> {
+        let network = self.network.borrow();
+        (0..self.nodes.len())
+            .filter(|i| !network.crashed[*i] && network.faults[*i].is_none())
+            .collect()
+    }
+
+    /// Check that no two honest nodes have committed different blocks at the same height
+    pub fn honest_nodes_agree(&self) -> bool {
+        let chains: Vec<Vec<BlockId>> = self
+            .honest_nodes()
+            .into_iter()
+            .map(|i| self.chain(i).into_iter().map(|b| b.block_id).collect())
+            .collect();
+        chains.iter().enumerate().all(|(i, chain)| {
+            chains[i + 1..].iter().all(|other| {
+                chain
+                    .iter()
+                    .zip(other.iter())
+                    .all(|(id, other_id)| id == other_id)
+            })
+        })
+    }
+
+    /// Check the blocks that the honest nodes committed with a `CommitChecker`, allowing them to
+    /// go `max_gap` steps without committing a block (or forever, if `max_gap` is `None`)
+    pub fn report(&self, max_gap: Option<u64>) -> Report {
+        let network = self.network.borrow();
+        let histories: Vec<CommitHistory> = self
+            .honest_nodes()
+            .into_iter()
+            .map(|i| CommitHistory {
+                node: i as u64,
+                commits: network.commits[i].clone(),
+                end: network.steps,
+            })
+            .collect();
+        network.checker.check(&histories, max_gap)
+    }
+
+    /// The blocks the node has committed, starting with genesis
+    pub fn chain(&self, node: usize) -> Vec<Block> {
+        self.network.borrow().chains[node].clone()
+    }
+
+    /// The block number of the node's chain head
+    pub fn height(&self, node: usize) -> u64 {
+        self.network.borrow().chain_head(node).block_num
+    }
+
+    // Handle an update the same way the engine does
+    fn deliver(&mut self, to: usize, update: Update) {
+        let node = &mut self.nodes[to];
+        let mut sender = None;
+        let res = match update {
+            Update::BlockNew(block) => node.on_block_new(block),
+            Update::BlockValid(block_id) => node.on_block_valid(block_id),
+            Update::BlockInvalid(_) => node.start_view_change(),
+            Update::BlockCommit(block_id) => node.on_block_commit(block_id),
+            Update::PeerMessage(message, sender_id) => {
+                let msg_type = PbftMessageType::from(message.message_type.as_str());
+                node.on_peer_activity(&sender_id, &msg_type);
+                sender = Some(sender_id);
+                node.on_peer_message(&message)
+            }
+            Update::PeerConnected(info) => node.on_peer_connected(info.peer_id),
+            Update::PeerDisconnected(peer_id) => node.on_peer_disconnected(peer_id),
+            Update::Shutdown => Ok(()),
+        };
+        handle_result(node, sender.as_ref(), res);
+    }
+
+    // The periodic work the engine does for a node
+    fn tick(&mut self, node: usize) {
+        let node = &mut self.nodes[node];
+        let res = node.check_peer_activity();
+        handle_result(node, None, res);
+        let res = node.try_publish();
+        handle_result(node, None, res);
+        let res = node.retry_backlog();
+        handle_result(node, None, res);
+    }
+
+    fn deliver_loopback(&mut self) {
+        loop {
+            let next = self.network.borrow_mut().loopback.pop_front();
+            match next {
+                Some((node, _)) if self.is_crashed(node) => (),
+                Some((node, message)) => {
+                    let res = self.nodes[node].on_peer_message(&message);
+                    handle_result(&mut self.nodes[node], None, res);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+// Log the result of handling an update, and make sure the node's state is persisted, as the
+// engine does
+fn handle_result(node: &mut PbftNode, sender: Option<&PeerId>, res: Result<(), PbftError>) {
+    handle_pbft_result(node, sender, res);
+    let res = node.persist_state();
+    handle_pbft_result(node, None, res);
+}
+
+/// The ID of the block that `publisher` builds on top of `previous_id`
+fn sim_block_id(publisher: usize, block_num: u64, previous_id: &BlockId) -> BlockId {
+    let mut sha = Sha256::new();
+    sha.input_str(&format!(
+        "Block {} published by node {} on {:?}",
+        block_num, publisher, previous_id
+    ));
+    BlockId::from(sha.result_str().as_bytes().to_vec())
+}
+
+// Stands in for the digest of the batches in the block `publisher` builds on top of `previous`
+fn block_summary(publisher: usize, previous: &Block) -> Vec<u8> {
+    let mut sha = Sha256::new();
+    sha.input_str(&format!(
+        "Batches for block {} published by node {}",
+        previous.block_num + 1,
+        publisher
+    ));
+    sha.result_str().as_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::PbftMode;
+
+    /// Four nodes commit blocks together
+    #[test]
+    fn commit_blocks() {
+        let mut sim = SimulatedNetwork::new(4, 1);
+        assert!(sim.run_until_height(4, 20_000));
+        assert!(sim.honest_nodes_agree());
+        let report = sim.report(Some(1_000));
+        assert!(report.is_ok(), "{}", report);
+        for i in 0..4 {
+            assert_eq!(sim.nodes[i].state.view, 0);
+            assert_eq!(
+                sim.chain(i)[1].signer_id,
+                sim.nodes[0].state.get_own_peer_id()
+            );
+        }
+    }
+
+    /// The same seed always gives the same run. (Blocks' payloads can still differ, since the
+    /// commits a node puts in a consensus seal depend on how its log happens to iterate.)
+    #[test]
+    fn deterministic() {
+        let run = |seed| {
+            let mut sim = SimulatedNetwork::new(4, seed);
+            for _ in 0..400 {
+                sim.step();
+            }
+            (0..4)
+                .map(|i| {
+                    (
+                        sim.chain(i)
+                            .into_iter()
+                            .map(|block| block.block_id)
+                            .collect::<Vec<_>>(),
+                        sim.nodes[i].state.seq_num,
+                        sim.nodes[i].state.phase.clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+    }
+
+    /// Nodes reach stable checkpoints and garbage collect their logs as they commit blocks
+    #[test]
+    fn checkpoints() {
+        let mut config = mock_config(4);
+        config.checkpoint_period = 2;
+        let mut sim = SimulatedNetwork::with_config(&config, 2);
+        assert!(sim.run_until_height(5, 20_000));
+        assert!(sim.honest_nodes_agree());
+        for node in &sim.nodes {
+            let checkpoint = node
+                .msg_log
+                .latest_stable_checkpoint
+                .as_ref()
+                .expect("No stable checkpoint");
+            assert!(checkpoint.seq_num >= 2);
+        }
+    }
+
+    /// When the primary crashes, the other nodes replace it and keep committing blocks
+    #[test]
+    fn view_change() {
+        let mut sim = SimulatedNetwork::new(4, 3);
+        assert!(sim.run_until_height(2, 20_000));
+
+        sim.crash(0);
+        let height = (1..4).map(|i| sim.height(i)).max().unwrap();
+        for i in 1..4 {
+            sim.expire_timeout(i);
+        }
+
+        assert!(sim.run_until_height(height + 2, 20_000));
+        assert!(sim.honest_nodes_agree());
+        let report = sim.report(None);
+        assert!(report.is_safe(), "{}", report);
+        for i in 1..4 {
+            assert_eq!(sim.nodes[i].state.view, 1);
+            assert_eq!(sim.nodes[i].state.mode, PbftMode::Normal);
+        }
+        let last = sim.chain(1).pop().unwrap();
+        assert_eq!(last.signer_id, sim.nodes[1].state.get_own_peer_id());
+    }
+}
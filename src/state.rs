@@ -17,18 +17,26 @@
 
 //! Information about a PBFT node's state
 
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use hex;
 
 use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
 
-use protos::pbft_message::PbftBlock;
+use protobuf::RepeatedField;
+
+use protos::pbft_message::{
+    PbftBlock, PbftMessageInfo, PbftNetworkChange, PbftPipelinedBlockSnapshot, PbftStateSnapshot,
+};
 
 use config::PbftConfig;
 use error::PbftError;
 use message_type::PbftMessageType;
-use timing::Timeout;
+use timeline::{Timeline, TimelineEvent, Timelines};
+use timing::{Clock, Timeout};
 
 // Possible roles for a node
 // Primary is in charge of making consensus decisions
@@ -55,6 +63,10 @@ pub enum PbftMode {
     Normal,
     ViewChanging,
     Checkpointing,
+
+    /// The network doesn't have enough nodes to be Byzantine fault tolerant, or doesn't include
+    /// this node yet; only changes to the network's peers are processed
+    Connecting,
 }
 
 impl fmt::Display for PbftState {
@@ -64,6 +76,7 @@ impl fmt::Display for PbftState {
             PbftMode::Normal => "N",
             PbftMode::Checkpointing => "C",
             PbftMode::ViewChanging => "V",
+            PbftMode::Connecting => "W",
         };
 
         let phase = match self.phase {
@@ -77,18 +90,24 @@ impl fmt::Display for PbftState {
 
         let wb = match self.working_block {
             WorkingBlockOption::WorkingBlock(ref block) => {
-                String::from(&hex::encode(block.get_block_id())[..6])
+                format!("{:.6}", hex::encode(block.get_block_id()))
             }
             WorkingBlockOption::TentativeWorkingBlock(ref block_id) => {
-                String::from(&hex::encode(block_id)[..5]) + "~"
+                format!("{:.5}~", hex::encode(block_id))
             }
             _ => String::from("~none~"),
         };
 
+        let pipelined = if self.pipeline.is_empty() {
+            String::new()
+        } else {
+            format!("+{}", self.pipeline.len())
+        };
+
         write!(
             f,
-            "({} {} {}, seq {}, wb {}), Node {}{:02}",
-            phase, mode, self.view, self.seq_num, wb, ast, self.id,
+            "({} {} {}, seq {}{}, wb {}), Node {}{:02}",
+            phase, mode, self.view, self.seq_num, pipelined, wb, ast, self.id,
         )
     }
 }
@@ -113,12 +132,56 @@ impl WorkingBlockOption {
     }
 }
 
+/// A block that is going through consensus ahead of the working block, when consensus is
+/// pipelined
+#[derive(Debug, PartialEq, Clone)]
+pub struct PbftPipelinedBlock {
+    /// The phase that consensus on this block is in
+    pub phase: PbftPhase,
+
+    pub block: PbftBlock,
+}
+
+/// What this node has heard from one of the network's peers
+#[derive(Debug, PartialEq, Clone)]
+pub struct PeerActivity {
+    /// When the peer's last message arrived, or when the peer joined the network if it hasn't
+    /// sent anything yet
+    pub last_message: Instant,
+
+    /// The view and sequence number of the peer's last validly signed message
+    pub last_view: u64,
+    pub last_seq_num: u64,
+
+    /// How many messages the peer has sent, by type; messages of a type this node doesn't know are
+    /// all counted as `Unset`
+    pub messages: BTreeMap<String, u64>,
+
+    /// Whether the peer went quiet, and hasn't been heard from since
+    pub silent: bool,
+}
+
+impl PeerActivity {
+    fn new(now: Instant) -> Self {
+        PeerActivity {
+            last_message: now,
+            last_view: 0,
+            last_seq_num: 0,
+            messages: BTreeMap::new(),
+            silent: false,
+        }
+    }
+}
+
 /// Information about the PBFT algorithm's state
 #[derive(Debug)]
 pub struct PbftState {
-    /// This node's ID
+    /// This node's ID (its position in the list of peers)
     pub id: u64,
 
+    /// This node's Peer ID
+    peer_id: PeerId,
+
     /// The node's current sequence number
     /// Always starts at 0; representative of an unknown sequence number.
     pub seq_num: u64,
@@ -136,9 +199,20 @@ pub struct PbftState {
     pub mode: PbftMode,
     pub pre_checkpoint_mode: PbftMode,
 
+    /// The view this node is trying to move to, while it is in `ViewChanging` mode
+    pub pending_view: u64,
+
     /// Map of peers in the network, including ourselves
     peer_ids: Vec<PeerId>,
 
+    /// The peers this node thinks should be in the network, based on which peers have connected
+    /// and disconnected; differs from `peer_ids` until the network agrees on a change
+    pub candidate_peers: Vec<PeerId>,
+
+    /// A change to the network's peers that has been agreed on, but won't take effect until this
+    /// node finishes the block with the change's sequence number
+    pub pending_network_change: Option<PbftNetworkChange>,
+
     /// The maximum number of faulty nodes in the network
     pub f: u64,
 
@@ -148,49 +222,150 @@ pub struct PbftState {
 
     /// The current block this node is working on
     pub working_block: WorkingBlockOption,
+
+    /// How many blocks can be going through consensus at once, including the working block
+    pub pipeline_depth: u64,
+
+    /// Blocks after the working block that are going through consensus ahead of it, by sequence
+    /// number. They're committed in order, once the blocks before them are on the chain.
+    pub pipeline: BTreeMap<u64, PbftPipelinedBlock>,
+
+    /// What this node has heard from each of the network's peers (other than itself)
+    pub peer_activity: HashMap<PeerId, PeerActivity>,
+
+    /// How long a peer can go without sending anything before it's considered silent
+    pub silence_threshold: Duration,
+
+    /// When the rounds of consensus this node is working on (and the latest ones it finished)
+    /// reached each of their steps
+    pub timelines: Timelines,
+
+    /// Where the time that messages arrive is taken from
+    clock: Rc<Clock>,
 }
 
 impl PbftState {
     /// Construct the initial state for a PBFT node
-    /// # Panics
-    /// Panics if the network this node is on does not have enough nodes to be Byzantine fault
-    /// tolernant.
-    pub fn new(id: u64, config: &PbftConfig) -> Self {
-        // Maximum number of faulty nodes in this network. Panic if there are not enough nodes.
-        let f = ((config.peers.len() - 1) / 3) as u64;
-        if f == 0 {
-            panic!("This network does not contain enough nodes to be fault tolerant");
-        }
-
-        PbftState {
-            id,
+    /// If the network this node is on does not have enough nodes to be Byzantine fault tolerant,
+    /// or doesn't include this node, the node starts out in `Connecting` mode. The view change
+    /// timer keeps time with `clock`.
+    pub fn new(peer_id: &PeerId, config: &PbftConfig, clock: Rc<Clock>) -> Self {
+        let mut state = PbftState {
+            id: 0,
+            peer_id: peer_id.clone(),
             seq_num: 0, // Default to unknown
             view: 0,    // Node ID 0 is default primary
             phase: PbftPhase::NotStarted,
-            role: if id == 0 {
-                PbftNodeRole::Primary
-            } else {
-                PbftNodeRole::Secondary
-            },
+            role: PbftNodeRole::Secondary,
             mode: PbftMode::Normal,
             pre_checkpoint_mode: PbftMode::Normal,
-            f,
-            peer_ids: config.peers.clone(),
-            timeout: Timeout::new(config.view_change_timeout),
+            pending_view: 0,
+            f: 0,
+            peer_ids: vec![],
+            candidate_peers: vec![],
+            pending_network_change: None,
+            timeout: Timeout::new(config.view_change_timeout, clock.clone()),
             working_block: WorkingBlockOption::NoWorkingBlock,
-        }
+            pipeline_depth: config.pipeline_depth,
+            pipeline: BTreeMap::new(),
+            peer_activity: HashMap::new(),
+            silence_threshold: config.silence_threshold(),
+            timelines: Timelines::new(),
+            clock,
+        };
+        state.set_peers(config.peers.clone());
+        state.reset_candidate_peers();
+        state
     }
 
     /// Check to see what type of message this node is expecting or sending, based on the current
     /// phase
     pub fn check_msg_type(&self) -> PbftMessageType {
-        match self.phase {
-            PbftPhase::PrePreparing => PbftMessageType::PrePrepare,
-            PbftPhase::Preparing => PbftMessageType::Prepare,
-            PbftPhase::Checking => PbftMessageType::Prepare,
-            PbftPhase::Committing => PbftMessageType::Commit,
-            _ => PbftMessageType::Unset,
+        msg_type_for_phase(&self.phase)
+    }
+
+    /// Check to see what type of message this node is expecting or sending for a sequence number,
+    /// which can be the working block's or that of a block in the pipeline
+    pub fn check_msg_type_for(&self, seq_num: u64) -> PbftMessageType {
+        if seq_num == self.seq_num {
+            return self.check_msg_type();
+        }
+        match self.pipeline.get(&seq_num) {
+            Some(pipelined) => msg_type_for_phase(&pipelined.phase),
+            None => PbftMessageType::Unset,
+        }
+    }
+
+    /// Count the blocks this node is working on, including the working block
+    pub fn num_in_flight(&self) -> u64 {
+        let working = if self.phase == PbftPhase::NotStarted {
+            0
+        } else {
+            1
+        };
+        working + self.pipeline.len() as u64
+    }
+
+    /// Tell if this node is already working on as many blocks as it can at once
+    pub fn pipeline_full(&self) -> bool {
+        self.num_in_flight() >= self.pipeline_depth
+    }
+
+    /// Obtain the sequence number of the last block this node is working on
+    pub fn last_seq_num(&self) -> u64 {
+        self.pipeline
+            .keys()
+            .next_back()
+            .cloned()
+            .unwrap_or(self.seq_num)
+    }
+
+    /// Obtain the sequence number that the next block added to the pipeline gets, if there's room
+    /// for one. The working block needs to have a sequence number first, and new blocks aren't
+    /// started during a view change or while the network is changing its peers.
+    pub fn next_pipelined_seq_num(&self) -> Option<u64> {
+        let has_seq_num = match self.phase {
+            PbftPhase::NotStarted => false,
+            PbftPhase::PrePreparing => self.is_primary(),
+            _ => true,
+        };
+        let normal = match self.mode {
+            PbftMode::Normal | PbftMode::Checkpointing => true,
+            _ => false,
+        };
+        if !has_seq_num || !normal || self.pipeline_full() || self.pending_network_change.is_some()
+        {
+            return None;
         }
+        Some(self.last_seq_num() + 1)
+    }
+
+    /// Make the block after the working block in the pipeline the new working block, once the
+    /// working block is finished (the node is back in `NotStarted`). Returns `false` if there's no
+    /// block in the pipeline with the next sequence number.
+    pub fn promote_pipelined_block(&mut self) -> bool {
+        if self.phase != PbftPhase::NotStarted {
+            return false;
+        }
+        match self.pipeline.remove(&(self.seq_num + 1)) {
+            Some(pipelined) => {
+                self.seq_num += 1;
+                self.phase = pipelined.phase;
+                self.working_block = WorkingBlockOption::WorkingBlock(pipelined.block);
+                self.timelines.working = self.timelines.pipelined.remove(&self.seq_num);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop working on all of the blocks in the pipeline, and return them in order
+    pub fn abort_pipeline(&mut self) -> Vec<PbftBlock> {
+        self.timelines.pipelined.clear();
+        ::std::mem::replace(&mut self.pipeline, BTreeMap::new())
+            .into_iter()
+            .map(|(_, pipelined)| pipelined.block)
+            .collect()
     }
 
     /// Obtain the node ID (u64) from a serialized PeerId
@@ -206,12 +381,127 @@ impl PbftState {
 
     /// Obtain the Peer ID for this node
     pub fn get_own_peer_id(&self) -> PeerId {
-        self.peer_ids[self.id as usize].clone()
+        self.peer_id.clone()
+    }
+
+    /// Obtain the Peer IDs of the nodes in the network, in order
+    pub fn get_peer_ids(&self) -> &[PeerId] {
+        &self.peer_ids
+    }
+
+    /// Tell if this node is one of the peers in the network
+    pub fn is_member(&self) -> bool {
+        self.peer_ids.contains(&self.peer_id)
+    }
+
+    /// Change the peers in the network
+    /// This node's ID and role and the maximum number of faulty nodes are recalculated. If the
+    /// new network isn't Byzantine fault tolerant or doesn't include this node, the node goes into
+    /// `Connecting` mode; a node that was `Connecting` goes back to `Normal` mode once it is.
+    pub fn set_peers(&mut self, peers: Vec<PeerId>) {
+        self.f = (peers.len().saturating_sub(1) / 3) as u64;
+        self.peer_ids = peers;
+        self.id = self
+            .peer_ids
+            .iter()
+            .position(|id| id == &self.peer_id)
+            .unwrap_or(self.peer_ids.len()) as u64;
+
+        if self.f == 0 || !self.is_member() {
+            if self.mode != PbftMode::Connecting {
+                warn!(
+                    "{}: Network of {} nodes isn't Byzantine fault tolerant, or doesn't include \
+                     this node; waiting for more peers",
+                    self,
+                    self.peer_ids.len()
+                );
+            }
+            self.mode = PbftMode::Connecting;
+        } else if self.mode == PbftMode::Connecting {
+            self.mode = PbftMode::Normal;
+        }
+
+        if self.is_member() && self.get_primary_peer_id() == self.peer_id {
+            self.upgrade_role();
+        } else {
+            self.downgrade_role();
+        }
+
+        // Peers that just joined haven't had a chance to be heard from yet
+        let now = self.clock.now();
+        let peer_ids = &self.peer_ids;
+        let own_id = &self.peer_id;
+        let peer_activity = &mut self.peer_activity;
+        peer_activity.retain(|peer_id, _| peer_ids.contains(peer_id) && peer_id != own_id);
+        for peer_id in peer_ids.iter().filter(|id| *id != own_id) {
+            peer_activity
+                .entry(peer_id.clone())
+                .or_insert_with(|| PeerActivity::new(now));
+        }
+    }
+
+    /// Record that a message of the given type arrived from a peer. Messages from nodes that
+    /// aren't in the network aren't recorded.
+    pub fn record_peer_message(&mut self, peer_id: &PeerId, msg_type: &PbftMessageType) {
+        let now = self.clock.now();
+        if let Some(activity) = self.peer_activity.get_mut(peer_id) {
+            activity.last_message = now;
+            activity.silent = false;
+            *activity.messages.entry(String::from(msg_type)).or_insert(0) += 1;
+        }
+    }
+
+    /// Record the view and sequence number of a message from one of the network's peers. This is
+    /// only done once the message's signature has been verified, so that a peer can't be made to
+    /// look like it's somewhere it isn't.
+    pub fn record_peer_progress(&mut self, info: &PbftMessageInfo) {
+        let peer_id = PeerId::from(info.get_signer_id().to_vec());
+        if let Some(activity) = self.peer_activity.get_mut(&peer_id) {
+            activity.last_view = info.get_view();
+            activity.last_seq_num = info.get_seq_num();
+        }
+    }
+
+    /// How long it's been since a peer's last message, or `None` if it isn't one of the network's
+    /// peers
+    pub fn peer_silence(&self, peer_id: &PeerId) -> Option<Duration> {
+        self.peer_activity
+            .get(peer_id)
+            .map(|activity| self.clock.now() - activity.last_message)
+    }
+
+    /// Mark the peers that haven't sent anything for at least the silence threshold as silent.
+    /// Returns the peers that went quiet since the last check, in network order.
+    pub fn update_silent_peers(&mut self) -> Vec<PeerId> {
+        let now = self.clock.now();
+        let mut quiet = vec![];
+        for peer_id in &self.peer_ids {
+            if let Some(activity) = self.peer_activity.get_mut(peer_id) {
+                if !activity.silent && now - activity.last_message >= self.silence_threshold {
+                    activity.silent = true;
+                    quiet.push(peer_id.clone());
+                }
+            }
+        }
+        quiet
+    }
+
+    /// Start over with the network's current peers (and this node) as the candidate peers
+    pub fn reset_candidate_peers(&mut self) {
+        self.candidate_peers = self.peer_ids.clone();
+        if !self.is_member() {
+            self.candidate_peers.push(self.peer_id.clone());
+        }
     }
 
     /// Obtain the Peer ID for the primary node in the network
     pub fn get_primary_peer_id(&self) -> PeerId {
-        let primary_node_id = (self.view % (self.peer_ids.len() as u64)) as usize;
+        self.get_primary_peer_id_for_view(self.view)
+    }
+
+    /// Obtain the Peer ID of the node that is the primary in the given view
+    pub fn get_primary_peer_id_for_view(&self, view: u64) -> PeerId {
+        let primary_node_id = (view % (self.peer_ids.len() as u64)) as usize;
         self.peer_ids[primary_node_id].clone()
     }
 
@@ -244,27 +534,267 @@ impl PbftState {
         if desired_phase == next {
             debug!("{}: Changing to {:?}", self, desired_phase);
             self.phase = desired_phase.clone();
+            self.record_step(TimelineEvent::for_phase(&desired_phase));
             Some(desired_phase)
         } else {
             debug!("{}: Didn't change to {:?}", self, desired_phase);
             None
         }
     }
+
+    /// Record that the working block's round of consensus reached a step. The round's timeline
+    /// starts at its first step, and is complete once the block is committed.
+    pub fn record_step(&mut self, event: TimelineEvent) {
+        let now = self.clock.now();
+        if self.timelines.working.is_none() {
+            // There's no round to change the view of, or to finish
+            if event == TimelineEvent::ViewChange || event == TimelineEvent::BlockCommit {
+                return;
+            }
+            self.timelines.working = Some(Timeline::new(now));
+        }
+
+        let block_id = match self.working_block {
+            WorkingBlockOption::WorkingBlock(ref block) => Some(block.get_block_id().to_vec()),
+            WorkingBlockOption::TentativeWorkingBlock(ref block_id) => {
+                Some(Vec::<u8>::from(block_id.clone()))
+            }
+            WorkingBlockOption::NoWorkingBlock => None,
+        };
+        if let Some(ref mut timeline) = self.timelines.working {
+            timeline.record(event, now);
+            // Secondaries don't know the block's sequence number until the PrePrepare arrives
+            if event != TimelineEvent::BlockNew {
+                timeline.seq_num = self.seq_num;
+                timeline.view = self.view;
+            }
+            if let Some(block_id) = block_id {
+                timeline.block_id = block_id;
+            }
+        }
+
+        if event == TimelineEvent::BlockCommit {
+            if let Some(timeline) = self.timelines.working.take() {
+                self.timelines.complete(timeline);
+            }
+        }
+    }
+
+    /// Record that the round of consensus for a block in the pipeline reached a step
+    pub fn record_pipelined_step(&mut self, seq_num: u64, event: TimelineEvent) {
+        let now = self.clock.now();
+        let block_id = self
+            .pipeline
+            .get(&seq_num)
+            .map(|pipelined| pipelined.block.get_block_id().to_vec());
+        let timeline = self
+            .timelines
+            .pipelined
+            .entry(seq_num)
+            .or_insert_with(|| Timeline::new(now));
+        timeline.record(event, now);
+        timeline.seq_num = seq_num;
+        timeline.view = self.view;
+        if let Some(block_id) = block_id {
+            timeline.block_id = block_id;
+        }
+    }
+
+    /// Record that a peer's `Prepare` or `Commit` arrived, if it's for a block this node is
+    /// working on
+    pub fn record_vote(&mut self, info: &PbftMessageInfo) {
+        let now = self.clock.now();
+        let seq_num = info.get_seq_num();
+        // The working block's sequence number is only known once its PrePrepare arrives
+        let timeline = if seq_num == self.seq_num && self.phase >= PbftPhase::Preparing {
+            self.timelines.working.as_mut()
+        } else {
+            self.timelines.pipelined.get_mut(&seq_num)
+        };
+        if let Some(timeline) = timeline {
+            timeline.record_vote(
+                &PeerId::from(info.get_signer_id().to_vec()),
+                info.get_msg_type(),
+                info.get_view(),
+                now,
+            );
+        }
+    }
+
+    /// Record the parts of this node's state that it needs to pick up where it left off after a
+    /// restart. A node that is checkpointing is recorded in the mode it was in before the
+    /// checkpoint started, since checkpoints aren't resumed.
+    pub fn snapshot(&self) -> PbftStateSnapshot {
+        let mode = if self.mode == PbftMode::Checkpointing {
+            self.pre_checkpoint_mode
+        } else {
+            self.mode
+        };
+
+        let mut snapshot = PbftStateSnapshot::new();
+        snapshot.set_view(self.view);
+        snapshot.set_seq_num(self.seq_num);
+        snapshot.set_phase(format!("{:?}", self.phase));
+        snapshot.set_mode(format!("{:?}", mode));
+        snapshot.set_pending_view(self.pending_view);
+        snapshot.set_peers(RepeatedField::from_vec(
+            self.peer_ids.iter().cloned().map(Vec::<u8>::from).collect(),
+        ));
+        if let Some(ref network_change) = self.pending_network_change {
+            snapshot.set_pending_network_change(network_change.clone());
+        }
+        match self.working_block {
+            WorkingBlockOption::WorkingBlock(ref block) => {
+                snapshot.set_working_block(block.clone())
+            }
+            WorkingBlockOption::TentativeWorkingBlock(ref block_id) => {
+                snapshot.set_tentative_block_id(Vec::<u8>::from(block_id.clone()))
+            }
+            WorkingBlockOption::NoWorkingBlock => (),
+        }
+        snapshot.set_pipeline(RepeatedField::from_vec(
+            self.pipeline
+                .iter()
+                .map(|(seq_num, pipelined)| {
+                    let mut pipelined_snapshot = PbftPipelinedBlockSnapshot::new();
+                    pipelined_snapshot.set_seq_num(*seq_num);
+                    pipelined_snapshot.set_phase(format!("{:?}", pipelined.phase));
+                    pipelined_snapshot.set_block(pipelined.block.clone());
+                    pipelined_snapshot
+                })
+                .collect(),
+        ));
+        snapshot
+    }
+
+    /// Pick up from a snapshot that was taken before a restart. This node's role is based on the
+    /// restored view and peers.
+    pub fn restore(&mut self, snapshot: &PbftStateSnapshot) -> Result<(), PbftError> {
+        self.phase = phase_from_str(snapshot.get_phase())?;
+        self.mode = match snapshot.get_mode() {
+            "Normal" => PbftMode::Normal,
+            "ViewChanging" => PbftMode::ViewChanging,
+            "Connecting" => PbftMode::Connecting,
+            mode => return Err(PbftError::StorageError(format!("Unknown mode: {}", mode))),
+        };
+        self.pre_checkpoint_mode = self.mode;
+
+        self.view = snapshot.get_view();
+        self.seq_num = snapshot.get_seq_num();
+        self.pending_view = snapshot.get_pending_view();
+        self.working_block = if snapshot.has_working_block() {
+            WorkingBlockOption::WorkingBlock(snapshot.get_working_block().clone())
+        } else if !snapshot.get_tentative_block_id().is_empty() {
+            WorkingBlockOption::TentativeWorkingBlock(BlockId::from(
+                snapshot.get_tentative_block_id().to_vec(),
+            ))
+        } else {
+            WorkingBlockOption::NoWorkingBlock
+        };
+        self.pipeline = BTreeMap::new();
+        for pipelined in snapshot.get_pipeline() {
+            self.pipeline.insert(
+                pipelined.get_seq_num(),
+                PbftPipelinedBlock {
+                    phase: phase_from_str(pipelined.get_phase())?,
+                    block: pipelined.get_block().clone(),
+                },
+            );
+        }
+
+        self.pending_network_change = if snapshot.has_pending_network_change() {
+            Some(snapshot.get_pending_network_change().clone())
+        } else {
+            None
+        };
+
+        let peers = if snapshot.get_peers().is_empty() {
+            self.peer_ids.clone()
+        } else {
+            snapshot
+                .get_peers()
+                .iter()
+                .map(|id| PeerId::from(id.clone()))
+                .collect()
+        };
+        self.set_peers(peers);
+        self.reset_candidate_peers();
+        Ok(())
+    }
+}
+
+// The type of message a node is expecting or sending in a phase
+fn msg_type_for_phase(phase: &PbftPhase) -> PbftMessageType {
+    match phase {
+        PbftPhase::PrePreparing => PbftMessageType::PrePrepare,
+        PbftPhase::Preparing => PbftMessageType::Prepare,
+        PbftPhase::Checking => PbftMessageType::Prepare,
+        PbftPhase::Committing => PbftMessageType::Commit,
+        _ => PbftMessageType::Unset,
+    }
+}
+
+// Parse the name of a phase, as it's written in a snapshot
+fn phase_from_str(phase: &str) -> Result<PbftPhase, PbftError> {
+    match phase {
+        "NotStarted" => Ok(PbftPhase::NotStarted),
+        "PrePreparing" => Ok(PbftPhase::PrePreparing),
+        "Preparing" => Ok(PbftPhase::Preparing),
+        "Checking" => Ok(PbftPhase::Checking),
+        "Committing" => Ok(PbftPhase::Committing),
+        "Finished" => Ok(PbftPhase::Finished),
+        phase => Err(PbftError::StorageError(format!("Unknown phase: {}", phase))),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use config::mock_config;
+    use timing::mock_clock;
 
-    /// Check that state responds to having an inadequately sized network
+    /// Check that state responds to having an inadequately sized network by waiting for more
+    /// peers to connect
     #[test]
     fn no_fault_tolerance() {
         let config = mock_config(1);
-        let caught = ::std::panic::catch_unwind(|| {
-            PbftState::new(0, &config);
-        }).is_err();
-        assert!(caught);
+        let state = PbftState::new(&config.peers[0], &config, mock_clock());
+        assert_eq!(state.mode, PbftMode::Connecting);
+        assert_eq!(state.f, 0);
+    }
+
+    /// Make sure that changing the network's peers recalculates `f`, the node's ID and role, and
+    /// whether the node has to wait in `Connecting` mode
+    #[test]
+    fn peer_changes() {
+        let config = mock_config(7);
+        let mut state = PbftState::new(&config.peers[4], &mock_config(4), mock_clock());
+        assert!(!state.is_member());
+        assert_eq!(state.mode, PbftMode::Connecting);
+        assert_eq!(state.candidate_peers, config.peers[..5].to_vec());
+
+        state.set_peers(config.peers.clone());
+        assert!(state.is_member());
+        assert_eq!(state.id, 4);
+        assert_eq!(state.f, 2);
+        assert_eq!(state.mode, PbftMode::Normal);
+        assert!(!state.is_primary());
+
+        // Node 4 is the primary in view 4
+        state.view = 4;
+        state.set_peers(config.peers[2..].to_vec());
+        assert_eq!(state.id, 2);
+        assert_eq!(state.f, 1);
+        assert_eq!(state.get_primary_peer_id(), config.peers[6]);
+        assert!(!state.is_primary());
+        state.set_peers(config.peers.clone());
+        assert!(state.is_primary());
+
+        // Too many nodes left
+        state.set_peers(config.peers[..3].to_vec());
+        assert_eq!(state.mode, PbftMode::Connecting);
+        assert!(!state.is_member());
+        assert!(!state.is_primary());
     }
 
     /// Check that the initial configuration of state is as we expect:
@@ -276,8 +806,8 @@ mod tests {
     #[test]
     fn initial_config() {
         let config = mock_config(4);
-        let state0 = PbftState::new(0, &config);
-        let state1 = PbftState::new(1, &config);
+        let state0 = PbftState::new(&config.peers[0], &config, mock_clock());
+        let state1 = PbftState::new(&config.peers[1], &config, mock_clock());
 
         assert!(state0.is_primary());
         assert!(!state1.is_primary());
@@ -312,7 +842,7 @@ mod tests {
     #[test]
     fn role_changes() {
         let config = mock_config(4);
-        let mut state = PbftState::new(0, &config);
+        let mut state = PbftState::new(&config.peers[0], &config, mock_clock());
 
         state.downgrade_role();
         assert!(!state.is_primary());
@@ -321,6 +851,43 @@ mod tests {
         assert!(state.is_primary());
     }
 
+    /// Make sure that peers are reported as silent once they haven't sent anything for the
+    /// silence threshold, and stop being silent as soon as they're heard from
+    #[test]
+    fn silent_peers() {
+        let config = mock_config(4);
+        let clock = mock_clock();
+        let mut state = PbftState::new(&config.peers[1], &config, clock.clone());
+        let threshold = config.silence_threshold();
+        assert_eq!(state.silence_threshold, threshold);
+        assert!(!state.peer_activity.contains_key(&config.peers[1]));
+        assert!(state.update_silent_peers().is_empty());
+
+        clock.advance(threshold);
+        state.record_peer_message(&config.peers[0], &PbftMessageType::Prepare);
+        assert_eq!(
+            state.update_silent_peers(),
+            vec![config.peers[2].clone(), config.peers[3].clone()]
+        );
+        assert!(state.peer_activity[&config.peers[2]].silent);
+        assert_eq!(state.peer_silence(&config.peers[2]), Some(threshold));
+
+        // Silent peers are only reported once
+        assert!(state.update_silent_peers().is_empty());
+
+        state.record_peer_message(&config.peers[2], &PbftMessageType::Commit);
+        assert!(!state.peer_activity[&config.peers[2]].silent);
+        assert_eq!(
+            state.peer_silence(&config.peers[2]),
+            Some(Duration::from_secs(0))
+        );
+
+        // Peers that leave the network aren't tracked anymore
+        state.set_peers(config.peers[..3].to_vec());
+        assert!(!state.peer_activity.contains_key(&config.peers[3]));
+        assert_eq!(state.peer_silence(&config.peers[3]), None);
+    }
+
     /// Make sure that a normal PBFT cycle works properly
     /// `NotStarted` => `PrePreparing` => `Preparing` => `Committing` => `Finished` => `NotStarted`
     /// Also make sure that no illegal phase changes are allowed to happen
@@ -328,7 +895,7 @@ mod tests {
     #[test]
     fn phase_changes() {
         let config = mock_config(4);
-        let mut state = PbftState::new(0, &config);
+        let mut state = PbftState::new(&config.peers[0], &config, mock_clock());
 
         assert!(state.switch_phase(PbftPhase::PrePreparing).is_some());
         assert!(state.switch_phase(PbftPhase::Preparing).is_some());
@@ -340,4 +907,168 @@ mod tests {
         assert!(state.switch_phase(PbftPhase::Finished).is_none());
         assert!(state.switch_phase(PbftPhase::Preparing).is_none());
     }
+
+    /// Make sure that the working block's round of consensus is timed from the block's arrival
+    /// until it's committed, along with the votes that arrived for it
+    #[test]
+    fn round_timeline() {
+        let config = mock_config(4);
+        let clock = mock_clock();
+        let mut state = PbftState::new(&config.peers[1], &config, clock.clone());
+        let vote = |state: &mut PbftState, msg_type: &PbftMessageType, signer: usize| {
+            let mut info = PbftMessageInfo::new();
+            info.set_msg_type(String::from(msg_type));
+            info.set_seq_num(1);
+            info.set_signer_id(Vec::<u8>::from(config.peers[signer].clone()));
+            state.record_vote(&info);
+        };
+
+        // Nothing to finish before a round starts
+        state.record_step(TimelineEvent::BlockCommit);
+        assert!(state.timelines.working.is_none());
+
+        state.switch_phase(PbftPhase::PrePreparing);
+        state.working_block = WorkingBlockOption::TentativeWorkingBlock(BlockId::from(vec![7]));
+        clock.advance(Duration::from_millis(10));
+        state.seq_num = 1;
+        state.switch_phase(PbftPhase::Preparing);
+        clock.advance(Duration::from_millis(5));
+        vote(&mut state, &PbftMessageType::Prepare, 0);
+        clock.advance(Duration::from_millis(20));
+        vote(&mut state, &PbftMessageType::Prepare, 2);
+        state.switch_phase(PbftPhase::Checking);
+        state.switch_phase(PbftPhase::Committing);
+        vote(&mut state, &PbftMessageType::Commit, 2);
+        state.switch_phase(PbftPhase::Finished);
+        assert!(state.timelines.take_completed().is_empty());
+
+        clock.advance(Duration::from_millis(15));
+        state.switch_phase(PbftPhase::NotStarted);
+        assert!(state.timelines.working.is_none());
+        let completed = state.timelines.take_completed();
+        assert_eq!(completed.len(), 1);
+
+        let timeline = &completed[0];
+        assert_eq!(timeline.seq_num, 1);
+        assert_eq!(timeline.block_id, vec![7]);
+        assert_eq!(timeline.duration(), Duration::from_millis(50));
+        assert_eq!(
+            timeline.time_of(TimelineEvent::PrepareQuorum),
+            Some(Duration::from_millis(35))
+        );
+        assert_eq!(timeline.votes.len(), 3);
+        assert_eq!(
+            timeline.slowest_peer(),
+            Some((config.peers[2].clone(), Duration::from_millis(20)))
+        );
+
+        // Votes for a block that isn't being worked on aren't recorded
+        vote(&mut state, &PbftMessageType::Commit, 3);
+        assert!(state.timelines.working.is_none());
+        assert!(state.timelines.pipelined.is_empty());
+    }
+
+    /// Make sure that blocks are only added to the pipeline when there's room for them, and that
+    /// they become the working block in order
+    #[test]
+    fn pipeline() {
+        let mut config = mock_config(4);
+        config.pipeline_depth = 3;
+        let mut state = PbftState::new(&config.peers[1], &config, mock_clock());
+        state.seq_num = 4;
+
+        // The working block needs a sequence number first
+        assert_eq!(state.next_pipelined_seq_num(), None);
+        state.phase = PbftPhase::PrePreparing;
+        assert_eq!(state.next_pipelined_seq_num(), None);
+        state.phase = PbftPhase::Preparing;
+        assert_eq!(state.next_pipelined_seq_num(), Some(5));
+
+        for seq_num in 5..7 {
+            state.pipeline.insert(
+                seq_num,
+                PbftPipelinedBlock {
+                    phase: PbftPhase::Preparing,
+                    block: PbftBlock::new(),
+                },
+            );
+        }
+        assert_eq!(state.num_in_flight(), 3);
+        assert!(state.pipeline_full());
+        assert_eq!(state.next_pipelined_seq_num(), None);
+        assert_eq!(state.last_seq_num(), 6);
+        assert_eq!(state.check_msg_type_for(6), PbftMessageType::Prepare);
+        assert_eq!(state.check_msg_type_for(7), PbftMessageType::Unset);
+
+        // Nothing moves up until the working block is finished
+        assert!(!state.promote_pipelined_block());
+        state.phase = PbftPhase::NotStarted;
+        assert!(state.promote_pipelined_block());
+        assert_eq!(state.seq_num, 5);
+        assert_eq!(state.phase, PbftPhase::Preparing);
+        assert_eq!(state.next_pipelined_seq_num(), Some(7));
+
+        assert_eq!(state.abort_pipeline().len(), 1);
+        assert_eq!(state.last_seq_num(), 5);
+    }
+
+    /// Make sure that a state can be restored from a snapshot of it, and that the restored node
+    /// takes the role that belongs to it in the restored view
+    #[test]
+    fn snapshot_restore() {
+        let config = mock_config(4);
+        let mut state = PbftState::new(&config.peers[1], &config, mock_clock());
+        state.view = 5;
+        state.seq_num = 7;
+        state.phase = PbftPhase::Committing;
+        state.mode = PbftMode::Checkpointing;
+        state.pre_checkpoint_mode = PbftMode::ViewChanging;
+        state.pending_view = 6;
+        let mut block = PbftBlock::new();
+        block.set_block_id(b"block".to_vec());
+        state.working_block = WorkingBlockOption::WorkingBlock(block.clone());
+        state.pipeline.insert(
+            8,
+            PbftPipelinedBlock {
+                phase: PbftPhase::Checking,
+                block,
+            },
+        );
+
+        let mut restored = PbftState::new(&config.peers[1], &config, mock_clock());
+        assert!(!restored.is_primary());
+        restored.restore(&state.snapshot()).unwrap();
+        assert_eq!(restored.view, 5);
+        assert_eq!(restored.seq_num, 7);
+        assert_eq!(restored.phase, PbftPhase::Committing);
+        assert_eq!(restored.mode, PbftMode::ViewChanging);
+        assert_eq!(restored.pending_view, 6);
+        assert_eq!(restored.working_block, state.working_block);
+        assert_eq!(restored.pipeline, state.pipeline);
+        assert!(restored.is_primary());
+
+        state.working_block = WorkingBlockOption::TentativeWorkingBlock(BlockId::from(vec![1]));
+        restored.restore(&state.snapshot()).unwrap();
+        assert_eq!(restored.working_block, state.working_block);
+
+        let mut snapshot = state.snapshot();
+        snapshot.set_phase(String::from("Dancing"));
+        assert!(restored.restore(&snapshot).is_err());
+    }
+
+    /// A working block's ID comes from a peer's message, so it could be any length; the state can
+    /// still be displayed
+    #[test]
+    fn display_short_block_id() {
+        let config = mock_config(4);
+        let mut state = PbftState::new(&config.peers[1], &config, mock_clock());
+
+        let mut block = PbftBlock::new();
+        block.set_block_id(vec![0xab]);
+        state.working_block = WorkingBlockOption::WorkingBlock(block);
+        assert!(state.to_string().contains("ab"));
+
+        state.working_block = WorkingBlockOption::TentativeWorkingBlock(BlockId::from(vec![]));
+        assert!(state.to_string().contains("~"));
+    }
 }
@@ -0,0 +1,328 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Persistent storage for a node's messages and state, so that it can recover from a crash
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use protobuf;
+use protobuf::{Message, ProtobufError};
+
+use protos::pbft_message::PbftStorageEntry;
+
+use error::PbftError;
+
+/// A place for a node to durably keep the messages it sends and accepts, along with snapshots of
+/// its state. When the node starts up, it reads the entries back in the order they were written.
+pub trait PbftStorage {
+    /// Write an entry; once this returns, the entry has to survive a crash
+    fn append(&mut self, entry: &PbftStorageEntry) -> Result<(), PbftError>;
+
+    /// Write an entry without waiting for it to be durable; it only has to survive a crash once a
+    /// later `append` returns. Entries are still read back in the order they were written.
+    fn append_unsynced(&mut self, entry: &PbftStorageEntry) -> Result<(), PbftError> {
+        self.append(entry)
+    }
+
+    /// Replace everything in storage with the given entries (used to throw away entries that
+    /// aren't needed anymore after a checkpoint)
+    fn compact(&mut self, entries: &[PbftStorageEntry]) -> Result<(), PbftError>;
+
+    /// Read back every entry, oldest first
+    fn load(&mut self) -> Result<Vec<PbftStorageEntry>, PbftError>;
+}
+
+/// Storage backed by an append-only file
+/// Each entry is written as its length (a 4-byte big-endian integer) followed by the serialized
+/// entry, and is flushed to disk (along with any unsynced entries before it) before `append`
+/// returns. An entry that was only partially written when the node crashed is discarded the next
+/// time the file is loaded.
+pub struct FileStorage {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileStorage {
+    /// Open the storage file at `path`, creating it (and its directory) if it doesn't exist yet
+    pub fn open(path: &Path) -> Result<Self, PbftError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| storage_error(path, &err))?;
+        }
+        let file = open_for_append(path)?;
+        Ok(FileStorage {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+}
+
+impl PbftStorage for FileStorage {
+    fn append(&mut self, entry: &PbftStorageEntry) -> Result<(), PbftError> {
+        self.file
+            .write_all(&encode_entry(entry)?)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|err| storage_error(&self.path, &err))
+    }
+
+    fn append_unsynced(&mut self, entry: &PbftStorageEntry) -> Result<(), PbftError> {
+        self.file
+            .write_all(&encode_entry(entry)?)
+            .map_err(|err| storage_error(&self.path, &err))
+    }
+
+    fn compact(&mut self, entries: &[PbftStorageEntry]) -> Result<(), PbftError> {
+        // Write the new contents next to the old file, then swap them, so a crash in the middle
+        // leaves one or the other intact. The swap itself isn't durable until the directory is
+        // synced.
+        let tmp_path = PathBuf::from(format!("{}.tmp", self.path.display()));
+        let mut bytes = Vec::new();
+        for entry in entries {
+            bytes.extend(encode_entry(entry)?);
+        }
+        File::create(&tmp_path)
+            .and_then(|mut tmp| tmp.write_all(&bytes).and_then(|_| tmp.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .and_then(|_| sync_parent_dir(&self.path))
+            .map_err(|err| storage_error(&tmp_path, &err))?;
+
+        self.file = open_for_append(&self.path)?;
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Vec<PbftStorageEntry>, PbftError> {
+        let bytes = fs::read(&self.path).map_err(|err| storage_error(&self.path, &err))?;
+
+        let (entries, len) = decode_entries(&bytes).map_err(|err| {
+            PbftError::StorageError(format!("Corrupt entry in {}: {}", self.path.display(), err))
+        })?;
+        if len < bytes.len() {
+            warn!(
+                "Discarding incomplete entry at the end of {}",
+                self.path.display()
+            );
+            self.file
+                .set_len(len as u64)
+                .map_err(|err| storage_error(&self.path, &err))?;
+        }
+        Ok(entries)
+    }
+}
+
+/// Storage that only lives in memory, for tests and for replaying traces
+/// Clones share the same entries, so a node can be "restarted" with the storage of a node that
+/// crashed.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    entries: Arc<Mutex<Vec<PbftStorageEntry>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    /// Obtain a copy of everything that has been written so far
+    pub fn entries(&self) -> Vec<PbftStorageEntry> {
+        self.entries.lock().expect("Storage lock poisoned").clone()
+    }
+}
+
+impl PbftStorage for MemoryStorage {
+    fn append(&mut self, entry: &PbftStorageEntry) -> Result<(), PbftError> {
+        self.entries
+            .lock()
+            .expect("Storage lock poisoned")
+            .push(entry.clone());
+        Ok(())
+    }
+
+    fn compact(&mut self, entries: &[PbftStorageEntry]) -> Result<(), PbftError> {
+        *self.entries.lock().expect("Storage lock poisoned") = entries.to_vec();
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Vec<PbftStorageEntry>, PbftError> {
+        Ok(self.entries())
+    }
+}
+
+// Flush the directory a file is in to disk, so that renaming the file survives a crash
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+fn open_for_append(path: &Path) -> Result<File, PbftError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| storage_error(path, &err))
+}
+
+/// Serialize an entry, prefixed with its length
+pub fn encode_entry<M: Message>(entry: &M) -> Result<Vec<u8>, PbftError> {
+    let entry_bytes = entry
+        .write_to_bytes()
+        .map_err(PbftError::SerializationError)?;
+    let len = entry_bytes.len() as u32;
+
+    let mut bytes = vec![
+        (len >> 24) as u8,
+        (len >> 16) as u8,
+        (len >> 8) as u8,
+        len as u8,
+    ];
+    bytes.extend(entry_bytes);
+    Ok(bytes)
+}
+
+/// Parse entries that were serialized with `encode_entry`, one after the other. Also returns how
+/// many bytes the complete entries take up; if the last entry was cut off, that's less than the
+/// length of `bytes`.
+pub fn decode_entries<M: Message>(bytes: &[u8]) -> Result<(Vec<M>, usize), ProtobufError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        if offset + 4 > bytes.len() {
+            break;
+        }
+        let len = bytes[offset..offset + 4]
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        if len > bytes.len() - offset - 4 {
+            break;
+        }
+
+        entries.push(protobuf::parse_from_bytes::<M>(
+            &bytes[offset + 4..offset + 4 + len],
+        )?);
+        offset += 4 + len;
+    }
+    Ok((entries, offset))
+}
+
+fn storage_error(path: &Path, err: &io::Error) -> PbftError {
+    PbftError::StorageError(format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protos::pbft_message::PbftSignedMessage;
+    use std::env;
+    use std::fs::remove_file;
+
+    /// Create an entry that holds a (fake) signed message
+    fn mock_entry(num: u8) -> PbftStorageEntry {
+        let mut signed_msg = PbftSignedMessage::new();
+        signed_msg.set_message(vec![num; num as usize]);
+        signed_msg.set_signature(format!("signature {}", num));
+
+        let mut entry = PbftStorageEntry::new();
+        entry.set_message_type(String::from("Prepare"));
+        entry.set_message(signed_msg);
+        entry
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("pbft-storage-{}-{}", name, ::std::process::id()));
+        remove_file(&path).unwrap_or(());
+        path
+    }
+
+    /// Entries written to a file can be read back after the file is reopened
+    #[test]
+    fn file_storage() {
+        let path = temp_path("file_storage");
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            assert!(storage.load().unwrap().is_empty());
+            for num in 0..10 {
+                storage.append(&mock_entry(num)).unwrap();
+            }
+        }
+
+        let mut storage = FileStorage::open(&path).unwrap();
+        let entries = storage.load().unwrap();
+        assert_eq!(entries.len(), 10);
+        assert_eq!(entries[7], mock_entry(7));
+
+        // Compacting replaces everything, and appending keeps working afterwards
+        storage.compact(&[mock_entry(3), mock_entry(4)]).unwrap();
+        storage.append(&mock_entry(5)).unwrap();
+        assert_eq!(
+            FileStorage::open(&path).unwrap().load().unwrap(),
+            vec![mock_entry(3), mock_entry(4), mock_entry(5)]
+        );
+
+        // Unsynced entries are read back in order with the synced ones
+        storage.append_unsynced(&mock_entry(6)).unwrap();
+        storage.append(&mock_entry(7)).unwrap();
+        let entries = FileStorage::open(&path).unwrap().load().unwrap();
+        assert_eq!(entries[3..].to_vec(), vec![mock_entry(6), mock_entry(7)]);
+
+        remove_file(&path).unwrap();
+    }
+
+    /// An entry that was cut off by a crash is dropped, without losing the ones before it or the
+    /// ones written after the restart
+    #[test]
+    fn torn_write() {
+        let path = temp_path("torn_write");
+        {
+            let mut storage = FileStorage::open(&path).unwrap();
+            storage.append(&mock_entry(1)).unwrap();
+            storage.append(&mock_entry(2)).unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut storage = FileStorage::open(&path).unwrap();
+        assert_eq!(storage.load().unwrap(), vec![mock_entry(1)]);
+        storage.append(&mock_entry(3)).unwrap();
+        assert_eq!(
+            FileStorage::open(&path).unwrap().load().unwrap(),
+            vec![mock_entry(1), mock_entry(3)]
+        );
+
+        remove_file(&path).unwrap();
+    }
+
+    /// Clones of in-memory storage see each other's entries
+    #[test]
+    fn memory_storage() {
+        let storage = MemoryStorage::new();
+        let mut writer = storage.clone();
+        writer.append(&mock_entry(1)).unwrap();
+        assert_eq!(storage.entries(), vec![mock_entry(1)]);
+
+        writer.compact(&[]).unwrap();
+        assert!(storage.entries().is_empty());
+    }
+}
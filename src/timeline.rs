@@ -0,0 +1,576 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Timelines of the rounds of consensus on each block
+//!
+//! The node's state records when each round reaches each of its steps (the block arriving, the
+//! `PrePrepare`, the `Prepare` and `Commit` quorums, the validator checking and committing the
+//! block), along with when each peer's votes arrived. Once a block is committed, its timeline is
+//! kept in a rolling buffer, and the engine can write it to a CSV or JSON-lines file, so the
+//! slowest phase and the slowest peer of each round can be found after the fact.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use hex;
+use serde_json::Value;
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+use error::PbftError;
+use state::PbftPhase;
+
+/// How many of the latest completed timelines are kept in memory
+pub const RECENT_TIMELINES: usize = 32;
+
+/// The steps in a round of consensus on a block
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TimelineEvent {
+    /// The block arrived from the validator
+    BlockNew,
+
+    /// The primary's `PrePrepare` for the block was accepted
+    PrePrepare,
+
+    /// `2f + 1` matching `Prepare` messages arrived
+    PrepareQuorum,
+
+    /// The validator finished checking the block
+    BlockValid,
+
+    /// `2f + 1` matching `Commit` messages arrived
+    CommitQuorum,
+
+    /// The validator committed the block
+    BlockCommit,
+
+    /// The network moved to a new view before the block was committed
+    ViewChange,
+}
+
+/// The steps that every round goes through, in order
+pub const STEPS: &[TimelineEvent] = &[
+    TimelineEvent::BlockNew,
+    TimelineEvent::PrePrepare,
+    TimelineEvent::PrepareQuorum,
+    TimelineEvent::BlockValid,
+    TimelineEvent::CommitQuorum,
+    TimelineEvent::BlockCommit,
+];
+
+impl TimelineEvent {
+    /// The step that entering a phase marks
+    pub fn for_phase(phase: &PbftPhase) -> TimelineEvent {
+        match phase {
+            PbftPhase::PrePreparing => TimelineEvent::BlockNew,
+            PbftPhase::Preparing => TimelineEvent::PrePrepare,
+            PbftPhase::Checking => TimelineEvent::PrepareQuorum,
+            PbftPhase::Committing => TimelineEvent::BlockValid,
+            PbftPhase::Finished => TimelineEvent::CommitQuorum,
+            PbftPhase::NotStarted => TimelineEvent::BlockCommit,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TimelineEvent::BlockNew => "BlockNew",
+            TimelineEvent::PrePrepare => "PrePrepare",
+            TimelineEvent::PrepareQuorum => "PrepareQuorum",
+            TimelineEvent::BlockValid => "BlockValid",
+            TimelineEvent::CommitQuorum => "CommitQuorum",
+            TimelineEvent::BlockCommit => "BlockCommit",
+            TimelineEvent::ViewChange => "ViewChange",
+        }
+    }
+}
+
+/// When a peer's vote for the block arrived
+#[derive(Debug, PartialEq, Clone)]
+pub struct Vote {
+    pub peer_id: PeerId,
+
+    /// `Prepare` or `Commit`
+    pub msg_type: String,
+
+    pub view: u64,
+
+    /// How long after the start of the round the vote arrived
+    pub at: Duration,
+}
+
+/// What happened when during the round of consensus on one block
+#[derive(Debug, PartialEq, Clone)]
+pub struct Timeline {
+    pub seq_num: u64,
+
+    /// The view the round ended in (or is in now, if it hasn't ended)
+    pub view: u64,
+
+    pub block_id: Vec<u8>,
+
+    /// The steps the round went through, in order, with how long after the start of the round
+    /// they happened
+    pub events: Vec<(TimelineEvent, Duration)>,
+
+    /// The votes that arrived from other nodes, in order
+    pub votes: Vec<Vote>,
+
+    start: Instant,
+}
+
+impl Timeline {
+    /// Start a timeline for a round that started at `start`
+    pub fn new(start: Instant) -> Self {
+        Timeline {
+            seq_num: 0,
+            view: 0,
+            block_id: vec![],
+            events: vec![],
+            votes: vec![],
+            start,
+        }
+    }
+
+    /// Record that the round reached a step. The block only arrives once, so it's only recorded
+    /// the first time; everything else may happen again in a later view.
+    pub fn record(&mut self, event: TimelineEvent, now: Instant) {
+        if event == TimelineEvent::BlockNew && self.time_of(event).is_some() {
+            return;
+        }
+        self.events.push((event, now - self.start));
+    }
+
+    /// Record a peer's vote, unless the peer already voted the same way in the same view
+    pub fn record_vote(&mut self, peer_id: &PeerId, msg_type: &str, view: u64, now: Instant) {
+        let voted = self
+            .votes
+            .iter()
+            .any(|vote| &vote.peer_id == peer_id && vote.msg_type == msg_type && vote.view == view);
+        if !voted {
+            self.votes.push(Vote {
+                peer_id: peer_id.clone(),
+                msg_type: msg_type.to_string(),
+                view,
+                at: now - self.start,
+            });
+        }
+    }
+
+    /// When the round last reached a step, if it did
+    pub fn time_of(&self, event: TimelineEvent) -> Option<Duration> {
+        self.events
+            .iter()
+            .rev()
+            .find(|(recorded, _)| *recorded == event)
+            .map(|(_, at)| *at)
+    }
+
+    /// How long the round has taken so far
+    pub fn duration(&self) -> Duration {
+        self.events.last().map(|(_, at)| *at).unwrap_or_default()
+    }
+
+    /// The step that took the longest to get to from the one before it, and how long it took
+    pub fn slowest_phase(&self) -> Option<(TimelineEvent, Duration)> {
+        let mut slowest: Option<(TimelineEvent, Duration)> = None;
+        for pair in self.events.windows(2) {
+            let (event, at) = pair[1];
+            let took = at - pair[0].1;
+            if slowest.map_or(true, |(_, longest)| took > longest) {
+                slowest = Some((event, took));
+            }
+        }
+        slowest
+    }
+
+    /// The peer whose votes in the round's final view lagged furthest behind the first vote of
+    /// the same type, and how far behind it was
+    pub fn slowest_peer(&self) -> Option<(PeerId, Duration)> {
+        let votes: Vec<&Vote> = self
+            .votes
+            .iter()
+            .filter(|vote| vote.view == self.view)
+            .collect();
+        let first_of = |msg_type: &str| {
+            votes
+                .iter()
+                .filter(|vote| vote.msg_type == msg_type)
+                .map(|vote| vote.at)
+                .min()
+                .unwrap_or_default()
+        };
+
+        let mut slowest: Option<(PeerId, Duration)> = None;
+        for vote in &votes {
+            let behind = vote.at - first_of(&vote.msg_type);
+            if slowest
+                .as_ref()
+                .map_or(true, |(_, furthest)| behind > *furthest)
+            {
+                slowest = Some((vote.peer_id.clone(), behind));
+            }
+        }
+        slowest
+    }
+
+    /// The timeline as a JSON object, with times in milliseconds since the start of the round
+    pub fn to_json(&self) -> Value {
+        json!({
+            "seq_num": self.seq_num,
+            "view": self.view,
+            "block_id": hex::encode(&self.block_id),
+            "duration_ms": millis(self.duration()),
+            "events": self.events.iter().map(|(event, at)| json!({
+                "event": event.as_str(),
+                "at_ms": millis(*at),
+            })).collect::<Vec<_>>(),
+            "votes": self.votes.iter().map(|vote| json!({
+                "peer": peer_hex(&vote.peer_id),
+                "type": vote.msg_type,
+                "view": vote.view,
+                "at_ms": millis(vote.at),
+            })).collect::<Vec<_>>(),
+            "slowest_phase": self.slowest_phase().map(|(event, took)| json!({
+                "event": event.as_str(),
+                "duration_ms": millis(took),
+            })),
+            "slowest_peer": self.slowest_peer().map(|(peer_id, behind)| json!({
+                "peer": peer_hex(&peer_id),
+                "behind_ms": millis(behind),
+            })),
+        })
+    }
+
+    /// The columns of a CSV file of timelines
+    pub fn csv_header() -> String {
+        let steps: Vec<String> = STEPS
+            .iter()
+            .map(|step| format!("{}_ms", step.as_str()))
+            .collect();
+        format!(
+            "seq_num,view,block_id,{},view_changes,slowest_phase,slowest_phase_ms,slowest_peer,\
+             slowest_peer_ms",
+            steps.join(",")
+        )
+    }
+
+    /// The timeline as a row of a CSV file; steps the round didn't reach are left empty
+    pub fn to_csv(&self) -> String {
+        let steps: Vec<String> = STEPS
+            .iter()
+            .map(|step| {
+                self.time_of(*step)
+                    .map_or(String::new(), |at| millis(at).to_string())
+            })
+            .collect();
+        let view_changes = self
+            .events
+            .iter()
+            .filter(|(event, _)| *event == TimelineEvent::ViewChange)
+            .count();
+        let (slowest_phase, slowest_phase_ms) = self
+            .slowest_phase()
+            .map_or((String::new(), String::new()), |(event, took)| {
+                (event.as_str().to_string(), millis(took).to_string())
+            });
+        let (slowest_peer, slowest_peer_ms) = self
+            .slowest_peer()
+            .map_or((String::new(), String::new()), |(peer_id, behind)| {
+                (peer_hex(&peer_id), millis(behind).to_string())
+            });
+        format!(
+            "{},{},{},{},{},{},{},{},{}",
+            self.seq_num,
+            self.view,
+            hex::encode(&self.block_id),
+            steps.join(","),
+            view_changes,
+            slowest_phase,
+            slowest_phase_ms,
+            slowest_peer,
+            slowest_peer_ms
+        )
+    }
+}
+
+/// The timelines of the rounds a node is working on, and of the latest rounds it completed
+#[derive(Debug, Default)]
+pub struct Timelines {
+    /// The round for the working block
+    pub working: Option<Timeline>,
+
+    /// The rounds for the blocks in the pipeline, by sequence number
+    pub pipelined: BTreeMap<u64, Timeline>,
+
+    /// The latest completed rounds, oldest first
+    recent: VecDeque<Timeline>,
+
+    /// How many of the newest rounds in `recent` haven't been taken with `take_completed` yet
+    untaken: usize,
+}
+
+impl Timelines {
+    pub fn new() -> Self {
+        Timelines::default()
+    }
+
+    /// Finish a round, and keep its timeline with the latest ones
+    pub fn complete(&mut self, timeline: Timeline) {
+        if self.recent.len() == RECENT_TIMELINES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(timeline);
+        self.untaken = ::std::cmp::min(self.untaken + 1, self.recent.len());
+    }
+
+    /// Stop keeping track of the rounds in progress, which won't be completed
+    pub fn abandon(&mut self) {
+        self.working = None;
+        self.pipelined.clear();
+    }
+
+    /// The latest completed rounds, oldest first
+    pub fn recent(&self) -> impl Iterator<Item = &Timeline> {
+        self.recent.iter()
+    }
+
+    /// Take the rounds that were completed since the last time; rounds that were pushed out of
+    /// the rolling buffer in the meantime are lost
+    pub fn take_completed(&mut self) -> Vec<Timeline> {
+        let skip = self.recent.len() - self.untaken;
+        self.untaken = 0;
+        self.recent.iter().skip(skip).cloned().collect()
+    }
+}
+
+/// The formats that timelines can be written in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TimelineFormat {
+    /// A header, then one row per round
+    Csv,
+
+    /// One JSON object per round
+    JsonLines,
+}
+
+/// Appends completed timelines to a file
+pub struct TimelineWriter {
+    path: PathBuf,
+    file: File,
+    format: TimelineFormat,
+}
+
+impl TimelineWriter {
+    /// Open the timeline file at `path`, creating it if it doesn't exist yet; a new CSV file
+    /// starts with a header
+    pub fn open(path: &Path, format: TimelineFormat) -> Result<Self, PbftError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| timeline_error(path, &err))?;
+        let empty = fs::metadata(path)
+            .map(|metadata| metadata.len() == 0)
+            .map_err(|err| timeline_error(path, &err))?;
+        if format == TimelineFormat::Csv && empty {
+            writeln!(file, "{}", Timeline::csv_header())
+                .map_err(|err| timeline_error(path, &err))?;
+        }
+        Ok(TimelineWriter {
+            path: path.to_path_buf(),
+            file,
+            format,
+        })
+    }
+
+    /// Write a completed timeline
+    pub fn write(&mut self, timeline: &Timeline) -> Result<(), PbftError> {
+        let line = match self.format {
+            TimelineFormat::Csv => timeline.to_csv(),
+            TimelineFormat::JsonLines => timeline.to_json().to_string(),
+        };
+        writeln!(self.file, "{}", line).map_err(|err| timeline_error(&self.path, &err))
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+fn peer_hex(peer_id: &PeerId) -> String {
+    hex::encode(Vec::<u8>::from(peer_id.clone()))
+}
+
+fn timeline_error(path: &Path, err: &io::Error) -> PbftError {
+    PbftError::TimelineError(format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn peer(id: u8) -> PeerId {
+        PeerId::from(vec![id])
+    }
+
+    // A round where node 3's votes were the last to arrive, and checking the block took longest
+    fn mock_timeline() -> Timeline {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        let mut timeline = Timeline::new(start);
+        timeline.seq_num = 5;
+        timeline.view = 1;
+        timeline.block_id = vec![0xab, 0xcd];
+        timeline.record(TimelineEvent::BlockNew, at(0));
+        timeline.record(TimelineEvent::PrePrepare, at(10));
+        timeline.record_vote(&peer(1), "Prepare", 1, at(20));
+        timeline.record_vote(&peer(3), "Prepare", 1, at(35));
+        timeline.record(TimelineEvent::PrepareQuorum, at(35));
+        timeline.record(TimelineEvent::BlockValid, at(135));
+        timeline.record_vote(&peer(1), "Commit", 1, at(140));
+        timeline.record_vote(&peer(3), "Commit", 1, at(190));
+        timeline.record(TimelineEvent::CommitQuorum, at(190));
+        timeline.record(TimelineEvent::BlockCommit, at(200));
+        timeline
+    }
+
+    /// The slowest phase is the longest gap between two steps, and the slowest peer is the one
+    /// whose vote lagged furthest behind the first vote of the same type
+    #[test]
+    fn slowest() {
+        let timeline = mock_timeline();
+        assert_eq!(timeline.duration(), Duration::from_millis(200));
+        assert_eq!(
+            timeline.time_of(TimelineEvent::BlockValid),
+            Some(Duration::from_millis(135))
+        );
+        assert_eq!(
+            timeline.slowest_phase(),
+            Some((TimelineEvent::BlockValid, Duration::from_millis(100)))
+        );
+        assert_eq!(
+            timeline.slowest_peer(),
+            Some((peer(3), Duration::from_millis(50)))
+        );
+
+        // Votes from an earlier view don't count, and neither do repeated votes
+        let mut timeline = timeline;
+        let late = timeline.start + Duration::from_millis(500);
+        timeline.record_vote(&peer(2), "Prepare", 0, late);
+        timeline.record_vote(&peer(1), "Commit", 1, late);
+        assert_eq!(timeline.votes.len(), 5);
+        assert_eq!(
+            timeline.slowest_peer(),
+            Some((peer(3), Duration::from_millis(50)))
+        );
+
+        // The block only arrives once
+        timeline.record(TimelineEvent::BlockNew, late);
+        assert_eq!(
+            timeline.time_of(TimelineEvent::BlockNew),
+            Some(Duration::from_millis(0))
+        );
+    }
+
+    /// Timelines are written with the time of each step, and what was slowest
+    #[test]
+    fn formats() {
+        let timeline = mock_timeline();
+        assert_eq!(
+            Timeline::csv_header(),
+            "seq_num,view,block_id,BlockNew_ms,PrePrepare_ms,PrepareQuorum_ms,BlockValid_ms,\
+             CommitQuorum_ms,BlockCommit_ms,view_changes,slowest_phase,slowest_phase_ms,\
+             slowest_peer,slowest_peer_ms"
+        );
+        assert_eq!(
+            timeline.to_csv(),
+            "5,1,abcd,0,10,35,135,190,200,0,BlockValid,100,03,50"
+        );
+
+        let json = timeline.to_json();
+        assert_eq!(json["duration_ms"], 200);
+        assert_eq!(json["events"][2]["event"], "PrepareQuorum");
+        assert_eq!(json["events"][2]["at_ms"], 35);
+        assert_eq!(json["votes"][1]["peer"], "03");
+        assert_eq!(json["slowest_phase"]["event"], "BlockValid");
+        assert_eq!(json["slowest_peer"]["behind_ms"], 50);
+
+        // A round that didn't get anywhere has nothing to report
+        let mut empty = Timeline::new(Instant::now());
+        empty.record(TimelineEvent::BlockNew, empty.start);
+        assert_eq!(empty.to_csv(), "0,0,,0,,,,,,0,,,,");
+        assert_eq!(empty.to_json()["slowest_peer"], Value::Null);
+    }
+
+    /// Only the latest timelines are kept, and each completed timeline is only taken once
+    #[test]
+    fn rolling_buffer() {
+        let mut timelines = Timelines::new();
+        for seq_num in 1..=(RECENT_TIMELINES as u64 + 5) {
+            let mut timeline = Timeline::new(Instant::now());
+            timeline.seq_num = seq_num;
+            timelines.complete(timeline);
+            if seq_num == 3 {
+                let taken: Vec<u64> = timelines
+                    .take_completed()
+                    .iter()
+                    .map(|timeline| timeline.seq_num)
+                    .collect();
+                assert_eq!(taken, vec![1, 2, 3]);
+            }
+        }
+
+        assert_eq!(timelines.recent().count(), RECENT_TIMELINES);
+        assert_eq!(timelines.recent().next().unwrap().seq_num, 6);
+        assert_eq!(timelines.take_completed().len(), RECENT_TIMELINES);
+        assert!(timelines.take_completed().is_empty());
+    }
+
+    /// A CSV file gets a header when it's created, but not when it's reopened
+    #[test]
+    fn timeline_file() {
+        let path = env::temp_dir().join(format!("pbft-timelines-{}.csv", ::std::process::id()));
+        fs::remove_file(&path).unwrap_or(());
+
+        let timeline = mock_timeline();
+        TimelineWriter::open(&path, TimelineFormat::Csv)
+            .unwrap()
+            .write(&timeline)
+            .unwrap();
+        TimelineWriter::open(&path, TimelineFormat::Csv)
+            .unwrap()
+            .write(&timeline)
+            .unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                Timeline::csv_header().as_str(),
+                timeline.to_csv().as_str(),
+                timeline.to_csv().as_str(),
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -17,28 +17,87 @@
 
 //! Timing-related structures
 
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
 use std::time::{Duration, Instant};
 
+/// A source of the current time; everything that needs to know how much time has passed gets it
+/// from a `Clock`, so that tests can decide how fast time goes by
+pub trait Clock: fmt::Debug {
+    fn now(&self) -> Instant;
+}
+
+/// The system's clock
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until it's moved forward with `advance`, for tests and for replaying
+/// traces
+#[derive(Debug)]
+pub struct MockClock {
+    now: Cell<Instant>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            now: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Move the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+/// Create a `MockClock` that can be shared with the structures that use it, for use in tests
+#[cfg(test)]
+pub fn mock_clock() -> Rc<MockClock> {
+    Rc::new(MockClock::new())
+}
+
 /// Encapsulates calling a function every so often
 pub struct Ticker {
+    clock: Rc<Clock>,
     last: Instant,
     timeout: Duration,
 }
 
 impl Ticker {
-    pub fn new(period: Duration) -> Self {
+    pub fn new(period: Duration, clock: Rc<Clock>) -> Self {
         Ticker {
-            last: Instant::now(),
+            last: clock.now(),
             timeout: period,
+            clock,
         }
     }
 
     // Do some work if the timeout has expired
     pub fn tick<T: FnMut()>(&mut self, mut callback: T) {
-        let elapsed = Instant::now() - self.last;
+        let elapsed = self.clock.now() - self.last;
         if elapsed >= self.timeout {
             callback();
-            self.last = Instant::now();
+            self.last = self.clock.now();
         }
     }
 }
@@ -54,23 +113,25 @@ enum TimeoutState {
 /// Check back on this timer every so often to see if it's expired
 #[derive(Debug)]
 pub struct Timeout {
+    clock: Rc<Clock>,
     state: TimeoutState,
     duration: Duration,
     start: Instant,
 }
 
 impl Timeout {
-    pub fn new(duration: Duration) -> Self {
+    pub fn new(duration: Duration, clock: Rc<Clock>) -> Self {
         Timeout {
             state: TimeoutState::Inactive,
             duration,
-            start: Instant::now(),
+            start: clock.now(),
+            clock,
         }
     }
 
     /// Update the timer state, and check if the timer is expired
     pub fn check_expired(&mut self) -> bool {
-        if self.state == TimeoutState::Active && Instant::now() - self.start > self.duration {
+        if self.state == TimeoutState::Active && self.clock.now() - self.start > self.duration {
             self.state = TimeoutState::Expired;
         }
         match self.state {
@@ -81,12 +142,31 @@ impl Timeout {
 
     pub fn start(&mut self) {
         self.state = TimeoutState::Active;
-        self.start = Instant::now();
+        self.start = self.clock.now();
     }
 
     pub fn stop(&mut self) {
         self.state = TimeoutState::Inactive;
-        self.start = Instant::now();
+        self.start = self.clock.now();
+    }
+
+    /// How long the timer lasts once it's started
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// How long is left until the timer expires (zero if it already has), or `None` if it isn't
+    /// running
+    pub fn remaining(&self) -> Option<Duration> {
+        match self.state {
+            TimeoutState::Inactive => None,
+            TimeoutState::Expired => Some(Duration::from_secs(0)),
+            TimeoutState::Active => Some(
+                self.duration
+                    .checked_sub(self.clock.now() - self.start)
+                    .unwrap_or_default(),
+            ),
+        }
     }
 }
 
@@ -94,57 +174,65 @@ impl Timeout {
 mod tests {
     use super::*;
 
-    macro_rules! assert_tolerance {
-        ($val1:expr, $val2:expr, $tol:expr) => {
-            if $val2 > $val1 && $val2 - $val1 > $tol {
-                panic!(
-                    "Value is not within tolerance ({:?} - {:?} > {:?})",
-                    $val2, $val1, $tol
-                );
-            }
-            if $val1 > $val2 && $val1 - $val2 > $tol {
-                panic!(
-                    "Value is not within tolerance ({:?} - {:?} > {:?})",
-                    $val1, $val2, $tol
-                );
-            }
-        };
-    }
-
-    /// Tell the ticker to wait for 100ms, then see if it actually waited 100 +/- 1ms
+    /// Tell the ticker to wait for 100ms, then make sure it only calls back once 100ms have
+    /// passed, and then not again until another 100ms have passed
     #[test]
     fn ticker() {
-        let time = Duration::from_millis(100);
-        let mut t = Ticker::new(time);
-        let start_time = Instant::now();
-        let mut end_time = Instant::now();
-        let mut triggered = false;
-        while !triggered {
-            t.tick(|| {
-                end_time = Instant::now();
-                triggered = true;
-            })
-        }
-        assert_tolerance!(end_time - start_time, time, Duration::from_millis(1));
+        let clock = mock_clock();
+        let mut t = Ticker::new(Duration::from_millis(100), clock.clone());
+        let mut calls = 0;
+
+        t.tick(|| calls += 1);
+        assert_eq!(calls, 0);
+
+        clock.advance(Duration::from_millis(99));
+        t.tick(|| calls += 1);
+        assert_eq!(calls, 0);
+
+        clock.advance(Duration::from_millis(1));
+        t.tick(|| calls += 1);
+        assert_eq!(calls, 1);
+
+        clock.advance(Duration::from_millis(50));
+        t.tick(|| calls += 1);
+        assert_eq!(calls, 1);
+
+        clock.advance(Duration::from_millis(50));
+        t.tick(|| calls += 1);
+        assert_eq!(calls, 2);
     }
 
     /// Create a Timeout that lasts for 100ms and check that it expires anytime after 100ms have
     /// passed. Check whether `.start()` and `.stop()` work as expected.
     #[test]
     fn timeout() {
-        let start_time = Instant::now();
-        let mut t = Timeout::new(Duration::from_millis(100));
+        let clock = mock_clock();
+        let mut t = Timeout::new(Duration::from_millis(100), clock.clone());
         assert_eq!(t.state, TimeoutState::Inactive);
-        assert_tolerance!(t.start, start_time, Duration::from_millis(1));
+        assert_eq!(t.start, clock.now());
+
+        // Time doesn't count until the timer is started
+        clock.advance(Duration::from_millis(200));
+        assert!(!t.check_expired());
+        assert_eq!(t.remaining(), None);
 
         t.start();
         assert_eq!(t.state, TimeoutState::Active);
-        ::std::thread::sleep(Duration::from_millis(110));
+        clock.advance(Duration::from_millis(60));
+        assert_eq!(t.remaining(), Some(Duration::from_millis(40)));
+        clock.advance(Duration::from_millis(40));
+        assert!(!t.check_expired());
+        assert_eq!(t.remaining(), Some(Duration::from_millis(0)));
 
+        clock.advance(Duration::from_millis(10));
         assert!(t.check_expired());
         assert_eq!(t.state, TimeoutState::Expired);
 
+        assert_eq!(t.remaining(), Some(Duration::from_millis(0)));
+
         t.stop();
         assert_eq!(t.state, TimeoutState::Inactive);
+        assert!(!t.check_expired());
+        assert_eq!(t.remaining(), None);
     }
 }
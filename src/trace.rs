@@ -0,0 +1,448 @@
+/*
+ * Copyright 2018 Bitwise IO, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * -----------------------------------------------------------------------------
+ */
+
+//! Traces of the updates a running engine receives, so that a node's run can be replayed offline
+//! (see the `replay` module and the `pbft-replay` binary)
+//!
+//! A trace file holds `PbftTraceEntry`s, written the same way `FileStorage` writes its entries.
+//! The first entry records what the engine started out with; each entry after it is an update
+//! from the validator, or a timeout while waiting for one, along with when it arrived.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+use hex;
+use protobuf::RepeatedField;
+use sawtooth_sdk::consensus::engine::{
+    Block, BlockId, PeerId, PeerInfo, PeerMessage, StartupState, Update,
+};
+
+use error::PbftError;
+use protos::pbft_message::{PbftTraceBlock, PbftTraceEntry, PbftTraceStartup};
+use storage::{decode_entries, encode_entry};
+use timing::Clock;
+
+/// Writes a trace of the updates an engine receives to a file
+pub struct TraceRecorder {
+    path: PathBuf,
+    file: File,
+    clock: Rc<Clock>,
+    start: Instant,
+}
+
+impl TraceRecorder {
+    /// Create the trace file at `path`, replacing it if it already exists; times in the trace are
+    /// measured from now
+    pub fn create(path: &Path, clock: Rc<Clock>) -> Result<Self, PbftError> {
+        let file = File::create(path).map_err(|err| trace_error(path, &err))?;
+        Ok(TraceRecorder {
+            path: path.to_path_buf(),
+            file,
+            start: clock.now(),
+            clock,
+        })
+    }
+
+    /// Record what the engine started out with, along with the on-chain settings it loaded; this
+    /// has to be recorded before any updates
+    pub fn record_startup(
+        &mut self,
+        startup_state: &StartupState,
+        settings: &HashMap<String, String>,
+    ) -> Result<(), PbftError> {
+        let mut startup = PbftTraceStartup::new();
+        startup.set_chain_head(trace_block(&startup_state.chain_head));
+        startup.set_peers(RepeatedField::from_vec(
+            startup_state
+                .peers
+                .iter()
+                .map(|info| Vec::<u8>::from(info.peer_id.clone()))
+                .collect(),
+        ));
+        startup.set_local_peer_id(Vec::<u8>::from(
+            startup_state.local_peer_info.peer_id.clone(),
+        ));
+        startup.set_settings(settings.clone());
+
+        let mut entry = PbftTraceEntry::new();
+        entry.set_entry_type(String::from("Startup"));
+        entry.set_startup(startup);
+        self.write(entry)
+    }
+
+    /// Record an update from the validator, or a timeout while waiting for one; losing the
+    /// connection to the validator isn't recorded, since the engine just stops
+    pub fn record_update(
+        &mut self,
+        incoming: &Result<Update, RecvTimeoutError>,
+    ) -> Result<(), PbftError> {
+        let mut entry = PbftTraceEntry::new();
+        match incoming {
+            Ok(update) => {
+                entry.set_entry_type(String::from(update_type(update)));
+                match update {
+                    Update::BlockNew(block) => entry.set_block(trace_block(block)),
+                    Update::BlockValid(block_id)
+                    | Update::BlockInvalid(block_id)
+                    | Update::BlockCommit(block_id) => {
+                        entry.set_block_id(Vec::<u8>::from(block_id.clone()))
+                    }
+                    Update::PeerMessage(message, sender_id) => {
+                        entry.set_peer_id(Vec::<u8>::from(sender_id.clone()));
+                        entry.set_message_type(message.message_type.clone());
+                        entry.set_message_content(message.content.clone());
+                    }
+                    Update::PeerConnected(info) => {
+                        entry.set_peer_id(Vec::<u8>::from(info.peer_id.clone()))
+                    }
+                    Update::PeerDisconnected(peer_id) => {
+                        entry.set_peer_id(Vec::<u8>::from(peer_id.clone()))
+                    }
+                    Update::Shutdown => (),
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => entry.set_entry_type(String::from("Timeout")),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        self.write(entry)
+    }
+
+    fn write(&mut self, mut entry: PbftTraceEntry) -> Result<(), PbftError> {
+        entry.set_elapsed_micros(to_micros(self.clock.now() - self.start));
+        let bytes = encode_entry(&entry)?;
+        self.file
+            .write_all(&bytes)
+            .map_err(|err| trace_error(&self.path, &err))
+    }
+}
+
+/// A trace that has been read back from a file
+pub struct Trace {
+    /// What the engine started out with
+    pub startup_state: StartupState,
+
+    /// The on-chain settings the engine was configured with
+    pub settings: HashMap<String, String>,
+
+    /// When the engine started, measured from when recording started
+    pub start: Duration,
+
+    /// Everything that happened after the engine started, in order
+    pub events: Vec<TraceEvent>,
+}
+
+/// An update from the validator, or `None` if the engine timed out waiting for one, along with
+/// when it arrived (measured from when recording started)
+pub struct TraceEvent {
+    pub elapsed: Duration,
+    pub update: Option<Update>,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let elapsed = self.elapsed.as_secs() as f64 + f64::from(self.elapsed.subsec_micros()) / 1e6;
+        write!(f, "[{:>12.6}] ", elapsed)?;
+        match self.update {
+            None => write!(f, "Timeout"),
+            Some(ref update) => {
+                write!(f, "{}", update_type(update))?;
+                match update {
+                    Update::BlockNew(block) => write!(
+                        f,
+                        " {} (block {}, from {})",
+                        short_hex(&block.block_id),
+                        block.block_num,
+                        short_hex(&block.signer_id)
+                    ),
+                    Update::BlockValid(block_id)
+                    | Update::BlockInvalid(block_id)
+                    | Update::BlockCommit(block_id) => write!(f, " {}", short_hex(block_id)),
+                    Update::PeerMessage(message, sender_id) => {
+                        write!(f, " {} from {}", message.message_type, short_hex(sender_id))
+                    }
+                    Update::PeerConnected(info) => write!(f, " {}", short_hex(&info.peer_id)),
+                    Update::PeerDisconnected(peer_id) => write!(f, " {}", short_hex(peer_id)),
+                    Update::Shutdown => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Read a trace back from the file at `path`. If the last entry was cut off (because the engine
+/// stopped while it was being written), it's left out.
+pub fn load_trace(path: &Path) -> Result<Trace, PbftError> {
+    let bytes = fs::read(path).map_err(|err| trace_error(path, &err))?;
+    let (entries, len) = decode_entries::<PbftTraceEntry>(&bytes).map_err(|err| {
+        PbftError::TraceError(format!("Corrupt entry in {}: {}", path.display(), err))
+    })?;
+    if len < bytes.len() {
+        warn!("Ignoring incomplete entry at the end of {}", path.display());
+    }
+
+    let mut entries = entries.into_iter();
+    let mut first = match entries.next() {
+        Some(entry) if entry.get_entry_type() == "Startup" => entry,
+        _ => {
+            return Err(PbftError::TraceError(format!(
+                "{} doesn't start with a Startup entry",
+                path.display()
+            )))
+        }
+    };
+    let mut startup = first.take_startup();
+    let startup_state = StartupState {
+        chain_head: block_from_trace(startup.get_chain_head()),
+        peers: startup
+            .get_peers()
+            .iter()
+            .map(|peer_id| PeerInfo {
+                peer_id: PeerId::from(peer_id.clone()),
+            })
+            .collect(),
+        local_peer_info: PeerInfo {
+            peer_id: PeerId::from(startup.get_local_peer_id().to_vec()),
+        },
+    };
+
+    let events = entries
+        .map(|entry| {
+            Ok(TraceEvent {
+                elapsed: Duration::from_micros(entry.get_elapsed_micros()),
+                update: update_from_entry(&entry)?,
+            })
+        })
+        .collect::<Result<Vec<TraceEvent>, PbftError>>()
+        .map_err(|err| PbftError::TraceError(format!("{}: {}", path.display(), err)))?;
+
+    Ok(Trace {
+        startup_state,
+        settings: startup.take_settings(),
+        start: Duration::from_micros(first.get_elapsed_micros()),
+        events,
+    })
+}
+
+// Recreate the update that a trace entry recorded (`None` for a timeout)
+fn update_from_entry(entry: &PbftTraceEntry) -> Result<Option<Update>, PbftError> {
+    let block_id = || BlockId::from(entry.get_block_id().to_vec());
+    let peer_id = || PeerId::from(entry.get_peer_id().to_vec());
+    let update = match entry.get_entry_type() {
+        "Timeout" => return Ok(None),
+        "BlockNew" => Update::BlockNew(block_from_trace(entry.get_block())),
+        "BlockValid" => Update::BlockValid(block_id()),
+        "BlockInvalid" => Update::BlockInvalid(block_id()),
+        "BlockCommit" => Update::BlockCommit(block_id()),
+        "PeerMessage" => Update::PeerMessage(
+            PeerMessage {
+                message_type: String::from(entry.get_message_type()),
+                content: entry.get_message_content().to_vec(),
+            },
+            peer_id(),
+        ),
+        "PeerConnected" => Update::PeerConnected(PeerInfo { peer_id: peer_id() }),
+        "PeerDisconnected" => Update::PeerDisconnected(peer_id()),
+        "Shutdown" => Update::Shutdown,
+        entry_type => {
+            return Err(PbftError::TraceError(format!(
+                "Unknown entry type: {}",
+                entry_type
+            )))
+        }
+    };
+    Ok(Some(update))
+}
+
+fn update_type(update: &Update) -> &'static str {
+    match update {
+        Update::BlockNew(_) => "BlockNew",
+        Update::BlockValid(_) => "BlockValid",
+        Update::BlockInvalid(_) => "BlockInvalid",
+        Update::BlockCommit(_) => "BlockCommit",
+        Update::PeerMessage(_, _) => "PeerMessage",
+        Update::PeerConnected(_) => "PeerConnected",
+        Update::PeerDisconnected(_) => "PeerDisconnected",
+        Update::Shutdown => "Shutdown",
+    }
+}
+
+fn trace_block(block: &Block) -> PbftTraceBlock {
+    let mut trace_block = PbftTraceBlock::new();
+    trace_block.set_block_id(Vec::<u8>::from(block.block_id.clone()));
+    trace_block.set_previous_id(Vec::<u8>::from(block.previous_id.clone()));
+    trace_block.set_signer_id(Vec::<u8>::from(block.signer_id.clone()));
+    trace_block.set_block_num(block.block_num);
+    trace_block.set_payload(block.payload.clone());
+    trace_block.set_summary(block.summary.clone());
+    trace_block
+}
+
+fn block_from_trace(trace_block: &PbftTraceBlock) -> Block {
+    Block {
+        block_id: BlockId::from(trace_block.get_block_id().to_vec()),
+        previous_id: BlockId::from(trace_block.get_previous_id().to_vec()),
+        signer_id: PeerId::from(trace_block.get_signer_id().to_vec()),
+        block_num: trace_block.get_block_num(),
+        payload: trace_block.get_payload().to_vec(),
+        summary: trace_block.get_summary().to_vec(),
+    }
+}
+
+fn to_micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+// The first few hex digits of an ID, which is enough to tell blocks and peers apart in a trace
+fn short_hex<T: Clone + Into<Vec<u8>>>(id: &T) -> String {
+    let id_hex = hex::encode(id.clone().into());
+    id_hex[..id_hex.len().min(6)].to_string()
+}
+
+fn trace_error(path: &Path, err: &io::Error) -> PbftError {
+    PbftError::TraceError(format!("{}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::mock_config;
+    use std::env;
+    use std::fs::{remove_file, OpenOptions};
+    use timing::mock_clock;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("pbft-trace-{}-{}", name, ::std::process::id()));
+        remove_file(&path).unwrap_or(());
+        path
+    }
+
+    fn mock_block(num: u64) -> Block {
+        Block {
+            block_id: BlockId::from(vec![num as u8; 4]),
+            previous_id: BlockId::from(vec![num as u8 - 1; 4]),
+            signer_id: PeerId::from(vec![1, 2, 3]),
+            block_num: num,
+            payload: vec![num as u8],
+            summary: vec![],
+        }
+    }
+
+    /// Everything the engine records can be read back, with the times it was recorded at, even if
+    /// the last entry was cut off
+    #[test]
+    fn record_and_load() {
+        let path = temp_path("record_and_load");
+        let clock = mock_clock();
+        let peers = mock_config(4).peers;
+        let mut settings = HashMap::new();
+        settings.insert(
+            String::from("sawtooth.consensus.pbft.block_duration"),
+            String::from("100"),
+        );
+
+        {
+            let mut recorder = TraceRecorder::create(&path, clock.clone()).unwrap();
+            clock.advance(Duration::from_millis(5));
+            recorder
+                .record_startup(
+                    &StartupState {
+                        chain_head: mock_block(1),
+                        peers: vec![PeerInfo {
+                            peer_id: peers[1].clone(),
+                        }],
+                        local_peer_info: PeerInfo {
+                            peer_id: peers[0].clone(),
+                        },
+                    },
+                    &settings,
+                )
+                .unwrap();
+
+            clock.advance(Duration::from_millis(10));
+            recorder
+                .record_update(&Ok(Update::BlockNew(mock_block(2))))
+                .unwrap();
+            recorder
+                .record_update(&Err(RecvTimeoutError::Timeout))
+                .unwrap();
+            recorder
+                .record_update(&Err(RecvTimeoutError::Disconnected))
+                .unwrap();
+            clock.advance(Duration::from_millis(1));
+            recorder
+                .record_update(&Ok(Update::PeerMessage(
+                    PeerMessage {
+                        message_type: String::from("Prepare"),
+                        content: vec![4, 5, 6],
+                    },
+                    peers[2].clone(),
+                )))
+                .unwrap();
+            recorder.record_update(&Ok(Update::Shutdown)).unwrap();
+        }
+
+        // Cut off the end of the last entry
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let trace = load_trace(&path).unwrap();
+        assert_eq!(trace.start, Duration::from_millis(5));
+        assert_eq!(trace.settings, settings);
+        assert_eq!(
+            trace.startup_state.chain_head.block_id,
+            mock_block(1).block_id
+        );
+        assert_eq!(trace.startup_state.peers.len(), 1);
+        assert_eq!(trace.startup_state.peers[0].peer_id, peers[1]);
+        assert_eq!(trace.startup_state.local_peer_info.peer_id, peers[0]);
+
+        assert_eq!(trace.events.len(), 3);
+        assert_eq!(trace.events[0].elapsed, Duration::from_millis(15));
+        match trace.events[0].update {
+            Some(Update::BlockNew(ref block)) => {
+                assert_eq!(block.block_id, mock_block(2).block_id);
+                assert_eq!(block.previous_id, mock_block(2).previous_id);
+                assert_eq!(block.block_num, 2);
+                assert_eq!(block.payload, vec![2]);
+            }
+            _ => panic!("Wrong update"),
+        }
+        assert!(trace.events[1].update.is_none());
+        assert_eq!(trace.events[2].elapsed, Duration::from_millis(16));
+        match trace.events[2].update {
+            Some(Update::PeerMessage(ref message, ref sender_id)) => {
+                assert_eq!(message.message_type, "Prepare");
+                assert_eq!(message.content, vec![4, 5, 6]);
+                assert_eq!(sender_id, &peers[2]);
+            }
+            _ => panic!("Wrong update"),
+        }
+
+        remove_file(&path).unwrap();
+    }
+}